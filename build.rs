@@ -0,0 +1,18 @@
+use std::process::Command;
+
+fn main() {
+    // Only meaningful when building from a Git checkout; crates.io tarballs don't have the history
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok());
+
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=XBGDUMP_GIT_HASH={}", hash.trim());
+    }
+}
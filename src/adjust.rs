@@ -0,0 +1,216 @@
+//! Tone adjustments applied to the final image before encoding.
+
+use image::DynamicImage;
+
+/// Gamma, brightness and contrast changes, grayscale conversion and inversion,
+/// applied in that order.
+///
+/// The default changes nothing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adjustments {
+    /// Values above 1 brighten the mid-tones, values below 1 darken them.
+    pub gamma: f32,
+    /// Added to every color channel, from -255 to 255.
+    pub brightness: i32,
+    /// Contrast change in percent, the same curve as [`DynamicImage::adjust_contrast`] uses.
+    pub contrast: f32,
+    /// Convert to grayscale, keeping the alpha channel if there is one.
+    pub grayscale: bool,
+    /// Invert the colors, but not the alpha channel.
+    pub invert: bool,
+}
+
+impl Default for Adjustments {
+    fn default() -> Self {
+        Self {
+            gamma: 1.0,
+            brightness: 0,
+            contrast: 0.0,
+            grayscale: false,
+            invert: false,
+        }
+    }
+}
+
+impl Adjustments {
+    /// Whether applying these would leave the image as it is.
+    pub fn is_identity(&self) -> bool {
+        *self == Adjustments::default()
+    }
+}
+
+/// Applies the adjustments to all color channels, leaving alpha alone.
+pub fn adjust(mut img: DynamicImage, adjustments: &Adjustments) -> DynamicImage {
+    if adjustments.gamma != 1.0 {
+        log!(Info, "Applying gamma {}.", adjustments.gamma);
+        apply_gamma(&mut img, adjustments.gamma);
+    }
+    if adjustments.brightness != 0 {
+        log!(Info, "Changing brightness by {}.", adjustments.brightness);
+        img = img.brighten(adjustments.brightness);
+    }
+    if adjustments.contrast != 0.0 {
+        log!(Info, "Changing contrast by {}%.", adjustments.contrast);
+        apply_contrast(&mut img, adjustments.contrast);
+    }
+    if adjustments.grayscale {
+        log!(Info, "Converting to grayscale.");
+        img = grayscale(&img);
+    }
+    if adjustments.invert {
+        log!(Info, "Inverting colors.");
+        // Leaves alpha alone, so masked areas stay transparent
+        img.invert();
+    }
+
+    img
+}
+
+/// Converts to grayscale by luminance (Rec. 709 weights) rather than plain averaging,
+/// keeping alpha and 16-bit precision where present.
+pub fn grayscale(img: &DynamicImage) -> DynamicImage {
+    let color = img.color();
+    match (
+        color.has_alpha(),
+        color.bytes_per_pixel() / color.channel_count() > 1,
+    ) {
+        (false, false) => DynamicImage::ImageLuma8(img.to_luma8()),
+        (true, false) => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        (false, true) => DynamicImage::ImageLuma16(img.to_luma16()),
+        (true, true) => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+    }
+}
+
+fn apply_gamma(img: &mut DynamicImage, gamma: f32) {
+    let exponent = 1.0 / gamma;
+    apply_curve(img, |v| v.powf(exponent));
+}
+
+/// Unlike [`DynamicImage::adjust_contrast`], this leaves the alpha channel alone.
+fn apply_contrast(img: &mut DynamicImage, contrast: f32) {
+    let factor = ((100.0 + contrast) / 100.0).powi(2);
+    apply_curve(img, |v| ((v - 0.5) * factor + 0.5).clamp(0.0, 1.0));
+}
+
+/// Maps every color channel through a curve from 0..=1 to 0..=1, turned into a lookup table once
+/// for the whole image.
+fn apply_curve(img: &mut DynamicImage, curve: impl Fn(f32) -> f32) {
+    let lut8 = || -> Vec<u8> {
+        (0..=u8::MAX)
+            .map(|v| (curve(f32::from(v) / 255.0) * 255.0).round() as u8)
+            .collect()
+    };
+    let lut16 = || -> Vec<u16> {
+        (0..=u16::MAX)
+            .map(|v| (curve(f32::from(v) / 65535.0) * 65535.0).round() as u16)
+            .collect()
+    };
+
+    match img {
+        DynamicImage::ImageLuma8(img) => map_channels(img, 1, false, &lut8()),
+        DynamicImage::ImageLumaA8(img) => map_channels(img, 2, true, &lut8()),
+        DynamicImage::ImageRgb8(img) => map_channels(img, 3, false, &lut8()),
+        DynamicImage::ImageRgba8(img) => map_channels(img, 4, true, &lut8()),
+        DynamicImage::ImageBgr8(img) => map_channels(img, 3, false, &lut8()),
+        DynamicImage::ImageBgra8(img) => map_channels(img, 4, true, &lut8()),
+        DynamicImage::ImageLuma16(img) => map_channels(img, 1, false, &lut16()),
+        DynamicImage::ImageLumaA16(img) => map_channels(img, 2, true, &lut16()),
+        DynamicImage::ImageRgb16(img) => map_channels(img, 3, false, &lut16()),
+        DynamicImage::ImageRgba16(img) => map_channels(img, 4, true, &lut16()),
+    }
+}
+
+fn map_channels<T: Copy + Into<usize>>(
+    samples: &mut [T],
+    channels: usize,
+    has_alpha: bool,
+    lut: &[T],
+) {
+    let colors = if has_alpha { channels - 1 } else { channels };
+    for pixel in samples.chunks_exact_mut(channels) {
+        for sample in &mut pixel[..colors] {
+            *sample = lut[(*sample).into()];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgba};
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgba8(ImageBuffer::from_fn(16, 16, |x, y| {
+            Rgba([
+                (x * 16) as u8,
+                (y * 16) as u8,
+                (x * y) as u8,
+                (255 - x * y) as u8,
+            ])
+        }))
+    }
+
+    #[test]
+    fn neutral_values_are_identity() {
+        let neutral = Adjustments::default();
+        let cases = [
+            ("default", neutral),
+            (
+                "gamma 1",
+                Adjustments {
+                    gamma: 1.0,
+                    ..neutral
+                },
+            ),
+            (
+                "brightness 0",
+                Adjustments {
+                    brightness: 0,
+                    ..neutral
+                },
+            ),
+            (
+                "contrast 0",
+                Adjustments {
+                    contrast: 0.0,
+                    ..neutral
+                },
+            ),
+        ];
+        let img = gradient();
+        for (name, adjustments) in cases {
+            assert!(adjustments.is_identity(), "{}", name);
+            assert_eq!(adjust(img.clone(), &adjustments), img, "{}", name);
+        }
+    }
+
+    #[test]
+    fn alpha_is_left_alone() {
+        let img = gradient();
+        let cases = [
+            Adjustments {
+                gamma: 2.2,
+                ..Default::default()
+            },
+            Adjustments {
+                brightness: -40,
+                ..Default::default()
+            },
+            Adjustments {
+                contrast: 30.0,
+                ..Default::default()
+            },
+            Adjustments {
+                invert: true,
+                ..Default::default()
+            },
+        ];
+        for adjustments in cases {
+            assert!(!adjustments.is_identity());
+            let adjusted = adjust(img.clone(), &adjustments).to_rgba8();
+            for (before, after) in img.to_rgba8().pixels().zip(adjusted.pixels()) {
+                assert_eq!(before[3], after[3], "{:?}", adjustments);
+            }
+        }
+    }
+}
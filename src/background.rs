@@ -0,0 +1,1240 @@
+use image::{buffer::ConvertBuffer, Bgra, DynamicImage, ImageBuffer, Pixel, Rgb, RgbImage};
+use std::{io::Write, str::FromStr};
+use x11rb::{
+    connection::Connection,
+    errors::ReplyError,
+    protocol::{
+        shm::ConnectionExt as ShmConnectionExt,
+        xproto::{
+            AtomEnum, ConnectionExt, Drawable, ImageFormat, ImageOrder, Pixmap, Setup, Window,
+        },
+    },
+    NONE,
+};
+
+use crate::{
+    desktop::{check_composite, find_desktop_windows, DesktopWindow, WindowPixmap},
+    error::{Result, XbgdumpError},
+    output::{encode_binary, Format, WriteOptions},
+    raw::RawImage,
+    shm::ShmSegment,
+};
+
+const RGBA_DEPTH: u8 = 32;
+/// 10 bits per channel, as used by deep color X servers.
+const DEEP_DEPTH: u8 = 30;
+const RGB_DEPTH: u8 = 24;
+/// 5-6-5 RGB.
+const RGB565_DEPTH: u8 = 16;
+/// 5-5-5 RGB.
+const RGB555_DEPTH: u8 = 15;
+/// Palette indices into the colormap, as on PseudoColor displays.
+const PALETTE_DEPTH: u8 = 8;
+
+pub type BgraImage = ImageBuffer<Bgra<u8>, Vec<u8>>;
+type Rgb16Image = ImageBuffer<Rgb<u16>, Vec<u16>>;
+
+// Image grabbing logic based on https://github.com/neXromancers/shotgun and
+// https://www.apriorit.com/dev-blog/672-lin-how-to-take-multi-monitor-screenshots-on-linux
+// Pixmap grabbing based on https://github.com/polybar/polybar
+
+/// Root window properties wallpaper setters store the background pixmap in, in order of preference.
+pub const BACKGROUND_ATOMS: &[&str] = &["_XROOTPMAP_ID", "ESETROOT_PMAP_ID", "_XSETROOT_ID"];
+
+/// What the pixels are read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The pixmap a wallpaper setter published in one of the root window's properties.
+    Pixmap,
+    /// The root window itself, as it's currently shown. Desktops that draw the wallpaper
+    /// without publishing a pixmap still paint it there, but any windows covering it are
+    /// read along with it.
+    RootWindow,
+    /// The windows of type `_NET_WM_WINDOW_TYPE_DESKTOP` some desktops draw the wallpaper into,
+    /// read through Composite and stitched together at their positions on the root window.
+    DesktopWindow,
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "pixmap" => Ok(Source::Pixmap),
+            "root-window" => Ok(Source::RootWindow),
+            "desktop-window" => Ok(Source::DesktopWindow),
+            _ => Err(format!(
+                "Invalid source '{}'. Expected pixmap, root-window or desktop-window.",
+                s
+            )),
+        }
+    }
+}
+
+/// Knobs for how the background is transferred from the X server.
+#[derive(Debug, Clone)]
+pub struct GrabOptions {
+    /// Transfer pixels through MIT-SHM shared memory if the server allows it.
+    pub use_shm: bool,
+    /// Drop the alpha channel of depth-32 pixmaps, which some wallpaper setters leave zeroed.
+    pub force_opaque: bool,
+    /// Widen 8-bit captures to 16 bits per channel, like depth-30 pixmaps always are.
+    pub force_16bit: bool,
+    /// Only look for the pixmap in this root window property, for setters that use none of
+    /// the [`BACKGROUND_ATOMS`].
+    pub property: Option<&'static str>,
+    pub source: Source,
+    /// Where to read the pixels from instead if no pixmap is set.
+    pub fallback: Option<Source>,
+    /// Rows per GetImage request, instead of as many as fit into the server's maximum request
+    /// length. Transfers through MIT-SHM always happen at once.
+    pub strip_height: Option<u16>,
+}
+
+impl Default for GrabOptions {
+    fn default() -> Self {
+        Self {
+            use_shm: true,
+            force_opaque: false,
+            force_16bit: false,
+            property: None,
+            source: Source::Pixmap,
+            fallback: None,
+            strip_height: None,
+        }
+    }
+}
+
+/// Where the background lives and what it looks like, without any pixel data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackgroundInfo {
+    pub source: Source,
+    /// The root window property the pixmap was found in, `None` for [`Source::RootWindow`].
+    pub property: Option<&'static str>,
+    /// The pixmap, the root window for [`Source::RootWindow`], or the first desktop window for
+    /// [`Source::DesktopWindow`].
+    pub pixmap: Pixmap,
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+    pub depth: u8,
+}
+
+/// Looks up the background pixmap of the given screen and its geometry.
+///
+/// This is cheap even over slow connections, since no pixels are transferred.
+pub fn probe_background(c: &impl Connection, screen_num: usize) -> Result<BackgroundInfo> {
+    probe_background_with(c, screen_num, &GrabOptions::default())
+}
+
+/// Like [`probe_background`], but looks in [`GrabOptions::property`] if set, and at the root
+/// window for [`GrabOptions::source`] and [`GrabOptions::fallback`].
+pub fn probe_background_with(
+    c: &impl Connection,
+    screen_num: usize,
+    options: &GrabOptions,
+) -> Result<BackgroundInfo> {
+    let root = c
+        .setup()
+        .roots
+        .get(screen_num)
+        .ok_or(XbgdumpError::NoSuchScreen(screen_num))?
+        .root;
+
+    let pixmap = match options.source {
+        Source::Pixmap => {
+            let found = match options.property {
+                Some(property) => find_background_pixmap_in(c, root, &[property]),
+                None => find_background_pixmap(c, root),
+            };
+            match (found, options.fallback) {
+                (Err(XbgdumpError::NoBackgroundPixmap), Some(Source::RootWindow)) => None,
+                (found, _) => Some(found?),
+            }
+        }
+        Source::RootWindow => None,
+        Source::DesktopWindow => return probe_desktop(c, root).map(|(info, _)| info),
+    };
+
+    if let Some((property, pixmap)) = pixmap {
+        log!(
+            Info,
+            "Using background pixmap 0x{:x} from {}.",
+            pixmap,
+            property
+        );
+        let info = drawable_info(c, Source::Pixmap, Some(property), pixmap)?;
+        // xsetroot only leaves a bitmap behind, but paints the root window itself
+        if info.depth != 1 || options.fallback != Some(Source::RootWindow) {
+            return Ok(info);
+        }
+    }
+
+    if options.source == Source::Pixmap {
+        log!(
+            Warn,
+            "No usable background pixmap set, capturing the root window instead. Windows covering the \
+            background will be captured as well."
+        );
+    }
+    log!(Info, "Using root window 0x{:x}.", root);
+    drawable_info(c, Source::RootWindow, None, root)
+}
+
+/// Looks up the desktop windows and describes the root-sized image they're stitched into.
+///
+/// Windows whose depth differs from the first one's are left out, as their pixels couldn't
+/// share a buffer.
+fn probe_desktop(
+    c: &impl Connection,
+    root: Window,
+) -> Result<(BackgroundInfo, Vec<DesktopWindow>)> {
+    check_composite(c)?;
+    let mut windows = find_desktop_windows(c, root)?;
+    let first = windows
+        .first()
+        .cloned()
+        .ok_or(XbgdumpError::NoDesktopWindow)?;
+    windows.retain(|window| {
+        if window.depth != first.depth {
+            log!(
+                Warn,
+                "Skipping desktop window 0x{:x}, its depth {} differs from the first one's, {}.",
+                window.window,
+                window.depth,
+                first.depth
+            );
+        }
+        window.depth == first.depth
+    });
+
+    let geometry = c.get_geometry(root)?.reply()?;
+    let info = BackgroundInfo {
+        source: Source::DesktopWindow,
+        property: None,
+        pixmap: first.window,
+        x: 0,
+        y: 0,
+        width: geometry.width,
+        height: geometry.height,
+        depth: first.depth,
+    };
+
+    Ok((info, windows))
+}
+
+/// Copies the desktop windows' pixels into one Z_PIXMAP image of the size in `info`, each at
+/// its position. Areas no window covers stay zero, i.e. black, or transparent at depth 32.
+fn stitch_desktop(
+    c: &impl Connection,
+    info: &BackgroundInfo,
+    windows: &[DesktopWindow],
+    strip_height: Option<u16>,
+) -> Result<Vec<u8>> {
+    let setup = c.setup();
+    let (bits_per_pixel, stride) = pixmap_layout(setup, info.depth, info.width)
+        .filter(|&(bits_per_pixel, _)| bits_per_pixel % 8 == 0)
+        .ok_or(XbgdumpError::UnsupportedDepth(info.depth))?;
+    let bytes_per_pixel = usize::from(bits_per_pixel / 8);
+    let mut canvas = vec![0; stride * usize::from(info.height)];
+
+    for window in windows {
+        let pixmap = WindowPixmap::new(c, window.window)?;
+        let (_, data) = get_image_in_strips(
+            c,
+            pixmap.pixmap(),
+            (0, 0),
+            (window.width, window.height),
+            window.depth,
+            strip_height,
+        )?;
+        drop(pixmap);
+
+        let (_, window_stride) = pixmap_layout(setup, window.depth, window.width)
+            .ok_or(XbgdumpError::UnsupportedDepth(window.depth))?;
+        if data.len() < window_stride * usize::from(window.height) {
+            return Err(XbgdumpError::TruncatedImage);
+        }
+
+        // Windows can hang off the edges of the screen
+        let left = i32::from(window.x).max(0);
+        let right = (i32::from(window.x) + i32::from(window.width)).min(i32::from(info.width));
+        if left >= right {
+            continue;
+        }
+        let len = (right - left) as usize * bytes_per_pixel;
+        let skip = (left - i32::from(window.x)) as usize * bytes_per_pixel;
+        for y in 0..window.height {
+            let canvas_y = i32::from(window.y) + i32::from(y);
+            if canvas_y < 0 || canvas_y >= i32::from(info.height) {
+                continue;
+            }
+            let src = usize::from(y) * window_stride + skip;
+            let dst = canvas_y as usize * stride + left as usize * bytes_per_pixel;
+            canvas[dst..dst + len].copy_from_slice(&data[src..src + len]);
+        }
+    }
+
+    Ok(canvas)
+}
+
+/// Queries the geometry of the pixmap or window the pixels will be read from.
+fn drawable_info(
+    c: &impl Connection,
+    source: Source,
+    property: Option<&'static str>,
+    drawable: Drawable,
+) -> Result<BackgroundInfo> {
+    let geometry = c.get_geometry(drawable)?.reply()?;
+    log!(
+        Info,
+        "Geometry is {}x{}+{}+{} at depth {}.",
+        geometry.width,
+        geometry.height,
+        geometry.x,
+        geometry.y,
+        geometry.depth
+    );
+
+    Ok(BackgroundInfo {
+        source,
+        property,
+        pixmap: drawable,
+        x: geometry.x,
+        y: geometry.y,
+        width: geometry.width,
+        height: geometry.height,
+        depth: geometry.depth,
+    })
+}
+
+/// Retrieves the background pixmap of the given screen and converts it according to its depth.
+pub fn grab_background(c: &impl Connection, screen_num: usize) -> Result<DynamicImage> {
+    grab_background_with(c, screen_num, &GrabOptions::default())
+}
+
+/// Like [`grab_background`], but with explicit [`GrabOptions`].
+pub fn grab_background_with(
+    c: &impl Connection,
+    screen_num: usize,
+    options: &GrabOptions,
+) -> Result<DynamicImage> {
+    grab_background_with_info(c, screen_num, options).map(|(img, _)| img)
+}
+
+/// Like [`grab_background_with`], but also returns where the pixels came from, as
+/// [`probe_background`] would have reported right before the transfer.
+pub fn grab_background_with_info(
+    c: &impl Connection,
+    screen_num: usize,
+    options: &GrabOptions,
+) -> Result<(DynamicImage, BackgroundInfo)> {
+    fetch(c, screen_num, options, |geometry, depth, data| {
+        let img = decode(c, screen_num, geometry, depth, data, options)?;
+        Ok((img, geometry.clone()))
+    })
+}
+
+/// Retrieves the background pixmap of the given screen and writes it to `w` as PAM,
+/// converting one row at a time.
+///
+/// Unlike encoding the result of [`grab_background_with`], this never holds a converted copy
+/// of the whole background, which matters for huge multi-monitor setups. That only works for
+/// depths 24 and 32; other depths are converted in full first. [`GrabOptions::force_16bit`] is
+/// ignored, since PAM output has 8 bits per channel anyway.
+///
+/// Returns where the pixels came from, like [`grab_background_with_info`].
+pub fn stream_background_pam(
+    c: &impl Connection,
+    screen_num: usize,
+    options: &GrabOptions,
+    w: &mut impl Write,
+) -> Result<BackgroundInfo> {
+    fetch(c, screen_num, options, |geometry, depth, data| {
+        let alpha = match depth {
+            RGBA_DEPTH => !options.force_opaque,
+            RGB_DEPTH => false,
+            _ => {
+                let img = decode(c, screen_num, geometry, depth, data, options)?;
+                encode_binary(&img, Format::Pam, w, &WriteOptions::default())?;
+                return Ok(geometry.clone());
+            }
+        };
+
+        let (width, height) = (usize::from(geometry.width), usize::from(geometry.height));
+        let (_, stride) = pixmap_layout(c.setup(), depth, geometry.width)
+            .ok_or(XbgdumpError::UnsupportedDepth(depth))?;
+        log!(Debug, "Streaming the background as PAM.");
+        write_pam_rows(data, width, height, stride, alpha, w)?;
+
+        Ok(geometry.clone())
+    })
+}
+
+/// Writes 32-bit BGRX or BGRA rows as PAM, producing the same bytes as image's encoder would for
+/// the converted image.
+fn write_pam_rows(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    stride: usize,
+    alpha: bool,
+    w: &mut impl Write,
+) -> Result<()> {
+    if stride < width * 4 || data.len() < stride * height {
+        return Err(XbgdumpError::TruncatedImage);
+    }
+
+    // Same header image's encoder writes
+    write!(
+        w,
+        "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL 255\nTUPLTYPE {}\nENDHDR\n",
+        width,
+        height,
+        if alpha { 4 } else { 3 },
+        if alpha { "RGB_ALPHA" } else { "RGB" }
+    )?;
+    let mut row_out = Vec::with_capacity(width * 4);
+    for row in data.chunks(stride.max(1)).take(height) {
+        row_out.clear();
+        for bgra in row[..width * 4].chunks_exact(4) {
+            row_out.extend_from_slice(&[bgra[2], bgra[1], bgra[0]]);
+            if alpha {
+                row_out.push(bgra[3]);
+            }
+        }
+        w.write_all(&row_out)?;
+    }
+
+    Ok(())
+}
+
+/// Converts the pixels [`fetch`] hands over into an image, according to their depth.
+fn decode(
+    c: &impl Connection,
+    screen_num: usize,
+    geometry: &BackgroundInfo,
+    depth: u8,
+    data: &[u8],
+    options: &GrabOptions,
+) -> Result<DynamicImage> {
+    let (width, height) = (geometry.width.into(), geometry.height.into());
+    let (_, stride) = pixmap_layout(c.setup(), depth, geometry.width)
+        .ok_or(XbgdumpError::UnsupportedDepth(depth))?;
+
+    let img = if depth == PALETTE_DEPTH {
+        let palette = query_palette(c, screen_num)?;
+        expand_palette(width, height, stride, data, &palette)?
+    } else {
+        let byte_order = c.setup().image_byte_order;
+        convert_depth(depth, width, height, stride, byte_order, data, options)?
+    };
+
+    Ok(if options.force_16bit { widen(img) } else { img })
+}
+
+/// Looks up the colors of the root window's colormap, for pixmaps holding palette indices.
+fn query_palette(c: &impl Connection, screen_num: usize) -> Result<Vec<Rgb<u8>>> {
+    let screen = &c.setup().roots[screen_num];
+    let attributes = c.get_window_attributes(screen.root)?.reply()?;
+    let entries = screen
+        .allowed_depths
+        .iter()
+        .flat_map(|depth| &depth.visuals)
+        .find(|visual| visual.visual_id == attributes.visual)
+        .map_or(256, |visual| visual.colormap_entries.min(256));
+    log!(
+        Debug,
+        "Querying {} colors of colormap {:#x}.",
+        entries,
+        attributes.colormap
+    );
+
+    let pixels = (0..u32::from(entries)).collect::<Vec<_>>();
+    let colors = c
+        .query_colors(attributes.colormap, &pixels)?
+        .reply()?
+        .colors;
+
+    // The server uses 16 bits per channel, of which only the top ones matter here
+    Ok(colors
+        .iter()
+        .map(|color| Rgb([color.red, color.green, color.blue].map(|v| (v >> 8) as u8)))
+        .collect())
+}
+
+/// Replaces every palette index with its color. Indices outside the palette become black.
+fn expand_palette(
+    width: u32,
+    height: u32,
+    stride: usize,
+    data: &[u8],
+    palette: &[Rgb<u8>],
+) -> Result<DynamicImage> {
+    if data.len() < stride * height as usize {
+        return Err(XbgdumpError::TruncatedImage);
+    }
+
+    Ok(DynamicImage::ImageRgb8(ImageBuffer::from_fn(
+        width,
+        height,
+        |x, y| {
+            let index = data[y as usize * stride + x as usize];
+            palette
+                .get(usize::from(index))
+                .copied()
+                .unwrap_or(Rgb([0, 0, 0]))
+        },
+    )))
+}
+
+/// Retrieves the background pixmap of the given screen exactly as the server sends it.
+///
+/// Unlike [`grab_background_with`], this works for any depth, since nothing is converted.
+/// [`GrabOptions::force_opaque`] and [`GrabOptions::force_16bit`] are ignored.
+pub fn grab_raw_with(
+    c: &impl Connection,
+    screen_num: usize,
+    options: &GrabOptions,
+) -> Result<RawImage> {
+    let setup = c.setup();
+    fetch(c, screen_num, options, |geometry, depth, data| {
+        let (bits_per_pixel, stride) = pixmap_layout(setup, depth, geometry.width)
+            .ok_or(XbgdumpError::UnsupportedDepth(depth))?;
+        let channel_order = match (depth, setup.image_byte_order) {
+            (RGB_DEPTH, ImageOrder::LSB_FIRST) if bits_per_pixel == 32 => *b"BGRX",
+            (RGB_DEPTH, _) if bits_per_pixel == 32 => *b"XRGB",
+            (RGBA_DEPTH, ImageOrder::LSB_FIRST) => *b"BGRA",
+            (RGBA_DEPTH, _) => *b"ARGB",
+            _ => [0; 4],
+        };
+
+        Ok(RawImage {
+            width: geometry.width.into(),
+            height: geometry.height.into(),
+            stride: stride as u32,
+            depth,
+            bits_per_pixel,
+            channel_order,
+            data: data.to_vec(),
+        })
+    })
+}
+
+/// Transfers the background's pixels, through MIT-SHM if possible, and hands them to `f`
+/// along with the depth the server reported.
+fn fetch<T>(
+    c: &impl Connection,
+    screen_num: usize,
+    options: &GrabOptions,
+    f: impl FnOnce(&BackgroundInfo, u8, &[u8]) -> Result<T>,
+) -> Result<T> {
+    if options.source == Source::DesktopWindow {
+        let root = c
+            .setup()
+            .roots
+            .get(screen_num)
+            .ok_or(XbgdumpError::NoSuchScreen(screen_num))?
+            .root;
+        let (info, windows) = probe_desktop(c, root)?;
+        let data = stitch_desktop(c, &info, &windows, options.strip_height)?;
+        return f(&info, info.depth, &data);
+    }
+
+    let geometry = probe_background_with(c, screen_num, options)?;
+    let pixmap = geometry.pixmap;
+    if let (Some(property), 1) = (geometry.property, geometry.depth) {
+        return Err(XbgdumpError::BitmapPixmap {
+            property,
+            width: geometry.width,
+            height: geometry.height,
+        });
+    }
+
+    let (width, height) = (geometry.width, geometry.height);
+    let shm = if options.use_shm {
+        image_size(c.setup(), geometry.depth, width, height).and_then(|len| {
+            match ShmSegment::new(c, len) {
+                Ok(shm) => Some(shm),
+                Err(e) => {
+                    log!(Debug, "Not using MIT-SHM: {:#}", e);
+                    None
+                }
+            }
+        })
+    } else {
+        None
+    };
+
+    if let Some(shm) = shm {
+        let reply = c
+            .shm_get_image(
+                pixmap,
+                geometry.x,
+                geometry.y,
+                width,
+                height,
+                !0, // All planes; X doesn't about extra bits
+                ImageFormat::Z_PIXMAP.into(),
+                shm.id(),
+                0,
+            )?
+            .reply()?;
+
+        let capacity = shm.data().len();
+        let data = shm
+            .data()
+            .get(..reply.size as usize)
+            .ok_or(XbgdumpError::ShmOverflow {
+                reported: reply.size as usize,
+                capacity,
+            })?;
+        log!(Debug, "Received {} bytes through MIT-SHM.", data.len());
+        return f(&geometry, reply.depth, data);
+    }
+
+    let (depth, data) = get_image_in_strips(
+        c,
+        pixmap,
+        (geometry.x, geometry.y),
+        (width, height),
+        geometry.depth,
+        options.strip_height,
+    )?;
+    f(&geometry, depth, &data)
+}
+
+/// Transfers the drawable's pixels with GetImage, in horizontal strips if they wouldn't fit
+/// into a single request of the server's maximum length, or into `strip_height` rows.
+///
+/// The strips are put back together in the layout a single request would have returned, and
+/// the depth the server reported is returned along with them. If a strip fails, the error
+/// says which rows it covered.
+fn get_image_in_strips(
+    c: &impl Connection,
+    drawable: Drawable,
+    (x, y): (i16, i16),
+    (width, height): (u16, u16),
+    depth: u8,
+    strip_height: Option<u16>,
+) -> Result<(u8, Vec<u8>)> {
+    let stride = pixmap_layout(c.setup(), depth, width).map_or(0, |(_, stride)| stride);
+    // With BIG-REQUESTS, this is the extended limit
+    let rows = strip_height.unwrap_or_else(|| {
+        (c.maximum_request_bytes() / stride.max(1)).clamp(1, usize::from(u16::MAX)) as u16
+    });
+
+    if rows >= height || stride == 0 {
+        let reply = c
+            .get_image(
+                ImageFormat::Z_PIXMAP,
+                drawable,
+                x,
+                y,
+                width,
+                height,
+                !0, // All planes; X doesn't about extra bits
+            )?
+            .reply()?;
+        log!(
+            Debug,
+            "Received {} bytes through GetImage.",
+            reply.data.len()
+        );
+        return Ok((reply.depth, reply.data));
+    }
+
+    log!(
+        Debug,
+        "Fetching {} rows of {} bytes per GetImage request.",
+        rows,
+        stride
+    );
+    let mut data = Vec::with_capacity(stride * usize::from(height));
+    let mut reply_depth = depth;
+    for top in (0..height).step_by(usize::from(rows.max(1))) {
+        let rows = rows.min(height - top);
+        let reply = c
+            .get_image(
+                ImageFormat::Z_PIXMAP,
+                drawable,
+                x,
+                y.wrapping_add(top as i16),
+                width,
+                rows,
+                !0,
+            )
+            .map_err(ReplyError::from)
+            .and_then(|cookie| cookie.reply())
+            .map_err(|error| XbgdumpError::StripFailed {
+                y: top,
+                height: rows,
+                error,
+            })?;
+        reply_depth = reply.depth;
+        data.extend_from_slice(&reply.data);
+    }
+    log!(
+        Debug,
+        "Received {} bytes through GetImage in {} strips.",
+        data.len(),
+        usize::from(height).div_ceil(usize::from(rows.max(1)))
+    );
+
+    Ok((reply_depth, data))
+}
+
+/// Finds the background pixmap, returning it together with the name of the property it was
+/// found in.
+pub fn find_background_pixmap(c: &impl Connection, root: Window) -> Result<(&'static str, Pixmap)> {
+    find_background_pixmap_in(c, root, BACKGROUND_ATOMS)
+}
+
+/// Like [`find_background_pixmap`], but looks through the given properties instead, the
+/// earlier ones taking precedence.
+///
+/// All properties are requested at once. If several are set to different pixmaps, e.g.
+/// because an older setter left `ESETROOT_PMAP_ID` behind, the first one still wins, but the
+/// mismatch is logged.
+pub fn find_background_pixmap_in(
+    c: &impl Connection,
+    root: Window,
+    properties: &[&'static str],
+) -> Result<(&'static str, Pixmap)> {
+    let atom_cookies = properties
+        .iter()
+        .map(|name| c.intern_atom(true, name.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let atoms = atom_cookies
+        .into_iter()
+        .map(|cookie| cookie.reply().map(|reply| reply.atom))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (name, atom) in properties.iter().zip(&atoms) {
+        log!(Debug, "Atom {} has ID {}.", name, atom);
+    }
+
+    // Atoms that were never interned can't be set on the root window either
+    let prop_cookies = properties
+        .iter()
+        .zip(atoms)
+        .filter(|&(_, atom)| atom != NONE)
+        .map(|(&name, atom)| {
+            c.get_property(false, root, atom, AtomEnum::PIXMAP, 0, 1)
+                .map(|cookie| (name, cookie))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut found = None;
+    for (name, cookie) in prop_cookies {
+        let prop = cookie.reply()?;
+
+        // This is what Polybar does and it works
+        match (prop.value32().and_then(|mut values| values.next()), found) {
+            (Some(pixmap), None) if pixmap != NONE => found = Some((name, pixmap)),
+            (Some(pixmap), Some((first, chosen))) if pixmap != NONE && pixmap != chosen => log!(
+                Info,
+                "{} and {} point to different pixmaps, 0x{:x} and 0x{:x}; using {}.",
+                first,
+                name,
+                chosen,
+                pixmap,
+                first
+            ),
+            (Some(pixmap), Some(_)) if pixmap != NONE => {}
+            _ => log!(Debug, "{} is not set.", name),
+        }
+    }
+
+    found.ok_or(XbgdumpError::NoBackgroundPixmap)
+}
+
+/// Current size of the root window, which follows RandR changes unlike the one in the setup.
+pub fn root_size(c: &impl Connection, root: Window) -> Result<(u32, u32)> {
+    let geometry = c.get_geometry(root)?.reply()?;
+
+    Ok((geometry.width.into(), geometry.height.into()))
+}
+
+/// Repeats the image across a canvas of the given size, like the server does with a pixmap
+/// smaller than the root window. Tiles at the right and bottom edges are cut off.
+pub fn tile(img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    match img {
+        DynamicImage::ImageRgb8(img) => DynamicImage::ImageRgb8(tile_buffer(img, width, height)),
+        DynamicImage::ImageRgba8(img) => DynamicImage::ImageRgba8(tile_buffer(img, width, height)),
+        DynamicImage::ImageRgb16(img) => DynamicImage::ImageRgb16(tile_buffer(img, width, height)),
+        DynamicImage::ImageRgba16(img) => {
+            DynamicImage::ImageRgba16(tile_buffer(img, width, height))
+        }
+        // Doesn't come out of the conversion, but doesn't hurt either
+        img => DynamicImage::ImageRgba8(tile_buffer(&img.to_rgba8(), width, height)),
+    }
+}
+
+fn tile_buffer<P: Pixel + 'static>(
+    tile: &ImageBuffer<P, Vec<P::Subpixel>>,
+    width: u32,
+    height: u32,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let (tile_width, tile_height) = tile.dimensions();
+    ImageBuffer::from_fn(width, height, |x, y| {
+        *tile.get_pixel(x % tile_width, y % tile_height)
+    })
+}
+
+/// Number of bytes a Z_PIXMAP image of the given dimensions occupies on the wire.
+fn image_size(setup: &Setup, depth: u8, width: u16, height: u16) -> Option<usize> {
+    let (_, stride) = pixmap_layout(setup, depth, width)?;
+
+    Some(stride * usize::from(height))
+}
+
+/// Bits per pixel and bytes per row of a Z_PIXMAP image of the given depth and width.
+fn pixmap_layout(setup: &Setup, depth: u8, width: u16) -> Option<(u8, usize)> {
+    let format = setup.pixmap_formats.iter().find(|f| f.depth == depth)?;
+    let pad = usize::from(format.scanline_pad);
+    let row_bits = usize::from(width) * usize::from(format.bits_per_pixel);
+
+    Some((format.bits_per_pixel, row_bits.div_ceil(pad) * pad / 8))
+}
+
+/// Widens 8-bit RGB and RGBA images to 16 bits per channel, for
+/// [`GrabOptions::force_16bit`] and farbfeld. Other images are returned as they are.
+pub(crate) fn widen(img: DynamicImage) -> DynamicImage {
+    // image widens by shifting, which would turn 0xff into 0xff00 rather than 0xffff
+    let samples = |raw: Vec<u8>| raw.into_iter().map(|v| u16::from(v) * 257).collect();
+    // Unwraps safe because the sample count stays the same
+    match img {
+        DynamicImage::ImageRgb8(buf) => DynamicImage::ImageRgb16(
+            ImageBuffer::from_raw(buf.width(), buf.height(), samples(buf.into_raw())).unwrap(),
+        ),
+        DynamicImage::ImageRgba8(buf) => DynamicImage::ImageRgba16(
+            ImageBuffer::from_raw(buf.width(), buf.height(), samples(buf.into_raw())).unwrap(),
+        ),
+        img => img,
+    }
+}
+
+fn convert_depth(
+    depth: u8,
+    width: u32,
+    height: u32,
+    stride: usize,
+    byte_order: ImageOrder,
+    data: &[u8],
+    options: &GrabOptions,
+) -> Result<DynamicImage> {
+    match depth {
+        DEEP_DEPTH => {
+            return convert_deep(width, height, byte_order, data).map(DynamicImage::ImageRgb16)
+        }
+        RGB565_DEPTH => {
+            return unpack_rgb565(data, width, height, stride, byte_order)
+                .map(DynamicImage::ImageRgb8)
+        }
+        RGB555_DEPTH => {
+            return unpack_rgb555(data, width, height, stride, byte_order)
+                .map(DynamicImage::ImageRgb8)
+        }
+        _ => {}
+    }
+
+    let bgra = ImageBuffer::<Bgra<u8>, _>::from_raw(width, height, data)
+        .ok_or(XbgdumpError::TruncatedImage)?;
+
+    match depth {
+        RGBA_DEPTH if options.force_opaque => {
+            log!(
+                Debug,
+                "Discarding the alpha channel of the depth-32 pixmap."
+            );
+            Ok(DynamicImage::ImageRgb8(bgra.convert()))
+        }
+        // I haven't actually tested this; it's just conjecture from 24-bit being BGR0
+        RGBA_DEPTH => Ok(DynamicImage::ImageRgba8(bgra.convert())),
+        RGB_DEPTH => Ok(DynamicImage::ImageRgb8(bgra.convert())),
+        depth => Err(XbgdumpError::UnsupportedDepth(depth)),
+    }
+}
+
+/// Unpacks 5 bits of red, 6 of green and 5 of blue from 16-bit words, from the most
+/// significant end.
+fn unpack_rgb565(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    byte_order: ImageOrder,
+) -> Result<RgbImage> {
+    unpack_16bpp(data, width, height, stride, byte_order, 6)
+}
+
+/// Unpacks 5 bits per channel from 16-bit words, with the most significant bit unused.
+fn unpack_rgb555(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    byte_order: ImageOrder,
+) -> Result<RgbImage> {
+    unpack_16bpp(data, width, height, stride, byte_order, 5)
+}
+
+/// Unpacks 16-bit words in the server's byte order with 5 bits each of red and blue around
+/// `green_bits` of green and widens the channels to 8 bits.
+fn unpack_16bpp(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    byte_order: ImageOrder,
+    green_bits: u32,
+) -> Result<RgbImage> {
+    if data.len() < stride * height as usize {
+        return Err(XbgdumpError::TruncatedImage);
+    }
+
+    // Replicating the top bits maps the maximum to 0xff rather than 0xf8
+    let widen = |v: u16, bits: u32| {
+        let v = v & ((1 << bits) - 1);
+        ((v << (8 - bits)) | (v >> (2 * bits - 8))) as u8
+    };
+
+    Ok(ImageBuffer::from_fn(width, height, |x, y| {
+        let i = y as usize * stride + x as usize * 2;
+        let bytes = [data[i], data[i + 1]];
+        let word = if byte_order == ImageOrder::MSB_FIRST {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        };
+        Rgb([
+            widen(word >> (5 + green_bits), 5),
+            widen(word >> 5, green_bits),
+            widen(word, 5),
+        ])
+    }))
+}
+
+/// Unpacks 10 bits per channel from 32-bit words in the server's byte order (2 bits padding,
+/// then red, green and blue from the most significant end) and widens them to 16 bits.
+fn convert_deep(
+    width: u32,
+    height: u32,
+    byte_order: ImageOrder,
+    data: &[u8],
+) -> Result<Rgb16Image> {
+    let len = width as usize * height as usize;
+    if data.len() < len * 4 {
+        return Err(XbgdumpError::TruncatedImage);
+    }
+
+    // Replicating the top bits maps 0x3ff to 0xffff rather than 0xffc0
+    let widen = |v: u32| {
+        let v = (v & 0x3ff) as u16;
+        (v << 6) | (v >> 4)
+    };
+    let samples = data
+        .chunks_exact(4)
+        .take(len)
+        .flat_map(|word| {
+            let bytes = [word[0], word[1], word[2], word[3]];
+            let word = if byte_order == ImageOrder::MSB_FIRST {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            };
+            [widen(word >> 20), widen(word >> 10), widen(word)]
+        })
+        .collect();
+
+    // Unwrap safe because exactly width * height pixels were taken
+    Ok(ImageBuffer::from_raw(width, height, samples).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockServer};
+
+    /// Packs 16-bit words into rows of `stride` bytes, two pixels per row.
+    fn rows16(words: &[u16], stride: usize, byte_order: ImageOrder) -> Vec<u8> {
+        words
+            .chunks(2)
+            .flat_map(|row| {
+                let mut bytes: Vec<_> = row
+                    .iter()
+                    .flat_map(|&word| match byte_order {
+                        ImageOrder::MSB_FIRST => word.to_be_bytes(),
+                        _ => word.to_le_bytes(),
+                    })
+                    .collect();
+                bytes.resize(stride, 0xaa);
+                bytes
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rgb565() {
+        let words = [0xf800, 0x07e0, 0x001f, 0x8410];
+        let expected = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [132, 130, 132]];
+        for byte_order in [ImageOrder::LSB_FIRST, ImageOrder::MSB_FIRST] {
+            // Padded to 8 bytes per row, which the unpacker has to skip
+            let data = rows16(&words, 8, byte_order);
+            let img = unpack_rgb565(&data, 2, 2, 8, byte_order).unwrap();
+            let pixels: Vec<_> = img.pixels().map(|p| p.0).collect();
+            assert_eq!(pixels, expected, "{:?}", byte_order);
+        }
+    }
+
+    #[test]
+    fn rgb555() {
+        // The unused top bit is set in the first word and must be ignored
+        let words = [0xfc00, 0x03e0, 0x001f, 0x4210];
+        let expected = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [132, 132, 132]];
+        for byte_order in [ImageOrder::LSB_FIRST, ImageOrder::MSB_FIRST] {
+            let data = rows16(&words, 4, byte_order);
+            let img = unpack_rgb555(&data, 2, 2, 4, byte_order).unwrap();
+            let pixels: Vec<_> = img.pixels().map(|p| p.0).collect();
+            assert_eq!(pixels, expected, "{:?}", byte_order);
+        }
+    }
+
+    #[test]
+    fn deep() {
+        let words: [u32; 4] = [0x3ff0_0000, 0x000f_fc00, 0xc000_03ff, 0x2008_0200];
+        let expected = [
+            [0xffff, 0, 0],
+            [0, 0xffff, 0],
+            [0, 0, 0xffff],
+            [0x8020, 0x8020, 0x8020],
+        ];
+        for byte_order in [ImageOrder::LSB_FIRST, ImageOrder::MSB_FIRST] {
+            let data: Vec<_> = words
+                .iter()
+                .flat_map(|&word| match byte_order {
+                    ImageOrder::MSB_FIRST => word.to_be_bytes(),
+                    _ => word.to_le_bytes(),
+                })
+                .collect();
+            let img = convert_deep(2, 2, byte_order, &data).unwrap();
+            let pixels: Vec<_> = img.pixels().map(|p| p.0).collect();
+            assert_eq!(pixels, expected, "{:?}", byte_order);
+        }
+    }
+
+    #[test]
+    fn streamed_pam_matches_buffered() {
+        let (width, height) = (5, 3);
+        let data: Vec<u8> = (0..width * height * 4).map(|i| (i * 7) as u8).collect();
+        let cases = [
+            (RGB_DEPTH, false, false),
+            (RGBA_DEPTH, true, false),
+            (RGBA_DEPTH, false, true),
+        ];
+        for (depth, alpha, force_opaque) in cases {
+            let options = GrabOptions {
+                force_opaque,
+                ..GrabOptions::default()
+            };
+            let img = convert_depth(
+                depth,
+                width as u32,
+                height as u32,
+                width * 4,
+                ImageOrder::LSB_FIRST,
+                &data,
+                &options,
+            )
+            .unwrap();
+            let mut buffered = Vec::new();
+            encode_binary(&img, Format::Pam, &mut buffered, &WriteOptions::default()).unwrap();
+
+            let mut streamed = Vec::new();
+            write_pam_rows(&data, width, height, width * 4, alpha, &mut streamed).unwrap();
+            assert_eq!(streamed, buffered, "depth {}", depth);
+        }
+    }
+
+    #[test]
+    fn truncated_16bpp() {
+        let data = rows16(&[0, 0], 4, ImageOrder::LSB_FIRST);
+        assert!(matches!(
+            unpack_rgb565(&data, 2, 2, 4, ImageOrder::LSB_FIRST),
+            Err(XbgdumpError::TruncatedImage)
+        ));
+    }
+
+    const PIXMAP: u32 = 0x40_0001;
+
+    /// A 3x2 BGRX pixmap whose pixel n is (n, 2n, 3n), with garbage in the padding byte.
+    fn bgrx_pixmap() -> Vec<u8> {
+        (1..=6u8).flat_map(|n| [3 * n, 2 * n, n, 0xaa]).collect()
+    }
+
+    fn expected_rgb() -> Vec<[u8; 3]> {
+        (1..=6).map(|n| [n, 2 * n, 3 * n]).collect()
+    }
+
+    #[test]
+    fn grab_from_property() {
+        let server = MockServer::new(3, 2)
+            .pixmap(PIXMAP, (3, 2), 24, bgrx_pixmap())
+            .property("_XROOTPMAP_ID", PIXMAP);
+        let img = grab_background(&server, 0).unwrap().into_rgb8();
+        let pixels: Vec<_> = img.pixels().map(|p| p.0).collect();
+        assert_eq!(pixels, expected_rgb());
+    }
+
+    #[test]
+    fn probe_falls_back_to_later_properties() {
+        let server = MockServer::new(1920, 1080)
+            .pixmap(PIXMAP, (3, 2), 24, bgrx_pixmap())
+            .property("ESETROOT_PMAP_ID", PIXMAP);
+        let info = probe_background(&server, 0).unwrap();
+        assert_eq!(
+            info,
+            BackgroundInfo {
+                source: Source::Pixmap,
+                property: Some("ESETROOT_PMAP_ID"),
+                pixmap: PIXMAP,
+                x: 0,
+                y: 0,
+                width: 3,
+                height: 2,
+                depth: 24,
+            }
+        );
+        assert_eq!(server.images.get(), 0);
+    }
+
+    const OTHER_PIXMAP: u32 = 0x40_0002;
+
+    #[test]
+    fn only_xrootpmap_id() {
+        let server = MockServer::new(3, 2).property("_XROOTPMAP_ID", PIXMAP);
+        let found = find_background_pixmap_in(&server, mock::ROOT, BACKGROUND_ATOMS).unwrap();
+        assert_eq!(found, ("_XROOTPMAP_ID", PIXMAP));
+    }
+
+    #[test]
+    fn agreeing_properties() {
+        let server = MockServer::new(3, 2)
+            .property("_XROOTPMAP_ID", PIXMAP)
+            .property("ESETROOT_PMAP_ID", PIXMAP);
+        let found = find_background_pixmap_in(&server, mock::ROOT, BACKGROUND_ATOMS).unwrap();
+        assert_eq!(found, ("_XROOTPMAP_ID", PIXMAP));
+    }
+
+    #[test]
+    fn xrootpmap_id_wins_over_stale_esetroot() {
+        let server = MockServer::new(3, 2)
+            .property("ESETROOT_PMAP_ID", OTHER_PIXMAP)
+            .property("_XROOTPMAP_ID", PIXMAP);
+        let found = find_background_pixmap_in(&server, mock::ROOT, BACKGROUND_ATOMS).unwrap();
+        assert_eq!(found, ("_XROOTPMAP_ID", PIXMAP));
+    }
+
+    #[test]
+    fn property_override() {
+        let server = MockServer::new(3, 2)
+            .pixmap(OTHER_PIXMAP, (3, 2), 24, bgrx_pixmap())
+            .property("_XROOTPMAP_ID", PIXMAP)
+            .property("_MY_BACKGROUND", OTHER_PIXMAP);
+        let options = GrabOptions {
+            property: Some("_MY_BACKGROUND"),
+            ..GrabOptions::default()
+        };
+        let info = probe_background_with(&server, 0, &options).unwrap();
+        assert_eq!(info.property, Some("_MY_BACKGROUND"));
+        assert_eq!(info.pixmap, OTHER_PIXMAP);
+
+        // The usual properties aren't looked at, even if the named one is missing
+        let options = GrabOptions {
+            property: Some("_UNSET"),
+            ..GrabOptions::default()
+        };
+        assert!(matches!(
+            probe_background_with(&server, 0, &options),
+            Err(XbgdumpError::NoBackgroundPixmap)
+        ));
+    }
+
+    #[test]
+    fn no_background() {
+        let server = MockServer::new(3, 2);
+        assert!(matches!(
+            grab_background(&server, 0),
+            Err(XbgdumpError::NoBackgroundPixmap)
+        ));
+        assert!(matches!(
+            grab_background(&server, 1),
+            Err(XbgdumpError::NoSuchScreen(1))
+        ));
+    }
+
+    /// Neighboring 10-bit values, which 8 bits per channel would merge.
+    const DEEP_VALUES: [u32; 3] = [0x200, 0x201, 0x3fe];
+
+    /// A 3x1 depth-30 pixmap with one of [`DEEP_VALUES`] in every channel of each pixel.
+    fn deep_pixmap() -> Vec<u8> {
+        DEEP_VALUES
+            .iter()
+            .flat_map(|&v| (v << 20 | v << 10 | v).to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn deep_capture_keeps_10_bits_in_png() {
+        let server = MockServer::new(3, 1)
+            .pixmap(PIXMAP, (3, 1), 30, deep_pixmap())
+            .property("_XROOTPMAP_ID", PIXMAP);
+        let img = grab_background(&server, 0).unwrap();
+        assert!(matches!(img, DynamicImage::ImageRgb16(_)));
+
+        let png = crate::output::encode_image(&img, Format::Png, &WriteOptions::default()).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap().into_rgb16();
+        let samples = decoded.pixels().map(|p| p[0]).collect::<Vec<_>>();
+        // Repeating the top bits keeps 0x3ff at 0xffff
+        assert_eq!(samples, [0x8020, 0x8060, 0xffbf]);
+        assert!(decoded.pixels().all(|p| p[0] == p[1] && p[1] == p[2]));
+    }
+
+    #[test]
+    fn force_16bit_widens_to_full_scale() {
+        let server = MockServer::new(3, 2)
+            .pixmap(PIXMAP, (3, 2), 24, bgrx_pixmap())
+            .property("_XROOTPMAP_ID", PIXMAP);
+        let options = GrabOptions {
+            force_16bit: true,
+            ..GrabOptions::default()
+        };
+        let img = grab_background_with(&server, 0, &options).unwrap();
+        let expected = expected_rgb()
+            .iter()
+            .map(|p| p.map(|v| u16::from(v) * 257))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            img.into_rgb16().pixels().map(|p| p.0).collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn strips_match_single_request() {
+        let server = MockServer::new(3, 2)
+            .pixmap(PIXMAP, (3, 2), 24, bgrx_pixmap())
+            .property("_XROOTPMAP_ID", PIXMAP);
+        let single = grab_background(&server, 0).unwrap();
+        assert_eq!(server.images.get(), 1);
+
+        let options = GrabOptions {
+            strip_height: Some(1),
+            ..GrabOptions::default()
+        };
+        let stripped = grab_background_with(&server, 0, &options).unwrap();
+        assert_eq!(server.images.get(), 3);
+        assert_eq!(stripped.as_bytes(), single.as_bytes());
+    }
+}
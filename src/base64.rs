@@ -0,0 +1,83 @@
+//! Streaming base64 (RFC 4648, standard alphabet with padding), so encoded images never have to
+//! be held in memory twice.
+
+use std::io::{self, Write};
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+/// Input bytes encoded per write to the inner writer; a multiple of 3, so only the end pads.
+const CHUNK: usize = 3 * 1024;
+
+/// Base64-encodes everything written to it and passes the text on to the inner writer.
+///
+/// [`Base64Writer::finish`] must be called to write out the last bytes and the padding.
+pub(crate) struct Base64Writer<W: Write> {
+    inner: W,
+    /// Input that didn't fill a whole group of three bytes yet.
+    pending: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl<W: Write> Base64Writer<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            pending: Vec::with_capacity(CHUNK),
+            out: Vec::with_capacity(CHUNK / 3 * 4),
+        }
+    }
+
+    /// Writes the remaining input with padding and returns the inner writer.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let pending = std::mem::take(&mut self.pending);
+        self.encode(&pending)?;
+
+        Ok(self.inner)
+    }
+
+    /// Encodes and writes `input`, padding the last group if it's incomplete.
+    fn encode(&mut self, input: &[u8]) -> io::Result<()> {
+        self.out.clear();
+        for group in input.chunks(3) {
+            let bytes = [
+                group[0],
+                *group.get(1).unwrap_or(&0),
+                *group.get(2).unwrap_or(&0),
+            ];
+            let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+            for i in 0..4 {
+                self.out.push(if i <= group.len() {
+                    ALPHABET[((bits >> (18 - 6 * i)) & 0x3f) as usize]
+                } else {
+                    b'='
+                });
+            }
+        }
+
+        self.inner.write_all(&self.out)
+    }
+}
+
+impl<W: Write> Write for Base64Writer<W> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len();
+        while !buf.is_empty() {
+            let take = (CHUNK - self.pending.len()).min(buf.len());
+            self.pending.extend_from_slice(&buf[..take]);
+            buf = &buf[take..];
+            if self.pending.len() == CHUNK {
+                let pending = std::mem::take(&mut self.pending);
+                self.encode(&pending)?;
+                self.pending = pending;
+                self.pending.clear();
+            }
+        }
+
+        Ok(len)
+    }
+
+    /// Only flushes the inner writer; incomplete groups have to wait for more input or
+    /// [`Base64Writer::finish`].
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,2094 @@
+use crate::completions::Shell;
+use getopts::{Matches, Options};
+use image::Rgb;
+use std::{
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use xbgdump::{
+    check_filename_template, resolve_format, Adjustments, CropGeometry, Flip, Format, LogLevel,
+    MaskFill, MonitorSelector, OutputDest, PngColorTag, PngCompression, PngFilter, PngOptions,
+    PnmSubtype, Rect, Rotation, ScaleFilter, ScaleTarget, Source, TextEncoding, TiffCompression,
+    WriteOptions, BACKGROUND_ATOMS,
+};
+
+/// Where the captured image ends up.
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// The whole background goes to a single destination, and optionally a copy to a file.
+    Single {
+        output: OutputDest,
+        format: Format,
+        tee: Option<PathBuf>,
+    },
+    /// The same capture goes to several destinations, each in its own format. No destinations
+    /// at all if only `--color-stats` are wanted.
+    Multiple { outputs: Vec<(OutputDest, Format)> },
+    /// Every enabled monitor gets its own file, named by expanding the template.
+    Split {
+        template: String,
+        format: Option<Format>,
+    },
+    /// Offered as PNG and BMP on X selections until other clients take them over.
+    Selections {
+        /// `CLIPBOARD`, `PRIMARY` or both.
+        selections: Vec<&'static str>,
+        /// Stop offering the image after this long.
+        timeout: Option<Duration>,
+    },
+}
+
+/// How long `--clipboard` and `--primary-selection` offer the image unless told otherwise.
+const DEFAULT_SELECTION_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long `--retry` waits between attempts unless told otherwise.
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How to notice background changes in watch mode.
+#[derive(Debug, Clone, Copy)]
+pub enum WatchMode {
+    /// Wait for PropertyNotify events on the root window.
+    Events,
+    /// Check the background properties every so many milliseconds.
+    Poll(i32),
+}
+
+/// Fully parsed command-line arguments for a capture run.
+#[derive(Debug, Clone)]
+pub struct Args {
+    pub target: Target,
+    pub mask: bool,
+    pub mask_fill: MaskFill,
+    /// Never produce transparent pixels, neither from the pixmap nor from masking.
+    pub force_opaque: bool,
+    /// Process and write 8-bit captures with 16 bits per channel.
+    pub force_16bit: bool,
+    /// Reduce deep color captures to 8 bits per channel right away.
+    pub force_8bit: bool,
+    pub crop: Option<CropGeometry>,
+    pub region: Option<Rect>,
+    pub png: PngOptions,
+    pub pnm_subtype: PnmSubtype,
+    pub tiff_compression: TiffCompression,
+    /// Quality of lossy formats from 0 to 100.
+    pub jpeg_quality: u8,
+    /// Quality of near-lossless WebP, `None` for lossless.
+    pub webp_quality: Option<u8>,
+    /// Most colors in XPM output.
+    pub xpm_colors: u16,
+    /// Most colors in GIF output.
+    pub gif_colors: u16,
+    /// Most colors in sixel output.
+    pub sixel_colors: u16,
+    /// Dither palette output.
+    pub dither: bool,
+    /// Scale the image down to the terminal's size, unless scaled explicitly.
+    pub fit_terminal: bool,
+    /// Terminal size in pixels, if given instead of asking the terminal.
+    pub terminal_size: Option<(u32, u32)>,
+    /// Color to flatten transparent BMP output over.
+    pub bmp_background: Option<Rgb<u8>>,
+    /// Print the image's average, median and dominant colors as JSON.
+    pub color_stats: bool,
+    /// Where to write the per-channel histogram as JSON.
+    pub histogram: Option<OutputDest>,
+    /// Print the color of the pixel at these coordinates of the final image.
+    pub pixel: Option<(u32, u32)>,
+    /// Print the pixel as `#rrggbbaa` instead of `rgba(...)`.
+    pub pixel_hex: bool,
+    /// Record the capture time and version in PNG files.
+    pub metadata: bool,
+    /// Write the image as a text form instead of binary.
+    pub text: Option<TextEncoding>,
+    /// End text output with a newline.
+    pub newline: bool,
+    /// ICC profile to tag PNG files with.
+    pub icc_profile: Option<PathBuf>,
+    pub scale: Option<ScaleTarget>,
+    pub filter: ScaleFilter,
+    pub flip: Option<Flip>,
+    pub rotate: Option<Rotation>,
+    pub adjustments: Adjustments,
+    pub allow_upscale: bool,
+    pub monitor: Option<MonitorSelector>,
+    pub strict: bool,
+    /// Whether monitor crops are turned to match the CRTC's rotation and reflection.
+    pub apply_rotation: bool,
+    pub shm: bool,
+    /// Rows per GetImage request, instead of as many as the server accepts.
+    pub strip_height: Option<u16>,
+    /// Repeat a pixmap smaller than the screen across all of it.
+    pub tile: bool,
+    /// Whether existing output files may be replaced.
+    pub clobber: bool,
+    /// Skip files whose last written image had the same pixels.
+    pub if_changed: bool,
+    /// Print the SHA-256 of the encoded data written.
+    pub checksum: bool,
+    pub display: Option<String>,
+    pub screen: Option<usize>,
+    /// Look for the background in this root window property only.
+    pub property: Option<&'static str>,
+    pub source: Source,
+    /// Where to capture from if there's no background pixmap.
+    pub fallback: Option<Source>,
+    pub watch: Option<WatchMode>,
+    /// How long to wait for the X server in total before giving up.
+    pub timeout: Option<Duration>,
+    /// How many times to try the first capture, at least once.
+    pub retry: u32,
+    /// How long to wait between attempts.
+    pub retry_delay: Duration,
+    /// Set by `-v`/`--quiet`; `None` leaves it to `RUST_LOG`.
+    pub log_level: Option<LogLevel>,
+}
+
+impl Args {
+    /// Whether the image is changed in any way between grabbing and encoding it.
+    pub fn is_processed(&self) -> bool {
+        self.mask
+            || self.crop.is_some()
+            || self.region.is_some()
+            || self.scale.is_some()
+            || self.flip.is_some()
+            || self.rotate.is_some()
+            || !self.adjustments.is_identity()
+            || self.monitor.is_some()
+            || self.tile
+            || self.force_opaque
+            || self.force_16bit
+            || self.force_8bit
+            || matches!(self.target, Target::Split { .. })
+    }
+
+    /// Root window properties to look for the background pixmap in.
+    pub fn properties(&self) -> &[&'static str] {
+        match &self.property {
+            Some(property) => std::slice::from_ref(property),
+            None => BACKGROUND_ATOMS,
+        }
+    }
+}
+
+/// Arguments of the `list-monitors` subcommand.
+#[derive(Debug, Clone)]
+pub struct ListArgs {
+    pub json: bool,
+    pub display: Option<String>,
+    pub screen: Option<usize>,
+    pub log_level: Option<LogLevel>,
+}
+
+/// Arguments of `--probe`, which only looks at the background without fetching it.
+#[derive(Debug, Clone)]
+pub struct ProbeArgs {
+    pub json: bool,
+    pub display: Option<String>,
+    pub screen: Option<usize>,
+    pub property: Option<&'static str>,
+    pub source: Source,
+    pub fallback: Option<Source>,
+    pub log_level: Option<LogLevel>,
+}
+
+/// What the binary should do after argument parsing.
+#[derive(Debug)]
+pub enum Command {
+    // Boxed since it's so much larger than the others
+    Capture(Box<Args>),
+    Probe(ProbeArgs),
+    ListMonitors(ListArgs),
+    Completions(Shell),
+    Help(String),
+    Version,
+}
+
+/// Invalid command line, reported together with a hint on how to get help.
+#[derive(Debug)]
+pub struct UsageError {
+    program: String,
+    message: String,
+}
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}\nTry '{} --help' for more information.",
+            self.program, self.message, self.program
+        )
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// What an option takes, as far as parsing and shell completion are concerned.
+#[derive(Debug, Clone)]
+pub enum OptArg {
+    /// A flag that may be given once.
+    Flag,
+    /// A flag that may be repeated, like `-vv`.
+    Repeated,
+    /// An option taking a value, described by `hint` in the help text.
+    Value {
+        hint: &'static str,
+        complete: Complete,
+    },
+}
+
+/// How shells should complete an option's value.
+#[derive(Debug, Clone)]
+pub enum Complete {
+    Nothing,
+    Files,
+    OneOf(Vec<&'static str>),
+}
+
+/// A single command-line option.
+///
+/// All options are described by these tables, so the parser and the completion scripts
+/// can't disagree about what exists.
+#[derive(Debug, Clone)]
+pub struct OptSpec {
+    /// Short name without the dash, or empty if there is none.
+    pub short: &'static str,
+    pub long: &'static str,
+    pub desc: String,
+    pub arg: OptArg,
+}
+
+fn flag(short: &'static str, long: &'static str, desc: impl Into<String>) -> OptSpec {
+    OptSpec {
+        short,
+        long,
+        desc: desc.into(),
+        arg: OptArg::Flag,
+    }
+}
+
+fn value(
+    short: &'static str,
+    long: &'static str,
+    desc: impl Into<String>,
+    hint: &'static str,
+    complete: Complete,
+) -> OptSpec {
+    OptSpec {
+        short,
+        long,
+        desc: desc.into(),
+        arg: OptArg::Value { hint, complete },
+    }
+}
+
+/// Subcommands, recognized only as the first argument.
+pub const SUBCOMMANDS: &[&str] = &["list-monitors", "completions"];
+
+/// Options shared by all commands that talk to the X server.
+fn connection_specs() -> Vec<OptSpec> {
+    vec![
+        OptSpec {
+            arg: OptArg::Repeated,
+            ..flag(
+                "v",
+                "verbose",
+                "Print what's going on to stderr. Repeat for more detail (-vv, -vvv).",
+            )
+        },
+        flag("q", "quiet", "Only print errors."),
+        value(
+            "d",
+            "display",
+            "X display to connect to instead of $DISPLAY.",
+            "DISPLAY",
+            Complete::Nothing,
+        ),
+        value(
+            "s",
+            "screen",
+            "X screen to use instead of the display's default screen.",
+            "N",
+            Complete::Nothing,
+        ),
+    ]
+}
+
+/// Options of the main capture command.
+pub fn capture_specs() -> Vec<OptSpec> {
+    let mut specs = vec![
+        value(
+            "o",
+            "output",
+            "Write to FILE instead of the positional argument (- for stdout).",
+            "FILE",
+            Complete::Files,
+        ),
+        value(
+            "",
+            "tee",
+            "Also write the same encoded image to FILE, e.g. to pipe it somewhere and keep a copy.",
+            "FILE",
+            Complete::Files,
+        ),
+        flag(
+            "",
+            "clipboard",
+            "Put the image on the clipboard instead of writing a file, and keep running \
+            until something else is copied.",
+        ),
+        flag(
+            "",
+            "primary-selection",
+            "Like --clipboard, but for the PRIMARY selection (middle-click paste). \
+            Both can be given.",
+        ),
+        value(
+            "",
+            "selection-timeout",
+            "Stop offering the image on selections after SECS seconds, 0 for never (default: 30).",
+            "SECS",
+            Complete::Nothing,
+        ),
+    ];
+    specs.extend(connection_specs());
+    specs.extend(vec![
+        value(
+            "f",
+            "format",
+            format!(
+                "Output format, overriding the file extension. One of {}.",
+                Format::names()
+            ),
+            "FORMAT",
+            Complete::OneOf(Format::ALL.iter().map(|f| f.name()).collect()),
+        ),
+        value(
+            "",
+            "png-compression",
+            format!(
+                "PNG compression effort. One of {} or a level from 1 to 9 (default: {}).",
+                PngCompression::ALL
+                    .iter()
+                    .map(|c| c.name())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                PngCompression::default().name()
+            ),
+            "LEVEL",
+            Complete::OneOf(PngCompression::ALL.iter().map(|c| c.name()).collect()),
+        ),
+        flag(
+            "",
+            "data-uri",
+            "Write a data:image/png;base64,... URI instead of the binary image \
+            (PNG, BMP, WebP, JPEG or GIF; stdout defaults to PNG).",
+        ),
+        flag(
+            "",
+            "base64",
+            "Write the image as plain base64 instead of binary, in any format \
+            (stdout defaults to PNG). With --data-uri, the URI prefix is added too.",
+        ),
+        flag("", "newline", "End --data-uri and --base64 output with a newline."),
+        flag(
+            "",
+            "color-stats",
+            "Print the average, median and five dominant colors of the final image to stdout \
+            as JSON, for generating color schemes. Without outputs, no image is written.",
+        ),
+        value(
+            "",
+            "histogram",
+            "Write how many pixels have each intensity, per channel, to FILE as JSON ('-' for \
+            stdout). Without outputs, no image is written.",
+            "FILE",
+            Complete::Files,
+        ),
+        value(
+            "",
+            "pixel",
+            "Print the color of the pixel at X,Y of the final image as rgba(R, G, B, A), or as \
+            #rrggbbaa with --format hex. Without outputs, no image is written.",
+            "X,Y",
+            Complete::Nothing,
+        ),
+        flag(
+            "",
+            "reproducible",
+            "Write the same bytes for the same pixels on every run: no metadata, and fixed PNG \
+            settings. Only for png, pam, ff and qoi output.",
+        ),
+        flag(
+            "",
+            "no-color-tag",
+            "Don't tag PNG files as sRGB, leaving it to the viewer how to show their colors.",
+        ),
+        value(
+            "",
+            "png-gamma",
+            "Tag PNG files with the gamma of the display, e.g. 1.8, instead of as sRGB.",
+            "GAMMA",
+            Complete::Nothing,
+        ),
+        flag(
+            "",
+            "no-metadata",
+            "Don't record the capture time, xbgdump version, display name and background \
+            geometry in PNG files.",
+        ),
+        value(
+            "",
+            "png-filter",
+            format!(
+                "PNG scanline filter. One of {} (default: {}).",
+                PngFilter::ALL
+                    .iter()
+                    .map(|f| f.name())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                PngFilter::default().name()
+            ),
+            "FILTER",
+            Complete::OneOf(PngFilter::ALL.iter().map(|f| f.name()).collect()),
+        ),
+        value(
+            "",
+            "pnm-subtype",
+            format!(
+                "Netpbm flavor of pam output: {} (default: {}). ppm and pgm have no \
+                alpha, so transparency is composited over black; pgm requires --grayscale, \
+                and ascii writes plain-text PGM or PPM.",
+                PnmSubtype::ALL
+                    .iter()
+                    .map(|s| s.name())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                PnmSubtype::default().name()
+            ),
+            "SUBTYPE",
+            Complete::OneOf(PnmSubtype::ALL.iter().map(|s| s.name()).collect()),
+        ),
+        value(
+            "",
+            "tiff-compression",
+            format!(
+                "TIFF compression. One of {} (default: {}).",
+                TiffCompression::ALL
+                    .iter()
+                    .map(|c| c.name())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                TiffCompression::default().name()
+            ),
+            "METHOD",
+            Complete::OneOf(TiffCompression::ALL.iter().map(|c| c.name()).collect()),
+        ),
+        value(
+            "",
+            "bmp-background-color",
+            "Composite transparent BMP output over COLOR (rrggbb, #rrggbb, #rgb or a name \
+            like white) instead of keeping the alpha channel, for tools that can't handle it.",
+            "COLOR",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "xpm-colors",
+            format!(
+                "Most colors in XPM output, from 1 to 4096 (default: {}). Images with more \
+                are reduced with median cut; transparency takes one more.",
+                WriteOptions::default().xpm_colors
+            ),
+            "COUNT",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "colors",
+            format!(
+                "Most colors in GIF output, from 2 to 256 including transparency \
+                (default: {}). Fully transparent pixels become transparent, the rest opaque.",
+                WriteOptions::default().gif_colors
+            ),
+            "COUNT",
+            Complete::Nothing,
+        ),
+        flag(
+            "",
+            "sixel",
+            "Show the image in the terminal as sixel graphics, scaled down to fit unless \
+            --scale, --max-dim or --resize is given. Like --format sixel - otherwise.",
+        ),
+        value(
+            "",
+            "sixel-colors",
+            format!(
+                "Most colors in sixel output, from 16 to 256 (default: {}).",
+                WriteOptions::default().sixel_colors
+            ),
+            "COUNT",
+            Complete::Nothing,
+        ),
+        flag(
+            "",
+            "kitty",
+            "Show the image in the terminal with kitty's graphics protocol, scaled down to fit \
+            unless --scale, --max-dim or --resize is given.",
+        ),
+        value(
+            "",
+            "kitty-id",
+            "Image ID to place the --kitty image under, from 1 to 4294967295.",
+            "ID",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "terminal-size",
+            "Size of the terminal in pixels for --sixel and --kitty, for terminals that don't \
+            report it.",
+            "WxH",
+            Complete::Nothing,
+        ),
+        flag(
+            "",
+            "dither",
+            "Dither XPM, GIF and sixel output when reducing its colors, which trades banding \
+            in gradients for noise.",
+        ),
+        value(
+            "",
+            "quality",
+            format!(
+                "Quality of lossy output from 0 to 100 (default: {}). JPEG is always lossy, \
+                where 0 is the same as 1 and transparency is composited over black; \
+                WebP only with --lossy. The other formats are lossless and ignore it.",
+                WriteOptions::default().jpeg_quality
+            ),
+            "QUALITY",
+            Complete::Nothing,
+        ),
+        flag(
+            "",
+            "lossy",
+            "Round colors in busy areas of WebP output according to --quality, for smaller \
+            files (near-lossless). Gradients and transparency are kept exact.",
+        ),
+        value(
+            "",
+            "webp-quality",
+            "Quality of near-lossless WebP output from 0 to 100, instead of --quality. \
+            Implies --lossy, so JPEG and WebP outputs of the same capture can differ.",
+            "QUALITY",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "icc-profile",
+            "Embed the ICC profile in FILE in PNG and JPEG output, instead of the display's own \
+            from the _ICC_PROFILE root window property. The colors are not converted.",
+            "FILE",
+            Complete::Files,
+        ),
+        value(
+            "",
+            "monitor",
+            "Only capture the monitor shown on the RandR output NAME (e.g. DP-1), \
+            or the CRTC with the given index.",
+            "NAME|INDEX",
+            Complete::Nothing,
+        ),
+        flag(
+            "",
+            "primary",
+            "Only capture the RandR primary output. Without a primary output, \
+            the first enabled monitor is used instead.",
+        ),
+        flag(
+            "",
+            "under-cursor",
+            "Only capture the monitor the mouse pointer is on, or the closest one if it's \
+            between monitors.",
+        ),
+        flag(
+            "",
+            "strict",
+            "Fail instead of falling back if --primary finds no primary output.",
+        ),
+        flag(
+            "",
+            "apply-rotation",
+            "Turn the images of --monitor, --primary and --split the way their CRTC is rotated \
+            and reflected, so they match the panel's native orientation.",
+        ),
+        flag(
+            "n",
+            "no-clobber",
+            "Refuse to overwrite existing files. Ignored for stdout.",
+        ),
+        flag(
+            "",
+            "force",
+            "Overwrite existing files (the default). Overrides an earlier --no-clobber.",
+        ),
+        value(
+            "",
+            "split",
+            "Write each monitor to its own file. {name}, {index}, {x}, {y}, {w} and {h} in TEMPLATE \
+            are replaced by the RandR output name, CRTC index and geometry.",
+            "TEMPLATE",
+            Complete::Files,
+        ),
+        flag("m", "mask", "Mask off-screen areas with full transparency."),
+        value(
+            "",
+            "mask-color",
+            "Fill masked areas with COLOR (#rgb, #rrggbb, a name like black, or transparent) \
+            instead of transparency. Solid colors keep the image RGB.",
+            "COLOR",
+            Complete::Nothing,
+        ),
+        flag(
+            "",
+            "force-opaque",
+            "Drop the alpha channel of 32-bit backgrounds and mask with black by default.",
+        ),
+        flag(
+            "",
+            "force-16bit",
+            "Widen 8-bit backgrounds to 16 bits per channel, as 30-bit ones always are.",
+        ),
+        value(
+            "",
+            "bit-depth",
+            "Bits per channel to process and write: 16 is the same as --force-16bit, \
+            8 reduces 30-bit backgrounds right after capturing them. \
+            By default, the background's own precision is kept.",
+            "8|16",
+            Complete::OneOf(vec!["8", "16"]),
+        ),
+        flag(
+            "",
+            "no-mask",
+            "Don't mask off-screen areas (the default). Skips all RandR requests.",
+        ),
+        value(
+            "",
+            "crop",
+            "Crop the final image to GEOMETRY (WxH+X+Y, negative offsets count from the \
+            right/bottom edge).",
+            "GEOMETRY",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "region",
+            "Crop the final image to exactly this rectangle, which must lie within the image.",
+            "X,Y,W,H",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "flip",
+            "Mirror the final image horizontally (h) or vertically (v). Applied before --rotate.",
+            "h|v",
+            Complete::OneOf(vec!["h", "v"]),
+        ),
+        value(
+            "",
+            "rotate",
+            "Rotate the final image clockwise by DEGREES (90, 180 or 270), after cropping.",
+            "DEGREES",
+            Complete::OneOf(vec!["90", "180", "270"]),
+        ),
+        value(
+            "",
+            "scale",
+            "Scale the final image by PERCENT (e.g. 25%) or a factor (e.g. 0.25).",
+            "PERCENT",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "max-dim",
+            "Scale the final image so its longer side is PX pixels long.",
+            "PX",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "resize",
+            "Scale the final image to SIZE: WxH stretches it to exactly that size, W or xH keep \
+            the aspect ratio. Applied after --crop and --region.",
+            "SIZE",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "filter",
+            format!(
+                "Resampling filter for --scale, --max-dim and --resize. One of {} (default: {}).",
+                ScaleFilter::ALL
+                    .iter()
+                    .map(|f| f.name())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                ScaleFilter::default().name()
+            ),
+            "FILTER",
+            Complete::OneOf(ScaleFilter::ALL.iter().map(|f| f.name()).collect()),
+        ),
+        value(
+            "",
+            "gamma",
+            "Apply gamma correction to the final image, brightening mid-tones above 1.",
+            "GAMMA",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "brightness",
+            "Add N (-255 to 255) to every color channel of the final image.",
+            "N",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "contrast",
+            "Change the contrast of the final image by PERCENT, negative values reduce it.",
+            "PERCENT",
+            Complete::Nothing,
+        ),
+        flag(
+            "",
+            "grayscale",
+            "Convert the final image to grayscale, keeping transparency.",
+        ),
+        flag(
+            "",
+            "invert",
+            "Invert the colors of the final image, after all other adjustments.",
+        ),
+        flag(
+            "",
+            "allow-upscale",
+            "Allow --scale, --max-dim and --resize to enlarge the image.",
+        ),
+        flag(
+            "w",
+            "watch",
+            "Keep running and write the background again whenever it changes.",
+        ),
+        value(
+            "",
+            "interval",
+            "Poll for changes every MS milliseconds instead of waiting for events. Implies --watch.",
+            "MS",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "timeout",
+            "Give up if talking to the X server takes longer than SECS seconds in total.",
+            "SECS",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "retry",
+            "Try up to N times if the X server can't be reached or no background is set yet, \
+            e.g. while logging in. The default of 1 doesn't retry.",
+            "N",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "retry-delay",
+            "Wait MS milliseconds between attempts (default: 1000).",
+            "MS",
+            Complete::Nothing,
+        ),
+        flag(
+            "",
+            "no-shm",
+            "Don't use MIT-SHM shared memory to transfer the image.",
+        ),
+        value(
+            "",
+            "strip-height",
+            "Transfer the image in strips of at most ROWS rows when not using MIT-SHM. By default, \
+            strips are as large as the server's maximum request length allows.",
+            "ROWS",
+            Complete::Nothing,
+        ),
+        flag(
+            "",
+            "tile",
+            "Repeat a background smaller than the screen across all of it, like the X server does.",
+        ),
+        flag(
+            "",
+            "if-changed",
+            "Don't rewrite files whose image hasn't changed since the last run, and exit with \
+            status 9 if nothing was written.",
+        ),
+        flag(
+            "",
+            "checksum",
+            "Print the SHA-256 of the data written, as sha256:HEX on stderr, to tell whether the \
+            background changed between runs.",
+        ),
+        flag(
+            "",
+            "probe",
+            "Print the background's size, depth and pixmap ID without fetching any pixels.",
+        ),
+        value(
+            "",
+            "property",
+            "Root window property holding the background pixmap, for setters that use neither \
+            _XROOTPMAP_ID nor ESETROOT_PMAP_ID.",
+            "NAME",
+            Complete::Nothing,
+        ),
+        value(
+            "",
+            "source",
+            "Capture the background pixmap (the default), the root window, or the desktop windows \
+            (through Composite), for desktops that paint the wallpaper without publishing a \
+            pixmap. Open windows covering the root window are captured along with it.",
+            "SOURCE",
+            Complete::OneOf(vec!["pixmap", "root-window", "desktop-window"]),
+        ),
+        value(
+            "",
+            "fallback",
+            "Capture the root window if no background pixmap is set. As with --source, open \
+            windows end up in the image.",
+            "SOURCE",
+            Complete::OneOf(vec!["root-window"]),
+        ),
+        flag(
+            "",
+            "list-monitors",
+            "Print the monitor layout instead of capturing, like the list-monitors subcommand.",
+        ),
+        flag(
+            "",
+            "json",
+            "With --probe or --list-monitors, print JSON instead of plain lines.",
+        ),
+        flag("h", "help", "Show this help."),
+        flag("V", "version", "Show version information."),
+    ]);
+
+    specs
+}
+
+/// Options of the `list-monitors` subcommand.
+pub fn list_specs() -> Vec<OptSpec> {
+    let mut specs = vec![flag(
+        "",
+        "json",
+        "Print the layout as JSON instead of one line per monitor.",
+    )];
+    specs.extend(connection_specs());
+    specs.push(flag("h", "help", "Show this help."));
+
+    specs
+}
+
+/// Options of the `completions` subcommand.
+pub fn completions_specs() -> Vec<OptSpec> {
+    vec![flag("h", "help", "Show this help.")]
+}
+
+fn build(specs: &[OptSpec]) -> Options {
+    let mut opts = Options::new();
+    for spec in specs {
+        match &spec.arg {
+            OptArg::Flag => opts.optflag(spec.short, spec.long, &spec.desc),
+            OptArg::Repeated => opts.optflagmulti(spec.short, spec.long, &spec.desc),
+            OptArg::Value { hint, .. } => opts.optopt(spec.short, spec.long, &spec.desc, hint),
+        };
+    }
+
+    opts
+}
+
+fn usage(program: &str, opts: &Options) -> String {
+    opts.usage(&format!(
+        "USAGE: {0} [options] [--] [<outfile>|-]...\n       {0} list-monitors [--json]\n       {0} completions <bash|zsh|fish>\n\
+    xbgdump saves the current X11 background to the specified file (or stdout for -).\n\
+    Unless --format is given, the format is inferred from the file extension; stdout defaults to PAM.\n\
+    Several outputs get the same capture, each file in the format of its extension; --format then only applies to -.\n\
+    Of the compressed lossless formats, qoi is the fastest to encode and webp the smallest.\n\
+    File names may contain strftime conversions, %w and %h for the image size, and %% for a literal %.\n\
+    --format raw writes the unconverted pixels after a 32-byte header, numbers in little endian:\n\
+    magic \"XBGDRAW1\", u32 width, u32 height, u32 stride, u8 depth, u8 bits per pixel,\n\
+    2 reserved bytes, 4 ASCII bytes of channel order (e.g. BGRX, zero if unknown), u32 data length.\n\
+    list-monitors prints the RandR layout used for masking, --split and --monitor.\n\
+    completions prints a shell completion script.\n\
+    Exit statuses: 0 success, 1 other errors, 2 usage error, 3 cannot connect to X,\n\
+    4 no background set, 5 X protocol error, 6 I/O error, 7 output exists (--no-clobber),\n\
+    8 timeout (--timeout), 9 unchanged (--if-changed).",
+        program
+    ))
+}
+
+fn list_usage(program: &str, opts: &Options) -> String {
+    opts.usage(&format!(
+        "USAGE: {} list-monitors [options]\n\
+    Prints every enabled CRTC as seen by xbgdump: output name, geometry, rotation and whether it's primary.\n\
+    With --json, disabled CRTCs are included and marked as inactive.",
+        program
+    ))
+}
+
+fn completions_usage(program: &str, opts: &Options) -> String {
+    opts.usage(&format!(
+        "USAGE: {} completions <bash|zsh|fish>\n\
+    Prints a completion script for the given shell to stdout.",
+        program
+    ))
+}
+
+/// Parses the full argument list, including the program name in the first position.
+pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Command, UsageError> {
+    let mut args = args.into_iter();
+    let program = args
+        .next()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "xbgdump".into());
+    let args: Vec<_> = args.collect();
+
+    let opts = build(&capture_specs());
+    let error = |message: String| UsageError {
+        program: program.clone(),
+        message,
+    };
+
+    // Only recognized in the first position, so `-- list-monitors` still writes to such a file
+    if args.first().is_some_and(|a| a == "list-monitors") {
+        let opts = build(&list_specs());
+        let parsed = opts.parse(&args[1..]).map_err(|e| error(e.to_string()))?;
+        if parsed.opt_present("h") {
+            return Ok(Command::Help(list_usage(&program, &opts)));
+        }
+
+        return list_from_matches(parsed)
+            .map(Command::ListMonitors)
+            .map_err(error);
+    }
+
+    if args.first().is_some_and(|a| a == "completions") {
+        let opts = build(&completions_specs());
+        let parsed = opts.parse(&args[1..]).map_err(|e| error(e.to_string()))?;
+        if parsed.opt_present("h") {
+            return Ok(Command::Help(completions_usage(&program, &opts)));
+        }
+
+        return match parsed.free.as_slice() {
+            [shell] => shell.parse().map(Command::Completions).map_err(error),
+            [] => Err(error("Missing shell. Expected bash, zsh or fish.".into())),
+            [_, extra, ..] => Err(error(format!("Unexpected argument '{}'.", extra))),
+        };
+    }
+
+    let parsed = opts.parse(&args).map_err(|e| error(e.to_string()))?;
+
+    // --help always wins, even if the rest of the command line is bogus
+    if parsed.opt_present("h") {
+        return Ok(Command::Help(usage(&program, &opts)));
+    }
+
+    if parsed.opt_present("V") {
+        return Ok(Command::Version);
+    }
+
+    if parsed.opt_present("list-monitors") {
+        reject_capture_options(&parsed, "list-monitors").map_err(&error)?;
+        return list_from_matches(parsed)
+            .map(Command::ListMonitors)
+            .map_err(error);
+    }
+
+    if parsed.opt_present("probe") {
+        return probe_from_matches(parsed)
+            .map(Command::Probe)
+            .map_err(error);
+    }
+
+    from_matches(parsed)
+        .map(|args| Command::Capture(Box::new(args)))
+        .map_err(error)
+}
+
+/// Catches typos in file name templates now rather than after capturing.
+fn check_template(path: &Path) -> Result<(), String> {
+    match path.to_str() {
+        Some(template) => check_filename_template(template).map_err(|e| e.to_string()),
+        // Never expanded, see expand_dest() in main.rs
+        None => Ok(()),
+    }
+}
+
+/// Parses the `X,Y` of `--pixel`.
+fn parse_pixel(s: &str) -> Result<(u32, u32), String> {
+    s.split_once(',')
+        .and_then(|(x, y)| Some((x.trim().parse().ok()?, y.trim().parse().ok()?)))
+        .ok_or_else(|| format!("Invalid pixel '{}'. Expected X,Y.", s))
+}
+
+fn parse_output(out: &str) -> OutputDest {
+    if out == "-" {
+        OutputDest::Stdout
+    } else {
+        OutputDest::File(out.into())
+    }
+}
+
+/// Resolves a pair of opposing flags, letting whichever was given last win.
+fn last_flag(parsed: &Matches, yes: &str, no: &str, default: bool) -> bool {
+    let last = |name| parsed.opt_positions(name).into_iter().max();
+    match (last(yes), last(no)) {
+        (Some(y), Some(n)) => y > n,
+        (Some(_), None) => true,
+        (None, Some(_)) => false,
+        (None, None) => default,
+    }
+}
+
+/// Parses `--gamma`, `--brightness`, `--contrast`, `--grayscale` and `--invert`.
+fn adjustments(parsed: &Matches) -> Result<Adjustments, String> {
+    let defaults = Adjustments::default();
+    let gamma = match parsed.opt_get::<f32>("gamma") {
+        Ok(Some(gamma)) if gamma.is_finite() && gamma > 0.0 => gamma,
+        Ok(Some(_)) => return Err("Gamma must be positive.".into()),
+        Ok(None) => defaults.gamma,
+        Err(e) => return Err(format!("Invalid gamma: {}.", e)),
+    };
+    let brightness = match parsed.opt_get::<i32>("brightness") {
+        Ok(Some(brightness)) if (-255..=255).contains(&brightness) => brightness,
+        Ok(Some(_)) => return Err("Brightness must be between -255 and 255.".into()),
+        Ok(None) => defaults.brightness,
+        Err(e) => return Err(format!("Invalid brightness: {}.", e)),
+    };
+    let contrast = match parsed.opt_get::<f32>("contrast") {
+        Ok(Some(contrast)) if contrast.is_finite() => contrast,
+        Ok(Some(_)) => return Err("Contrast must be a finite number.".into()),
+        Ok(None) => defaults.contrast,
+        Err(e) => return Err(format!("Invalid contrast: {}.", e)),
+    };
+
+    Ok(Adjustments {
+        gamma,
+        brightness,
+        contrast,
+        grayscale: parsed.opt_present("grayscale"),
+        invert: parsed.opt_present("invert"),
+    })
+}
+
+/// Parses `-v` and `--quiet`.
+fn log_level(parsed: &Matches) -> Result<Option<LogLevel>, String> {
+    match (parsed.opt_count("v"), parsed.opt_present("q")) {
+        (0, false) => Ok(None),
+        (0, true) => Ok(Some(LogLevel::Error)),
+        (count, false) => Ok(Some(LogLevel::from_verbosity(count))),
+        (_, true) => Err("--verbose and --quiet are mutually exclusive.".into()),
+    }
+}
+
+/// Parses `--display` and `--screen`.
+fn connection_matches(parsed: &Matches) -> Result<(Option<String>, Option<usize>), String> {
+    let display = parsed.opt_str("d");
+    if display.as_deref().is_some_and(|d| d.trim().is_empty()) {
+        return Err("Display name must not be empty.".into());
+    }
+
+    let screen = parsed
+        .opt_get("s")
+        .map_err(|e| format!("Invalid screen number: {}.", e))?;
+
+    Ok((display, screen))
+}
+
+fn list_from_matches(parsed: Matches) -> Result<ListArgs, String> {
+    if let Some(extra) = parsed.free.first() {
+        return Err(format!("Unexpected argument '{}'.", extra));
+    }
+
+    let (display, screen) = connection_matches(&parsed)?;
+    Ok(ListArgs {
+        json: parsed.opt_present("json"),
+        display,
+        screen,
+        log_level: log_level(&parsed)?,
+    })
+}
+
+fn from_matches(parsed: Matches) -> Result<Args, String> {
+    if parsed.opt_present("json") {
+        return Err("--json requires --probe or --list-monitors.".into());
+    }
+
+    // With --pixel, `--format hex` picks how the pixel is printed rather than an image format
+    let pixel_hex = parsed.opt_present("pixel")
+        && parsed
+            .opt_str("f")
+            .is_some_and(|name| name.eq_ignore_ascii_case("hex"));
+    let format = parsed
+        .opt_str("f")
+        .filter(|_| !pixel_hex)
+        .map(|name| {
+            Format::from_name(&name).ok_or_else(|| {
+                format!(
+                    "Unknown format '{}'. Supported formats: {}.",
+                    name,
+                    Format::names()
+                )
+            })
+        })
+        .transpose()?;
+
+    let text_output = parsed.opt_present("data-uri") || parsed.opt_present("base64");
+    // Formats that only make sense shown in the terminal
+    let terminal = match (parsed.opt_present("sixel"), parsed.opt_present("kitty")) {
+        (true, true) => return Err("--sixel and --kitty are mutually exclusive.".into()),
+        (true, false) => Some(("--sixel", Format::Sixel)),
+        (false, true) => Some(("--kitty", Format::Png)),
+        (false, false) => None,
+    };
+    let target = match (
+        parsed.opt_str("split"),
+        parsed.opt_str("o"),
+        parsed.free.as_slice(),
+    ) {
+        (split, o, free)
+            if parsed.opt_present("clipboard") || parsed.opt_present("primary-selection") =>
+        {
+            if split.is_some()
+                || o.is_some()
+                || !free.is_empty()
+                || parsed.opt_present("tee")
+                || terminal.is_some()
+            {
+                return Err(
+                    "--clipboard and --primary-selection cannot be combined with \
+                    output files, --tee, --split, --sixel or --kitty."
+                        .into(),
+                );
+            }
+            if format.is_some() {
+                return Err("Selections always offer both PNG and BMP, drop --format.".into());
+            }
+            let selections = [("clipboard", "CLIPBOARD"), ("primary-selection", "PRIMARY")]
+                .iter()
+                .filter(|(flag, _)| parsed.opt_present(flag))
+                .map(|&(_, selection)| selection)
+                .collect();
+            let timeout = match parsed.opt_get::<f64>("selection-timeout") {
+                Ok(Some(0.0)) => None,
+                Ok(Some(secs)) if secs.is_finite() && secs > 0.0 => {
+                    Some(Duration::from_secs_f64(secs))
+                }
+                Ok(Some(_)) => return Err("Selection timeout must not be negative.".into()),
+                Ok(None) => Some(DEFAULT_SELECTION_TIMEOUT),
+                Err(e) => return Err(format!("Invalid selection timeout: {}.", e)),
+            };
+            Target::Selections {
+                selections,
+                timeout,
+            }
+        }
+        _ if parsed.opt_present("selection-timeout") => {
+            return Err("--selection-timeout requires --clipboard or --primary-selection.".into())
+        }
+        (split, o, free) if terminal.is_some() => {
+            let (flag, terminal_format) = terminal.expect("checked by the guard");
+            if split.is_some()
+                || o.is_some()
+                || !free.is_empty()
+                || parsed.opt_present("tee")
+                || format.is_some()
+            {
+                return Err(format!(
+                    "{} always writes to stdout, drop output files, --tee, --split and --format.",
+                    flag
+                ));
+            }
+            Target::Single {
+                output: OutputDest::Stdout,
+                format: terminal_format,
+                tee: None,
+            }
+        }
+        (None, None, [])
+            if ["color-stats", "histogram", "pixel"]
+                .iter()
+                .any(|flag| parsed.opt_present(flag))
+                && !parsed.opt_present("tee")
+                && format.is_none() =>
+        {
+            Target::Multiple {
+                outputs: Vec::new(),
+            }
+        }
+        (Some(_), Some(_), _) | (Some(_), None, [_, ..]) => {
+            return Err("--split cannot be combined with an output file.".into())
+        }
+        (Some(_), _, _) if parsed.opt_present("tee") => {
+            return Err("--tee cannot be combined with --split.".into())
+        }
+        (Some(template), None, []) => {
+            check_template(Path::new(&template))?;
+            // Catch unusable extensions now rather than after capturing,
+            // unless the extension itself is templated
+            let templated_ext = Path::new(&template)
+                .extension()
+                .is_some_and(|ext| ext.to_string_lossy().contains(['{', '%']));
+            if format.is_none() && !templated_ext {
+                resolve_format(None, &OutputDest::File(template.clone().into()))
+                    .map_err(|e| e.to_string())?;
+            }
+            Target::Split { template, format }
+        }
+        (None, None, outputs @ [_, _, ..]) => {
+            if parsed.opt_present("tee") {
+                return Err("--tee cannot be combined with several outputs, \
+                    list the copy as another output instead."
+                    .into());
+            }
+            let outputs = outputs
+                .iter()
+                .map(|out| parse_output(out))
+                .collect::<Vec<_>>();
+            if outputs.iter().filter(|&o| *o == OutputDest::Stdout).count() > 1 {
+                return Err("'-' can only be given once.".into());
+            }
+            if format.is_some() && !outputs.contains(&OutputDest::Stdout) {
+                return Err("With several outputs, --format only applies to '-'; \
+                    files go by their extension."
+                    .into());
+            }
+
+            let mut targets = Vec::<(OutputDest, Format)>::with_capacity(outputs.len());
+            for output in outputs {
+                let format = match (&output, format) {
+                    (OutputDest::Stdout, Some(format)) => format,
+                    (OutputDest::Stdout, None) if text_output => Format::Png,
+                    (OutputDest::File(path), _) => {
+                        check_template(path)?;
+                        if targets.iter().any(|(o, _)| *o == output) {
+                            return Err(format!("{} is given more than once.", output));
+                        }
+                        resolve_format(None, &output).map_err(|e| e.to_string())?
+                    }
+                    (OutputDest::Stdout, None) => {
+                        resolve_format(None, &output).map_err(|e| e.to_string())?
+                    }
+                };
+                if format == Format::Raw {
+                    return Err("Raw output can only go to a single output.".into());
+                }
+                targets.push((output, format));
+            }
+            Target::Multiple { outputs: targets }
+        }
+        (None, o, free) => {
+            let output = match (o, free) {
+                (Some(_), [extra, ..]) => return Err(format!("Unexpected argument '{}'.", extra)),
+                (Some(out), []) => parse_output(&out),
+                (None, []) => OutputDest::File("bg.png".into()),
+                (None, [out]) => parse_output(out),
+                (None, [_, extra, ..]) => return Err(format!("Unexpected argument '{}'.", extra)),
+            };
+            let tee = parsed.opt_str("tee").map(PathBuf::from);
+            if let OutputDest::File(path) = &output {
+                check_template(path)?;
+            }
+            if let Some(tee) = &tee {
+                check_template(tee)?;
+            }
+            let format = match (format, &output, &tee) {
+                (Some(format), _, _) => format,
+                // Stdout has no extension to go by, but the copy might
+                (None, OutputDest::Stdout, Some(tee)) => {
+                    Format::from_path(tee).unwrap_or(Format::Pam)
+                }
+                // PAM can't go into data URIs, PNG is what they're usually used for
+                (None, OutputDest::Stdout, None) if text_output => Format::Png,
+                (None, output, _) => resolve_format(None, output).map_err(|e| e.to_string())?,
+            };
+            if let Some(tee) = &tee {
+                if output == OutputDest::File(tee.clone()) {
+                    return Err("--tee must name a different file than the output.".into());
+                }
+                // The data is only encoded once, so both destinations get the same format
+                let tee_format = Format::from_path(tee).filter(|&f| f != format);
+                if let (Some(tee_format), None) = (tee_format, parsed.opt_str("f")) {
+                    return Err(format!(
+                        "'{}' looks like {}, but would get {} data. Pass --format to confirm.",
+                        tee.display(),
+                        tee_format.name(),
+                        format.name()
+                    ));
+                }
+            }
+            Target::Single {
+                output,
+                format,
+                tee,
+            }
+        }
+    };
+
+    let (display, screen) = connection_matches(&parsed)?;
+
+    let (force_16bit, force_8bit) = match (
+        parsed.opt_present("force-16bit"),
+        parsed.opt_str("bit-depth").as_deref(),
+    ) {
+        (true, Some(_)) => {
+            return Err("--force-16bit and --bit-depth are mutually exclusive.".into())
+        }
+        (force_16bit, None) => (force_16bit, false),
+        (_, Some("16")) => (true, false),
+        (_, Some("8")) => (false, true),
+        (_, Some(depth)) => {
+            return Err(format!("Invalid bit depth '{}'. Expected 8 or 16.", depth))
+        }
+    };
+
+    let force_opaque = parsed.opt_present("force-opaque");
+    let mask_fill = match (
+        parsed
+            .opt_str("mask-color")
+            .map(|color| color.parse())
+            .transpose()?,
+        force_opaque,
+    ) {
+        (Some(MaskFill::Transparent), true) => {
+            return Err("--mask-color transparent cannot be combined with --force-opaque.".into())
+        }
+        (Some(fill), _) => fill,
+        (None, true) => MaskFill::Solid([0, 0, 0].into()),
+        (None, false) => MaskFill::default(),
+    };
+
+    let crop = parsed
+        .opt_str("crop")
+        .map(|geometry| geometry.parse())
+        .transpose()?;
+    if crop.is_some() && matches!(target, Target::Split { .. }) {
+        return Err("--crop cannot be combined with --split.".into());
+    }
+    let region = parsed
+        .opt_str("region")
+        .map(|region| region.parse())
+        .transpose()?;
+    match (&crop, &region, &target) {
+        (Some(_), Some(_), _) => return Err("--crop and --region are mutually exclusive.".into()),
+        (_, Some(_), Target::Split { .. }) => {
+            return Err("--region cannot be combined with --split.".into())
+        }
+        _ => {}
+    }
+
+    let reproducible = parsed.opt_present("reproducible");
+    if reproducible && (parsed.opt_present("png-compression") || parsed.opt_present("png-filter")) {
+        return Err(
+            "--reproducible fixes the PNG settings, drop --png-compression and --png-filter."
+                .into(),
+        );
+    }
+    let color_tag = match (
+        parsed.opt_present("no-color-tag"),
+        parsed.opt_get::<f32>("png-gamma"),
+    ) {
+        (true, Ok(Some(_)) | Err(_)) => {
+            return Err("--no-color-tag and --png-gamma are mutually exclusive.".into())
+        }
+        (true, _) => PngColorTag::None,
+        (false, Ok(Some(gamma))) if gamma.is_finite() && gamma > 0.0 => PngColorTag::Gamma(gamma),
+        (false, Ok(Some(_))) => return Err("PNG gamma must be positive.".into()),
+        (false, Ok(None)) => PngColorTag::Srgb,
+        (false, Err(e)) => return Err(format!("Invalid PNG gamma: {}.", e)),
+    };
+    // Reproducible output gets pinned settings rather than the defaults, which might change
+    let png = PngOptions {
+        compression: match parsed.opt_str("png-compression") {
+            Some(level) => level.parse()?,
+            None if reproducible => PngCompression::Fast,
+            None => PngCompression::default(),
+        },
+        filter: match parsed.opt_str("png-filter") {
+            Some(filter) => filter.parse()?,
+            None if reproducible => PngFilter::Sub,
+            None => PngFilter::default(),
+        },
+        color_tag,
+        // Only known once the background is captured
+        creation_time: None,
+        text: Vec::new(),
+        // Read by the caller, so it's only done once even in watch mode
+        icc_profile: None,
+    };
+
+    let pnm_subtype = parsed
+        .opt_str("pnm-subtype")
+        .map(|subtype| subtype.parse())
+        .transpose()?
+        .unwrap_or_default();
+
+    let tiff_compression = parsed
+        .opt_str("tiff-compression")
+        .map(|compression| compression.parse())
+        .transpose()?
+        .unwrap_or_default();
+
+    let xpm_colors = match parsed.opt_get::<u16>("xpm-colors") {
+        Ok(Some(colors)) if (1..=4096).contains(&colors) => colors,
+        Ok(None) => WriteOptions::default().xpm_colors,
+        Ok(Some(_)) | Err(_) => return Err("XPM colors must be between 1 and 4096.".into()),
+    };
+
+    let gif_colors = match parsed.opt_get::<u16>("colors") {
+        Ok(Some(colors)) if (2..=256).contains(&colors) => colors,
+        Ok(None) => WriteOptions::default().gif_colors,
+        Ok(Some(_)) | Err(_) => return Err("GIF colors must be between 2 and 256.".into()),
+    };
+
+    let sixel_colors = match parsed.opt_get::<u16>("sixel-colors") {
+        Ok(Some(colors)) if (16..=256).contains(&colors) => colors,
+        Ok(None) => WriteOptions::default().sixel_colors,
+        Ok(Some(_)) | Err(_) => return Err("Sixel colors must be between 16 and 256.".into()),
+    };
+
+    let fit_terminal = terminal.is_some();
+    let terminal_size = match parsed.opt_str("terminal-size") {
+        Some(_) if !fit_terminal => {
+            return Err("--terminal-size requires --sixel or --kitty.".into())
+        }
+        Some(size) => match ScaleTarget::parse_size(&size)? {
+            ScaleTarget::Exact(width, height) => Some((width, height)),
+            _ => return Err(format!("Invalid terminal size '{}'. Expected WxH.", size)),
+        },
+        None => None,
+    };
+
+    let bmp_background = parsed
+        .opt_str("bmp-background-color")
+        .map(|color| {
+            // The bare hex digits are fine too, since there's no name to confuse them with
+            let color = if color.len() == 6 && color.bytes().all(|b| b.is_ascii_hexdigit()) {
+                format!("#{}", color)
+            } else {
+                color
+            };
+            match color.parse()? {
+                MaskFill::Solid(color) => Ok(color),
+                MaskFill::Transparent => {
+                    Err("The BMP background color must not be transparent.".to_string())
+                }
+            }
+        })
+        .transpose()?;
+
+    let jpeg_quality = match parsed.opt_get::<u8>("quality") {
+        Ok(Some(quality)) if quality <= 100 => quality,
+        Ok(None) => WriteOptions::default().jpeg_quality,
+        Ok(Some(_)) | Err(_) => return Err("Quality must be between 0 and 100.".into()),
+    };
+    let webp_quality = match parsed.opt_get::<u8>("webp-quality") {
+        Ok(Some(quality)) if quality <= 100 => Some(quality),
+        Ok(None) => parsed.opt_present("lossy").then_some(jpeg_quality),
+        Ok(Some(_)) | Err(_) => return Err("WebP quality must be between 0 and 100.".into()),
+    };
+
+    let scale = match (parsed.opt_str("scale"), parsed.opt_get("max-dim")) {
+        (Some(_), Ok(Some(_))) => {
+            return Err("--scale and --max-dim are mutually exclusive.".into())
+        }
+        (Some(scale), _) => Some(scale.parse()?),
+        (None, Ok(Some(0))) => return Err("Maximum dimension must be positive.".into()),
+        (None, Ok(max_dim)) => max_dim.map(ScaleTarget::MaxDim),
+        (None, Err(e)) => return Err(format!("Invalid maximum dimension: {}.", e)),
+    };
+    let scale = match (scale, parsed.opt_str("resize")) {
+        (Some(_), Some(_)) => {
+            return Err("--resize cannot be combined with --scale or --max-dim.".into())
+        }
+        (None, Some(size)) => Some(ScaleTarget::parse_size(&size)?),
+        (scale, None) => scale,
+    };
+    let filter = parsed
+        .opt_str("filter")
+        .map(|filter| filter.parse())
+        .transpose()?
+        .unwrap_or_default();
+
+    let flip = parsed
+        .opt_str("flip")
+        .map(|flip| flip.parse())
+        .transpose()?;
+    let rotate = parsed
+        .opt_str("rotate")
+        .map(|rotation| rotation.parse())
+        .transpose()?;
+    let adjustments = adjustments(&parsed)?;
+
+    let interval: Option<i32> = parsed
+        .opt_get("interval")
+        .map_err(|e| format!("Invalid interval: {}.", e))?;
+    let watch = match interval {
+        Some(interval) if interval > 0 => Some(WatchMode::Poll(interval)),
+        Some(_) => return Err("Interval must be positive.".into()),
+        None if parsed.opt_present("w") => Some(WatchMode::Events),
+        None => None,
+    };
+
+    let timeout = match parsed.opt_get::<f64>("timeout") {
+        Ok(Some(secs)) if secs.is_finite() && secs > 0.0 => Some(Duration::from_secs_f64(secs)),
+        Ok(Some(_)) => return Err("Timeout must be positive.".into()),
+        Ok(None) => None,
+        Err(e) => return Err(format!("Invalid timeout: {}.", e)),
+    };
+    if timeout.is_some() && watch.is_some() {
+        return Err("--timeout cannot be combined with --watch.".into());
+    }
+    let retry = match parsed.opt_get::<u32>("retry") {
+        Ok(Some(0)) => return Err("Retry count must be positive.".into()),
+        Ok(retry) => retry.unwrap_or(1),
+        Err(e) => return Err(format!("Invalid retry count: {}.", e)),
+    };
+    let retry_delay = parsed
+        .opt_get::<u64>("retry-delay")
+        .map_err(|e| format!("Invalid retry delay: {}.", e))?
+        .map_or(DEFAULT_RETRY_DELAY, Duration::from_millis);
+    let (property, source, fallback) = source_matches(&parsed)?;
+    let strip_height = match parsed.opt_get::<u16>("strip-height") {
+        Ok(Some(0)) => return Err("Strip height must be positive.".into()),
+        Ok(rows) => rows,
+        Err(e) => return Err(format!("Invalid strip height: {}.", e)),
+    };
+    // Watching only notices new pixmaps, not windows being drawn
+    if source != Source::Pixmap && watch.is_some() {
+        return Err("--watch only works with --source pixmap.".into());
+    }
+    if matches!(target, Target::Selections { .. }) && (watch.is_some() || timeout.is_some()) {
+        return Err(
+            "--clipboard and --primary-selection cannot be combined with --watch or --timeout."
+                .into(),
+        );
+    }
+
+    let clobber = last_flag(&parsed, "force", "n", true);
+    if !clobber && watch.is_some() {
+        return Err("--no-clobber cannot be combined with --watch.".into());
+    }
+
+    let if_changed = parsed.opt_present("if-changed");
+    let without_file = match &target {
+        Target::Single { output, .. } => *output == OutputDest::Stdout,
+        Target::Multiple { outputs } => outputs.is_empty(),
+        Target::Split { .. } => false,
+        Target::Selections { .. } => true,
+    };
+    if if_changed && without_file {
+        return Err("--if-changed requires an output file.".into());
+    }
+    let checksum = parsed.opt_present("checksum");
+    let writes_nothing = match &target {
+        Target::Multiple { outputs } => outputs.is_empty(),
+        Target::Selections { .. } => true,
+        _ => false,
+    };
+    if checksum && writes_nothing {
+        return Err("--checksum requires an output.".into());
+    }
+
+    let monitor = match (
+        parsed.opt_str("monitor"),
+        parsed.opt_present("primary"),
+        parsed.opt_present("under-cursor"),
+    ) {
+        (None, false, false) => None,
+        (Some(monitor), false, false) => Some(monitor.parse()?),
+        (None, true, false) => Some(MonitorSelector::Primary),
+        (None, false, true) => Some(MonitorSelector::UnderCursor),
+        _ => return Err("--monitor, --primary and --under-cursor are mutually exclusive.".into()),
+    };
+    if monitor.is_some() && matches!(target, Target::Split { .. }) {
+        return Err(
+            "--monitor, --primary and --under-cursor cannot be combined with --split.".into(),
+        );
+    }
+
+    if reproducible {
+        let formats = match &target {
+            Target::Single { format, .. } => vec![*format],
+            Target::Multiple { outputs } => outputs.iter().map(|&(_, format)| format).collect(),
+            Target::Split { template, format } => format
+                .or_else(|| Format::from_path(Path::new(template)))
+                .into_iter()
+                .collect(),
+            Target::Selections { .. } => {
+                return Err("--reproducible cannot be combined with selections.".into())
+            }
+        };
+        if let Some(format) = formats.into_iter().find(|f| !f.is_reproducible()) {
+            return Err(format!(
+                "{} output is not guaranteed to be reproducible. Use png, pam, ff or qoi instead.",
+                format.name()
+            ));
+        }
+    }
+
+    let color_stats = parsed.opt_present("color-stats");
+    let histogram = parsed.opt_str("histogram").map(|out| parse_output(&out));
+    if let Some(OutputDest::File(path)) = &histogram {
+        check_template(path)?;
+    }
+    let pixel = parsed
+        .opt_str("pixel")
+        .map(|s| parse_pixel(&s))
+        .transpose()?;
+    // Statistics of the one final image, next to or instead of it: whether each was
+    // asked for, and whether it goes to stdout
+    let stats = [
+        ("--color-stats", color_stats, color_stats),
+        (
+            "--histogram",
+            histogram.is_some(),
+            histogram == Some(OutputDest::Stdout),
+        ),
+        ("--pixel", pixel.is_some(), pixel.is_some()),
+    ];
+    let printing = stats
+        .iter()
+        .filter(|&&(_, _, stdout)| stdout)
+        .map(|&(flag, _, _)| flag)
+        .collect::<Vec<_>>();
+    if let [first, second, ..] = printing[..] {
+        return Err(format!(
+            "{} and {} cannot both print to stdout.",
+            first, second
+        ));
+    }
+    if let Some(&(flag, _, _)) = stats.iter().find(|&&(_, given, _)| given) {
+        let to_stdout = match &target {
+            Target::Single { output, .. } => *output == OutputDest::Stdout,
+            Target::Multiple { outputs } => outputs.iter().any(|(o, _)| *o == OutputDest::Stdout),
+            Target::Split { .. } | Target::Selections { .. } => {
+                return Err(format!(
+                    "{} cannot be combined with --split or selections.",
+                    flag
+                ))
+            }
+        };
+        if let (true, Some(flag)) = (to_stdout, printing.first()) {
+            return Err(format!(
+                "{} prints to stdout, so the image cannot go there.",
+                flag
+            ));
+        }
+    }
+
+    let kitty_id = match parsed.opt_get::<u32>("kitty-id") {
+        Ok(Some(_)) if !parsed.opt_present("kitty") => {
+            return Err("--kitty-id requires --kitty.".into())
+        }
+        Ok(Some(id)) if id > 0 => Some(id),
+        Ok(None) => None,
+        Ok(Some(_)) | Err(_) => {
+            return Err(format!(
+                "Kitty image ID must be between 1 and {}.",
+                u32::MAX
+            ))
+        }
+    };
+    let text = if parsed.opt_present("kitty") {
+        if text_output {
+            return Err("--kitty cannot be combined with --data-uri or --base64.".into());
+        }
+        Some(TextEncoding::Kitty(kitty_id))
+    } else if parsed.opt_present("data-uri") {
+        let formats = match &target {
+            Target::Single { format, .. } => vec![*format],
+            Target::Multiple { outputs } => outputs.iter().map(|&(_, format)| format).collect(),
+            Target::Split { format, .. } => format.iter().copied().collect(),
+            Target::Selections { .. } => {
+                return Err("--data-uri cannot be combined with selections.".into())
+            }
+        };
+        if let Some(format) = formats
+            .into_iter()
+            .find(|f| f.data_uri_mime_type().is_none())
+        {
+            return Err(format!(
+                "{} cannot be used in data URIs. Use png, bmp, webp, jpeg or gif instead.",
+                format.name()
+            ));
+        }
+        Some(TextEncoding::DataUri)
+    } else if parsed.opt_present("base64") {
+        if matches!(target, Target::Selections { .. }) {
+            return Err("--base64 cannot be combined with selections.".into());
+        }
+        Some(TextEncoding::Base64)
+    } else {
+        None
+    };
+    if !matches!(text, Some(TextEncoding::DataUri | TextEncoding::Base64))
+        && parsed.opt_present("newline")
+    {
+        return Err("--newline requires --data-uri or --base64.".into());
+    }
+
+    let args = Args {
+        target,
+        mask: last_flag(&parsed, "m", "no-mask", false),
+        mask_fill,
+        force_opaque,
+        force_16bit,
+        force_8bit,
+        crop,
+        region,
+        png,
+        pnm_subtype,
+        tiff_compression,
+        jpeg_quality,
+        webp_quality,
+        xpm_colors,
+        gif_colors,
+        sixel_colors,
+        dither: parsed.opt_present("dither"),
+        fit_terminal,
+        terminal_size,
+        bmp_background,
+        color_stats,
+        histogram,
+        pixel,
+        pixel_hex,
+        metadata: !parsed.opt_present("no-metadata") && !reproducible,
+        text,
+        newline: parsed.opt_present("newline"),
+        icc_profile: parsed.opt_str("icc-profile").map(PathBuf::from),
+        scale,
+        filter,
+        flip,
+        rotate,
+        adjustments,
+        allow_upscale: parsed.opt_present("allow-upscale"),
+        monitor,
+        strict: parsed.opt_present("strict"),
+        apply_rotation: parsed.opt_present("apply-rotation"),
+        shm: !parsed.opt_present("no-shm"),
+        strip_height,
+        tile: parsed.opt_present("tile"),
+        clobber,
+        if_changed,
+        checksum,
+        display,
+        screen,
+        property,
+        source,
+        fallback,
+        watch,
+        timeout,
+        retry,
+        retry_delay,
+        log_level: log_level(&parsed)?,
+    };
+
+    let raw = match &args.target {
+        Target::Single { format, .. } => *format == Format::Raw,
+        // Already rejected above
+        Target::Multiple { .. } => false,
+        Target::Split { template, format } => {
+            format.or_else(|| Format::from_path(Path::new(template))) == Some(Format::Raw)
+        }
+        Target::Selections { .. } => false,
+    };
+    if raw && args.is_processed() {
+        return Err(
+            "Raw output is written as received from the X server and cannot be \
+            combined with masking, monitor selection, --split, cropping, transforms, scaling, \
+            adjustments, --tile, --force-opaque or --bit-depth."
+                .into(),
+        );
+    }
+    if args.pnm_subtype == PnmSubtype::Pgm && !args.adjustments.grayscale {
+        return Err("--pnm-subtype pgm requires --grayscale.".into());
+    }
+    if raw && args.if_changed {
+        return Err("--if-changed cannot be combined with raw output.".into());
+    }
+    if raw && (args.color_stats || args.histogram.is_some() || args.pixel.is_some()) {
+        return Err(
+            "--color-stats, --histogram and --pixel cannot be combined with raw output.".into(),
+        );
+    }
+
+    Ok(args)
+}
+
+fn probe_from_matches(parsed: Matches) -> Result<ProbeArgs, String> {
+    if let Some(extra) = parsed.free.first() {
+        return Err(format!("Unexpected argument '{}'.", extra));
+    }
+    reject_capture_options(&parsed, "probe")?;
+
+    let (display, screen) = connection_matches(&parsed)?;
+    let (property, source, fallback) = source_matches(&parsed)?;
+    Ok(ProbeArgs {
+        json: parsed.opt_present("json"),
+        display,
+        screen,
+        property,
+        source,
+        fallback,
+        log_level: log_level(&parsed)?,
+    })
+}
+
+/// Parses `--property`.
+///
+/// The name is leaked, since it ends up in [`BackgroundInfo`](xbgdump::BackgroundInfo) next to
+/// the built-in ones; it's only parsed once per run.
+fn property_matches(parsed: &Matches) -> Result<Option<&'static str>, String> {
+    match parsed.opt_str("property") {
+        Some(name) if name.is_empty() => Err("Property name must not be empty.".into()),
+        Some(name) => Ok(Some(Box::leak(name.into_boxed_str()))),
+        None => Ok(None),
+    }
+}
+
+/// Parses `--source` and `--fallback`, together with `--property` since only pixmaps are
+/// looked up in one.
+fn source_matches(
+    parsed: &Matches,
+) -> Result<(Option<&'static str>, Source, Option<Source>), String> {
+    let property = property_matches(parsed)?;
+    let source = parsed
+        .opt_str("source")
+        .map(|source| source.parse())
+        .transpose()?
+        .unwrap_or(Source::Pixmap);
+    let fallback = match parsed.opt_str("fallback").as_deref() {
+        Some("root-window") => Some(Source::RootWindow),
+        Some(fallback) => {
+            return Err(format!(
+                "Invalid fallback '{}'. Expected root-window.",
+                fallback
+            ))
+        }
+        None => None,
+    };
+
+    if source != Source::Pixmap {
+        let conflict = match (property, fallback) {
+            (Some(_), _) => Some("--property"),
+            (_, Some(_)) => Some("--fallback"),
+            _ => None,
+        };
+        if let Some(conflict) = conflict {
+            return Err(format!("{} only applies to --source pixmap.", conflict));
+        }
+    }
+
+    Ok((property, source, fallback))
+}
+
+/// Makes sure a mode that doesn't capture anything was only given options it understands.
+fn reject_capture_options(parsed: &Matches, mode: &str) -> Result<(), String> {
+    let ignored = capture_specs().into_iter().find(|spec| {
+        spec.long != mode
+            && !matches!(
+                spec.long,
+                "json" | "display" | "screen" | "verbose" | "quiet"
+            )
+            && !(mode == "probe" && matches!(spec.long, "property" | "source" | "fallback"))
+            && parsed.opt_present(spec.long)
+    });
+    match ignored {
+        Some(spec) => Err(format!(
+            "--{} cannot be combined with --{}.",
+            spec.long, mode
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command, UsageError> {
+        parse(
+            std::iter::once("xbgdump")
+                .chain(args.iter().copied())
+                .map(OsString::from),
+        )
+    }
+
+    fn capture(args: &[&str]) -> Box<Args> {
+        match parse_args(args) {
+            Ok(Command::Capture(args)) => args,
+            other => panic!("expected a capture, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dash_writes_pam_to_stdout() {
+        match capture(&["-"]).target {
+            Target::Single {
+                output: OutputDest::Stdout,
+                format: Format::Pam,
+                tee: None,
+            } => {}
+            target => panic!("expected PAM on stdout, got {:?}", target),
+        }
+    }
+
+    #[test]
+    fn help_wins_over_extra_arguments() {
+        assert!(matches!(
+            parse_args(&["--help", "extra"]),
+            Ok(Command::Help(_))
+        ));
+    }
+
+    #[test]
+    fn extra_argument_is_a_usage_error() {
+        let err = parse_args(&["-o", "-", "extra"]).unwrap_err();
+        assert_eq!(err.message, "Unexpected argument 'extra'.");
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn webp_is_lossless_by_default() {
+        assert_eq!(capture(&["bg.webp"]).webp_quality, None);
+        assert_eq!(
+            capture(&["--lossy", "--quality", "40", "bg.webp"]).webp_quality,
+            Some(40)
+        );
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn webp_quality_overrides_quality() {
+        let args = capture(&["--quality", "90", "--webp-quality", "50", "bg.webp"]);
+        assert_eq!(args.jpeg_quality, 90);
+        assert_eq!(args.webp_quality, Some(50));
+        for quality in &["101", "-1", "high"] {
+            let err = parse_args(&["--webp-quality", quality, "bg.webp"]).unwrap_err();
+            assert_eq!(err.message, "WebP quality must be between 0 and 100.");
+        }
+    }
+
+    #[test]
+    fn reproducible_pins_png_settings() {
+        let args = capture(&["--reproducible", "bg.png"]);
+        assert_eq!(args.png.compression, PngCompression::Fast);
+        assert_eq!(args.png.filter, PngFilter::Sub);
+        assert!(!args.metadata);
+
+        let err = parse_args(&["--reproducible", "--png-filter", "paeth", "bg.png"]).unwrap_err();
+        assert_eq!(
+            err.message,
+            "--reproducible fixes the PNG settings, drop --png-compression and --png-filter."
+        );
+    }
+
+    #[test]
+    fn reproducible_rejects_other_formats() {
+        for &args in &[
+            &["--reproducible", "bg.jpg"][..],
+            &["--reproducible", "bg.png", "bg.jpg"],
+            &["--reproducible", "--format", "jpeg", "-"],
+        ] {
+            assert_eq!(
+                parse_args(args).unwrap_err().message,
+                "jpeg output is not guaranteed to be reproducible. Use png, pam, ff or qoi instead."
+            );
+        }
+        for name in &["bg.pam", "bg.ff", "bg.qoi"] {
+            assert!(parse_args(&["--reproducible", name]).is_ok());
+        }
+    }
+
+    #[test]
+    fn quality_range() {
+        assert_eq!(
+            capture(&["bg.jpg"]).jpeg_quality,
+            WriteOptions::default().jpeg_quality
+        );
+        for &(quality, expected) in &[("0", 0), ("100", 100), ("42", 42)] {
+            assert_eq!(
+                capture(&["--quality", quality, "bg.jpg"]).jpeg_quality,
+                expected
+            );
+        }
+        for quality in &["101", "-1", "85.5", "best"] {
+            let err = parse_args(&["--quality", quality, "bg.jpg"]).unwrap_err();
+            assert_eq!(err.message, "Quality must be between 0 and 100.");
+        }
+    }
+
+    #[test]
+    fn timeout_must_be_positive() {
+        assert_eq!(capture(&["-"]).timeout, None);
+        assert_eq!(
+            capture(&["--timeout", "2.5", "-"]).timeout,
+            Some(Duration::from_millis(2500))
+        );
+        for timeout in &["0", "-2", "inf", "NaN"] {
+            let err = parse_args(&["--timeout", timeout, "-"]).unwrap_err();
+            assert_eq!(err.message, "Timeout must be positive.", "{}", timeout);
+        }
+        let err = parse_args(&["--timeout", "soon", "-"]).unwrap_err();
+        assert!(err.message.starts_with("Invalid timeout: "));
+        let err = parse_args(&["--timeout", "1", "--watch", "-"]).unwrap_err();
+        assert_eq!(err.message, "--timeout cannot be combined with --watch.");
+    }
+
+    #[test]
+    fn pixel_coordinates_and_hex() {
+        let args = capture(&["--pixel", "3, 4"]);
+        assert_eq!(args.pixel, Some((3, 4)));
+        assert!(!args.pixel_hex);
+        assert!(capture(&["--pixel", "0,0", "--format", "HEX"]).pixel_hex);
+
+        for pixel in &["3", "x,4", "-1,2", "1,2,3"] {
+            let err = parse_args(&["--pixel", pixel]).unwrap_err();
+            assert_eq!(
+                err.message,
+                format!("Invalid pixel '{}'. Expected X,Y.", pixel)
+            );
+        }
+        // hex is only a format for --pixel
+        let err = parse_args(&["--format", "hex", "-"]).unwrap_err();
+        assert!(err.message.starts_with("Unknown format 'hex'."));
+    }
+
+    #[test]
+    fn bit_depth() {
+        let args = capture(&["--bit-depth", "16", "bg.png"]);
+        assert!(args.force_16bit && !args.force_8bit);
+        let args = capture(&["--bit-depth", "8", "bg.png"]);
+        assert!(!args.force_16bit && args.force_8bit);
+        assert!(capture(&["--force-16bit", "bg.png"]).force_16bit);
+
+        let err = parse_args(&["--bit-depth", "10", "bg.png"]).unwrap_err();
+        assert_eq!(err.message, "Invalid bit depth '10'. Expected 8 or 16.");
+        let err = parse_args(&["--force-16bit", "--bit-depth", "16", "bg.png"]).unwrap_err();
+        assert_eq!(
+            err.message,
+            "--force-16bit and --bit-depth are mutually exclusive."
+        );
+    }
+}
@@ -0,0 +1,327 @@
+//! Shell completion scripts, generated from the same option tables the parser uses.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use crate::cli::{
+    capture_specs, completions_specs, list_specs, Complete, OptArg, OptSpec, SUBCOMMANDS,
+};
+
+const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(format!(
+                "Unknown shell '{}'. Expected bash, zsh or fish.",
+                s
+            )),
+        }
+    }
+}
+
+/// The completion script for the given shell.
+pub fn generate(shell: Shell) -> String {
+    match shell {
+        Shell::Bash => bash(),
+        Shell::Zsh => zsh(),
+        Shell::Fish => fish(),
+    }
+}
+
+/// All spellings of an option, e.g. `-o --output`.
+fn names(spec: &OptSpec) -> Vec<String> {
+    let mut names = Vec::new();
+    if !spec.short.is_empty() {
+        names.push(format!("-{}", spec.short));
+    }
+    names.push(format!("--{}", spec.long));
+
+    names
+}
+
+/// The first sentence of the help text, which is all that fits into a completion menu.
+fn summary(spec: &OptSpec) -> &str {
+    let desc = &spec.desc;
+    let end = desc
+        .match_indices(". ")
+        .map(|(i, _)| i)
+        .find(|&i| !desc[..i].ends_with("e.g") && !desc[..i].ends_with("i.e"));
+    match end {
+        Some(end) => &desc[..end],
+        None => desc.trim_end_matches('.'),
+    }
+}
+
+fn bash() -> String {
+    let capture = capture_specs();
+    let list = list_specs();
+    let word_list = |specs: &[OptSpec]| specs.iter().flat_map(names).collect::<Vec<_>>().join(" ");
+
+    // Options taking values, deduplicated across commands since they share the same meaning
+    let mut values = BTreeMap::new();
+    for spec in capture.iter().chain(&list) {
+        if let OptArg::Value { complete, .. } = &spec.arg {
+            values.insert(names(spec).join("|"), complete.clone());
+        }
+    }
+    let value_cases = values
+        .into_iter()
+        .map(|(pattern, complete)| {
+            let reply = match complete {
+                Complete::Nothing => String::new(),
+                Complete::Files => "COMPREPLY=($(compgen -f -- \"$cur\")); ".into(),
+                Complete::OneOf(values) => format!(
+                    "COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); ",
+                    values.join(" ")
+                ),
+            };
+            format!("        {}) {}return ;;\n", pattern, reply)
+        })
+        .collect::<String>();
+
+    format!(
+        r#"_xbgdump() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    local opts
+
+    case "${{COMP_WORDS[1]}}" in
+        list-monitors) opts="{list}" ;;
+        completions)
+            if [[ $cur == -* ]]; then
+                COMPREPLY=($(compgen -W "{completions}" -- "$cur"))
+            elif [[ $COMP_CWORD -eq 2 ]]; then
+                COMPREPLY=($(compgen -W "{shells}" -- "$cur"))
+            fi
+            return ;;
+        *) opts="{capture}" ;;
+    esac
+
+    case "$prev" in
+{value_cases}    esac
+
+    if [[ $cur == -* ]]; then
+        COMPREPLY=($(compgen -W "$opts" -- "$cur"))
+    elif [[ $COMP_CWORD -eq 1 ]]; then
+        COMPREPLY=($(compgen -W "{subcommands}" -- "$cur") $(compgen -f -- "$cur"))
+    else
+        COMPREPLY=($(compgen -f -- "$cur"))
+    fi
+}}
+
+complete -o filenames -F _xbgdump xbgdump
+"#,
+        list = word_list(&list),
+        completions = word_list(&completions_specs()),
+        shells = SHELLS.join(" "),
+        capture = word_list(&capture),
+        value_cases = value_cases,
+        subcommands = SUBCOMMANDS.join(" "),
+    )
+}
+
+fn zsh() -> String {
+    let escape = |s: &str| {
+        s.replace('\\', "\\\\")
+            .replace('\'', "'\\''")
+            .replace('[', "\\[")
+            .replace(']', "\\]")
+            .replace(':', "\\:")
+    };
+    let arguments = |specs: &[OptSpec]| {
+        specs
+            .iter()
+            .map(|spec| {
+                let names = names(spec);
+                let prefix = match (&spec.arg, names.as_slice()) {
+                    (OptArg::Repeated, [short, long]) => format!("'*'{{{},{}}}", short, long),
+                    (OptArg::Repeated, _) => format!("'*{}'", names[0]),
+                    (_, [short, long]) => {
+                        format!("'({} {})'{{{},{}}}", short, long, short, long)
+                    }
+                    _ => format!("'{}'", names[0]),
+                };
+                let arg = match &spec.arg {
+                    OptArg::Value { hint, complete } => {
+                        let action = match complete {
+                            Complete::Nothing => " ".into(),
+                            Complete::Files => "_files".into(),
+                            Complete::OneOf(values) => format!("({})", values.join(" ")),
+                        };
+                        format!(":{}:{}", escape(hint), action)
+                    }
+                    _ => String::new(),
+                };
+                format!("        {}'[{}]{}'\n", prefix, escape(summary(spec)), arg)
+            })
+            .collect::<String>()
+    };
+
+    format!(
+        r#"#compdef xbgdump
+
+_xbgdump_target() {{
+    if (( CURRENT == 2 )); then
+        _alternative 'commands:command:({subcommands})' 'files:output file:_files'
+    else
+        _files
+    fi
+}}
+
+_xbgdump() {{
+    local -a capture list completions
+    capture=(
+{capture}        '*:output file:_xbgdump_target'
+    )
+    list=(
+{list}    )
+    completions=(
+{completions}        ':shell:({shells})'
+    )
+
+    case $words[2] in
+        list-monitors)
+            shift words
+            (( CURRENT-- ))
+            _arguments -s $list ;;
+        completions)
+            shift words
+            (( CURRENT-- ))
+            _arguments -s $completions ;;
+        *)
+            _arguments -s $capture ;;
+    esac
+}}
+
+_xbgdump "$@"
+"#,
+        subcommands = SUBCOMMANDS.join(" "),
+        capture = arguments(&capture_specs()),
+        list = arguments(&list_specs()),
+        completions = arguments(&completions_specs()),
+        shells = SHELLS.join(" "),
+    )
+}
+
+fn fish() -> String {
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('\'', "\\'");
+    let subcommands = SUBCOMMANDS.join(" ");
+    let lines = |condition: &str, specs: &[OptSpec]| {
+        specs
+            .iter()
+            .map(|spec| {
+                let mut line = format!("complete -c xbgdump -n '{}'", condition);
+                if !spec.short.is_empty() {
+                    line += &format!(" -s {}", spec.short);
+                }
+                line += &format!(" -l {}", spec.long);
+                if let OptArg::Value { complete, .. } = &spec.arg {
+                    line += &match complete {
+                        Complete::Nothing => " -x".into(),
+                        Complete::Files => " -r -F".into(),
+                        Complete::OneOf(values) => format!(" -x -a '{}'", values.join(" ")),
+                    };
+                }
+                line += &format!(" -d '{}'\n", escape(summary(spec)));
+                line
+            })
+            .collect::<String>()
+    };
+
+    let mut script = format!(
+        "complete -c xbgdump -n '__fish_use_subcommand' -a '{}'\n",
+        subcommands
+    );
+    script += &lines(
+        &format!("not __fish_seen_subcommand_from {}", subcommands),
+        &capture_specs(),
+    );
+    script += &lines("__fish_seen_subcommand_from list-monitors", &list_specs());
+    script += &lines(
+        "__fish_seen_subcommand_from completions",
+        &completions_specs(),
+    );
+    script += &format!(
+        "complete -c xbgdump -n '__fish_seen_subcommand_from completions' -x -a '{}'\n",
+        SHELLS.join(" ")
+    );
+
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xbgdump::Format;
+
+    #[test]
+    fn scripts_cover_options_formats_and_subcommands() {
+        for &shell in &[Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let script = generate(shell);
+            for spec in capture_specs()
+                .iter()
+                .chain(&list_specs())
+                .chain(&completions_specs())
+            {
+                let option = match shell {
+                    Shell::Fish => format!("-l {} ", spec.long),
+                    Shell::Bash | Shell::Zsh => format!("--{}", spec.long),
+                };
+                assert!(
+                    script.contains(&option),
+                    "{:?} lacks --{}",
+                    shell,
+                    spec.long
+                );
+            }
+            assert!(script.contains("completions"), "{:?}", shell);
+            assert!(script.contains("list-monitors"), "{:?}", shell);
+            assert!(script.contains(
+                &Format::ALL
+                    .iter()
+                    .map(|f| f.name())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ));
+            assert!(script.contains("bash zsh fish"), "{:?}", shell);
+        }
+    }
+
+    #[test]
+    fn bash_completes_format_values() {
+        let script = generate(Shell::Bash);
+        assert!(script.contains("        -f|--format) COMPREPLY=($(compgen -W \"png pam"));
+        assert!(script
+            .trim_end()
+            .ends_with("complete -o filenames -F _xbgdump xbgdump"));
+    }
+
+    #[test]
+    fn zsh_completes_format_values() {
+        let script = generate(Shell::Zsh);
+        assert!(script.starts_with("#compdef xbgdump\n"));
+        assert!(script.contains("'(-f --format)'{-f,--format}'[Output format, overriding the file extension]:FORMAT:(png pam"));
+    }
+
+    #[test]
+    fn fish_completes_format_values() {
+        let script = generate(Shell::Fish);
+        assert!(script.starts_with(
+            "complete -c xbgdump -n '__fish_use_subcommand' -a 'list-monitors completions'\n"
+        ));
+        assert!(script.contains(" -s f -l format -x -a 'png pam"));
+        // Quotes in descriptions are escaped
+        assert!(script.contains("-d 'Print what\\'s going on to stderr'"));
+    }
+}
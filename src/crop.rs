@@ -0,0 +1,249 @@
+use image::{DynamicImage, GenericImageView};
+use std::{convert::TryFrom, str::FromStr};
+
+use crate::error::{Result, XbgdumpError};
+
+/// An axis-aligned rectangle in image coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Rect {
+    type Err = String;
+
+    /// Parses `X,Y,WIDTH,HEIGHT`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split(',').map(str::trim).collect::<Vec<_>>();
+        let [x, y, width, height] = parts.as_slice() else {
+            return Err(format!(
+                "Invalid region '{}'. Expected X,Y,WIDTH,HEIGHT.",
+                s
+            ));
+        };
+        if parts.iter().any(|part| part.starts_with('-')) {
+            return Err(format!(
+                "Invalid region '{}'. Coordinates and sizes must not be negative.",
+                s
+            ));
+        }
+
+        let parse = |part: &str| {
+            part.parse()
+                .map_err(|_| format!("Invalid region '{}'. Expected X,Y,WIDTH,HEIGHT.", s))
+        };
+        let rect = Rect {
+            x: parse(x)?,
+            y: parse(y)?,
+            width: parse(width)?,
+            height: parse(height)?,
+        };
+        if rect.width == 0 || rect.height == 0 {
+            return Err(format!("Region '{}' is empty.", s));
+        }
+
+        Ok(rect)
+    }
+}
+
+/// Offset of a crop rectangle along one axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offset {
+    /// Distance of the rectangle's left/top edge from the image's left/top edge.
+    FromStart(u32),
+    /// Distance of the rectangle's right/bottom edge from the image's right/bottom edge.
+    FromEnd(u32),
+}
+
+/// A crop rectangle in X geometry syntax (`WxH+X+Y`), as understood by xterm and friends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CropGeometry {
+    pub width: u32,
+    pub height: u32,
+    pub x: Offset,
+    pub y: Offset,
+}
+
+impl FromStr for CropGeometry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid geometry '{}'. Expected WxH[+-]X[+-]Y.", s);
+
+        let size_end = s.find(['+', '-']).unwrap_or(s.len());
+        let (size, offsets) = s.split_at(size_end);
+        let (width, height) = size.split_once(['x', 'X']).ok_or_else(invalid)?;
+        let width = width.parse().map_err(|_| invalid())?;
+        let height = height.parse().map_err(|_| invalid())?;
+
+        let (x, y) = match offsets {
+            "" => (Offset::FromStart(0), Offset::FromStart(0)),
+            offsets => {
+                // Skip the leading sign so splitting finds the one between X and Y
+                let y_start = offsets[1..].find(['+', '-']).ok_or_else(invalid)? + 1;
+                let (x, y) = offsets.split_at(y_start);
+                (
+                    parse_offset(x).ok_or_else(invalid)?,
+                    parse_offset(y).ok_or_else(invalid)?,
+                )
+            }
+        };
+
+        Ok(CropGeometry {
+            width,
+            height,
+            x,
+            y,
+        })
+    }
+}
+
+fn parse_offset(s: &str) -> Option<Offset> {
+    let (sign, value) = s.split_at(1);
+    let value = value.parse().ok()?;
+    match sign {
+        "+" => Some(Offset::FromStart(value)),
+        "-" => Some(Offset::FromEnd(value)),
+        _ => None,
+    }
+}
+
+/// Clamps the interval `[start, start + len)` to `[0, max)`.
+fn clamp_axis(start: i64, len: u32, max: u32) -> (u32, u32) {
+    let end = (start + i64::from(len)).clamp(0, i64::from(max));
+    let start = start.clamp(0, end);
+    // Both conversions are safe because 0 <= start <= end <= max
+    (
+        u32::try_from(start).unwrap(),
+        u32::try_from(end - start).unwrap(),
+    )
+}
+
+impl CropGeometry {
+    /// Turns the geometry into a rectangle within an image of the given size.
+    ///
+    /// Returns the rectangle and whether it had to be clamped to fit the image.
+    /// Fails if nothing of the rectangle is left inside the image.
+    pub fn resolve(&self, img_width: u32, img_height: u32) -> Result<(Rect, bool)> {
+        let start = |offset, len, max| match offset {
+            Offset::FromStart(offset) => i64::from(offset),
+            Offset::FromEnd(offset) => i64::from(max) - i64::from(offset) - i64::from(len),
+        };
+
+        let (x, width) = clamp_axis(start(self.x, self.width, img_width), self.width, img_width);
+        let (y, height) = clamp_axis(
+            start(self.y, self.height, img_height),
+            self.height,
+            img_height,
+        );
+        if width == 0 || height == 0 {
+            return Err(XbgdumpError::CropOutside {
+                width: img_width,
+                height: img_height,
+            });
+        }
+
+        let clamped = width != self.width || height != self.height;
+        Ok((
+            Rect {
+                x,
+                y,
+                width,
+                height,
+            },
+            clamped,
+        ))
+    }
+}
+
+/// Crops the image to the given geometry, clamping it to the image bounds.
+pub fn crop(img: &DynamicImage, geometry: &CropGeometry) -> Result<(DynamicImage, bool)> {
+    let (img_width, img_height) = img.dimensions();
+    let (rect, clamped) = geometry.resolve(img_width, img_height)?;
+
+    Ok((
+        img.crop_imm(rect.x, rect.y, rect.width, rect.height),
+        clamped,
+    ))
+}
+
+/// Crops the image to exactly the given rectangle, which has to lie within the image.
+///
+/// Unlike [`crop`], nothing is clamped.
+pub fn crop_region(img: &DynamicImage, region: &Rect) -> Result<DynamicImage> {
+    let (width, height) = img.dimensions();
+    let fits = |start: u32, len: u32, max: u32| u64::from(start) + u64::from(len) <= u64::from(max);
+    if !fits(region.x, region.width, width) || !fits(region.y, region.height, height) {
+        return Err(XbgdumpError::RegionOutside {
+            region: *region,
+            width,
+            height,
+        });
+    }
+
+    Ok(img.crop_imm(region.x, region.y, region.width, region.height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(32, 16, |x, y| {
+            Rgb([(x * 8) as u8, (y * 16) as u8, 0])
+        }))
+    }
+
+    #[test]
+    fn full_image_is_identity() {
+        let img = gradient();
+        for geometry in ["32x16", "32x16+0+0", "32x16-0-0", "32x16+0-0"] {
+            let geometry: CropGeometry = geometry.parse().unwrap();
+            let (cropped, clamped) = crop(&img, &geometry).unwrap();
+            assert!(!clamped, "{:?}", geometry);
+            assert_eq!(cropped, img, "{:?}", geometry);
+        }
+
+        let region = "0,0,32,16".parse().unwrap();
+        assert_eq!(crop_region(&img, &region).unwrap(), img);
+    }
+
+    #[test]
+    fn resolve() {
+        let cases = [
+            ("10x5+2+3", (2, 3, 10, 5), false),
+            ("10x5-2-3", (20, 8, 10, 5), false),
+            ("40x20+0+0", (0, 0, 32, 16), true),
+            ("10x10+28+10", (28, 10, 4, 6), true),
+        ];
+        for (geometry, (x, y, width, height), expected_clamped) in cases {
+            let (rect, clamped) = geometry
+                .parse::<CropGeometry>()
+                .unwrap()
+                .resolve(32, 16)
+                .unwrap();
+            let expected = Rect {
+                x,
+                y,
+                width,
+                height,
+            };
+            assert_eq!(
+                (rect, clamped),
+                (expected, expected_clamped),
+                "{}",
+                geometry
+            );
+        }
+
+        let outside: CropGeometry = "10x10+40+0".parse().unwrap();
+        assert!(matches!(
+            outside.resolve(32, 16),
+            Err(XbgdumpError::CropOutside { .. })
+        ));
+    }
+}
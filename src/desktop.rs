@@ -0,0 +1,181 @@
+//! Desktop windows, which XFCE, nemo-desktop and other file managers draw the wallpaper into
+//! instead of publishing a root pixmap.
+//!
+//! Their contents are read from the pixmap the Composite extension keeps for each redirected
+//! window, so unlike with the root window, other windows on top of them don't end up in the
+//! image. Without a compositor, the window is only redirected right before reading it, and
+//! the server fills the new pixmap from what's on screen at that point.
+
+use x11rb::{
+    connection::Connection,
+    errors::ConnectionError,
+    protocol::{
+        composite::{ConnectionExt as CompositeConnectionExt, Redirect},
+        xproto::{AtomEnum, ConnectionExt, MapState, Pixmap, Window},
+    },
+    NONE,
+};
+
+use crate::error::{Result, XbgdumpError};
+
+/// A mapped window of type `_NET_WM_WINDOW_TYPE_DESKTOP`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DesktopWindow {
+    pub window: Window,
+    /// Position relative to the root window.
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+    pub depth: u8,
+}
+
+/// Finds all mapped desktop windows below `root`, e.g. one per monitor.
+///
+/// Window managers usually reparent them into frames, so the whole tree is searched, one level
+/// at a time to keep the number of round trips down. Desktop windows' own children aren't.
+pub(crate) fn find_desktop_windows(
+    c: &impl Connection,
+    root: Window,
+) -> Result<Vec<DesktopWindow>> {
+    let type_cookie = c.intern_atom(true, b"_NET_WM_WINDOW_TYPE")?;
+    let desktop_cookie = c.intern_atom(true, b"_NET_WM_WINDOW_TYPE_DESKTOP")?;
+    let window_type = type_cookie.reply()?.atom;
+    let desktop_type = desktop_cookie.reply()?.atom;
+    // Nobody ever set a window type, so there can't be any desktop windows either
+    if window_type == NONE || desktop_type == NONE {
+        return Ok(Vec::new());
+    }
+
+    let mut found = Vec::new();
+    let mut level = c.query_tree(root)?.reply()?.children;
+    while !level.is_empty() {
+        let cookies = level
+            .iter()
+            .map(|&window| {
+                Ok((
+                    window,
+                    c.get_window_attributes(window)?,
+                    c.get_property(false, window, window_type, AtomEnum::ATOM, 0, 32)?,
+                    c.query_tree(window)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, ConnectionError>>()?;
+
+        let mut next = Vec::new();
+        for (window, attributes, types, tree) in cookies {
+            // The window might have gone away in the meantime
+            let (attributes, types, tree) = match (attributes.reply(), types.reply(), tree.reply())
+            {
+                (Ok(attributes), Ok(types), Ok(tree)) => (attributes, types, tree),
+                _ => continue,
+            };
+            // Children of unmapped windows aren't shown either
+            if attributes.map_state != MapState::VIEWABLE {
+                continue;
+            }
+
+            let is_desktop = types
+                .value32()
+                .is_some_and(|mut types| types.any(|t| t == desktop_type));
+            if is_desktop {
+                found.push(window);
+            } else {
+                next.extend(tree.children);
+            }
+        }
+        level = next;
+    }
+
+    found
+        .into_iter()
+        .map(|window| {
+            let geometry = c.get_geometry(window)?.reply()?;
+            let position = c.translate_coordinates(window, root, 0, 0)?.reply()?;
+            log!(
+                Info,
+                "Found desktop window 0x{:x} at {}x{}+{}+{}, depth {}.",
+                window,
+                geometry.width,
+                geometry.height,
+                position.dst_x,
+                position.dst_y,
+                geometry.depth
+            );
+
+            Ok(DesktopWindow {
+                window,
+                x: position.dst_x,
+                y: position.dst_y,
+                width: geometry.width,
+                height: geometry.height,
+                depth: geometry.depth,
+            })
+        })
+        .collect()
+}
+
+/// Makes sure the server has Composite 0.2, which introduced naming window pixmaps.
+pub(crate) fn check_composite(c: &impl Connection) -> Result<()> {
+    let version = c
+        .composite_query_version(0, 2)
+        .map_err(|e| match e {
+            ConnectionError::UnsupportedExtension => XbgdumpError::CompositeUnsupported,
+            e => e.into(),
+        })?
+        .reply()?;
+    if (version.major_version, version.minor_version) < (0, 2) {
+        return Err(XbgdumpError::CompositeUnsupported);
+    }
+
+    Ok(())
+}
+
+/// The pixmap backing a desktop window, redirected for as long as this lives.
+///
+/// Frees the pixmap and undoes the redirection when dropped. Automatic redirection keeps the
+/// window on screen, and doesn't get in the way of a compositor that redirected it already.
+pub(crate) struct WindowPixmap<'c, C: Connection> {
+    c: &'c C,
+    window: Window,
+    pixmap: Pixmap,
+}
+
+impl<'c, C: Connection> WindowPixmap<'c, C> {
+    pub(crate) fn new(c: &'c C, window: Window) -> Result<Self> {
+        let pixmap = c.generate_id()?;
+        c.composite_redirect_window(window, Redirect::AUTOMATIC)?
+            .check()?;
+
+        // From here on, Drop takes care of the cleanup
+        let named = Self { c, window, pixmap };
+        c.composite_name_window_pixmap(window, pixmap)?.check()?;
+        log!(
+            Debug,
+            "Named pixmap 0x{:x} for desktop window 0x{:x}.",
+            pixmap,
+            window
+        );
+
+        Ok(named)
+    }
+
+    pub(crate) fn pixmap(&self) -> Pixmap {
+        self.pixmap
+    }
+}
+
+impl<C: Connection> Drop for WindowPixmap<'_, C> {
+    fn drop(&mut self) {
+        // If naming failed, freeing just produces an error we don't care about
+        if let Ok(cookie) = self.c.free_pixmap(self.pixmap) {
+            let _ = cookie.check();
+        }
+        if let Ok(cookie) = self
+            .c
+            .composite_unredirect_window(self.window, Redirect::AUTOMATIC)
+        {
+            let _ = cookie.check();
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use image::ImageError;
+use std::{error::Error, fmt, io, path::PathBuf};
+use x11rb::errors::{ConnectionError, ReplyError, ReplyOrIdError};
+
+use crate::{crop::Rect, output::Format};
+
+/// Everything that can go wrong while retrieving or writing the background.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum XbgdumpError {
+    /// None of the properties looked at, by default the
+    /// [`BACKGROUND_ATOMS`](crate::BACKGROUND_ATOMS), is set on the root window.
+    NoBackgroundPixmap,
+    /// The background pixmap has a depth we can't convert.
+    UnsupportedDepth(u8),
+    /// The pixmap found is a bitmap without colors, like the placeholder xsetroot keeps in
+    /// `_XSETROOT_ID`.
+    BitmapPixmap {
+        property: &'static str,
+        width: u16,
+        height: u16,
+    },
+    /// No mapped window has the type `_NET_WM_WINDOW_TYPE_DESKTOP`.
+    NoDesktopWindow,
+    /// The server doesn't support Composite, or not a recent enough version.
+    CompositeUnsupported,
+    /// Fetching one strip of a large image failed.
+    StripFailed {
+        /// First row of the strip.
+        y: u16,
+        height: u16,
+        error: ReplyError,
+    },
+    /// The server sent less pixel data than the pixmap's geometry requires.
+    TruncatedImage,
+    NoSuchScreen(usize),
+    /// A request failed or the connection broke down.
+    X11Error(ReplyError),
+    /// The connection ran out of XIDs.
+    IdsExhausted,
+    /// `--timeout` expired and the connection was cut while a request was pending.
+    Timeout,
+    /// Encoding the final image failed.
+    ImageError(ImageError),
+    /// The server doesn't support RandR, or not a recent enough version.
+    RandrUnsupported,
+    /// RandR reports no CRTCs at all.
+    NoMonitors,
+    /// A monitor was selected by an index RandR doesn't know about.
+    NoSuchCrtc {
+        index: usize,
+        count: usize,
+    },
+    /// A monitor was selected by an output name RandR doesn't know about.
+    NoSuchOutput {
+        name: String,
+        available: Vec<String>,
+    },
+    /// The selected output exists, but doesn't show anything.
+    OutputDisabled {
+        name: String,
+        connected: bool,
+    },
+    NoPrimaryOutput,
+    /// The selected CRTC exists, but doesn't show anything.
+    MonitorDisabled(String),
+    /// The crop area lies completely outside an image of the given size.
+    CropOutside {
+        width: u32,
+        height: u32,
+    },
+    /// The region doesn't fit into an image of the given size.
+    RegionOutside {
+        region: Rect,
+        width: u32,
+        height: u32,
+    },
+    /// Scaling would have enlarged the image without that being allowed.
+    UpscaleRejected {
+        from: (u32, u32),
+        to: (u32, u32),
+    },
+    /// The output file exists and overwriting was disabled.
+    OutputExists(PathBuf),
+    /// A file name template contains a `%` conversion we don't know.
+    UnknownSpecifier {
+        template: String,
+        specifier: String,
+    },
+    /// The file is too short or lacks the signature of an ICC profile.
+    InvalidIccProfile(PathBuf),
+    /// Data URIs were requested for a format browsers can't display.
+    NoDataUriMimeType(Format),
+    /// The image is larger than the format can store.
+    ImageTooLarge {
+        format: Format,
+        max: u32,
+    },
+    /// Raw output was requested for an image that didn't come straight from the server.
+    RawFromImage,
+    /// The data doesn't start with a raw header, or is shorter than the header says.
+    InvalidRawImage,
+    /// Neither an explicit format nor a known file extension was given.
+    UnknownFormat(PathBuf),
+    /// Another client grabbed the selection right away.
+    SelectionUnavailable(String),
+    /// MIT-SHM is too old to pass file descriptors, or missing entirely.
+    ShmUnsupported,
+    /// The server claims to have written more into shared memory than we allocated.
+    ShmOverflow {
+        reported: usize,
+        capacity: usize,
+    },
+    /// A system call for setting up shared memory failed.
+    Sys(io::Error),
+    IoError(io::Error),
+}
+
+/// Result type used throughout the library.
+pub type Result<T, E = XbgdumpError> = std::result::Result<T, E>;
+
+impl fmt::Display for XbgdumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XbgdumpError::NoBackgroundPixmap => write!(f, "No background pixmap set."),
+            XbgdumpError::UnsupportedDepth(depth) => {
+                write!(f, "Unsupported pixel depth {}.", depth)
+            }
+            XbgdumpError::BitmapPixmap {
+                property: "_XSETROOT_ID",
+                width,
+                height,
+            } => write!(
+                f,
+                "_XSETROOT_ID only holds a {}x{} bitmap, which xsetroot keeps to track its colors, \
+                not the background. Set the background with a tool that publishes \
+                _XROOTPMAP_ID, e.g. hsetroot or feh.",
+                width, height
+            ),
+            XbgdumpError::BitmapPixmap {
+                property,
+                width,
+                height,
+            } => write!(
+                f,
+                "{} holds a {}x{} bitmap of depth 1, which has no colors to capture.",
+                property, width, height
+            ),
+            XbgdumpError::NoDesktopWindow => write!(f, "No desktop window found."),
+            XbgdumpError::CompositeUnsupported => write!(
+                f,
+                "Composite is not supported by the X server, or too old to name window pixmaps."
+            ),
+            XbgdumpError::StripFailed { y, height, .. } => write!(
+                f,
+                "Failed to fetch rows {} to {} of the image.",
+                y,
+                u32::from(*y) + u32::from(*height) - 1
+            ),
+            XbgdumpError::TruncatedImage => {
+                write!(f, "Server sent less pixel data than the image requires.")
+            }
+            XbgdumpError::NoSuchScreen(screen) => write!(f, "Screen {} does not exist.", screen),
+            XbgdumpError::X11Error(_) => write!(f, "X11 request failed."),
+            XbgdumpError::IdsExhausted => write!(f, "X11 connection ran out of IDs."),
+            XbgdumpError::Timeout => write!(f, "Timed out waiting for the X server."),
+            XbgdumpError::ImageError(_) => write!(f, "Failed to encode image."),
+            XbgdumpError::RandrUnsupported => write!(f, "RandR is not supported by the X server."),
+            XbgdumpError::NoMonitors => write!(f, "RandR reports zero screens."),
+            XbgdumpError::NoSuchCrtc { index, count } => write!(
+                f,
+                "Monitor {} does not exist. RandR reports {} CRTCs.",
+                index, count
+            ),
+            XbgdumpError::NoSuchOutput { name, available } => write!(
+                f,
+                "Output {} does not exist. Available outputs: {}.",
+                name,
+                available.join(", ")
+            ),
+            XbgdumpError::OutputDisabled { name, connected } => write!(
+                f,
+                "Output {} is {}.",
+                name,
+                if *connected {
+                    "disabled"
+                } else {
+                    "disconnected"
+                }
+            ),
+            XbgdumpError::NoPrimaryOutput => write!(f, "No primary output set."),
+            XbgdumpError::MonitorDisabled(name) => write!(f, "Monitor {} is disabled.", name),
+            XbgdumpError::CropOutside { width, height } => write!(
+                f,
+                "Crop area does not overlap the {}x{} image.",
+                width, height
+            ),
+            XbgdumpError::RegionOutside {
+                region,
+                width,
+                height,
+            } => write!(
+                f,
+                "Region {},{},{},{} exceeds the {}x{} image.",
+                region.x, region.y, region.width, region.height, width, height
+            ),
+            XbgdumpError::UpscaleRejected { from, to } => write!(
+                f,
+                "Scaling {}x{} to {}x{} would enlarge the image.",
+                from.0, from.1, to.0, to.1
+            ),
+            XbgdumpError::OutputExists(path) => {
+                write!(f, "'{}' already exists.", path.display())
+            }
+            XbgdumpError::UnknownSpecifier {
+                template,
+                specifier,
+            } => write!(
+                f,
+                "Unknown specifier '{}' in '{}'. Use %% for a literal percent sign.",
+                specifier, template
+            ),
+            XbgdumpError::InvalidIccProfile(path) => {
+                write!(f, "'{}' is not an ICC profile.", path.display())
+            }
+            XbgdumpError::NoDataUriMimeType(format) => write!(
+                f,
+                "{} cannot be used in data URIs. Use png, bmp, webp, jpeg or gif instead.",
+                format.name()
+            ),
+            XbgdumpError::ImageTooLarge { format, max } => write!(
+                f,
+                "{} images can be at most {} pixels wide and high.",
+                format.name(),
+                max
+            ),
+            XbgdumpError::RawFromImage => {
+                write!(f, "Raw output is only possible for unprocessed captures.")
+            }
+            XbgdumpError::InvalidRawImage => write!(f, "Not a complete raw xbgdump image."),
+            XbgdumpError::UnknownFormat(path) => write!(
+                f,
+                "Cannot infer format from '{}'. Supported formats: {}.",
+                path.display(),
+                Format::names()
+            ),
+            XbgdumpError::SelectionUnavailable(selection) => {
+                write!(f, "Failed to take over the {} selection.", selection)
+            }
+            XbgdumpError::ShmUnsupported => {
+                write!(f, "MIT-SHM does not support file descriptor passing.")
+            }
+            XbgdumpError::ShmOverflow { reported, capacity } => write!(
+                f,
+                "Server reported {} bytes, but only {} fit into shared memory.",
+                reported, capacity
+            ),
+            XbgdumpError::Sys(_) => write!(f, "Failed to set up shared memory."),
+            XbgdumpError::IoError(_) => write!(f, "I/O error."),
+        }
+    }
+}
+
+impl Error for XbgdumpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            XbgdumpError::X11Error(e) => Some(e),
+            XbgdumpError::StripFailed { error, .. } => Some(error),
+            XbgdumpError::ImageError(e) => Some(e),
+            XbgdumpError::Sys(e) => Some(e),
+            XbgdumpError::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ReplyError> for XbgdumpError {
+    fn from(e: ReplyError) -> Self {
+        XbgdumpError::X11Error(e)
+    }
+}
+
+impl From<ConnectionError> for XbgdumpError {
+    fn from(e: ConnectionError) -> Self {
+        XbgdumpError::X11Error(e.into())
+    }
+}
+
+impl From<ReplyOrIdError> for XbgdumpError {
+    fn from(e: ReplyOrIdError) -> Self {
+        match e {
+            ReplyOrIdError::IdsExhausted => XbgdumpError::IdsExhausted,
+            ReplyOrIdError::ConnectionError(e) => e.into(),
+            ReplyOrIdError::X11Error(e) => ReplyError::X11Error(e).into(),
+        }
+    }
+}
+
+impl From<ImageError> for XbgdumpError {
+    fn from(e: ImageError) -> Self {
+        XbgdumpError::ImageError(e)
+    }
+}
+
+impl From<io::Error> for XbgdumpError {
+    fn from(e: io::Error) -> Self {
+        XbgdumpError::IoError(e)
+    }
+}
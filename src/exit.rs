@@ -0,0 +1,132 @@
+//! Exit statuses, so scripts can tell failures apart without parsing messages.
+
+use crate::cli::UsageError;
+use image::ImageError;
+use std::{io, process::ExitCode};
+use x11rb::errors::ConnectError;
+use xbgdump::XbgdumpError;
+
+/// Every status the binary exits with. The numbers are part of the interface and must not change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Success = 0,
+    /// Anything not covered by a more specific status.
+    Failure = 1,
+    Usage = 2,
+    Connect = 3,
+    NoBackground = 4,
+    /// A request failed or the connection broke down after it was established.
+    Protocol = 5,
+    Io = 6,
+    /// `--no-clobber` refused to overwrite a file.
+    OutputExists = 7,
+    Timeout = 8,
+    /// `--if-changed` found nothing to write.
+    Unchanged = 9,
+}
+
+impl Status {
+    /// Picks the status for an error by its most specific known cause.
+    pub fn classify(e: &anyhow::Error) -> Status {
+        e.chain()
+            .find_map(|cause| {
+                if let Some(e) = cause.downcast_ref::<XbgdumpError>() {
+                    return match e {
+                        XbgdumpError::NoBackgroundPixmap
+                        | XbgdumpError::BitmapPixmap { .. }
+                        | XbgdumpError::NoDesktopWindow => Some(Status::NoBackground),
+                        XbgdumpError::OutputExists(_) => Some(Status::OutputExists),
+                        XbgdumpError::Timeout => Some(Status::Timeout),
+                        XbgdumpError::X11Error(_)
+                        | XbgdumpError::StripFailed { .. }
+                        | XbgdumpError::IdsExhausted => Some(Status::Protocol),
+                        XbgdumpError::IoError(_) => Some(Status::Io),
+                        XbgdumpError::ImageError(ImageError::IoError(_)) => Some(Status::Io),
+                        _ => None,
+                    };
+                }
+                if cause.is::<UsageError>() {
+                    return Some(Status::Usage);
+                }
+                if cause.is::<ConnectError>() {
+                    return Some(Status::Connect);
+                }
+                if cause.is::<io::Error>() {
+                    return Some(Status::Io);
+                }
+
+                None
+            })
+            .unwrap_or(Status::Failure)
+    }
+
+    /// Short description printed along with the error message.
+    pub fn category(self) -> &'static str {
+        match self {
+            Status::Success => "success",
+            Status::Failure => "error",
+            Status::Usage => "usage error",
+            Status::Connect => "cannot connect",
+            Status::NoBackground => "no background",
+            Status::Protocol => "X protocol error",
+            Status::Io => "I/O error",
+            Status::OutputExists => "output exists",
+            Status::Timeout => "timeout",
+            Status::Unchanged => "unchanged",
+        }
+    }
+}
+
+impl From<Status> for ExitCode {
+    fn from(status: Status) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn usage_error() {
+        let e = match crate::cli::parse(["xbgdump", "--no-such-option"].iter().map(Into::into)) {
+            Err(e) => anyhow::Error::new(e),
+            Ok(command) => panic!("expected a usage error, got {:?}", command),
+        };
+        assert_eq!(Status::classify(&e), Status::Usage);
+        assert_eq!(Status::Usage as u8, 2);
+    }
+
+    #[test]
+    fn no_background() {
+        for e in [
+            XbgdumpError::NoBackgroundPixmap,
+            XbgdumpError::NoDesktopWindow,
+        ] {
+            let e = Err::<(), _>(e).context("Capturing").unwrap_err();
+            assert_eq!(Status::classify(&e), Status::NoBackground);
+        }
+        assert_eq!(Status::NoBackground as u8, 4);
+    }
+
+    #[test]
+    fn timeout() {
+        let e = Err::<(), _>(XbgdumpError::Timeout)
+            .context("Capturing")
+            .unwrap_err();
+        assert_eq!(Status::classify(&e), Status::Timeout);
+        assert_eq!(Status::Timeout as u8, 8);
+    }
+
+    #[test]
+    fn most_specific_cause_wins() {
+        let e = anyhow::Error::new(io::Error::from(io::ErrorKind::PermissionDenied))
+            .context("Writing bg.png");
+        assert_eq!(Status::classify(&e), Status::Io);
+        assert_eq!(
+            Status::classify(&anyhow::anyhow!("Something else")),
+            Status::Failure
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Encoder for the suckless [farbfeld](https://tools.suckless.org/farbfeld/) format.
+//!
+//! image has one too, but it only takes RGBA16 and writes every channel separately, which is
+//! painfully slow on stdout.
+
+use image::{DynamicImage, GenericImageView};
+use std::io::{self, Write};
+
+use crate::background::widen;
+
+/// Encodes the image as farbfeld: a 16-byte header, then big-endian RGBA with 16 bits per channel.
+pub fn encode(img: &DynamicImage, w: &mut impl Write) -> io::Result<()> {
+    let (width, height) = img.dimensions();
+    let mut header = Vec::with_capacity(16);
+    header.extend_from_slice(b"farbfeld");
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    w.write_all(&header)?;
+
+    let samples = match img {
+        DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_)
+        | DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageRgba16(_) => img.to_rgba16().into_raw(),
+        _ => widen(DynamicImage::ImageRgba8(img.to_rgba8()))
+            .to_rgba16()
+            .into_raw(),
+    };
+    let mut out = Vec::with_capacity(64 * 1024);
+    for chunk in samples.chunks(32 * 1024) {
+        out.clear();
+        out.extend(chunk.iter().flat_map(|v| v.to_be_bytes()));
+        w.write_all(&out)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Luma, Rgba};
+    use std::convert::TryInto;
+
+    /// Parses the file like ff2png does: the magic, big-endian dimensions, then RGBA16.
+    fn decode(ff: &[u8]) -> ImageBuffer<Rgba<u16>, Vec<u16>> {
+        assert_eq!(&ff[..8], b"farbfeld");
+        let width = u32::from_be_bytes(ff[8..12].try_into().unwrap());
+        let height = u32::from_be_bytes(ff[12..16].try_into().unwrap());
+        assert_eq!(ff.len(), 16 + 8 * (width * height) as usize);
+        let samples = ff[16..]
+            .chunks_exact(2)
+            .map(|s| u16::from_be_bytes([s[0], s[1]]))
+            .collect();
+        ImageBuffer::from_raw(width, height, samples).unwrap()
+    }
+
+    fn encoded(img: &DynamicImage) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode(img, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn header() {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(258, 1, Rgba([0; 4])));
+        let ff = encoded(&img);
+        assert_eq!(&ff[..16], b"farbfeld\0\0\x01\x02\0\0\0\x01");
+    }
+
+    #[test]
+    fn widens_to_full_scale() {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(3, 2, |x, y| {
+            Rgba([0xff, 0, x as u8 * 100, 0x80 + y as u8])
+        }));
+        let ff = encoded(&img);
+        assert_eq!(&ff[16..24], [0xff, 0xff, 0, 0, 0, 0, 0x80, 0x80]);
+        let decoded = decode(&ff);
+        let expected = img
+            .to_rgba8()
+            .iter()
+            .map(|&v| u16::from(v) * 257)
+            .collect::<Vec<_>>();
+        assert_eq!(decoded.as_raw(), &expected);
+        // Narrowing back gives the original samples
+        assert_eq!(
+            DynamicImage::ImageRgba16(decoded).to_rgba8(),
+            img.to_rgba8()
+        );
+    }
+
+    #[test]
+    fn keeps_16_bit_samples() {
+        let img = DynamicImage::ImageRgba16(ImageBuffer::from_fn(2, 2, |x, y| {
+            Rgba([0x1234, x as u16, y as u16 * 0x0101, 0xfffe])
+        }));
+        assert_eq!(decode(&encoded(&img)), img.to_rgba16());
+    }
+
+    #[test]
+    fn gray_becomes_opaque_rgba() {
+        let img = DynamicImage::ImageLuma8(ImageBuffer::from_pixel(1, 1, Luma([0x42])));
+        let decoded = decode(&encoded(&img));
+        assert_eq!(decoded.get_pixel(0, 0).0, [0x4242, 0x4242, 0x4242, 0xffff]);
+    }
+}
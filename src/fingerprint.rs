@@ -0,0 +1,140 @@
+//! Change detection for repeated captures, based on the pixels rather than the encoded file.
+//!
+//! The fingerprint of the last image written to a file is kept in a small state file next to it.
+
+use image::{DynamicImage, GenericImageView};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{error::Result, output::Format};
+
+/// First line of every state file. Bump the version whenever the fingerprint changes meaning,
+/// e.g. because the pixel conversion changed, so old state files no longer match.
+const STATE_HEADER: &str = "xbgdump-state 1";
+
+/// Hashes the image's pixels, dimensions and layout, plus the format it will be written in.
+pub fn fingerprint(img: &DynamicImage, format: Format) -> u64 {
+    let (width, height) = img.dimensions();
+    let mut hash = Fnv1a::new();
+    hash.write(format.name().as_bytes());
+    hash.write(&width.to_le_bytes());
+    hash.write(&height.to_le_bytes());
+    hash.write(format!("{:?}", img.color()).as_bytes());
+    hash.write(img.as_bytes());
+
+    hash.0
+}
+
+/// Where the fingerprint for the given output file is kept: a hidden file in the same directory.
+pub fn state_path(output: &Path) -> PathBuf {
+    let mut name = ".".to_owned();
+    name += &output.file_name().unwrap_or_default().to_string_lossy();
+    name += ".xbgdump-state";
+
+    output.with_file_name(name)
+}
+
+/// Whether the output file exists and was last written with the given fingerprint.
+///
+/// Missing, unreadable or outdated state files count as changed.
+pub fn is_unchanged(output: &Path, fingerprint: u64) -> bool {
+    if !output.exists() {
+        return false;
+    }
+
+    let state = match fs::read_to_string(state_path(output)) {
+        Ok(state) => state,
+        Err(_) => return false,
+    };
+    let mut lines = state.lines();
+    if lines.next() != Some(STATE_HEADER) {
+        return false;
+    }
+
+    lines.next() == Some(format!("{:016x}", fingerprint).as_str())
+}
+
+/// Remembers the fingerprint of the image just written to the output file.
+pub fn save_state(output: &Path, fingerprint: u64) -> Result<()> {
+    let state = format!("{}\n{:016x}\n", STATE_HEADER, fingerprint);
+
+    Ok(fs::write(state_path(output), state)?)
+}
+
+/// 64-bit FNV-1a, which is fast enough for a few megabytes and stable across Rust versions,
+/// unlike the hasher in std.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(16, 9, |x, y| Rgb([x as u8, y as u8, 7])))
+    }
+
+    #[test]
+    fn same_pixels_same_fingerprint() {
+        assert_eq!(
+            fingerprint(&gradient(), Format::Png),
+            fingerprint(&gradient(), Format::Png)
+        );
+        // The format is part of it, since the file would differ
+        assert_ne!(
+            fingerprint(&gradient(), Format::Png),
+            fingerprint(&gradient(), Format::Pam)
+        );
+    }
+
+    #[test]
+    fn one_pixel_changes_fingerprint() {
+        let mut changed = gradient().into_rgb8();
+        changed.put_pixel(15, 8, Rgb([15, 8, 8]));
+        assert_ne!(
+            fingerprint(&gradient(), Format::Png),
+            fingerprint(&DynamicImage::ImageRgb8(changed), Format::Png)
+        );
+    }
+
+    #[test]
+    fn state_files() {
+        let dir = std::env::temp_dir().join(format!("xbgdump-state-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("bg.png");
+        let print = fingerprint(&gradient(), Format::Png);
+
+        // Nothing written yet
+        assert!(!is_unchanged(&output, print));
+        fs::write(&output, "png").unwrap();
+        assert!(!is_unchanged(&output, print));
+
+        save_state(&output, print).unwrap();
+        assert_eq!(state_path(&output), dir.join(".bg.png.xbgdump-state"));
+        assert!(is_unchanged(&output, print));
+        assert!(!is_unchanged(&output, print ^ 1));
+
+        // State from another version of the fingerprint counts as changed
+        let state = fs::read_to_string(state_path(&output)).unwrap();
+        let other = state.replacen(STATE_HEADER, "xbgdump-state 0", 1);
+        fs::write(state_path(&output), other).unwrap();
+        assert!(!is_unchanged(&output, print));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
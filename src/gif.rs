@@ -0,0 +1,378 @@
+//! Encoder for single-frame GIF89a.
+//!
+//! image only has a GIF encoder behind its `gif` feature, which pulls in the gif crate. GIF is
+//! a palette format with at most 256 colors, so the image goes through the same median cut as
+//! XPM first, see [`quantize`](crate::quantize). Only fully transparent pixels become
+//! transparent, since GIF has no partial transparency; the rest keep their color.
+
+use image::{DynamicImage, GenericImageView};
+use std::{collections::HashMap, io::Write};
+
+use crate::{
+    error::{Result, XbgdumpError},
+    output::Format,
+    quantize,
+};
+
+/// Neither side of an image may be longer than this, as the screen descriptor only has 16 bits.
+pub(crate) const MAX_DIMENSION: u32 = u16::MAX as u32;
+/// Used without an explicit limit, the most a GIF palette can hold.
+pub(crate) const DEFAULT_COLORS: u16 = 256;
+
+/// Introduces an extension block.
+const EXTENSION: u8 = 0x21;
+/// Graphic control extension, which holds the transparent color index.
+const GRAPHIC_CONTROL: u8 = 0xf9;
+/// Image descriptor.
+const IMAGE: u8 = 0x2c;
+const TRAILER: u8 = 0x3b;
+
+/// Codes can't be wider than this.
+const MAX_CODE_WIDTH: u32 = 12;
+
+/// Encodes the image as a GIF with at most `max_colors` palette entries, transparency included.
+pub fn encode(img: &DynamicImage, max_colors: u16, dither: bool, w: &mut impl Write) -> Result<()> {
+    let (width, height) = img.dimensions();
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(XbgdumpError::ImageTooLarge {
+            format: Format::Gif,
+            max: MAX_DIMENSION,
+        });
+    }
+
+    let rgba = img.to_rgba8();
+    let transparent = rgba.pixels().any(|p| p[3] == 0);
+    // Transparency takes a palette entry of its own
+    let max_colors = usize::from(max_colors.clamp(2, 256)) - usize::from(transparent);
+    let indexed = quantize::reduce(&rgba, max_colors, 1, dither);
+    let transparent_index = indexed.palette.len() as u8;
+    let entries = indexed.palette.len() + usize::from(transparent);
+    // The color table holds a power of two entries, at least two
+    let table_bits = (1..=8).find(|&bits| 1 << bits >= entries).unwrap_or(8);
+
+    let mut out = Vec::with_capacity(64 * 1024);
+    out.extend_from_slice(b"GIF89a");
+    out.extend_from_slice(&(width as u16).to_le_bytes());
+    out.extend_from_slice(&(height as u16).to_le_bytes());
+    // Global color table, 8 bits per primary, then the background color and aspect ratio
+    out.extend_from_slice(&[0xf0 | (table_bits - 1), 0, 0]);
+    for color in &indexed.palette {
+        out.extend_from_slice(color);
+    }
+    out.resize(
+        out.len() + 3 * ((1 << table_bits) - indexed.palette.len()),
+        0,
+    );
+    if transparent {
+        out.extend_from_slice(&[EXTENSION, GRAPHIC_CONTROL, 4, 1, 0, 0, transparent_index, 0]);
+    }
+    out.push(IMAGE);
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(&(width as u16).to_le_bytes());
+    out.extend_from_slice(&(height as u16).to_le_bytes());
+    out.push(0);
+    w.write_all(&out)?;
+
+    // Even two-color images start at 2 bits, as 1 isn't allowed
+    let min_code_width = u32::from(table_bits).max(2);
+    let data = lzw(
+        indexed
+            .pixels
+            .iter()
+            .map(|p| p.map_or(transparent_index, |i| i as u8)),
+        min_code_width,
+    );
+    out.clear();
+    out.push(min_code_width as u8);
+    // The data goes in sub-blocks of up to 255 bytes, each with its length in front
+    for block in data.chunks(255) {
+        out.push(block.len() as u8);
+        out.extend_from_slice(block);
+        if out.len() >= 64 * 1024 {
+            w.write_all(&out)?;
+            out.clear();
+        }
+    }
+    out.extend_from_slice(&[0, TRAILER]);
+    w.write_all(&out)?;
+
+    Ok(())
+}
+
+/// Compresses palette indices with GIF's LZW variant.
+///
+/// Codes are packed starting from the least significant bit, and the code width only grows
+/// once a code of the full width has actually been assigned, unlike TIFF's. Once the table is
+/// full, a clear code starts it over, like giflib does.
+fn lzw(pixels: impl Iterator<Item = u8>, min_code_width: u32) -> Vec<u8> {
+    let clear = 1 << min_code_width;
+    let end = clear + 1;
+    let mut table = HashMap::<(u16, u8), u16>::new();
+    let mut w = LzwWriter {
+        out: Vec::new(),
+        buffer: 0,
+        len: 0,
+        width: min_code_width + 1,
+    };
+    let mut next_code = end + 1;
+    w.write(clear);
+
+    let mut prefix = None;
+    for pixel in pixels {
+        let code = match prefix {
+            None => {
+                prefix = Some(u16::from(pixel));
+                continue;
+            }
+            Some(code) => code,
+        };
+        if let Some(&extended) = table.get(&(code, pixel)) {
+            prefix = Some(extended);
+            continue;
+        }
+
+        w.write(code);
+        if next_code >= (1 << MAX_CODE_WIDTH) - 1 {
+            w.write(clear);
+            table.clear();
+            next_code = end + 1;
+            w.width = min_code_width + 1;
+        } else {
+            table.insert((code, pixel), next_code);
+            next_code += 1;
+            if next_code > 1 << w.width && w.width < MAX_CODE_WIDTH {
+                w.width += 1;
+            }
+        }
+        prefix = Some(u16::from(pixel));
+    }
+
+    if let Some(code) = prefix {
+        w.write(code);
+        // The decoder adds an entry for this code too, and reads the end code after that
+        if next_code >= 1 << w.width && w.width < MAX_CODE_WIDTH {
+            w.width += 1;
+        }
+    }
+    w.write(end);
+    w.finish()
+}
+
+/// Packs codes of varying width into bytes, least significant bit first.
+struct LzwWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    len: u32,
+    width: u32,
+}
+
+impl LzwWriter {
+    fn write(&mut self, code: u16) {
+        self.buffer |= u32::from(code) << self.len;
+        self.len += self.width;
+        while self.len >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    use std::convert::TryInto;
+
+    struct Decoded {
+        width: u16,
+        height: u16,
+        palette: Vec<[u8; 3]>,
+        transparent: Option<u8>,
+        indices: Vec<u8>,
+    }
+
+    /// Undoes [`lzw`], the way giflib reads it.
+    fn unlzw(data: &[u8], min_code_width: u32) -> Vec<u8> {
+        let clear = 1 << min_code_width;
+        let end = clear + 1;
+        let reset = |table: &mut Vec<Vec<u8>>| {
+            table.clear();
+            table.extend((0..clear).map(|i| vec![i as u8]));
+            table.extend(vec![Vec::new(); 2]);
+        };
+        let mut table = Vec::new();
+        reset(&mut table);
+        let (mut out, mut pos, mut width) = (Vec::new(), 0, min_code_width + 1);
+        let mut previous: Option<Vec<u8>> = None;
+        loop {
+            let mut code = 0;
+            for i in 0..width {
+                code |= usize::from(data[pos / 8] >> (pos % 8) & 1) << i;
+                pos += 1;
+            }
+            if code == clear {
+                reset(&mut table);
+                width = min_code_width + 1;
+                previous = None;
+                continue;
+            }
+            if code == end {
+                break;
+            }
+
+            let entry = match (table.get(code), &previous) {
+                (Some(entry), _) => entry.clone(),
+                (None, Some(previous)) => [&previous[..], &previous[..1]].concat(),
+                (None, None) => panic!("undefined code {}", code),
+            };
+            if let Some(mut previous) = previous {
+                if table.len() < 1 << MAX_CODE_WIDTH {
+                    previous.push(entry[0]);
+                    table.push(previous);
+                }
+            }
+            out.extend_from_slice(&entry);
+            previous = Some(entry);
+            if table.len() == 1 << width && width < MAX_CODE_WIDTH {
+                width += 1;
+            }
+        }
+        out
+    }
+
+    fn decode(gif: &[u8]) -> Decoded {
+        let short = |at: usize| u16::from_le_bytes(gif[at..at + 2].try_into().unwrap());
+        assert_eq!(&gif[..6], b"GIF89a");
+        let (width, height) = (short(6), short(8));
+        let flags = gif[10];
+        assert_ne!(flags & 0x80, 0, "global color table");
+        let palette = gif[13..13 + 3 * (2 << (flags & 7))]
+            .chunks_exact(3)
+            .map(|c| [c[0], c[1], c[2]])
+            .collect::<Vec<_>>();
+
+        let mut pos = 13 + 3 * palette.len();
+        let mut transparent = None;
+        // Concatenates sub-blocks, returning where they end
+        let blocks = |mut pos: usize| {
+            let mut data = Vec::new();
+            while gif[pos] != 0 {
+                let len = usize::from(gif[pos]);
+                data.extend_from_slice(&gif[pos + 1..pos + 1 + len]);
+                pos += 1 + len;
+            }
+            (data, pos + 1)
+        };
+        let mut indices = None;
+        loop {
+            match gif[pos] {
+                EXTENSION => {
+                    assert_eq!(gif[pos + 1], GRAPHIC_CONTROL);
+                    let (data, next) = blocks(pos + 2);
+                    if data[0] & 1 == 1 {
+                        transparent = Some(data[3]);
+                    }
+                    pos = next;
+                }
+                IMAGE => {
+                    assert_eq!(&gif[pos + 1..pos + 5], [0, 0, 0, 0]);
+                    assert_eq!((short(pos + 5), short(pos + 7)), (width, height));
+                    assert_eq!(gif[pos + 9], 0, "no local table or interlacing");
+                    let min_code_width = u32::from(gif[pos + 10]);
+                    let (data, next) = blocks(pos + 11);
+                    indices = Some(unlzw(&data, min_code_width));
+                    pos = next;
+                }
+                TRAILER => break,
+                block => panic!("unexpected block {:#x}", block),
+            }
+        }
+        assert_eq!(pos + 1, gif.len());
+
+        Decoded {
+            width,
+            height,
+            palette,
+            transparent,
+            indices: indices.expect("an image"),
+        }
+    }
+
+    fn encoded(img: &RgbaImage, max_colors: u16) -> Decoded {
+        let mut out = Vec::new();
+        encode(
+            &DynamicImage::ImageRgba8(img.clone()),
+            max_colors,
+            false,
+            &mut out,
+        )
+        .unwrap();
+        decode(&out)
+    }
+
+    fn noise(i: u32) -> u8 {
+        (i.wrapping_mul(2_654_435_761) >> 24) as u8
+    }
+
+    #[test]
+    fn few_colors_decode_exactly() {
+        // Noise over far more than 4096 pixels, so the code table starts over several times
+        let colors = [
+            [255, 0, 0],
+            [0, 255, 0],
+            [0, 0, 255],
+            [9, 9, 9],
+            [200, 100, 50],
+        ];
+        let img = RgbaImage::from_fn(200, 120, |x, y| {
+            let [r, g, b] = colors[usize::from(noise(y * 200 + x)) % colors.len()];
+            Rgba([r, g, b, 255])
+        });
+        let gif = encoded(&img, DEFAULT_COLORS);
+        assert_eq!((gif.width, gif.height), (200, 120));
+        assert_eq!(gif.transparent, None);
+        // Five colors need a table of eight
+        assert_eq!(gif.palette.len(), 8);
+        assert_eq!(gif.indices.len(), 200 * 120);
+        for (index, pixel) in gif.indices.iter().zip(img.pixels()) {
+            assert_eq!(gif.palette[usize::from(*index)], pixel.0[..3]);
+        }
+    }
+
+    #[test]
+    fn palette_stays_within_limit() {
+        let img = RgbaImage::from_fn(64, 64, |x, y| Rgba([x as u8 * 4, y as u8 * 4, 128, 255]));
+        for &max_colors in &[2, 16, 100] {
+            let gif = encoded(&img, max_colors);
+            assert!(gif.palette.len() <= usize::from(max_colors).next_power_of_two());
+            assert!(gif.indices.iter().all(|&i| u16::from(i) < max_colors));
+        }
+    }
+
+    #[test]
+    fn transparent_pixels_get_their_own_index() {
+        let img = RgbaImage::from_fn(90, 60, |x, y| {
+            if x < 30 {
+                Rgba([0, 0, 0, 0])
+            } else {
+                Rgba([noise(y * 90 + x), 50, 50, 255])
+            }
+        });
+        let gif = encoded(&img, 16);
+        let transparent = gif.transparent.expect("a transparent index");
+        // Transparency counts towards the limit
+        assert!(gif.palette.len() <= 16);
+        assert!(gif.indices.iter().all(|&i| i <= transparent));
+        for (index, pixel) in gif.indices.iter().zip(img.pixels()) {
+            assert_eq!(*index == transparent, pixel[3] == 0);
+        }
+    }
+}
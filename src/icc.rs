@@ -0,0 +1,58 @@
+//! Display ICC profiles as published by color management daemons.
+//!
+//! Following the "ICC Profiles in X" specification, the profile of the first screen is in the
+//! `_ICC_PROFILE` property of the root window, and that of screen n in `_ICC_PROFILE_n`.
+
+use x11rb::{
+    connection::Connection,
+    protocol::xproto::{AtomEnum, ConnectionExt, Window},
+    NONE,
+};
+
+use crate::error::Result;
+
+/// Size of the fixed ICC profile header, which ends with the `acsp` signature among others.
+const HEADER_LEN: usize = 128;
+
+/// Reads the ICC profile of the given screen, in the Xinerama sense, from the root window.
+///
+/// Returns `None` if no profile is set, including when the property holds something that
+/// doesn't look like a profile.
+pub fn root_icc_profile(
+    c: &impl Connection,
+    root: Window,
+    screen: usize,
+) -> Result<Option<Vec<u8>>> {
+    let name = match screen {
+        0 => "_ICC_PROFILE".to_owned(),
+        n => format!("_ICC_PROFILE_{}", n),
+    };
+    let atom = c.intern_atom(true, name.as_bytes())?.reply()?.atom;
+    if atom == NONE {
+        log!(Debug, "{} was never set.", name);
+        return Ok(None);
+    }
+
+    // Lengths are in 32-bit units, the server cuts them down to what's there
+    let prop = c
+        .get_property(false, root, atom, AtomEnum::ANY, 0, u32::MAX / 4)?
+        .reply()?;
+    if prop.format != 8 || prop.value.is_empty() {
+        log!(Debug, "{} is not set.", name);
+        return Ok(None);
+    }
+
+    let profile = prop.value;
+    if !is_icc_profile(&profile) {
+        log!(Debug, "{} doesn't hold an ICC profile.", name);
+        return Ok(None);
+    }
+    log!(Debug, "Found a {}-byte profile in {}.", profile.len(), name);
+
+    Ok(Some(profile))
+}
+
+/// Checks the header for the `acsp` signature every ICC profile has.
+pub(crate) fn is_icc_profile(profile: &[u8]) -> bool {
+    profile.len() >= HEADER_LEN && &profile[36..40] == b"acsp"
+}
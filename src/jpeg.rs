@@ -0,0 +1,581 @@
+//! Minimal baseline JPEG encoder.
+//!
+//! image only has a JPEG encoder together with its decoder, which pulls in the jpeg-decoder
+//! crate. This writes sequential Huffman-coded JPEG with the example tables from Annex K of the
+//! standard and 4:2:0 chroma subsampling, which every decoder out there understands.
+
+use image::{DynamicImage, GenericImageView};
+use std::{borrow::Cow, convert::TryFrom, f32::consts::PI, io::Write};
+
+use crate::{
+    error::{Result, XbgdumpError},
+    output::Format,
+};
+
+/// Neither side of an image may be longer than this, as the frame header only has 16 bits.
+pub(crate) const MAX_DIMENSION: u32 = u16::MAX as u32;
+/// Used without an explicit quality, a common compromise between size and artifacts.
+pub(crate) const DEFAULT_QUALITY: u8 = 85;
+
+const SOI: u8 = 0xd8;
+const APP0: u8 = 0xe0;
+const APP2: u8 = 0xe2;
+const DQT: u8 = 0xdb;
+const SOF0: u8 = 0xc0;
+const DHT: u8 = 0xc4;
+const SOS: u8 = 0xda;
+const EOI: u8 = 0xd9;
+
+/// Identifies APP2 segments holding a piece of an ICC profile.
+const ICC_SIGNATURE: &[u8; 12] = b"ICC_PROFILE\0";
+/// A segment can hold this much of a profile, after its length, the signature, the sequence
+/// number and the number of segments.
+const ICC_CHUNK_LEN: usize = u16::MAX as usize - 2 - ICC_SIGNATURE.len() - 2;
+
+/// Flush the entropy-coded data to the writer once this much has piled up.
+const FLUSH_LEN: usize = 64 * 1024;
+
+// Tables K.1 and K.2, in natural order
+#[rustfmt::skip]
+const LUMA_QUANT: [u8; 64] = [
+    16, 11, 10, 16,  24,  40,  51,  61,
+    12, 12, 14, 19,  26,  58,  60,  55,
+    14, 13, 16, 24,  40,  57,  69,  56,
+    14, 17, 22, 29,  51,  87,  80,  62,
+    18, 22, 37, 56,  68, 109, 103,  77,
+    24, 35, 55, 64,  81, 104, 113,  92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103,  99,
+];
+#[rustfmt::skip]
+const CHROMA_QUANT: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Natural index of every coefficient, in the order they are written.
+#[rustfmt::skip]
+const ZIGZAG: [usize; 64] = [
+     0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// Tables K.3 to K.6: the number of codes of each length from 1 to 16 bits, then the symbols
+const DC_LUMA_LENGTHS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const DC_CHROMA_LENGTHS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+const AC_LUMA_LENGTHS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+const AC_LUMA_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+const AC_CHROMA_LENGTHS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const AC_CHROMA_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+    0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+    0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+    0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+/// End of block: all remaining coefficients are zero.
+const EOB: u8 = 0x00;
+/// Sixteen zero coefficients in a row.
+const ZRL: u8 = 0xf0;
+/// Baseline JPEG can't code larger AC coefficients, which only high qualities get close to.
+const MAX_AC: i32 = 1023;
+
+/// Encodes the image as baseline JPEG with the given quality from 1 to 100; 0 counts as 1.
+///
+/// JPEG has no alpha channel, so transparent pixels are composited over black. Grayscale
+/// images stay grayscale; everything is reduced to 8 bits per channel. An ICC profile, if any,
+/// is split across as many `APP2` segments as it needs.
+pub fn encode(
+    img: &DynamicImage,
+    quality: u8,
+    icc_profile: Option<&[u8]>,
+    w: &mut impl Write,
+) -> Result<()> {
+    let (width, height) = img.dimensions();
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(XbgdumpError::ImageTooLarge {
+            format: Format::Jpeg,
+            max: MAX_DIMENSION,
+        });
+    }
+
+    let (channels, pixels) = flatten(img);
+    let mut encoder = Encoder::new(quality);
+    let mut out = encoder.headers(width, height, channels == 1, icc_profile);
+    let (width, height) = (width as usize, height as usize);
+    // Edge blocks repeat the last row and column, which compresses better than black
+    let pixel = |x: usize, y: usize| {
+        let i = (y.min(height - 1) * width + x.min(width - 1)) * channels;
+        &pixels[i..i + channels]
+    };
+
+    if channels == 1 {
+        for block_y in (0..height).step_by(8) {
+            for block_x in (0..width).step_by(8) {
+                let mut block = [0.0; 64];
+                for (i, value) in block.iter_mut().enumerate() {
+                    *value = f32::from(pixel(block_x + i % 8, block_y + i / 8)[0]) - 128.0;
+                }
+                encoder.encode_block(&mut out, &block, 0);
+            }
+            out = flush(w, out)?;
+        }
+    } else {
+        // Every MCU covers 16x16 pixels: four luma blocks and one averaged block per chroma channel
+        for mcu_y in (0..height).step_by(16) {
+            for mcu_x in (0..width).step_by(16) {
+                let mut luma = [[0.0; 64]; 4];
+                let mut cb = [0.0; 64];
+                let mut cr = [0.0; 64];
+                for dy in 0..16 {
+                    for dx in 0..16 {
+                        let rgb = pixel(mcu_x + dx, mcu_y + dy);
+                        let (r, g, b) = (f32::from(rgb[0]), f32::from(rgb[1]), f32::from(rgb[2]));
+                        luma[dy / 8 * 2 + dx / 8][dy % 8 * 8 + dx % 8] =
+                            0.299 * r + 0.587 * g + 0.114 * b - 128.0;
+                        let chroma = dy / 2 * 8 + dx / 2;
+                        cb[chroma] += (-0.168_736 * r - 0.331_264 * g + 0.5 * b) / 4.0;
+                        cr[chroma] += (0.5 * r - 0.418_688 * g - 0.081_312 * b) / 4.0;
+                    }
+                }
+                for block in &luma {
+                    encoder.encode_block(&mut out, block, 0);
+                }
+                encoder.encode_block(&mut out, &cb, 1);
+                encoder.encode_block(&mut out, &cr, 2);
+            }
+            out = flush(w, out)?;
+        }
+    }
+
+    let mut out = encoder.bits.finish(out);
+    out.extend_from_slice(&[0xff, EOI]);
+    w.write_all(&out)?;
+
+    Ok(())
+}
+
+/// Writes out the data once there's enough of it, handing back the buffer to fill next.
+fn flush(w: &mut impl Write, mut out: Vec<u8>) -> Result<Vec<u8>> {
+    if out.len() >= FLUSH_LEN {
+        w.write_all(&out)?;
+        out.clear();
+    }
+
+    Ok(out)
+}
+
+/// Channel count (1 for grayscale, 3 for RGB) and the 8-bit samples, with alpha applied.
+fn flatten(img: &DynamicImage) -> (usize, Cow<'_, [u8]>) {
+    let over_black = |v: u8, a: u8| ((u16::from(v) * u16::from(a) + 127) / 255) as u8;
+    match img {
+        DynamicImage::ImageLuma8(buf) => (1, Cow::Borrowed(buf.as_raw())),
+        DynamicImage::ImageRgb8(buf) => (3, Cow::Borrowed(buf.as_raw())),
+        img if img.color().channel_count() <= 2 => {
+            let samples = img
+                .to_luma_alpha8()
+                .pixels()
+                .map(|p| over_black(p[0], p[1]))
+                .collect();
+            (1, Cow::Owned(samples))
+        }
+        img if img.color().has_alpha() => {
+            let samples = img
+                .to_rgba8()
+                .pixels()
+                .flat_map(|p| [0, 1, 2].map(|c| over_black(p[c], p[3])))
+                .collect();
+            (3, Cow::Owned(samples))
+        }
+        img => (3, Cow::Owned(img.to_rgb8().into_raw())),
+    }
+}
+
+/// Scales an Annex K table like libjpeg does, so qualities mean the same as elsewhere.
+///
+/// libjpeg also treats 0 as 1, which makes every table entry as coarse as possible.
+fn scale_quant(table: &[u8; 64], quality: u8) -> [u16; 64] {
+    let quality = u32::from(quality.clamp(1, 100));
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+
+    table.map(|q| ((u32::from(q) * scale + 50) / 100).clamp(1, 255) as u16)
+}
+
+/// Code and its length in bits for every symbol of a Huffman table.
+struct HuffmanCodes([(u16, u8); 256]);
+
+impl HuffmanCodes {
+    /// Assigns canonical codes as in Annex C: shorter first, in symbol order within a length.
+    fn new(lengths: &[u8; 16], values: &[u8]) -> Self {
+        let mut codes = [(0, 0); 256];
+        let mut code = 0;
+        let mut values = values.iter();
+        for (len, &count) in (1..).zip(lengths) {
+            for &value in values.by_ref().take(count.into()) {
+                codes[usize::from(value)] = (code, len);
+                code += 1;
+            }
+            code <<= 1;
+        }
+
+        Self(codes)
+    }
+}
+
+/// All the state that carries over from block to block.
+struct Encoder {
+    /// Basis functions of the 8-point DCT, `dct[u][x]` for frequency `u`.
+    dct: [[f32; 8]; 8],
+    /// Luma and chroma quantization tables, in natural order.
+    quant: [[u16; 64]; 2],
+    /// DC and AC codes for luma, then for chroma.
+    codes: [[HuffmanCodes; 2]; 2],
+    /// The last DC coefficient of each component, as DC is coded as the difference to it.
+    last_dc: [i32; 3],
+    bits: BitWriter,
+}
+
+impl Encoder {
+    fn new(quality: u8) -> Self {
+        let mut dct = [[0.0; 8]; 8];
+        for (u, row) in dct.iter_mut().enumerate() {
+            let scale = if u == 0 { 0.5 / 2f32.sqrt() } else { 0.5 };
+            for (x, value) in row.iter_mut().enumerate() {
+                *value = scale * ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos();
+            }
+        }
+
+        Self {
+            dct,
+            quant: [
+                scale_quant(&LUMA_QUANT, quality),
+                scale_quant(&CHROMA_QUANT, quality),
+            ],
+            codes: [
+                [
+                    HuffmanCodes::new(&DC_LUMA_LENGTHS, &DC_VALUES),
+                    HuffmanCodes::new(&AC_LUMA_LENGTHS, &AC_LUMA_VALUES),
+                ],
+                [
+                    HuffmanCodes::new(&DC_CHROMA_LENGTHS, &DC_VALUES),
+                    HuffmanCodes::new(&AC_CHROMA_LENGTHS, &AC_CHROMA_VALUES),
+                ],
+            ],
+            last_dc: [0; 3],
+            bits: BitWriter::default(),
+        }
+    }
+
+    /// Everything from SOI up to the start of the entropy-coded data.
+    fn headers(
+        &self,
+        width: u32,
+        height: u32,
+        grayscale: bool,
+        icc_profile: Option<&[u8]>,
+    ) -> Vec<u8> {
+        let mut out = vec![0xff, SOI];
+        // JFIF 1.1 without physical resolution or thumbnail
+        segment(
+            &mut out,
+            APP0,
+            &[b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0],
+        );
+
+        if let Some(profile) = icc_profile {
+            // Sequence numbers are a single byte and start at 1
+            let chunks = profile.chunks(ICC_CHUNK_LEN);
+            match u8::try_from(chunks.len()) {
+                Ok(count) => {
+                    for (chunk, number) in chunks.zip(1..) {
+                        let data = [&ICC_SIGNATURE[..], &[number, count], chunk].concat();
+                        segment(&mut out, APP2, &data);
+                    }
+                }
+                Err(_) => log!(
+                    Warn,
+                    "The ICC profile is too large for JPEG, leaving it out."
+                ),
+            }
+        }
+
+        let tables = if grayscale { 1 } else { 2 };
+        let mut dqt = Vec::new();
+        for (id, quant) in self.quant.iter().take(tables).enumerate() {
+            // 8-bit precision; values are at most 255 anyway
+            dqt.push(id as u8);
+            dqt.extend(ZIGZAG.iter().map(|&i| quant[i] as u8));
+        }
+        segment(&mut out, DQT, &dqt);
+
+        // Precision, size, then ID, sampling factors and quantization table of each component
+        let mut sof = vec![8];
+        sof.extend_from_slice(&(height as u16).to_be_bytes());
+        sof.extend_from_slice(&(width as u16).to_be_bytes());
+        if grayscale {
+            sof.extend_from_slice(&[1, 1, 0x11, 0]);
+        } else {
+            sof.extend_from_slice(&[3, 1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1]);
+        }
+        segment(&mut out, SOF0, &sof);
+
+        let huffman: [(u8, &[u8; 16], &[u8]); 4] = [
+            (0x00, &DC_LUMA_LENGTHS, &DC_VALUES),
+            (0x10, &AC_LUMA_LENGTHS, &AC_LUMA_VALUES),
+            (0x01, &DC_CHROMA_LENGTHS, &DC_VALUES),
+            (0x11, &AC_CHROMA_LENGTHS, &AC_CHROMA_VALUES),
+        ];
+        let mut dht = Vec::new();
+        for (class_and_id, lengths, values) in huffman.iter().take(2 * tables) {
+            dht.push(*class_and_id);
+            dht.extend_from_slice(*lengths);
+            dht.extend_from_slice(values);
+        }
+        segment(&mut out, DHT, &dht);
+
+        // Component IDs with their DC and AC tables, then the full spectral range
+        if grayscale {
+            segment(&mut out, SOS, &[1, 1, 0x00, 0, 63, 0]);
+        } else {
+            segment(&mut out, SOS, &[3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0]);
+        }
+
+        out
+    }
+
+    /// Transforms, quantizes and codes a block of level-shifted samples of the given component.
+    fn encode_block(&mut self, out: &mut Vec<u8>, block: &[f32; 64], component: usize) {
+        let table = component.min(1);
+        let coefficients = self.fdct(block);
+        let quant = &self.quant[table];
+        let quantized = ZIGZAG.map(|i| (coefficients[i] / f32::from(quant[i])).round() as i32);
+        let [dc_codes, ac_codes] = &self.codes[table];
+
+        let diff = quantized[0] - self.last_dc[component];
+        self.last_dc[component] = quantized[0];
+        let (size, bits) = magnitude(diff);
+        self.bits.write_code(out, dc_codes, size);
+        self.bits.write(out, bits, size);
+
+        let mut zeros = 0;
+        for &coefficient in &quantized[1..] {
+            if coefficient == 0 {
+                zeros += 1;
+                continue;
+            }
+            while zeros >= 16 {
+                self.bits.write_code(out, ac_codes, ZRL);
+                zeros -= 16;
+            }
+            let (size, bits) = magnitude(coefficient.clamp(-MAX_AC, MAX_AC));
+            self.bits.write_code(out, ac_codes, zeros << 4 | size);
+            self.bits.write(out, bits, size);
+            zeros = 0;
+        }
+        if zeros > 0 {
+            self.bits.write_code(out, ac_codes, EOB);
+        }
+    }
+
+    /// Two-dimensional DCT-II as rows, then columns.
+    fn fdct(&self, block: &[f32; 64]) -> [f32; 64] {
+        let mut rows = [0.0; 64];
+        for y in 0..8 {
+            for u in 0..8 {
+                rows[y * 8 + u] = (0..8).map(|x| self.dct[u][x] * block[y * 8 + x]).sum();
+            }
+        }
+
+        let mut out = [0.0; 64];
+        for v in 0..8 {
+            for u in 0..8 {
+                out[v * 8 + u] = (0..8).map(|y| self.dct[v][y] * rows[y * 8 + u]).sum();
+            }
+        }
+
+        out
+    }
+}
+
+/// Appends a marker segment, whose length includes the length field itself.
+fn segment(out: &mut Vec<u8>, marker: u8, data: &[u8]) {
+    out.extend_from_slice(&[0xff, marker]);
+    out.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+/// Number of bits a coefficient needs and those bits, with negative numbers as one's complement.
+fn magnitude(value: i32) -> (u8, u32) {
+    let size = 32 - value.unsigned_abs().leading_zeros();
+    let bits = if value < 0 { value - 1 } else { value };
+
+    (size as u8, bits as u32 & ((1 << size) - 1))
+}
+
+/// Packs bits MSB first, escaping every 0xff byte with a zero as the format requires.
+#[derive(Default)]
+struct BitWriter {
+    buffer: u32,
+    len: u8,
+}
+
+impl BitWriter {
+    /// Appends the lowest `len` bits of `bits`, which may be at most 16.
+    fn write(&mut self, out: &mut Vec<u8>, bits: u32, len: u8) {
+        self.buffer = self.buffer << len | bits;
+        self.len += len;
+        while self.len >= 8 {
+            self.len -= 8;
+            let byte = (self.buffer >> self.len) as u8;
+            out.push(byte);
+            if byte == 0xff {
+                out.push(0);
+            }
+        }
+        self.buffer &= (1 << self.len) - 1;
+    }
+
+    fn write_code(&mut self, out: &mut Vec<u8>, codes: &HuffmanCodes, symbol: u8) {
+        let (code, len) = codes.0[usize::from(symbol)];
+        self.write(out, code.into(), len);
+    }
+
+    /// Pads the last byte with one bits and returns the output.
+    fn finish(&mut self, mut out: Vec<u8>) -> Vec<u8> {
+        let padding = (8 - self.len) % 8;
+        self.write(&mut out, (1 << padding) - 1, padding);
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb, Rgba};
+
+    /// Gradients with some deterministic noise, so quality makes a difference.
+    fn test_image() -> DynamicImage {
+        let mut seed = 1u32;
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(100, 60, |x, y| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let noise = (seed >> 24) as u8 / 8;
+            Rgb([(x * 2) as u8 + noise, (y * 4) as u8, noise * 3])
+        }))
+    }
+
+    fn encoded(img: &DynamicImage, quality: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode(img, quality, None, &mut out).unwrap();
+        out
+    }
+
+    /// Walks the marker segments up to the scan and returns the markers seen and the scan data.
+    fn segments(jpeg: &[u8]) -> (Vec<(u8, &[u8])>, &[u8]) {
+        assert_eq!(jpeg[..2], [0xff, SOI]);
+        let mut segments = Vec::new();
+        let mut rest = &jpeg[2..];
+        loop {
+            assert_eq!(rest[0], 0xff);
+            let marker = rest[1];
+            let len = usize::from(u16::from_be_bytes([rest[2], rest[3]]));
+            segments.push((marker, &rest[4..2 + len]));
+            rest = &rest[2 + len..];
+            if marker == SOS {
+                return (segments, rest);
+            }
+        }
+    }
+
+    #[test]
+    fn structure() {
+        let jpeg = encoded(&test_image(), DEFAULT_QUALITY);
+        let (segments, scan) = segments(&jpeg);
+        let markers: Vec<_> = segments.iter().map(|&(marker, _)| marker).collect();
+        assert_eq!(markers, [APP0, DQT, SOF0, DHT, SOS]);
+
+        let (_, frame) = segments[2];
+        // 8 bits, 60 rows, 100 columns, 3 components with luma subsampled 2x2
+        assert_eq!(frame[..7], [8, 0, 60, 0, 100, 3, 1]);
+        assert_eq!(frame[7], 0x22);
+
+        // Every 0xff in the entropy-coded data is stuffed, so the only marker is EOI at the end
+        let (data, end) = scan.split_at(scan.len() - 2);
+        assert_eq!(end, [0xff, EOI]);
+        for pair in data.windows(2).filter(|pair| pair[0] == 0xff) {
+            assert_eq!(pair[1], 0);
+        }
+    }
+
+    #[test]
+    fn quality_trades_size() {
+        let img = test_image();
+        let low = encoded(&img, 10).len();
+        let high = encoded(&img, 95).len();
+        assert!(low < high, "{} bytes at 10, {} at 95", low, high);
+        assert_eq!(encoded(&img, 0), encoded(&img, 1));
+    }
+
+    #[test]
+    fn grayscale_has_one_component() {
+        let img = DynamicImage::ImageLuma8(test_image().to_luma8());
+        let jpeg = encoded(&img, DEFAULT_QUALITY);
+        let (segments, _) = segments(&jpeg);
+        let (_, frame) = segments
+            .iter()
+            .find(|&&(marker, _)| marker == SOF0)
+            .unwrap();
+        assert_eq!(frame[5], 1);
+    }
+
+    #[test]
+    fn alpha_over_black() {
+        let img =
+            DynamicImage::ImageRgba8(ImageBuffer::from_pixel(1, 1, Rgba([200, 100, 50, 128])));
+        let (channels, pixels) = flatten(&img);
+        assert_eq!((channels, &pixels[..]), (3, &[100, 50, 25][..]));
+    }
+
+    #[test]
+    fn quant_scaling() {
+        assert_eq!(scale_quant(&LUMA_QUANT, 50), LUMA_QUANT.map(u16::from));
+        assert_eq!(scale_quant(&LUMA_QUANT, 100), [1; 64]);
+        assert_eq!(scale_quant(&LUMA_QUANT, 1), [255; 64]);
+    }
+}
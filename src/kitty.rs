@@ -0,0 +1,85 @@
+//! Framing for kitty's terminal graphics protocol, which displays PNG images inline.
+//!
+//! The image goes to the terminal as base64 in escape sequences, each carrying at most 4096
+//! bytes of it; every chunk but the last says that more are coming. See
+//! <https://sw.kovidgoyal.net/kitty/graphics-protocol/>.
+
+use std::io::{self, Write};
+
+/// Most base64 bytes the protocol allows in one escape sequence.
+const CHUNK: usize = 4096;
+/// Application program command introducer for graphics.
+const START: &[u8] = b"\x1b_G";
+/// String terminator.
+const END: &[u8] = b"\x1b\\";
+
+/// Wraps base64 written to it in graphics escape sequences and passes them on.
+///
+/// A chunk is only sent once more base64 follows it, so it's known whether it's the last one;
+/// [`KittyWriter::finish`] must be called to send that.
+pub(crate) struct KittyWriter<W: Write> {
+    inner: W,
+    /// Image ID to place the image under, if any.
+    id: Option<u32>,
+    pending: Vec<u8>,
+    /// Whether the first chunk, which carries the image's keys, has been sent.
+    started: bool,
+}
+
+impl<W: Write> KittyWriter<W> {
+    pub(crate) fn new(inner: W, id: Option<u32>) -> Self {
+        Self {
+            inner,
+            id,
+            pending: Vec::with_capacity(2 * CHUNK),
+            started: false,
+        }
+    }
+
+    /// Sends the last chunk and returns the inner writer.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let pending = std::mem::take(&mut self.pending);
+        self.send(&pending, false)?;
+
+        Ok(self.inner)
+    }
+
+    fn send(&mut self, chunk: &[u8], more: bool) -> io::Result<()> {
+        self.inner.write_all(START)?;
+        if !self.started {
+            // Transmit PNG data and display it right away, without replying to the shell
+            self.inner.write_all(b"a=T,f=100,q=2,")?;
+            if let Some(id) = self.id {
+                write!(self.inner, "i={},", id)?;
+            }
+            self.started = true;
+        }
+        write!(self.inner, "m={};", u8::from(more))?;
+        self.inner.write_all(chunk)?;
+        self.inner.write_all(END)
+    }
+}
+
+impl<W: Write> Write for KittyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if self.pending.len() > CHUNK {
+            // Keep the last chunk back, even if it's full, as it might be the final one
+            let ready = (self.pending.len() - 1) / CHUNK * CHUNK;
+            let mut pending = std::mem::take(&mut self.pending);
+            for chunk in pending[..ready].chunks(CHUNK) {
+                self.send(chunk, true)?;
+            }
+            pending.drain(..ready);
+            self.pending = pending;
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Only flushes the inner writer; the pending chunk has to wait for more input or
+    /// [`KittyWriter::finish`].
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
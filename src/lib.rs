@@ -0,0 +1,101 @@
+//! Library interface to retrieve the current X11 background.
+//!
+//! The `xbgdump` binary is a thin wrapper around these functions;
+//! other tools can use them to get at the background without shelling out.
+
+// Declared first so the macro is available in all other modules
+#[macro_use]
+mod log;
+
+mod adjust;
+mod background;
+mod base64;
+mod crop;
+mod desktop;
+mod error;
+mod farbfeld;
+mod fingerprint;
+mod gif;
+mod icc;
+mod jpeg;
+mod kitty;
+mod mask;
+#[cfg(test)]
+mod mock;
+mod monitors;
+mod output;
+mod png_writer;
+mod qoi;
+mod quantize;
+mod raw;
+mod scale;
+mod selection;
+mod sha256;
+mod shm;
+mod sixel;
+mod stats;
+mod template;
+mod tiff;
+mod transform;
+mod watch;
+#[cfg(feature = "webp")]
+mod webp;
+mod xpm;
+
+pub use adjust::{adjust, grayscale, Adjustments};
+pub use background::{
+    find_background_pixmap, find_background_pixmap_in, grab_background, grab_background_with,
+    grab_background_with_info, grab_raw_with, probe_background, probe_background_with, root_size,
+    stream_background_pam, tile, BackgroundInfo, BgraImage, GrabOptions, Source, BACKGROUND_ATOMS,
+};
+pub use crop::{crop, crop_region, CropGeometry, Offset, Rect};
+pub use error::{Result, XbgdumpError};
+pub use fingerprint::{fingerprint, is_unchanged, save_state, state_path};
+pub use icc::root_icc_profile;
+#[doc(hidden)]
+pub use log::write_log;
+pub use log::{log_enabled, set_log_level, LogLevel};
+pub use mask::{mask_monitors, mask_offscreen, MaskFill};
+pub use monitors::{
+    primary_monitor, query_masking_layout, query_monitors, query_monitors_and_primary,
+    select_monitor, Monitor, MonitorSelector, OutputInfo,
+};
+pub use output::{
+    encode_image, resolve_format, write_encoded, write_image, write_image_with, Format, OutputDest,
+    PnmSubtype, TextEncoding, WriteOptions,
+};
+pub use png_writer::{read_icc_profile, PngColorTag, PngCompression, PngFilter, PngOptions};
+pub use raw::{RawImage, RAW_HEADER_LEN, RAW_MAGIC};
+pub use scale::{scale, ScaleFilter, ScaleTarget};
+pub use selection::{serve_selections, SelectionData};
+pub use sha256::sha256;
+pub use stats::{color_stats, histogram, ColorStats, Histogram};
+pub use template::{check_filename_template, expand_filename};
+pub use tiff::TiffCompression;
+pub use transform::{transform, Flip, Rotation};
+pub use watch::BackgroundWatcher;
+
+/// Human-readable version information, including the Git commit if built from a checkout.
+pub fn version_string() -> String {
+    let mut version = format!("xbgdump {}", env!("CARGO_PKG_VERSION"));
+    if let Some(hash) = option_env!("XBGDUMP_GIT_HASH") {
+        version += &format!(" ({})", hash);
+    }
+    version += &format!("\nformats: {}", Format::names());
+
+    version
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_string_names_version_and_formats() {
+        let version = version_string();
+        let first = version.lines().next().unwrap();
+        assert!(first.starts_with(&format!("xbgdump {}", env!("CARGO_PKG_VERSION"))));
+        assert!(version.contains(&format!("\nformats: {}", Format::names())));
+        assert!(version.contains("png, pam"));
+    }
+}
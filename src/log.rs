@@ -0,0 +1,103 @@
+//! Tiny leveled logger writing to stderr, so stdout stays reserved for image data.
+
+use std::{
+    env::var,
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// How much diagnostic output to produce, from least to most.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    const ALL: [LogLevel; 5] = [
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+
+    /// Reads the level from `RUST_LOG`, like env_logger would.
+    ///
+    /// Both plain levels (`debug`) and per-crate directives (`xbgdump=debug`) are understood;
+    /// directives for other crates are ignored.
+    pub fn from_env() -> Option<LogLevel> {
+        let spec = var("RUST_LOG").ok()?;
+        // Later directives override earlier ones
+        spec.rsplit(',')
+            .find_map(|directive| match directive.split_once('=') {
+                Some((target, level)) if target.trim() == env!("CARGO_PKG_NAME") => {
+                    level.parse().ok()
+                }
+                Some(_) => None,
+                None => directive.parse().ok(),
+            })
+    }
+
+    /// Level for the given number of `-v` flags, starting from the default of [`LogLevel::Warn`].
+    pub fn from_verbosity(count: usize) -> LogLevel {
+        LogLevel::ALL[(count + 1).min(LogLevel::ALL.len() - 1)]
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            LogLevel::Error => "Error",
+            LogLevel::Warn => "Warning",
+            LogLevel::Info => "Info",
+            LogLevel::Debug => "Debug",
+            LogLevel::Trace => "Trace",
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            // There's no way to silence errors, so this is as quiet as it gets
+            "off" | "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!("Invalid log level '{}'.", s)),
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Warn as u8);
+
+/// Sets the most verbose level that still gets printed.
+pub fn set_log_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether messages of the given level are currently printed.
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+#[doc(hidden)]
+pub fn write_log(level: LogLevel, args: fmt::Arguments<'_>) {
+    eprintln!("{}: {}", level.label(), args);
+}
+
+/// Prints a message to stderr if the level is enabled, e.g. `log!(Debug, "Got {} bytes.", n)`.
+#[macro_export]
+macro_rules! log {
+    ($level:ident, $($arg:tt)+) => {
+        if $crate::log_enabled($crate::LogLevel::$level) {
+            $crate::write_log($crate::LogLevel::$level, format_args!($($arg)+));
+        }
+    };
+}
@@ -1,233 +1,1223 @@
+mod cli;
+mod completions;
+mod exit;
+
 use anyhow::{bail, Context};
-use getopts::Options;
-use image::{
-    buffer::ConvertBuffer, pnm::PNMSubtype, Bgra, DynamicImage, GenericImage, GenericImageView,
-    ImageBuffer, ImageOutputFormat, Rgba,
+use cli::{Args, Command, ListArgs, ProbeArgs, Target, WatchMode};
+use exit::Status;
+use image::{DynamicImage, GenericImageView, Rgb, Rgba};
+use nix::{
+    errno::Errno,
+    libc::c_int,
+    poll::{poll, PollFd, PollFlags},
+    sys::{
+        signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+        socket::{shutdown, Shutdown},
+    },
 };
 use std::{
     borrow::Cow,
-    convert::{TryFrom, TryInto},
-    env::args_os,
-    io::stdout,
+    env::{args_os, var_os},
+    io::{stdout, Write},
+    process::{self, ExitCode},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use std::{
+    os::unix::io::AsRawFd,
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicI32, Ordering},
 };
 use x11rb::{
     connection::Connection,
-    cookie::Cookie,
-    protocol::{
-        randr::{
-            ConnectionExt as RRConnectionExt, GetCrtcInfoReply, GetScreenResourcesCurrentReply,
-        },
-        xproto::{AtomEnum, ConnectionExt, ImageFormat, Pixmap, Window},
-    },
+    errors::{ConnectError, ReplyError},
+    protocol::xproto::Window,
+    rust_connection::RustConnection,
+};
+use xbgdump::{
+    adjust, color_stats, crop, crop_region, encode_image, expand_filename,
+    find_background_pixmap_in, fingerprint, grab_background_with_info, grab_raw_with, histogram,
+    is_unchanged, log, mask_offscreen, primary_monitor, probe_background_with, query_monitors,
+    query_monitors_and_primary, read_icc_profile, resolve_format, root_icc_profile, root_size,
+    save_state, scale, select_monitor, serve_selections, set_log_level, sha256, state_path,
+    stream_background_pam, tile, transform, version_string, write_encoded, write_image_with,
+    BackgroundInfo, BackgroundWatcher, Format, GrabOptions, LogLevel, Monitor, MonitorSelector,
+    OutputDest, PnmSubtype, ScaleTarget, SelectionData, Source, TextEncoding, WriteOptions,
+    XbgdumpError,
 };
 
-const RGBA_DEPTH: u8 = 32;
-const RGB_DEPTH: u8 = 24;
+fn main() -> ExitCode {
+    let result = match cli::parse(args_os()) {
+        Ok(Command::Capture(args)) => {
+            init_logging(args.log_level);
+            run(*args)
+        }
+        Ok(Command::Probe(args)) => {
+            init_logging(args.log_level);
+            probe(args).map(|()| Status::Success)
+        }
+        Ok(Command::ListMonitors(args)) => {
+            init_logging(args.log_level);
+            list_monitors(args).map(|()| Status::Success)
+        }
+        Ok(Command::Help(usage)) => {
+            print!("{}", usage);
+            return ExitCode::SUCCESS;
+        }
+        Ok(Command::Completions(shell)) => {
+            print!("{}", completions::generate(shell));
+            return ExitCode::SUCCESS;
+        }
+        Ok(Command::Version) => {
+            println!("{}", version_string());
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return Status::Usage.into();
+        }
+    };
 
-type BgraImage = ImageBuffer<Bgra<u8>, Vec<u8>>;
+    match result {
+        Ok(status) => status.into(),
+        Err(e) => {
+            // Same format the Termination impl for Result would use, plus the category
+            let status = Status::classify(&e);
+            eprintln!("Error ({}): {:?}", status.category(), e);
+            status.into()
+        }
+    }
+}
 
-fn print_usage(program: &str, opts: Options) {
-    print!(
-        "{}",
-        opts.usage(&format!(
-            "USAGE: {} [options] [<outfile>.png|<outfile>.pam|-]\n\
-    xbgdump saves the current X11 background to the specified file (or stdout for -).",
-            program
-        ))
-    )
+/// Command-line flags win over `RUST_LOG`, which wins over the default.
+fn init_logging(flags: Option<LogLevel>) {
+    if let Some(level) = flags.or_else(LogLevel::from_env) {
+        set_log_level(level);
+    }
 }
 
-// Image grabbing logic based on https://github.com/neXromancers/shotgun and
-// https://www.apriorit.com/dev-blog/672-lin-how-to-take-multi-monitor-screenshots-on-linux
-// Pixmap grabbing based on https://github.com/polybar/polybar
+fn run(mut args: Args) -> anyhow::Result<Status> {
+    if let Some(timeout) = args.timeout {
+        start_watchdog(timeout);
+    }
 
-fn main() -> anyhow::Result<()> {
-    let args: Vec<_> = args_os().map(Cow::from).collect();
-    let (program, args) = args
-        .split_first()
-        .map(|(p, a)| (p.to_string_lossy(), a))
-        .unwrap_or(("xbgdump".into(), &[]));
+    if matches!(args.text, Some(TextEncoding::Kitty(_)))
+        && !var_os("TERM").is_some_and(|term| term.to_string_lossy().contains("kitty"))
+    {
+        log!(
+            Warn,
+            "TERM doesn't mention kitty, the terminal might not understand kitty's graphics \
+            protocol."
+        );
+    }
 
-    let mut opts = Options::new();
-    opts.optflag("m", "mask", "Mask off-screen areas with full transparency.");
-    opts.optflag("h", "help", "Show this help.");
+    if let Some(path) = &args.icc_profile {
+        let profile = read_icc_profile(path)
+            .with_context(|| format!("Failed to read ICC profile '{}'.", path.display()))?;
+        args.png.icc_profile = Some(profile);
+    }
+
+    let (c, screen_num, root, written) = with_retries(&args, || {
+        let (c, screen_num, root) = timed("Connecting to the X server", || {
+            connect(args.display.as_deref(), args.screen)
+        })?;
+        X_SOCKET.store(c.stream().as_raw_fd(), Ordering::SeqCst);
 
-    let parsed = opts.parse(args)?;
+        let written = capture(&c, screen_num, root, &args).map_err(timed_out)?;
+        Ok((c, screen_num, root, written))
+    })?;
 
-    if parsed.opt_present("h") || parsed.free.len() > 1 {
-        print_usage(program.as_ref(), opts);
-        // TODO: Exit code
-        // Rust makes this very complicated
-        // The only stable way to set the exit code is to use std::process::exit(), which has the
-        // issue of not running destructors.
-        // Setting the exit code via regular control flow requires the unstable Termination trait,
-        // which is provided for Result, but always calls Debug::fmt in the error case, so there's
-        // no way to silently exit with an error status without custom types.
-        return Ok(());
+    match args.watch {
+        Some(mode) => watch(&c, screen_num, root, &args, mode).map(|()| Status::Success),
+        None if written => Ok(Status::Success),
+        // Only possible with --if-changed
+        None => Ok(Status::Unchanged),
     }
+}
 
-    let out_file: Cow<_> = parsed
-        .free
-        .first()
-        .map(Into::into)
-        .unwrap_or("bg.png".into());
+/// Replaces the error with [`XbgdumpError::Timeout`] if it happened because the watchdog cut
+/// the connection, since whatever request was pending when it did is beside the point.
+fn timed_out(e: anyhow::Error) -> anyhow::Error {
+    if !TIMED_OUT.load(Ordering::SeqCst) {
+        return e;
+    }
 
-    let mask = parsed.opt_present("m");
+    log!(Debug, "{:?}", e);
+    XbgdumpError::Timeout.into()
+}
 
-    let (c, screen_num) = x11rb::connect(None)?;
-    let root = c.setup().roots[screen_num].root;
+/// Runs `attempt` up to `--retry` times while it fails in a way that might resolve itself,
+/// waiting `--retry-delay` in between. Returns the last error once all attempts failed.
+fn with_retries<T>(
+    args: &Args,
+    mut attempt: impl FnMut() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    for n in 1.. {
+        match attempt() {
+            Err(e) if n < args.retry && is_transient(&e) && !TIMED_OUT.load(Ordering::SeqCst) => {
+                log!(
+                    Info,
+                    "Attempt {} of {} failed, retrying in {} ms.",
+                    n,
+                    args.retry,
+                    args.retry_delay.as_millis()
+                );
+                log!(Debug, "{:?}", e);
+                // The connection is gone, so the watchdog has nothing to cut while we wait
+                X_SOCKET.store(-1, Ordering::SeqCst);
+                thread::sleep(args.retry_delay);
+            }
+            result => return result,
+        }
+    }
 
-    let raw_bg = get_background(&c, root).context("Failed to get background image.")?;
+    unreachable!("attempts are counted without bound")
+}
 
-    let processed_image = if mask {
-        mask_offscreen(&c, root, raw_bg).context("Failed to mask off-screen areas.")?
-    } else {
-        raw_bg
+/// Whether an error is worth retrying, e.g. at login, while the X server is still starting
+/// or the wallpaper setter hasn't run yet.
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<XbgdumpError>(),
+            Some(
+                XbgdumpError::NoBackgroundPixmap
+                    | XbgdumpError::NoDesktopWindow
+                    | XbgdumpError::X11Error(ReplyError::ConnectionError(_))
+            )
+        ) || cause.is::<ConnectError>()
+    })
+}
+
+/// Set once `--timeout` expired, so the resulting connection error can be reported as such.
+static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+/// Socket of the X connection, or -1 while still connecting.
+static X_SOCKET: AtomicI32 = AtomicI32::new(-1);
+
+/// Cuts the X connection once the timeout expires, which makes the pending request fail with
+/// an error that [`timed_out`] turns into [`XbgdumpError::Timeout`].
+///
+/// Files are only written after the last request and always atomically, so this can't leave
+/// a partial output behind. Before the connection is up, there's nothing to clean up at all,
+/// so the process just exits.
+fn start_watchdog(timeout: Duration) {
+    thread::spawn(move || {
+        thread::sleep(timeout);
+        TIMED_OUT.store(true, Ordering::SeqCst);
+        match X_SOCKET.load(Ordering::SeqCst) {
+            -1 => {
+                eprintln!(
+                    "Error ({}): {}",
+                    Status::Timeout.category(),
+                    XbgdumpError::Timeout
+                );
+                process::exit(Status::Timeout as i32);
+            }
+            socket => {
+                let _ = shutdown(socket, Shutdown::Both);
+            }
+        }
+    });
+}
+
+/// Connects to the X server and resolves the screen to use, with errors fit for users.
+fn connect(
+    display: Option<&str>,
+    screen: Option<usize>,
+) -> anyhow::Result<(RustConnection, usize, Window)> {
+    let (c, default_screen) = RustConnection::connect(display).with_context(|| match display {
+        Some(display) => format!("Failed to connect to X display '{}'.", display),
+        None => match var_os("DISPLAY") {
+            Some(display) => format!(
+                "Failed to connect to X display '{}' from $DISPLAY.",
+                display.to_string_lossy()
+            ),
+            None => "Failed to connect to X display: neither --display nor $DISPLAY is set.".into(),
+        },
+    })?;
+    let screen_num = screen.unwrap_or(default_screen);
+    let screens = &c.setup().roots;
+    let root = match screens.get(screen_num) {
+        Some(screen) => screen.root,
+        None => bail!(
+            "Screen {} does not exist. Available screens: 0 to {}.",
+            screen_num,
+            screens.len() - 1
+        ),
     };
 
-    if out_file == "-" {
-        processed_image
-            .write_to(
-                &mut stdout(),
-                ImageOutputFormat::Pnm(PNMSubtype::ArbitraryMap),
-            )
-            .context("Failed to write image.")?;
+    Ok((c, screen_num, root))
+}
+
+/// Prints where the background is and how large it is, without fetching it.
+fn probe(args: ProbeArgs) -> anyhow::Result<()> {
+    let (c, screen_num, _) = connect(args.display.as_deref(), args.screen)?;
+    let info = probe_background_with(
+        &c,
+        screen_num,
+        &GrabOptions {
+            property: args.property,
+            source: args.source,
+            fallback: args.fallback,
+            ..GrabOptions::default()
+        },
+    )
+    .context("Failed to find background.")?;
+
+    let (source, drawable) = match info.source {
+        Source::Pixmap => ("pixmap", "pixmap"),
+        Source::RootWindow => ("root-window", "window"),
+        Source::DesktopWindow => ("desktop-window", "window"),
+    };
+    if args.json {
+        println!(
+            "{{\"width\":{},\"height\":{},\"depth\":{},\"source\":\"{}\",\"pixmap\":{},\
+            \"property\":{}}}",
+            info.width,
+            info.height,
+            info.depth,
+            source,
+            info.pixmap,
+            info.property.map_or("null".into(), json_string)
+        );
     } else {
-        processed_image
-            .save(out_file.as_ref())
-            .context("Failed to save image.")?;
+        println!(
+            "{}x{} depth={} {}=0x{:x}",
+            info.width, info.height, info.depth, drawable, info.pixmap
+        );
     }
 
     Ok(())
 }
 
-fn get_background(c: &impl Connection, root: Window) -> anyhow::Result<DynamicImage> {
-    let bg_atom = c
-        .intern_atom(true, b"_XROOTPMAP_ID")
-        .context("Failed to create cookie to retrieve background atom ID.")?
-        .reply()
-        .context("Failed to get background atom ID.")?
-        .atom;
-
-    let prop = c
-        .get_property(false, root, bg_atom, AtomEnum::PIXMAP, 0, 1)
-        .context("Failed to create cookie to get background pixmap.")?
-        .reply()
-        .context("Failed to get background pixmap.")?;
-
-    // This is what Polybar does and it works
-    let mut value_iter = prop
-        .value32()
-        .with_context(|| format!("Unexpected pixmap reply format {}.", prop.format))?;
-    let pixmap: Pixmap = value_iter.next().context("No background pixmap set.")?;
-    if value_iter.next() != None {
-        bail!("Too many values in pixmap reply.");
-    }
-
-    let geometry = c
-        .get_geometry(pixmap)
-        .context("Failed to create cookie to retrieve background geometry.")?
-        .reply()
-        .context("Failed to grab background geometry.")?;
-
-    let image_x = c
-        .get_image(
-            ImageFormat::Z_PIXMAP,
-            pixmap,
-            geometry.x,
-            geometry.y,
-            geometry.width,
-            geometry.height,
-            !0, // All planes; X doesn't about extra bits
+/// Prints every enabled CRTC, to help debug masking and find `--monitor` values.
+fn list_monitors(args: ListArgs) -> anyhow::Result<()> {
+    let (c, _, root) = connect(args.display.as_deref(), args.screen)?;
+    let (monitors, primary) =
+        query_monitors_and_primary(&c, root).context("Failed to retrieve screen layout.")?;
+
+    if args.json {
+        // Disabled CRTCs are included so scripts can see every valid --monitor index
+        let entries = monitors
+            .iter()
+            .map(|m| {
+                format!(
+                    "{{\"index\":{},\"name\":{},\"x\":{},\"y\":{},\"width\":{},\"height\":{},\"rotation\":{},\"active\":{},\"primary\":{}}}",
+                    m.index,
+                    m.name.as_deref().map_or_else(|| "null".into(), json_string),
+                    m.x,
+                    m.y,
+                    m.width,
+                    m.height,
+                    json_string(&m.rotation_name()),
+                    m.is_enabled(),
+                    primary == Some(m.crtc)
+                )
+            })
+            .collect::<Vec<_>>();
+        println!("[{}]", entries.join(","));
+    } else {
+        for m in monitors.iter().filter(|m| m.is_enabled()) {
+            println!(
+                "{} {}x{}+{}+{} {}{}",
+                m.display_name(),
+                m.width,
+                m.height,
+                m.x,
+                m.y,
+                m.rotation_name(),
+                if primary == Some(m.crtc) {
+                    " primary"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Quotes a string for JSON output.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            ch if ch.is_control() => quoted += &format!("\\u{:04x}", u32::from(ch)),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// Grabs the background once and writes it wherever the arguments say.
+///
+/// Returns whether anything was written, which is only ever false with `--if-changed`.
+fn capture(
+    // Sync for serving selections, which needs a second thread
+    c: &(impl Connection + Sync),
+    screen_num: usize,
+    root: Window,
+    args: &Args,
+) -> anyhow::Result<bool> {
+    if let Target::Single {
+        output,
+        format: Format::Raw,
+        tee,
+    } = &args.target
+    {
+        return capture_raw(c, screen_num, output, tee.as_deref(), args);
+    }
+    if let Target::Single {
+        output: OutputDest::Stdout,
+        format: Format::Pam,
+        tee: None,
+    } = &args.target
+    {
+        if !args.is_processed()
+            && args.text.is_none()
+            && args.pnm_subtype == PnmSubtype::Pam
+            && !args.checksum
+        {
+            return capture_pam_stream(c, screen_num, args);
+        }
+    }
+
+    let (raw_bg, info) = timed("Grabbing the background", || {
+        grab_background_with_info(
+            c,
+            screen_num,
+            &GrabOptions {
+                use_shm: args.shm,
+                force_opaque: args.force_opaque,
+                force_16bit: args.force_16bit,
+                property: args.property,
+                source: args.source,
+                fallback: args.fallback,
+                strip_height: args.strip_height,
+            },
         )
-        .context("Failed to create cookie to retrieve background contents.")?
-        .reply()
-        .context("Failed to grab background contents.")?;
+    })
+    .context("Failed to get background image.")?;
+    let raw_bg = if args.force_8bit {
+        reduce_to_8bit(raw_bg)
+    } else {
+        raw_bg
+    };
+    let raw_bg = fill_screen(c, root, raw_bg, args.tile)?;
+    // Shared by all files written for this capture, even if writing them takes a while
+    let time = SystemTime::now();
+    let args = &*with_capture_metadata(args, c, screen_num, &info);
+
+    match &args.target {
+        Target::Single {
+            output,
+            format,
+            tee,
+        } => {
+            let (processed_image, monitor) = process(c, root, raw_bg, args)?;
+            write_stats(&processed_image, monitor.as_ref(), time, args)?;
+            let args = &*with_root_icc_profile(args, c, root, monitor.as_ref());
+            let output = expand_dest(output, monitor.as_ref(), processed_image.dimensions(), time)?;
+            let tee = tee
+                .as_ref()
+                .map(|tee| {
+                    let tee = OutputDest::File(tee.clone());
+                    expand_dest(&tee, monitor.as_ref(), processed_image.dimensions(), time)
+                })
+                .transpose()?;
+            write_output(&processed_image, &output, tee.as_ref(), *format, time, args)
+        }
+        Target::Multiple { outputs } => {
+            let (processed_image, monitor) = process(c, root, raw_bg, args)?;
+            write_stats(&processed_image, monitor.as_ref(), time, args)?;
+            let args = &*with_root_icc_profile(args, c, root, monitor.as_ref());
+            // With only the stats wanted, printing them counts as writing
+            write_multiple(&processed_image, monitor.as_ref(), outputs, time, args)
+                .map(|written| written || outputs.is_empty())
+        }
+        Target::Split { template, format } => {
+            write_split(c, root, &raw_bg, template, *format, time, args)
+        }
+        Target::Selections {
+            selections,
+            timeout,
+        } => {
+            let (processed_image, _) = process(c, root, raw_bg, args)?;
+            let args = &*with_root_icc_profile(args, c, root, None);
+            let options = write_options(args, time);
+            let offers = timed("Encoding", || {
+                [Format::Png, Format::Bmp]
+                    .iter()
+                    .map(|&format| {
+                        Ok(SelectionData {
+                            mime_type: format.mime_type(),
+                            data: encode_image(&processed_image, format, &options)?,
+                        })
+                    })
+                    .collect::<xbgdump::Result<Vec<_>>>()
+            })
+            .context("Failed to encode image.")?;
+            serve_selections(c, screen_num, selections, &offers, *timeout)
+                .context("Failed to serve the selection.")?;
+            Ok(true)
+        }
+    }
+}
+
+/// Prints the color statistics and the picked pixel, and writes the histogram, if asked for.
+fn write_stats(
+    image: &DynamicImage,
+    monitor: Option<&Monitor>,
+    time: SystemTime,
+    args: &Args,
+) -> anyhow::Result<()> {
+    if args.color_stats {
+        print_color_stats(image);
+    }
+    if let Some((x, y)) = args.pixel {
+        let (width, height) = image.dimensions();
+        if x >= width || y >= height {
+            bail!(
+                "Pixel {},{} is outside of the {}x{} image.",
+                x,
+                y,
+                width,
+                height
+            );
+        }
+        // 16-bit images are scaled down to 8 bits per channel here
+        let Rgba([r, g, b, a]) = image.get_pixel(x, y);
+        if args.pixel_hex {
+            println!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a);
+        } else {
+            // Like CSS, with alpha from 0 to 1
+            let alpha = format!("{:.3}", f32::from(a) / 255.0);
+            let alpha = alpha.trim_end_matches('0').trim_end_matches('.');
+            println!("rgba({}, {}, {}, {})", r, g, b, alpha);
+        }
+    }
+    if let Some(dest) = &args.histogram {
+        let dest = expand_dest(dest, monitor, image.dimensions(), time)?;
+        let histogram = timed("Counting intensities", || histogram(image));
+        let channels = [
+            ("r", Some(&histogram.r)),
+            ("g", Some(&histogram.g)),
+            ("b", Some(&histogram.b)),
+            ("a", histogram.a.as_ref()),
+        ];
+        let entries = channels
+            .iter()
+            .filter_map(|&(name, counts)| {
+                let counts = counts?.iter().map(u64::to_string).collect::<Vec<_>>();
+                Some(format!("\"{}\":[{}]", name, counts.join(",")))
+            })
+            .collect::<Vec<_>>();
+        let json = format!("{{{}}}\n", entries.join(","));
+        log!(Info, "Writing the histogram to {}.", dest);
+        write_encoded(json.as_bytes(), &dest, &write_options(args, time))
+            .with_context(|| format!("Failed to write the histogram to {}.", dest))?;
+    }
+
+    Ok(())
+}
+
+/// Prints the image's color statistics as JSON, or `null` if it's entirely transparent.
+fn print_color_stats(image: &DynamicImage) {
+    let stats = timed("Computing color statistics", || color_stats(image));
+    let hex = |Rgb([r, g, b]): Rgb<u8>| format!("\"#{:02x}{:02x}{:02x}\"", r, g, b);
+    match stats {
+        Some(stats) => println!(
+            "{{\"average\":{},\"median\":{},\"dominant\":[{}]}}",
+            hex(stats.average),
+            hex(stats.median),
+            stats
+                .dominant
+                .into_iter()
+                .map(hex)
+                .collect::<Vec<_>>()
+                .join(",")
+        ),
+        None => println!("null"),
+    }
+}
 
-    let bgra = BgraImage::from_raw(geometry.width.into(), geometry.height.into(), image_x.data)
-        .context("Failed to create image.")?;
+/// Adds what the background was captured from to the PNG metadata, unless `--no-metadata`
+/// turned it off.
+fn with_capture_metadata<'a>(
+    args: &'a Args,
+    c: &impl Connection,
+    screen_num: usize,
+    info: &BackgroundInfo,
+) -> Cow<'a, Args> {
+    if !args.metadata {
+        return Cow::Borrowed(args);
+    }
 
-    match image_x.depth {
-        // I haven't actually tested this; it's just conjecture from 24-bit being BGR0
-        RGBA_DEPTH => Ok(DynamicImage::ImageRgba8(bgra.convert())),
-        RGB_DEPTH => Ok(DynamicImage::ImageRgb8(bgra.convert())),
-        depth => bail!("Unsupported pixel depth {}.", depth),
+    let screen = &c.setup().roots[screen_num];
+    let mut args = args.clone();
+    let display = args
+        .display
+        .clone()
+        .or_else(|| var_os("DISPLAY").map(|d| d.to_string_lossy().into_owned()));
+    if let Some(display) = display {
+        args.png.text.push(("X Display", display));
     }
+    args.png.text.extend([
+        (
+            "X Root Size",
+            format!("{}x{}", screen.width_in_pixels, screen.height_in_pixels),
+        ),
+        (
+            "X Pixmap Geometry",
+            format!("{}x{}+{}+{}", info.width, info.height, info.x, info.y),
+        ),
+        ("X Pixmap Depth", info.depth.to_string()),
+    ]);
+
+    Cow::Owned(args)
 }
 
-fn mask_offscreen(
+/// Embeds the display's ICC profile from the root window, unless `--icc-profile` gave one.
+///
+/// A single monitor gets its own profile if there is one, the whole background that of the
+/// first monitor.
+fn with_root_icc_profile<'a>(
+    args: &'a Args,
     c: &impl Connection,
     root: Window,
-    // Needs to be mutable for .sub_image(), even though it's never modified
-    mut raw_bg: DynamicImage,
-) -> anyhow::Result<DynamicImage> {
-    // Largely inspired by the similar code in shotgun
-    let GetScreenResourcesCurrentReply {
-        config_timestamp,
-        crtcs,
-        ..
-    } = c
-        .randr_get_screen_resources_current(root)
-        .context("Failed to create cookie to retrieve RandR resources.")?
-        .reply()
-        .context("Failed to retrieve RandR resources. Is RandR supported?")?;
-
-    let crtc_info_cookies = crtcs
-        .into_iter()
-        .map(|crtc| c.randr_get_crtc_info(crtc, config_timestamp))
-        .collect::<Result<Vec<_>, _>>()
-        .context("Failed to create cookies to retrieve screen layout.")?;
-    let crtc_infos = crtc_info_cookies
+    monitor: Option<&Monitor>,
+) -> Cow<'a, Args> {
+    if args.png.icc_profile.is_some() {
+        return Cow::Borrowed(args);
+    }
+
+    // Profiles are numbered like Xinerama screens, i.e. by position among the enabled monitors
+    let screen = monitor.and_then(|monitor| {
+        query_monitors(c, root)
+            .ok()?
+            .iter()
+            .filter(|m| m.is_enabled())
+            .position(|m| m.index == monitor.index)
+    });
+    with_root_icc_profile_of(args, c, root, screen)
+}
+
+/// Like [`with_root_icc_profile`], with the monitor's position already known.
+fn with_root_icc_profile_of<'a>(
+    args: &'a Args,
+    c: &impl Connection,
+    root: Window,
+    screen: Option<usize>,
+) -> Cow<'a, Args> {
+    if args.png.icc_profile.is_some() {
+        return Cow::Borrowed(args);
+    }
+
+    // Monitors without a profile of their own fall back to the first one's
+    let profile = screen
+        .filter(|&screen| screen > 0)
         .into_iter()
-        .map(Cookie::reply)
-        .collect::<Result<Vec<_>, _>>()
-        .context("Failed to retrieve screen layout.")?;
+        .chain([0])
+        .find_map(|screen| match root_icc_profile(c, root, screen) {
+            Ok(profile) => profile,
+            Err(e) => {
+                log!(Debug, "Failed to read the display's ICC profile: {:#}", e);
+                None
+            }
+        });
+    match profile {
+        Some(profile) => {
+            let mut args = args.clone();
+            args.png.icc_profile = Some(profile);
+            Cow::Owned(args)
+        }
+        None => Cow::Borrowed(args),
+    }
+}
+
+/// Writes the background as the server sent it, without any conversion or processing.
+fn capture_raw(
+    c: &impl Connection,
+    screen_num: usize,
+    output: &OutputDest,
+    tee: Option<&Path>,
+    args: &Args,
+) -> anyhow::Result<bool> {
+    let raw = timed("Grabbing the background", || {
+        grab_raw_with(
+            c,
+            screen_num,
+            &GrabOptions {
+                use_shm: args.shm,
+                property: args.property,
+                source: args.source,
+                fallback: args.fallback,
+                strip_height: args.strip_height,
+                ..GrabOptions::default()
+            },
+        )
+    })
+    .context("Failed to get background image.")?;
+    let time = SystemTime::now();
+
+    let size = (raw.width, raw.height);
+    let output = expand_dest(output, None, size, time)?;
+    let tee = tee
+        .map(|tee| expand_dest(&OutputDest::File(tee.into()), None, size, time))
+        .transpose()?;
+    let options = write_options(args, time);
+    let data = raw.encode();
+    write_data(&data, &output, tee.as_ref(), Format::Raw, &options)?;
+    print_checksum(&data, &output, args);
+
+    Ok(true)
+}
+
+/// Converts and writes the background to stdout one row at a time, for plain PAM captures.
+///
+/// Skips building the whole image, which would double memory use for nothing.
+fn capture_pam_stream(c: &impl Connection, screen_num: usize, args: &Args) -> anyhow::Result<bool> {
+    log!(
+        Info,
+        "Writing {} as {}.",
+        OutputDest::Stdout,
+        Format::Pam.name()
+    );
+    let options = GrabOptions {
+        use_shm: args.shm,
+        property: args.property,
+        source: args.source,
+        fallback: args.fallback,
+        strip_height: args.strip_height,
+        ..GrabOptions::default()
+    };
+    let stdout = stdout();
+    let mut lock = stdout.lock();
+    let info = timed("Grabbing and writing", || {
+        let info = stream_background_pam(c, screen_num, &options, &mut lock)?;
+        lock.flush()?;
+        Ok::<_, xbgdump::XbgdumpError>(info)
+    })
+    .context("Failed to write the background to stdout.")?;
+
+    // Only known once the pixels are out, but worth pointing out all the same
+    let root = c.setup().roots[screen_num].root;
+    let screen = root_size(c, root).context("Failed to get the screen size.")?;
+    let size = (info.width.into(), info.height.into());
+    if size.0 < screen.0 || size.1 < screen.1 {
+        warn_small_background(size, screen);
+    }
+
+    Ok(true)
+}
 
-    match crtc_infos.len() {
-        0 => bail!("RandR reports zero screens."),
-        1 => return Ok(raw_bg),
-        _ => {}
+/// Picks the monitor or masks the background as requested, then applies the post-processing.
+///
+/// Also returns the selected monitor, if any, for expanding templates.
+fn process(
+    c: &impl Connection,
+    root: Window,
+    raw_bg: DynamicImage,
+    args: &Args,
+) -> anyhow::Result<(DynamicImage, Option<Monitor>)> {
+    let (image, monitor) = if let Some(selector) = &args.monitor {
+        // The exact rectangle of a monitor is on-screen, so there's nothing to mask
+        let monitor = timed("Querying the screen layout", || {
+            pick_monitor(c, root, selector, args.strict)
+        })?;
+        let image = monitor.crop(&raw_bg).with_context(|| {
+            format!(
+                "Monitor {} lies outside the background pixmap.",
+                monitor.display_name()
+            )
+        })?;
+        let image = if args.apply_rotation {
+            monitor.apply_rotation(image)
+        } else {
+            image
+        };
+        (image, Some(monitor))
+    } else if args.mask {
+        let image = timed("Masking", || {
+            mask_offscreen(raw_bg, c, root, args.mask_fill)
+        })
+        .context("Failed to mask off-screen areas.")?;
+        (image, None)
+    } else {
+        (raw_bg, None)
     };
 
-    let (total_width, total_height) = raw_bg.dimensions();
-    let mut masked = ImageBuffer::from_pixel(total_width, total_height, Rgba([0, 0, 0, 0]));
-    for GetCrtcInfoReply {
-        x,
-        y,
+    let image = timed("Post-processing", || post_process(image, args))?;
+
+    Ok((image, monitor))
+}
+
+/// Tiles a background smaller than the root window if requested, or points out that it's small.
+fn fill_screen(
+    c: &impl Connection,
+    root: Window,
+    raw_bg: DynamicImage,
+    expand: bool,
+) -> anyhow::Result<DynamicImage> {
+    let (root_width, root_height) = root_size(c, root).context("Failed to get the screen size.")?;
+    let (width, height) = raw_bg.dimensions();
+    if width >= root_width && height >= root_height {
+        return Ok(raw_bg);
+    }
+
+    if !expand {
+        warn_small_background((width, height), (root_width, root_height));
+        return Ok(raw_bg);
+    }
+
+    log!(
+        Info,
+        "Tiling the {}x{} background to {}x{}.",
         width,
         height,
-        ..
-    } in crtc_infos
-    {
-        if i32::from(x) + i32::from(width) < 0 || i32::from(y) + i32::from(height) < 0 {
-            // No on-screen portions, nothing to do
-            continue;
+        root_width,
+        root_height
+    );
+    Ok(tile(&raw_bg, root_width, root_height))
+}
+
+/// Points out a background smaller than the screen, which the X server probably repeats.
+fn warn_small_background((width, height): (u32, u32), (root_width, root_height): (u32, u32)) {
+    log!(
+        Warn,
+        "The {}x{} background is smaller than the {}x{} screen and probably tiled. \
+        Use --tile to expand it.",
+        width,
+        height,
+        root_width,
+        root_height
+    );
+}
+
+/// Applies `--bit-depth 8` to deep color captures; 8-bit ones are returned as they are.
+fn reduce_to_8bit(image: DynamicImage) -> DynamicImage {
+    match image {
+        DynamicImage::ImageRgb16(_) => DynamicImage::ImageRgb8(image.to_rgb8()),
+        DynamicImage::ImageRgba16(_) => DynamicImage::ImageRgba8(image.to_rgba8()),
+        image => image,
+    }
+}
+
+/// Runs `f` and logs how long it took.
+fn timed<T>(what: &str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    log!(Info, "{} took {:.1?}.", what, start.elapsed());
+
+    result
+}
+
+/// Resolves the monitor selection, falling back to the first monitor without a primary output.
+fn pick_monitor(
+    c: &impl Connection,
+    root: Window,
+    selector: &MonitorSelector,
+    strict: bool,
+) -> anyhow::Result<Monitor> {
+    if *selector != MonitorSelector::Primary || strict {
+        return Ok(select_monitor(c, root, selector)?);
+    }
+
+    if let Some(primary) = primary_monitor(c, root).context("Failed to retrieve screen layout.")? {
+        return Ok(primary);
+    }
+
+    let first = query_monitors(c, root)
+        .context("Failed to retrieve screen layout.")?
+        .into_iter()
+        .find(Monitor::is_enabled)
+        .context("No enabled monitors.")?;
+    log!(
+        Warn,
+        "No primary output set, using {} instead.",
+        first.display_name()
+    );
+
+    Ok(first)
+}
+
+/// Applies the transformations requested on the command line that don't need the X server.
+///
+/// The order is fixed: --crop, --region, --flip and --rotate, scaling, then the color
+/// adjustments, which end with --grayscale and --invert.
+fn post_process(mut image: DynamicImage, args: &Args) -> anyhow::Result<DynamicImage> {
+    if let Some(geometry) = &args.crop {
+        let (cropped, clamped) = crop(&image, geometry).context("Failed to crop image.")?;
+        if clamped {
+            let (width, height) = cropped.dimensions();
+            log!(
+                Warn,
+                "Crop area exceeds the image and was clamped to {}x{}.",
+                width,
+                height
+            );
         }
+        image = cropped;
+    }
 
-        // Do some clamping in case we're not entirely on-screen
-        // I don't know if that's even possible for the root window,
-        // but having the code is better than randomly tripping an assertion.
-        let (x, width): (u32, u32) = if x < 0 {
-            // Unwrap safe because width + x >= 0
-            (0, u32::try_from(i32::from(width) + i32::from(x)).unwrap())
-        } else {
-            // Unwrap safe because x >= 0 at this point
-            (x.try_into().unwrap(), width.into())
+    if let Some(region) = &args.region {
+        image = crop_region(&image, region).context("Failed to crop image.")?;
+    }
+
+    // Before scaling, so --resize sizes refer to the final orientation
+    if args.flip.is_some() || args.rotate.is_some() {
+        image = transform(image, args.flip, args.rotate);
+    }
+
+    let target = match args.scale {
+        Some(target) => Some(target),
+        None if args.fit_terminal => fit_terminal(image.dimensions(), args.terminal_size),
+        None => None,
+    };
+    if let Some(target) = target {
+        image = scale(&image, target, args.filter, args.allow_upscale)
+            .context("Failed to scale image.")?;
+    }
+
+    // After scaling, so there are fewer pixels to adjust
+    if !args.adjustments.is_identity() {
+        image = adjust(image, &args.adjustments);
+    }
+
+    Ok(image)
+}
+
+/// How to scale an image of the given size down to fit the terminal, if it doesn't already.
+///
+/// Without an explicit size, the terminal is asked for its size in pixels; if it only knows
+/// its rows and columns, cells are assumed to be 10 by 20 pixels. One row is left free for the
+/// prompt. Output that doesn't go to a terminal isn't scaled.
+fn fit_terminal((width, height): (u32, u32), size: Option<(u32, u32)>) -> Option<ScaleTarget> {
+    let (max_width, max_height) = match size {
+        Some(size) => size,
+        None => {
+            let mut size = nix::pty::Winsize {
+                ws_row: 0,
+                ws_col: 0,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            };
+            // SAFETY: TIOCGWINSZ only writes a Winsize to the pointer
+            if let Err(e) = unsafe { terminal_size(stdout().as_raw_fd(), &mut size) } {
+                log!(Debug, "Not scaling to the terminal: {}", e);
+                return None;
+            }
+            let rows = u32::from(size.ws_row.max(2));
+            match (size.ws_xpixel, size.ws_ypixel) {
+                (0, _) | (_, 0) => (u32::from(size.ws_col) * 10, (rows - 1) * 20),
+                (x, y) => (u32::from(x), u32::from(y) * (rows - 1) / rows),
+            }
+        }
+    };
+    log!(
+        Debug,
+        "Fitting the image into {}x{}.",
+        max_width,
+        max_height
+    );
+
+    if width <= max_width && height <= max_height {
+        None
+    } else if u64::from(width) * u64::from(max_height) > u64::from(height) * u64::from(max_width) {
+        Some(ScaleTarget::Width(max_width.max(1)))
+    } else {
+        Some(ScaleTarget::Height(max_height.max(1)))
+    }
+}
+
+nix::ioctl_read_bad!(terminal_size, nix::libc::TIOCGWINSZ, nix::pty::Winsize);
+
+/// Fills in a file name template now that the final image is known.
+///
+/// `{name}` and the other monitor placeholders are expanded first, then the `%` conversions.
+/// Paths that aren't valid UTF-8 are used as-is.
+fn expand_dest(
+    dest: &OutputDest,
+    monitor: Option<&Monitor>,
+    (width, height): (u32, u32),
+    time: SystemTime,
+) -> anyhow::Result<OutputDest> {
+    let template = match dest {
+        OutputDest::File(path) => match path.to_str() {
+            Some(template) => template,
+            None => return Ok(dest.clone()),
+        },
+        OutputDest::Stdout => return Ok(OutputDest::Stdout),
+    };
+
+    let template = match monitor {
+        Some(monitor) => monitor.expand_template(template),
+        None => template.to_owned(),
+    };
+
+    Ok(OutputDest::File(
+        expand_filename(&template, width, height, time)?.into(),
+    ))
+}
+
+fn write_options(args: &Args, time: SystemTime) -> WriteOptions {
+    let mut png = args.png.clone();
+    if args.metadata {
+        png.creation_time = Some(time);
+    }
+
+    WriteOptions {
+        clobber: args.clobber,
+        png,
+        pnm_subtype: args.pnm_subtype,
+        tiff_compression: args.tiff_compression,
+        jpeg_quality: args.jpeg_quality,
+        webp_quality: args.webp_quality,
+        xpm_colors: args.xpm_colors,
+        gif_colors: args.gif_colors,
+        sixel_colors: args.sixel_colors,
+        dither: args.dither,
+        bmp_background: args.bmp_background,
+        text: args.text,
+        newline: args.newline,
+    }
+}
+
+/// Writes the image to the output and the `--tee` file, if any.
+///
+/// With a `--tee` file or `--checksum`, the image is encoded once up front. With
+/// `--if-changed`, nothing is written if the output already holds the same pixels; otherwise
+/// the new fingerprint is remembered afterwards. Returns whether anything was written.
+fn write_output(
+    image: &DynamicImage,
+    output: &OutputDest,
+    tee: Option<&OutputDest>,
+    format: Format,
+    time: SystemTime,
+    args: &Args,
+) -> anyhow::Result<bool> {
+    let state = match output {
+        OutputDest::File(path) if args.if_changed => {
+            let fingerprint = timed("Fingerprinting", || fingerprint(image, format));
+            if is_unchanged(path, fingerprint) {
+                log!(Info, "{} is unchanged, not writing it.", output);
+                return Ok(false);
+            }
+            Some((path, fingerprint))
+        }
+        _ => None,
+    };
+
+    let options = write_options(args, time);
+    if tee.is_some() || args.checksum {
+        let data = timed("Encoding", || encode_image(image, format, &options))
+            .context("Failed to encode image.")?;
+        write_data(&data, output, tee, format, &options)?;
+        print_checksum(&data, output, args);
+    } else {
+        log!(Info, "Writing {} as {}.", output, format.name());
+        timed("Encoding and writing", || {
+            write_image_with(image, output, format, &options)
+        })
+        .with_context(|| format!("Failed to write image to {}.", output))?
+    }
+
+    if let Some((path, fingerprint)) = state {
+        save_state(path, fingerprint).with_context(|| {
+            format!("Failed to save state to '{}'.", state_path(path).display())
+        })?;
+    }
+
+    Ok(true)
+}
+
+/// Writes already encoded data to the output and the `--tee` file, if any.
+///
+/// Both writes are attempted even if one fails, so e.g. a closed pipe doesn't cost the file.
+fn write_data(
+    data: &[u8],
+    output: &OutputDest,
+    tee: Option<&OutputDest>,
+    format: Format,
+    options: &WriteOptions,
+) -> anyhow::Result<()> {
+    let [output_result, tee_result] = [Some(output), tee].map(|dest| {
+        let dest = match dest {
+            Some(dest) => dest,
+            None => return Ok(()),
+        };
+        log!(Info, "Writing {} as {}.", dest, format.name());
+        timed("Writing", || write_encoded(data, dest, options))
+            .with_context(|| format!("Failed to write image to {}.", dest))
+    });
+    if let (Err(e), Err(_)) = (&output_result, &tee_result) {
+        log!(Error, "{:?}", e);
+    }
+
+    output_result.and(tee_result)
+}
+
+/// Prints the SHA-256 of data that was written for `--checksum`, naming the destination if
+/// the capture writes several.
+fn print_checksum(data: &[u8], dest: &OutputDest, args: &Args) {
+    if !args.checksum {
+        return;
+    }
+
+    let hex = sha256(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    match &args.target {
+        Target::Multiple { outputs } if outputs.len() > 1 => eprintln!("sha256:{} {}", hex, dest),
+        Target::Split { .. } => eprintln!("sha256:{} {}", hex, dest),
+        _ => eprintln!("sha256:{}", hex),
+    }
+}
+
+/// Writes the image to every output in turn, each in its own format.
+///
+/// A failed output doesn't stop the rest; errors are logged as they happen, except for the last
+/// one, which is returned once all outputs have been tried.
+fn write_multiple(
+    image: &DynamicImage,
+    monitor: Option<&Monitor>,
+    outputs: &[(OutputDest, Format)],
+    time: SystemTime,
+    args: &Args,
+) -> anyhow::Result<bool> {
+    let mut written = false;
+    let mut last_error = None;
+    for (output, format) in outputs {
+        let result = expand_dest(output, monitor, image.dimensions(), time)
+            .and_then(|output| write_output(image, &output, None, *format, time, args));
+        match result {
+            Ok(w) => written |= w,
+            Err(e) => {
+                if let Some(previous) = last_error.replace(e) {
+                    log!(Error, "{:?}", previous);
+                }
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) => Err(e),
+        None => Ok(written),
+    }
+}
+
+/// Writes every enabled monitor's part of the background to its own file.
+fn write_split(
+    c: &impl Connection,
+    root: Window,
+    raw_bg: &DynamicImage,
+    template: &str,
+    format: Option<Format>,
+    time: SystemTime,
+    args: &Args,
+) -> anyhow::Result<bool> {
+    let mut written = false;
+    // Each monitor's area is on-screen by definition, so there's nothing to mask
+    let monitors = timed("Querying the screen layout", || query_monitors(c, root))
+        .context("Failed to retrieve screen layout.")?;
+    for (screen, monitor) in monitors.iter().filter(|m| m.is_enabled()).enumerate() {
+        let image = match monitor.crop(raw_bg) {
+            Some(image) => image,
+            None => continue,
         };
-        let (y, height): (u32, u32) = if y < 0 {
-            // Unwrap safe because height + y >= 0
-            (0, u32::try_from(i32::from(height) + i32::from(y)).unwrap())
+        let image = if args.apply_rotation {
+            monitor.apply_rotation(image)
         } else {
-            // Unwrap safe because y >= 0 at this point
-            (y.try_into().unwrap(), height.into())
+            image
         };
+        let args = &*with_root_icc_profile_of(args, c, root, Some(screen));
 
-        let area = raw_bg.sub_image(x, y, width, height);
-        masked.copy_from(&area, x, y).expect(
-            "Failed to copy on-screen areas into final result. \
-                        This is a bug in the sizing calculations.",
-        );
+        let image = post_process(image, args)?;
+        let output = OutputDest::File(template.into());
+        let output = expand_dest(&output, Some(monitor), image.dimensions(), time)?;
+        let format = resolve_format(format, &output)?;
+        written |= write_output(&image, &output, None, format, time, args)
+            .with_context(|| format!("Failed to write monitor {}.", monitor.display_name()))?;
     }
 
-    Ok(DynamicImage::ImageRgba8(masked))
+    Ok(written)
+}
+
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_: c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+/// Re-captures the background whenever it changes, until SIGINT or SIGTERM arrives.
+fn watch(
+    c: &RustConnection,
+    screen_num: usize,
+    root: Window,
+    args: &Args,
+    mode: WatchMode,
+) -> anyhow::Result<()> {
+    // No SA_RESTART, so a signal interrupts poll() instead of being retried transparently
+    let action = SigAction::new(
+        SigHandler::Handler(request_stop),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for signal in [Signal::SIGINT, Signal::SIGTERM] {
+        // SAFETY: The handler only stores to an atomic, which is async-signal-safe.
+        unsafe { sigaction(signal, &action) }.context("Failed to install signal handler.")?;
+    }
+
+    let watcher = BackgroundWatcher::with_properties(c, root, args.properties())
+        .context("Failed to subscribe to background changes.")?;
+    let mut last_pixmap = find_background_pixmap_in(c, root, args.properties())
+        .ok()
+        .map(|(_, p)| p);
+
+    while !STOP.load(Ordering::SeqCst) {
+        let changed = match mode {
+            WatchMode::Events => {
+                // x11rb may have already buffered events, so check before blocking
+                if watcher.changed().context("Failed to receive X events.")? {
+                    true
+                } else {
+                    let mut fds = [PollFd::new(c.stream().as_raw_fd(), PollFlags::POLLIN)];
+                    match poll(&mut fds, -1) {
+                        Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => {}
+                        Err(e) => return Err(e).context("Failed to wait for X events."),
+                    }
+                    watcher.changed().context("Failed to receive X events.")?
+                }
+            }
+            WatchMode::Poll(interval) => {
+                match poll(&mut [], interval) {
+                    Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => {}
+                    Err(e) => return Err(e).context("Failed to sleep."),
+                }
+                let pixmap = find_background_pixmap_in(c, root, args.properties())
+                    .ok()
+                    .map(|(_, p)| p);
+                let changed = pixmap != last_pixmap;
+                last_pixmap = pixmap;
+                changed
+            }
+        };
+
+        if changed && !STOP.load(Ordering::SeqCst) {
+            // The setter might be in the middle of replacing the pixmap; try again next time
+            if let Err(e) = capture(c, screen_num, root, args) {
+                log!(Error, "{:?}", e);
+            }
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,216 @@
+use image::{
+    DynamicImage, GenericImage, GenericImageView, ImageBuffer, Pixel, Rgb, RgbImage, RgbaImage,
+};
+use std::str::FromStr;
+use x11rb::{connection::Connection, protocol::xproto::Window};
+
+use crate::{
+    error::{Result, XbgdumpError},
+    monitors::{query_masking_layout, Monitor},
+};
+
+/// What to fill areas of the background with that aren't shown on any monitor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MaskFill {
+    /// Full transparency, which turns the result into an RGBA image.
+    #[default]
+    Transparent,
+    /// A solid color, keeping the result RGB.
+    Solid(Rgb<u8>),
+}
+
+impl FromStr for MaskFill {
+    type Err = String;
+
+    /// Accepts `transparent`, a handful of color names, `#rgb` and `#rrggbb`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let solid = |r, g, b| Ok(MaskFill::Solid(Rgb([r, g, b])));
+        let invalid = || {
+            format!(
+                "Invalid color '{}'. Expected #rgb, #rrggbb, transparent or a color name.",
+                s
+            )
+        };
+        match s.to_ascii_lowercase().as_str() {
+            "transparent" => Ok(MaskFill::Transparent),
+            "black" => solid(0, 0, 0),
+            "white" => solid(255, 255, 255),
+            "gray" | "grey" => solid(128, 128, 128),
+            "red" => solid(255, 0, 0),
+            "green" => solid(0, 255, 0),
+            "blue" => solid(0, 0, 255),
+            hex => {
+                let digits = hex
+                    .strip_prefix('#')
+                    .filter(|d| d.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .ok_or_else(invalid)?;
+                // Unwraps safe because all digits were checked to be valid hex
+                let channel = |d: &str| u8::from_str_radix(d, 16).unwrap();
+                match digits.len() {
+                    // #rgb is shorthand for #rrggbb
+                    3 => {
+                        let short = |i| channel(&digits[i..=i]) * 0x11;
+                        solid(short(0), short(1), short(2))
+                    }
+                    6 => solid(
+                        channel(&digits[0..2]),
+                        channel(&digits[2..4]),
+                        channel(&digits[4..6]),
+                    ),
+                    _ => Err(invalid()),
+                }
+            }
+        }
+    }
+}
+
+/// Replaces all areas of the background not visible on any RandR CRTC (or Xinerama screen)
+/// with the given fill.
+pub fn mask_offscreen(
+    raw_bg: DynamicImage,
+    c: &impl Connection,
+    root: Window,
+    fill: MaskFill,
+) -> Result<DynamicImage> {
+    let monitors = match query_masking_layout(c, root)? {
+        Some(monitors) => monitors,
+        None => {
+            log!(
+                Info,
+                "Neither RandR nor Xinerama is available, treating the background as one screen."
+            );
+            return Ok(raw_bg);
+        }
+    };
+
+    match monitors.len() {
+        0 => return Err(XbgdumpError::NoMonitors),
+        1 => return Ok(raw_bg),
+        _ => {}
+    };
+
+    Ok(mask_monitors(raw_bg, &monitors, fill))
+}
+
+/// Like [`mask_offscreen`], but with an already known screen layout.
+pub fn mask_monitors(raw_bg: DynamicImage, monitors: &[Monitor], fill: MaskFill) -> DynamicImage {
+    let (width, height) = raw_bg.dimensions();
+    // Keep the precision of depth-30 and --force-16bit captures
+    let deep = raw_bg.color().bytes_per_pixel() / raw_bg.color().channel_count() > 1;
+    match (fill, deep) {
+        (MaskFill::Transparent, false) => DynamicImage::ImageRgba8(copy_visible(
+            raw_bg.into_rgba8(),
+            RgbaImage::from_pixel(width, height, [0, 0, 0, 0].into()),
+            monitors,
+        )),
+        (MaskFill::Solid(color), false) => DynamicImage::ImageRgb8(copy_visible(
+            raw_bg.into_rgb8(),
+            RgbImage::from_pixel(width, height, color),
+            monitors,
+        )),
+        (MaskFill::Transparent, true) => DynamicImage::ImageRgba16(copy_visible(
+            raw_bg.into_rgba16(),
+            ImageBuffer::from_pixel(width, height, [0, 0, 0, 0].into()),
+            monitors,
+        )),
+        (MaskFill::Solid(color), true) => DynamicImage::ImageRgb16(copy_visible(
+            raw_bg.into_rgb16(),
+            ImageBuffer::from_pixel(width, height, color.0.map(|c| u16::from(c) * 257).into()),
+            monitors,
+        )),
+    }
+}
+
+fn copy_visible<P: Pixel + 'static>(
+    // Needs to be mutable for .sub_image(), even though it's never modified
+    mut raw_bg: ImageBuffer<P, Vec<P::Subpixel>>,
+    mut masked: ImageBuffer<P, Vec<P::Subpixel>>,
+    monitors: &[Monitor],
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let (total_width, total_height) = raw_bg.dimensions();
+    for monitor in monitors {
+        let (x, y, width, height) = match monitor.visible_rect(total_width, total_height) {
+            Some(rect) => rect,
+            // No on-screen portions, nothing to do
+            None => continue,
+        };
+
+        let area = raw_bg.sub_image(x, y, width, height);
+        masked.copy_from(&area, x, y).expect(
+            "Failed to copy on-screen areas into final result. \
+                        This is a bug in the sizing calculations.",
+        );
+    }
+
+    masked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockServer, ROOT};
+
+    /// A 4x2 image that is white all over.
+    fn white() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 2, [255; 3].into()))
+    }
+
+    #[test]
+    fn without_randr_nothing_is_masked() {
+        let server = MockServer::new(4, 2);
+        let masked = mask_offscreen(white(), &server, ROOT, MaskFill::Transparent).unwrap();
+        assert_eq!(masked.as_bytes(), white().as_bytes());
+    }
+
+    #[test]
+    fn single_monitor_is_not_masked() {
+        let server = MockServer::new(4, 2).monitors(&[("DP-1", 0, 0, 2, 2)]);
+        let masked = mask_offscreen(white(), &server, ROOT, MaskFill::Transparent).unwrap();
+        assert_eq!(masked.as_bytes(), white().as_bytes());
+    }
+
+    #[test]
+    fn deep_colors_survive_masking_into_png() {
+        // A depth-30 pixmap whose pixels all hold the 10-bit value 0x155 in every channel
+        let pixmap = 0x40_0001;
+        let word = 0x155 << 20 | 0x155 << 10 | 0x155u32;
+        let server = MockServer::new(2, 2)
+            .pixmap(pixmap, (2, 2), 30, word.to_le_bytes().repeat(4))
+            .property("_XROOTPMAP_ID", pixmap)
+            .monitors(&[("DP-1", 0, 0, 2, 1), ("HDMI-1", 0, 1, 1, 1)]);
+        let img = crate::background::grab_background(&server, 0).unwrap();
+        let masked = mask_offscreen(img, &server, ROOT, MaskFill::Transparent).unwrap();
+        assert!(matches!(masked, DynamicImage::ImageRgba16(_)));
+
+        let png =
+            crate::output::encode_image(&masked, crate::output::Format::Png, &Default::default())
+                .unwrap();
+        let decoded = image::load_from_memory(&png).unwrap();
+        assert!(matches!(decoded, DynamicImage::ImageRgba16(_)));
+        let pixels = decoded
+            .into_rgba16()
+            .pixels()
+            .map(|p| p.0)
+            .collect::<Vec<_>>();
+        // 0x155 widened to 16 bits, and the bottom right pixel is on no monitor
+        let deep = [0x5555, 0x5555, 0x5555, 0xffff];
+        assert_eq!(pixels, [deep, deep, deep, [0; 4]]);
+    }
+
+    #[test]
+    fn offscreen_areas_are_filled() {
+        // The top right and bottom left pixels aren't shown anywhere
+        let server =
+            MockServer::new(4, 2).monitors(&[("DP-1", 0, 0, 3, 1), ("HDMI-1", 1, 1, 3, 1)]);
+        let fill = MaskFill::Solid(Rgb([255, 0, 0]));
+        let masked = mask_offscreen(white(), &server, ROOT, fill)
+            .unwrap()
+            .into_rgb8();
+        let red: Vec<_> = masked
+            .enumerate_pixels()
+            .filter(|(_, _, p)| p.0 == [255, 0, 0])
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        assert_eq!(red, [(3, 0), (0, 1)]);
+    }
+}
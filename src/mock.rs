@@ -0,0 +1,434 @@
+//! An in-memory X server for tests, answering the handful of requests the library sends.
+//!
+//! Requests are decoded straight from the bytes x11rb serializes, and replies are built by hand
+//! in the wire format, so the real reply parsers run on them. Anything the mock doesn't know
+//! panics with its opcode, which keeps tests from silently relying on defaults.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    convert::TryInto,
+    io::IoSlice,
+};
+use x11rb::{
+    connection::{
+        BufWithFds, Connection, DiscardMode, ReplyOrError, RequestConnection, RequestKind,
+        SequenceNumber,
+    },
+    cookie::{Cookie, CookieWithFds, VoidCookie},
+    errors::{ConnectionError, ParseError, ReplyOrIdError},
+    protocol::{
+        randr,
+        xproto::{
+            AtomEnum, BackingStore, Format, ImageOrder, Screen, Setup, GET_GEOMETRY_REQUEST,
+            GET_IMAGE_REQUEST, GET_PROPERTY_REQUEST, INTERN_ATOM_REQUEST,
+        },
+        Event,
+    },
+    utils::RawFdContainer,
+    x11_utils::{ExtensionInformation, TryParse, TryParseFd, X11Error},
+};
+
+pub const ROOT: u32 = 1;
+const RANDR_OPCODE: u8 = 140;
+
+/// A pixmap or window and its Z_PIXMAP contents.
+struct Drawable {
+    x: i16,
+    y: i16,
+    width: u16,
+    height: u16,
+    depth: u8,
+    data: Vec<u8>,
+}
+
+/// A RandR CRTC, driven by a single output of the same name.
+struct Crtc {
+    name: &'static str,
+    x: i16,
+    y: i16,
+    width: u16,
+    height: u16,
+}
+
+pub struct MockServer {
+    setup: Setup,
+    drawables: HashMap<u32, Drawable>,
+    properties: HashMap<&'static str, u32>,
+    crtcs: Option<Vec<Crtc>>,
+    atoms: RefCell<Vec<String>>,
+    replies: RefCell<HashMap<SequenceNumber, Vec<u8>>>,
+    sequence: Cell<SequenceNumber>,
+    next_id: Cell<u32>,
+    /// Number of GetImage requests answered.
+    pub images: Cell<usize>,
+}
+
+impl MockServer {
+    /// A little-endian server with one screen of the given size and depth 24, without RandR and
+    /// without any background.
+    pub fn new(width: u16, height: u16) -> Self {
+        let format = |depth, bits_per_pixel| Format {
+            depth,
+            bits_per_pixel,
+            scanline_pad: 32,
+        };
+        let screen = Screen {
+            root: ROOT,
+            default_colormap: 0,
+            white_pixel: 0xffffff,
+            black_pixel: 0,
+            current_input_masks: 0,
+            width_in_pixels: width,
+            height_in_pixels: height,
+            width_in_millimeters: 0,
+            height_in_millimeters: 0,
+            min_installed_maps: 1,
+            max_installed_maps: 1,
+            root_visual: 0,
+            backing_stores: BackingStore::NOT_USEFUL,
+            save_unders: false,
+            root_depth: 24,
+            allowed_depths: Vec::new(),
+        };
+        let setup = Setup {
+            status: 1,
+            protocol_major_version: 11,
+            protocol_minor_version: 0,
+            length: 0,
+            release_number: 0,
+            resource_id_base: 0x0020_0000,
+            resource_id_mask: 0x001f_ffff,
+            motion_buffer_size: 0,
+            maximum_request_length: u16::MAX,
+            image_byte_order: ImageOrder::LSB_FIRST,
+            bitmap_format_bit_order: ImageOrder::LSB_FIRST,
+            bitmap_format_scanline_unit: 32,
+            bitmap_format_scanline_pad: 32,
+            min_keycode: 8,
+            max_keycode: 255,
+            vendor: b"xbgdump tests".to_vec(),
+            pixmap_formats: vec![
+                format(1, 1),
+                format(16, 16),
+                format(24, 32),
+                format(30, 32),
+                format(32, 32),
+            ],
+            roots: vec![screen],
+        };
+
+        let mut drawables = HashMap::new();
+        drawables.insert(
+            ROOT,
+            Drawable {
+                x: 0,
+                y: 0,
+                width,
+                height,
+                depth: 24,
+                data: Vec::new(),
+            },
+        );
+
+        MockServer {
+            setup,
+            drawables,
+            properties: HashMap::new(),
+            crtcs: None,
+            atoms: RefCell::new(Vec::new()),
+            replies: RefCell::new(HashMap::new()),
+            sequence: Cell::new(0),
+            next_id: Cell::new(0x0020_0000),
+            images: Cell::new(0),
+        }
+    }
+
+    /// Adds a pixmap with the given Z_PIXMAP data.
+    pub fn pixmap(
+        mut self,
+        id: u32,
+        (width, height): (u16, u16),
+        depth: u8,
+        data: Vec<u8>,
+    ) -> Self {
+        let drawable = Drawable {
+            x: 0,
+            y: 0,
+            width,
+            height,
+            depth,
+            data,
+        };
+        self.drawables.insert(id, drawable);
+        self
+    }
+
+    /// Sets a root window property to a pixmap ID.
+    pub fn property(mut self, name: &'static str, pixmap: u32) -> Self {
+        self.properties.insert(name, pixmap);
+        self
+    }
+
+    /// Enables RandR with one CRTC per `(name, x, y, width, height)`.
+    pub fn monitors(mut self, monitors: &[(&'static str, i16, i16, u16, u16)]) -> Self {
+        let crtcs = monitors
+            .iter()
+            .map(|&(name, x, y, width, height)| Crtc {
+                name,
+                x,
+                y,
+                width,
+                height,
+            })
+            .collect();
+        self.crtcs = Some(crtcs);
+        self
+    }
+
+    fn atom_name(&self, atom: u32) -> Option<String> {
+        let index = atom.checked_sub(1000)? as usize;
+        self.atoms.borrow().get(index).cloned()
+    }
+
+    fn answer(&self, request: &[u8]) -> Vec<u8> {
+        let u16_at = |i: usize| u16::from_ne_bytes(request[i..i + 2].try_into().unwrap());
+        let u32_at = |i: usize| u32::from_ne_bytes(request[i..i + 4].try_into().unwrap());
+
+        match request[0] {
+            INTERN_ATOM_REQUEST => {
+                let name = String::from_utf8(request[8..8 + usize::from(u16_at(4))].to_vec());
+                let name = name.unwrap();
+                let mut atoms = self.atoms.borrow_mut();
+                let index = atoms.iter().position(|a| *a == name).unwrap_or_else(|| {
+                    atoms.push(name);
+                    atoms.len() - 1
+                });
+                reply(0, &(1000 + index as u32).to_ne_bytes(), &[])
+            }
+            GET_PROPERTY_REQUEST => {
+                let pixmap = self
+                    .atom_name(u32_at(8))
+                    .and_then(|name| self.properties.get(name.as_str()).copied());
+                match pixmap {
+                    Some(pixmap) => {
+                        let fixed = [u32::from(AtomEnum::PIXMAP), 0, 1].map(u32::to_ne_bytes);
+                        reply(32, &fixed.concat(), &pixmap.to_ne_bytes())
+                    }
+                    None => reply(0, &[0; 12], &[]),
+                }
+            }
+            GET_GEOMETRY_REQUEST => {
+                let drawable = &self.drawables[&u32_at(4)];
+                let mut fixed = ROOT.to_ne_bytes().to_vec();
+                fixed.extend_from_slice(&drawable.x.to_ne_bytes());
+                fixed.extend_from_slice(&drawable.y.to_ne_bytes());
+                fixed.extend_from_slice(&drawable.width.to_ne_bytes());
+                fixed.extend_from_slice(&drawable.height.to_ne_bytes());
+                reply(drawable.depth, &fixed, &[])
+            }
+            GET_IMAGE_REQUEST => {
+                self.images.set(self.images.get() + 1);
+                let drawable = &self.drawables[&u32_at(4)];
+                let (y, height) = (usize::from(u16_at(10)), usize::from(u16_at(14)));
+                let stride = drawable.data.len() / usize::from(drawable.height);
+                let rows = &drawable.data[y * stride..(y + height) * stride];
+                reply(drawable.depth, &[], rows)
+            }
+            RANDR_OPCODE => self.answer_randr(request),
+            opcode => panic!("Unexpected request with opcode {}", opcode),
+        }
+    }
+
+    fn answer_randr(&self, request: &[u8]) -> Vec<u8> {
+        let crtcs = self.crtcs.as_ref().unwrap();
+        let u32_at = |i: usize| u32::from_ne_bytes(request[i..i + 4].try_into().unwrap());
+        // CRTC n is ID 100 + n, its output 200 + n
+        let ids = |base: u32| (0..crtcs.len() as u32).map(move |i| base + i);
+
+        match request[1] {
+            randr::GET_SCREEN_RESOURCES_CURRENT_REQUEST => {
+                let mut fixed = [0, 0].map(u32::to_ne_bytes).concat();
+                for count in [crtcs.len(), crtcs.len(), 0, 0] {
+                    fixed.extend_from_slice(&(count as u16).to_ne_bytes());
+                }
+                let lists: Vec<_> = ids(100)
+                    .chain(ids(200))
+                    .flat_map(u32::to_ne_bytes)
+                    .collect();
+                reply(0, &fixed, &lists)
+            }
+            randr::GET_CRTC_INFO_REQUEST => {
+                let index = u32_at(4) - 100;
+                let crtc = &crtcs[index as usize];
+                let mut fixed = 0u32.to_ne_bytes().to_vec();
+                fixed.extend_from_slice(&crtc.x.to_ne_bytes());
+                fixed.extend_from_slice(&crtc.y.to_ne_bytes());
+                fixed.extend_from_slice(&crtc.width.to_ne_bytes());
+                fixed.extend_from_slice(&crtc.height.to_ne_bytes());
+                // Any mode but NONE enables the CRTC
+                fixed.extend_from_slice(&1u32.to_ne_bytes());
+                for value in [1, 1, 1, 0] {
+                    fixed.extend_from_slice(&(value as u16).to_ne_bytes());
+                }
+                reply(0, &fixed, &(200 + index).to_ne_bytes())
+            }
+            randr::GET_OUTPUT_INFO_REQUEST => {
+                let index = u32_at(4) - 200;
+                let name = crtcs[index as usize].name.as_bytes();
+                let mut fixed = [0, 100 + index, 0, 0].map(u32::to_ne_bytes).concat();
+                // Connected, unknown subpixel order
+                fixed.extend_from_slice(&[0, 0]);
+                for count in [0, 0, 0, 0, name.len()] {
+                    fixed.extend_from_slice(&(count as u16).to_ne_bytes());
+                }
+                reply(0, &fixed, name)
+            }
+            minor => panic!("Unexpected RandR request with minor opcode {}", minor),
+        }
+    }
+
+    fn send(&self, bufs: &[IoSlice<'_>], has_reply: bool) -> SequenceNumber {
+        let sequence = self.sequence.get() + 1;
+        self.sequence.set(sequence);
+        if has_reply {
+            let request: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+            let reply = self.answer(&request);
+            self.replies.borrow_mut().insert(sequence, reply);
+        }
+        sequence
+    }
+}
+
+/// Puts a reply together: the 32-byte header with `data` as the second byte and as much of
+/// `fixed` as fits, then the rest of `fixed` and the variable part, padded to 4 bytes.
+fn reply(data: u8, fixed: &[u8], variable: &[u8]) -> Vec<u8> {
+    let mut out = vec![1, data, 0, 0, 0, 0, 0, 0];
+    out.extend_from_slice(fixed);
+    if out.len() < 32 {
+        out.resize(32, 0);
+    }
+    out.extend_from_slice(variable);
+    out.resize(out.len().div_ceil(4) * 4, 0);
+    let length = ((out.len() - 32) / 4) as u32;
+    out[4..8].copy_from_slice(&length.to_ne_bytes());
+    out
+}
+
+impl RequestConnection for MockServer {
+    type Buf = Vec<u8>;
+
+    fn send_request_with_reply<R: TryParse>(
+        &self,
+        bufs: &[IoSlice<'_>],
+        _fds: Vec<RawFdContainer>,
+    ) -> Result<Cookie<'_, Self, R>, ConnectionError> {
+        Ok(Cookie::new(self, self.send(bufs, true)))
+    }
+
+    fn send_request_with_reply_with_fds<R: TryParseFd>(
+        &self,
+        _bufs: &[IoSlice<'_>],
+        _fds: Vec<RawFdContainer>,
+    ) -> Result<CookieWithFds<'_, Self, R>, ConnectionError> {
+        unimplemented!("No request with file descriptors is mocked")
+    }
+
+    fn send_request_without_reply(
+        &self,
+        bufs: &[IoSlice<'_>],
+        _fds: Vec<RawFdContainer>,
+    ) -> Result<VoidCookie<'_, Self>, ConnectionError> {
+        Ok(VoidCookie::new(self, self.send(bufs, false)))
+    }
+
+    fn discard_reply(&self, sequence: SequenceNumber, _kind: RequestKind, _mode: DiscardMode) {
+        self.replies.borrow_mut().remove(&sequence);
+    }
+
+    fn prefetch_extension_information(&self, _name: &'static str) -> Result<(), ConnectionError> {
+        Ok(())
+    }
+
+    fn extension_information(
+        &self,
+        name: &'static str,
+    ) -> Result<Option<ExtensionInformation>, ConnectionError> {
+        Ok(match name {
+            randr::X11_EXTENSION_NAME if self.crtcs.is_some() => Some(ExtensionInformation {
+                major_opcode: RANDR_OPCODE,
+                first_event: 89,
+                first_error: 147,
+            }),
+            _ => None,
+        })
+    }
+
+    fn wait_for_reply_or_raw_error(
+        &self,
+        sequence: SequenceNumber,
+    ) -> Result<ReplyOrError<Vec<u8>>, ConnectionError> {
+        let reply = self.replies.borrow_mut().remove(&sequence);
+        Ok(ReplyOrError::Reply(reply.expect("reply already taken")))
+    }
+
+    fn wait_for_reply(&self, sequence: SequenceNumber) -> Result<Option<Vec<u8>>, ConnectionError> {
+        Ok(self.replies.borrow_mut().remove(&sequence))
+    }
+
+    fn wait_for_reply_with_fds_raw(
+        &self,
+        _sequence: SequenceNumber,
+    ) -> Result<ReplyOrError<BufWithFds<Vec<u8>>, Vec<u8>>, ConnectionError> {
+        unimplemented!("No request with file descriptors is mocked")
+    }
+
+    fn check_for_raw_error(
+        &self,
+        _sequence: SequenceNumber,
+    ) -> Result<Option<Vec<u8>>, ConnectionError> {
+        Ok(None)
+    }
+
+    fn prefetch_maximum_request_bytes(&self) {}
+
+    fn maximum_request_bytes(&self) -> usize {
+        usize::from(self.setup.maximum_request_length) * 4
+    }
+
+    fn parse_error(&self, _error: &[u8]) -> Result<X11Error, ParseError> {
+        unimplemented!("The mock never sends errors")
+    }
+
+    fn parse_event(&self, _event: &[u8]) -> Result<Event, ParseError> {
+        unimplemented!("The mock never sends events")
+    }
+}
+
+impl Connection for MockServer {
+    fn wait_for_raw_event_with_sequence(
+        &self,
+    ) -> Result<x11rb::connection::RawEventAndSeqNumber<Vec<u8>>, ConnectionError> {
+        unimplemented!("The mock never sends events")
+    }
+
+    fn poll_for_raw_event_with_sequence(
+        &self,
+    ) -> Result<Option<x11rb::connection::RawEventAndSeqNumber<Vec<u8>>>, ConnectionError> {
+        Ok(None)
+    }
+
+    fn flush(&self) -> Result<(), ConnectionError> {
+        Ok(())
+    }
+
+    fn setup(&self) -> &Setup {
+        &self.setup
+    }
+
+    fn generate_id(&self) -> Result<u32, ReplyOrIdError> {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        Ok(id)
+    }
+}
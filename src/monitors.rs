@@ -0,0 +1,520 @@
+use image::{DynamicImage, GenericImageView};
+use std::{convert::TryFrom, str::FromStr};
+use x11rb::{
+    connection::Connection,
+    cookie::Cookie,
+    errors::{ConnectionError, ReplyError},
+    protocol::{
+        randr::{
+            Connection as RRConnection, ConnectionExt as RRConnectionExt, Crtc,
+            GetScreenResourcesCurrentReply, Output, Rotation,
+        },
+        xproto::{ConnectionExt as XConnectionExt, QueryPointerReply, Window},
+    },
+    NONE,
+};
+
+use crate::error::{Result, XbgdumpError};
+
+/// A RandR CRTC, i.e. a rectangle of the root window shown on one or more outputs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Monitor {
+    /// Position of the CRTC in the server's list.
+    pub index: usize,
+    /// `NONE` for screens reported by Xinerama.
+    pub crtc: Crtc,
+    /// Name of the first output driven by this CRTC, if any.
+    pub name: Option<String>,
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+    /// Raw RandR rotation/reflection bits.
+    pub rotation: u16,
+    pub outputs: Vec<Output>,
+    enabled: bool,
+}
+
+impl Monitor {
+    /// Whether the CRTC currently shows anything.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled && self.width > 0 && self.height > 0
+    }
+
+    /// Output name, or a placeholder derived from the index for CRTCs without outputs.
+    pub fn display_name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("crtc-{}", self.index))
+    }
+
+    /// The part of this monitor that lies within an image of the given size,
+    /// as `(x, y, width, height)`, or `None` if there's no overlap.
+    pub fn visible_rect(&self, img_width: u32, img_height: u32) -> Option<(u32, u32, u32, u32)> {
+        // Do some clamping in case we're not entirely on-screen
+        // I don't know if that's even possible for the root window,
+        // but having the code is better than randomly tripping an assertion.
+        let clamp = |start: i16, len: u16, max: u32| {
+            let end = (i64::from(start) + i64::from(len)).min(i64::from(max));
+            let start = i64::from(start).max(0);
+            // Both conversions are safe because 0 <= start < end <= max
+            (start < end).then(|| {
+                (
+                    u32::try_from(start).unwrap(),
+                    u32::try_from(end - start).unwrap(),
+                )
+            })
+        };
+
+        let (x, width) = clamp(self.x, self.width, img_width)?;
+        let (y, height) = clamp(self.y, self.height, img_height)?;
+        Some((x, y, width, height))
+    }
+
+    /// Cuts this monitor's area out of the full background.
+    pub fn crop(&self, img: &DynamicImage) -> Option<DynamicImage> {
+        let (img_width, img_height) = img.dimensions();
+        let (x, y, width, height) = self.visible_rect(img_width, img_height)?;
+        Some(img.crop_imm(x, y, width, height))
+    }
+
+    /// Turns a crop of this monitor's area the way the CRTC rotates and reflects it, giving
+    /// the image in the panel's native orientation. RandR rotates counterclockwise.
+    pub fn apply_rotation(&self, img: DynamicImage) -> DynamicImage {
+        let rotation = u16::from(Rotation::ROTATE0)
+            | u16::from(Rotation::ROTATE90)
+            | u16::from(Rotation::ROTATE180)
+            | u16::from(Rotation::ROTATE270);
+        let img = match self.rotation & rotation {
+            r if r == u16::from(Rotation::ROTATE90) => img.rotate270(),
+            r if r == u16::from(Rotation::ROTATE180) => img.rotate180(),
+            r if r == u16::from(Rotation::ROTATE270) => img.rotate90(),
+            _ => img,
+        };
+        let img = if self.rotation & u16::from(Rotation::REFLECT_X) != 0 {
+            img.fliph()
+        } else {
+            img
+        };
+        if self.rotation & u16::from(Rotation::REFLECT_Y) != 0 {
+            img.flipv()
+        } else {
+            img
+        }
+    }
+
+    /// Rotation and reflection in the same terms xrandr uses, e.g. `left` or `normal reflect-x`.
+    pub fn rotation_name(&self) -> String {
+        let rotation = u16::from(Rotation::ROTATE0)
+            | u16::from(Rotation::ROTATE90)
+            | u16::from(Rotation::ROTATE180)
+            | u16::from(Rotation::ROTATE270);
+        let mut name = match self.rotation & rotation {
+            r if r == u16::from(Rotation::ROTATE90) => "left",
+            r if r == u16::from(Rotation::ROTATE180) => "inverted",
+            r if r == u16::from(Rotation::ROTATE270) => "right",
+            _ => "normal",
+        }
+        .to_owned();
+
+        if self.rotation & u16::from(Rotation::REFLECT_X) != 0 {
+            name += " reflect-x";
+        }
+        if self.rotation & u16::from(Rotation::REFLECT_Y) != 0 {
+            name += " reflect-y";
+        }
+
+        name
+    }
+
+    /// Substitutes `{name}`, `{index}`, `{x}`, `{y}`, `{w}` and `{h}` in a file name template.
+    pub fn expand_template(&self, template: &str) -> String {
+        template
+            .replace("{name}", &self.display_name())
+            .replace("{index}", &self.index.to_string())
+            .replace("{x}", &self.x.to_string())
+            .replace("{y}", &self.y.to_string())
+            .replace("{w}", &self.width.to_string())
+            .replace("{h}", &self.height.to_string())
+    }
+}
+
+/// A RandR output, i.e. a connector that may or may not have a monitor attached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputInfo {
+    pub output: Output,
+    pub name: String,
+    /// The CRTC driving this output, or `NONE` if it's disabled.
+    pub crtc: Crtc,
+    pub connected: bool,
+}
+
+/// How to pick a single monitor out of the layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MonitorSelector {
+    /// CRTC index, as used by `{index}` in split templates.
+    Index(usize),
+    /// RandR output name, e.g. `DP-1`.
+    Name(String),
+    /// Whichever output RandR considers the primary one.
+    Primary,
+    /// The monitor the mouse pointer is on, or the closest one.
+    UnderCursor,
+}
+
+impl FromStr for MonitorSelector {
+    type Err = String;
+
+    /// Numbers are taken as indices, anything else as an output name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("Monitor name must not be empty.".into());
+        }
+
+        Ok(s.parse()
+            .map(MonitorSelector::Index)
+            .unwrap_or_else(|_| MonitorSelector::Name(s.into())))
+    }
+}
+
+/// Retrieves the current RandR layout of the screen the root window belongs to.
+pub fn query_monitors(c: &impl Connection, root: Window) -> Result<Vec<Monitor>> {
+    query_layout(c, root).map(|(monitors, _)| monitors)
+}
+
+/// Retrieves the screen layout for masking, falling back to Xinerama on servers without RandR.
+///
+/// Returns `None` if neither extension is available, in which case all of the pixmap is on-screen.
+/// The Xinerama fallback requires the `xinerama` feature.
+pub fn query_masking_layout(c: &impl Connection, root: Window) -> Result<Option<Vec<Monitor>>> {
+    match query_monitors(c, root) {
+        Ok(monitors) => Ok(Some(monitors)),
+        Err(XbgdumpError::RandrUnsupported) => {
+            #[cfg(feature = "xinerama")]
+            {
+                if let Some(monitors) = query_xinerama(c)? {
+                    return Ok(Some(monitors));
+                }
+            }
+
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Retrieves the screens Xinerama knows about, or `None` if it isn't active.
+#[cfg(feature = "xinerama")]
+fn query_xinerama(c: &impl Connection) -> Result<Option<Vec<Monitor>>> {
+    use x11rb::protocol::xinerama::ConnectionExt as XineramaConnectionExt;
+
+    let active = match c.xinerama_is_active() {
+        Ok(cookie) => cookie.reply()?.state != 0,
+        Err(ConnectionError::UnsupportedExtension) => false,
+        Err(e) => return Err(e.into()),
+    };
+    if !active {
+        return Ok(None);
+    }
+
+    let screens = c.xinerama_query_screens()?.reply()?.screen_info;
+    log!(Debug, "Xinerama reports {} screens.", screens.len());
+
+    Ok(Some(
+        screens
+            .into_iter()
+            .enumerate()
+            .map(|(index, screen)| Monitor {
+                index,
+                crtc: NONE,
+                name: None,
+                x: screen.x_org,
+                y: screen.y_org,
+                width: screen.width,
+                height: screen.height,
+                rotation: Rotation::ROTATE0.into(),
+                outputs: Vec::new(),
+                enabled: true,
+            })
+            .collect(),
+    ))
+}
+
+/// Retrieves the RandR layout together with the CRTC showing the primary output, if any.
+pub fn query_monitors_and_primary(
+    c: &impl Connection,
+    root: Window,
+) -> Result<(Vec<Monitor>, Option<Crtc>)> {
+    // Doesn't depend on the layout, so it can share a round trip with it
+    let primary = c.randr_get_output_primary(root).map_err(randr_error)?;
+    let (monitors, outputs) = query_layout(c, root)?;
+    let primary = primary_crtc(primary.reply()?.output, &outputs);
+    Ok((monitors, primary))
+}
+
+/// Finds the single monitor described by `selector`.
+///
+/// Fails with a descriptive message if it doesn't exist or is currently disabled.
+pub fn select_monitor(
+    c: &impl Connection,
+    root: Window,
+    selector: &MonitorSelector,
+) -> Result<Monitor> {
+    // Requests that don't depend on the layout go out along with it
+    let primary = match selector {
+        MonitorSelector::Primary => Some(c.randr_get_output_primary(root).map_err(randr_error)?),
+        _ => None,
+    };
+    let pointer = match selector {
+        MonitorSelector::UnderCursor => Some(c.query_pointer(root)?),
+        _ => None,
+    };
+    let (monitors, outputs) = query_layout(c, root)?;
+
+    let monitor = match selector {
+        MonitorSelector::Index(index) => {
+            let count = monitors.len();
+            monitors
+                .into_iter()
+                .nth(*index)
+                .ok_or(XbgdumpError::NoSuchCrtc {
+                    index: *index,
+                    count,
+                })?
+        }
+        MonitorSelector::Name(name) => {
+            let output = outputs.iter().find(|o| &o.name == name).ok_or_else(|| {
+                XbgdumpError::NoSuchOutput {
+                    name: name.clone(),
+                    available: outputs.iter().map(|o| o.name.clone()).collect(),
+                }
+            })?;
+            if output.crtc == NONE {
+                return Err(XbgdumpError::OutputDisabled {
+                    name: name.clone(),
+                    connected: output.connected,
+                });
+            }
+
+            // Unwrap safe because RandR only reports CRTCs it also lists in the screen resources
+            monitors
+                .into_iter()
+                .find(|m| m.crtc == output.crtc)
+                .unwrap()
+        }
+        // Unwraps safe because the cookies were created for exactly these selectors
+        MonitorSelector::Primary => {
+            let primary = primary.unwrap().reply()?.output;
+            find_primary(primary, monitors, &outputs).ok_or(XbgdumpError::NoPrimaryOutput)?
+        }
+        MonitorSelector::UnderCursor => find_under_cursor(pointer.unwrap().reply()?, monitors)?,
+    };
+
+    if !monitor.is_enabled() {
+        return Err(XbgdumpError::MonitorDisabled(monitor.display_name()));
+    }
+
+    Ok(monitor)
+}
+
+/// Finds the enabled monitor containing the pointer, or the closest one if the pointer is in
+/// a gap between monitors.
+fn find_under_cursor(pointer: QueryPointerReply, monitors: Vec<Monitor>) -> Result<Monitor> {
+    if !pointer.same_screen {
+        log!(Warn, "The pointer is on a different screen.");
+    }
+    let (x, y) = (i32::from(pointer.root_x), i32::from(pointer.root_y));
+    log!(Debug, "Pointer is at {},{}.", x, y);
+
+    // Squared distance from the pointer to the monitor's rectangle, 0 if it's inside
+    let distance = |m: &Monitor| {
+        let axis = |pos: i32, start: i16, len: u16| {
+            let (start, end) = (i32::from(start), i32::from(start) + i32::from(len));
+            let d = i64::from((start - pos).max(pos - end + 1).max(0));
+            d * d
+        };
+        axis(x, m.x, m.width) + axis(y, m.y, m.height)
+    };
+
+    let closest = monitors
+        .into_iter()
+        .filter(Monitor::is_enabled)
+        .min_by_key(distance)
+        .ok_or(XbgdumpError::NoMonitors)?;
+    if distance(&closest) > 0 {
+        log!(
+            Warn,
+            "The pointer at {},{} is not on any monitor, using the closest one, {}.",
+            x,
+            y,
+            closest.display_name()
+        );
+    }
+
+    Ok(closest)
+}
+
+/// Finds the monitor showing the primary output, if one is set and enabled.
+pub fn primary_monitor(c: &impl Connection, root: Window) -> Result<Option<Monitor>> {
+    let primary = c.randr_get_output_primary(root).map_err(randr_error)?;
+    let (monitors, outputs) = query_layout(c, root)?;
+    Ok(find_primary(primary.reply()?.output, monitors, &outputs))
+}
+
+fn find_primary(
+    primary: Output,
+    monitors: Vec<Monitor>,
+    outputs: &[OutputInfo],
+) -> Option<Monitor> {
+    let crtc = primary_crtc(primary, outputs)?;
+
+    monitors
+        .into_iter()
+        .find(|m| m.crtc == crtc)
+        .filter(Monitor::is_enabled)
+}
+
+/// The CRTC driving the primary output, or `None` if there's no primary output or it's disabled.
+fn primary_crtc(primary: Output, outputs: &[OutputInfo]) -> Option<Crtc> {
+    outputs
+        .iter()
+        .find(|o| o.output == primary)
+        .map(|o| o.crtc)
+        .filter(|&crtc| crtc != NONE)
+}
+
+/// Sending RandR requests fails right away if the server doesn't have the extension at all.
+fn randr_error(e: ConnectionError) -> XbgdumpError {
+    match e {
+        ConnectionError::UnsupportedExtension => XbgdumpError::RandrUnsupported,
+        e => e.into(),
+    }
+}
+
+/// Retrieves all CRTCs and outputs of the screen the root window belongs to.
+fn query_layout(c: &impl Connection, root: Window) -> Result<(Vec<Monitor>, Vec<OutputInfo>)> {
+    // Largely inspired by the similar code in shotgun
+    let GetScreenResourcesCurrentReply {
+        config_timestamp,
+        crtcs,
+        outputs,
+        ..
+    } = c
+        .randr_get_screen_resources_current(root)
+        .map_err(randr_error)?
+        .reply()
+        // The root window is valid, so the only reason for an error is RandR < 1.3
+        .map_err(|e| match e {
+            ReplyError::X11Error(_) => XbgdumpError::RandrUnsupported,
+            e => e.into(),
+        })?;
+
+    log!(
+        Debug,
+        "RandR reports {} CRTCs and {} outputs.",
+        crtcs.len(),
+        outputs.len()
+    );
+
+    // Send all requests before waiting for any of the replies
+    let crtc_info_cookies = crtcs
+        .iter()
+        .map(|&crtc| c.randr_get_crtc_info(crtc, config_timestamp))
+        .collect::<Result<Vec<_>, _>>()?;
+    let output_info_cookies = outputs
+        .iter()
+        .map(|&output| c.randr_get_output_info(output, config_timestamp))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let crtc_infos = crtc_info_cookies
+        .into_iter()
+        .map(Cookie::reply)
+        .collect::<Result<Vec<_>, _>>()?;
+    let outputs = outputs
+        .into_iter()
+        .zip(output_info_cookies)
+        .map(|(output, cookie)| {
+            cookie.reply().map(|info| {
+                log!(Trace, "Output {}: {:?}", output, info);
+                OutputInfo {
+                    output,
+                    name: String::from_utf8_lossy(&info.name).into_owned(),
+                    crtc: info.crtc,
+                    connected: info.connection == RRConnection::CONNECTED,
+                }
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let monitors = crtcs
+        .into_iter()
+        .zip(crtc_infos)
+        .inspect(|(crtc, info)| log!(Trace, "CRTC {}: {:?}", crtc, info))
+        .enumerate()
+        .map(|(index, (crtc, info))| Monitor {
+            index,
+            crtc,
+            name: info
+                .outputs
+                .first()
+                .and_then(|first| outputs.iter().find(|o| o.output == *first))
+                .map(|o| o.name.clone()),
+            x: info.x,
+            y: info.y,
+            width: info.width,
+            height: info.height,
+            rotation: info.rotation,
+            outputs: info.outputs,
+            enabled: info.mode != NONE,
+        })
+        .inspect(|monitor| {
+            if monitor.is_enabled() {
+                log!(
+                    Info,
+                    "CRTC {} ({}): {}x{}+{}+{}, rotation {}.",
+                    monitor.index,
+                    monitor.display_name(),
+                    monitor.width,
+                    monitor.height,
+                    monitor.x,
+                    monitor.y,
+                    monitor.rotation_name()
+                );
+            } else {
+                log!(Info, "CRTC {}: disabled.", monitor.index);
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok((monitors, outputs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(name: Option<&str>) -> Monitor {
+        Monitor {
+            index: 1,
+            crtc: NONE,
+            name: name.map(Into::into),
+            x: 1920,
+            y: 0,
+            width: 2560,
+            height: 1440,
+            rotation: Rotation::ROTATE0.into(),
+            outputs: Vec::new(),
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn expand_template() {
+        assert_eq!(
+            monitor(Some("DP-1")).expand_template("bg_{name}_{index}_{x}+{y}_{w}x{h}.png"),
+            "bg_DP-1_1_1920+0_2560x1440.png"
+        );
+        // Xinerama screens have no output name
+        assert_eq!(monitor(None).expand_template("{name}.png"), "crtc-1.png");
+    }
+}
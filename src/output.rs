@@ -0,0 +1,770 @@
+use image::{
+    pnm::{PNMSubtype, SampleEncoding},
+    DynamicImage, GenericImageView, ImageBuffer, ImageOutputFormat, Rgb,
+};
+use std::{
+    borrow::Cow,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{stdout, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[cfg(feature = "webp")]
+use crate::webp;
+use crate::{
+    base64::Base64Writer,
+    error::{Result, XbgdumpError},
+    farbfeld, gif, jpeg,
+    kitty::KittyWriter,
+    png_writer::{write_png, PngOptions},
+    qoi, sixel,
+    tiff::{self, TiffCompression},
+    xpm,
+};
+
+/// Where the final image should be written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputDest {
+    /// Standard output.
+    Stdout,
+    /// A file, atomically replaced if it exists.
+    File(PathBuf),
+}
+
+impl fmt::Display for OutputDest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputDest::Stdout => write!(f, "stdout"),
+            OutputDest::File(path) => write!(f, "'{}'", path.display()),
+        }
+    }
+}
+
+/// Encodings supported for the final image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    /// PAM, the arbitrary-channel member of the PNM family.
+    Pam,
+    Bmp,
+    /// Baseline TIFF, optionally LZW or deflate compressed.
+    Tiff,
+    Tga,
+    /// The Quite OK Image Format, losslessly compressed but much faster to encode than PNG.
+    Qoi,
+    /// The suckless image format: a tiny header and 16-bit RGBA, for piping into its tools.
+    Farbfeld,
+    /// Lossless WebP, usually smaller than PNG, or near-lossless for smaller files still.
+    #[cfg(feature = "webp")]
+    Webp,
+    /// Baseline JPEG, lossy and without transparency.
+    Jpeg,
+    /// XPM3 text, reduced to a limited number of colors.
+    Xpm,
+    /// Single-frame GIF, reduced to at most 256 colors.
+    Gif,
+    /// DEC sixel graphics, for showing the image in a terminal.
+    Sixel,
+    /// The pixels as sent by the X server, see [`RawImage`](crate::RawImage).
+    ///
+    /// Only available straight from [`grab_raw_with`](crate::grab_raw_with), there is no way
+    /// to encode a [`DynamicImage`] like this.
+    Raw,
+}
+
+impl Format {
+    /// All supported formats, in the order they are listed in help texts.
+    pub const ALL: &'static [Format] = &[
+        Format::Png,
+        Format::Pam,
+        Format::Bmp,
+        Format::Tiff,
+        Format::Tga,
+        Format::Qoi,
+        Format::Farbfeld,
+        #[cfg(feature = "webp")]
+        Format::Webp,
+        Format::Jpeg,
+        Format::Xpm,
+        Format::Gif,
+        Format::Sixel,
+        Format::Raw,
+    ];
+
+    /// Canonical name used for `--format`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Pam => "pam",
+            Format::Bmp => "bmp",
+            Format::Tiff => "tiff",
+            Format::Tga => "tga",
+            Format::Qoi => "qoi",
+            Format::Farbfeld => "ff",
+            #[cfg(feature = "webp")]
+            Format::Webp => "webp",
+            Format::Jpeg => "jpeg",
+            Format::Xpm => "xpm",
+            Format::Gif => "gif",
+            Format::Sixel => "sixel",
+            Format::Raw => "raw",
+        }
+    }
+
+    /// MIME type, e.g. for offering the image on a selection.
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Format::Png => "image/png",
+            Format::Pam => "image/x-portable-arbitrarymap",
+            Format::Bmp => "image/bmp",
+            Format::Tiff => "image/tiff",
+            Format::Tga => "image/x-tga",
+            Format::Qoi => "image/qoi",
+            Format::Farbfeld => "image/x-farbfeld",
+            #[cfg(feature = "webp")]
+            Format::Webp => "image/webp",
+            Format::Jpeg => "image/jpeg",
+            Format::Xpm => "image/x-xpixmap",
+            Format::Gif => "image/gif",
+            Format::Sixel => "image/x-sixel",
+            Format::Raw => "application/octet-stream",
+        }
+    }
+
+    /// MIME type for data URIs, only for formats that browsers can display.
+    pub fn data_uri_mime_type(self) -> Option<&'static str> {
+        match self {
+            Format::Png | Format::Bmp | Format::Jpeg | Format::Gif => Some(self.mime_type()),
+            #[cfg(feature = "webp")]
+            Format::Webp => Some(self.mime_type()),
+            Format::Pam
+            | Format::Tiff
+            | Format::Tga
+            | Format::Qoi
+            | Format::Farbfeld
+            | Format::Xpm
+            | Format::Sixel
+            | Format::Raw => None,
+        }
+    }
+
+    /// Whether the same pixels always encode to the same bytes, on every platform and as long
+    /// as the encoder settings don't change, which `--reproducible` promises.
+    pub fn is_reproducible(self) -> bool {
+        matches!(
+            self,
+            Format::Png | Format::Pam | Format::Farbfeld | Format::Qoi
+        )
+    }
+
+    /// Parses a format name as accepted by `--format`, case-insensitively.
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_ascii_lowercase().as_str() {
+            "png" => Some(Format::Png),
+            "pam" | "pnm" => Some(Format::Pam),
+            "bmp" => Some(Format::Bmp),
+            "tiff" | "tif" => Some(Format::Tiff),
+            "tga" => Some(Format::Tga),
+            "qoi" => Some(Format::Qoi),
+            "ff" | "farbfeld" => Some(Format::Farbfeld),
+            #[cfg(feature = "webp")]
+            "webp" => Some(Format::Webp),
+            "jpeg" | "jpg" => Some(Format::Jpeg),
+            "xpm" => Some(Format::Xpm),
+            "gif" => Some(Format::Gif),
+            "sixel" | "six" => Some(Format::Sixel),
+            "raw" => Some(Format::Raw),
+            _ => None,
+        }
+    }
+
+    /// Infers the format from a file extension.
+    pub fn from_path(path: &Path) -> Option<Format> {
+        Format::from_name(path.extension()?.to_str()?)
+    }
+
+    /// Comma-separated list of all format names, for help and error messages.
+    pub fn names() -> String {
+        Format::ALL
+            .iter()
+            .map(|f| f.name())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// The equivalent encoder from the image crate, if it has one.
+    fn image_format(self) -> Option<ImageOutputFormat> {
+        match self {
+            Format::Png => None,
+            Format::Pam => Some(ImageOutputFormat::Pnm(PNMSubtype::ArbitraryMap)),
+            Format::Bmp => Some(ImageOutputFormat::Bmp),
+            Format::Tga => Some(ImageOutputFormat::Tga),
+            Format::Tiff
+            | Format::Qoi
+            | Format::Farbfeld
+            | Format::Jpeg
+            | Format::Xpm
+            | Format::Gif
+            | Format::Sixel
+            | Format::Raw => None,
+            #[cfg(feature = "webp")]
+            Format::Webp => None,
+        }
+    }
+}
+
+/// Flavors of netpbm output for [`Format::Pam`], for consumers that predate PAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PnmSubtype {
+    /// PAM (P7), which keeps grayscale and alpha as they are.
+    #[default]
+    Pam,
+    /// Binary PPM (P6), always RGB.
+    Ppm,
+    /// Binary PGM (P5), always grayscale.
+    Pgm,
+    /// Plain-text PGM (P2) for grayscale images, plain-text PPM (P3) otherwise.
+    Ascii,
+}
+
+impl PnmSubtype {
+    /// All subtypes, in the order they are listed in help texts.
+    pub const ALL: &'static [PnmSubtype] = &[
+        PnmSubtype::Pam,
+        PnmSubtype::Ppm,
+        PnmSubtype::Pgm,
+        PnmSubtype::Ascii,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PnmSubtype::Pam => "pam",
+            PnmSubtype::Ppm => "ppm",
+            PnmSubtype::Pgm => "pgm",
+            PnmSubtype::Ascii => "ascii",
+        }
+    }
+}
+
+impl FromStr for PnmSubtype {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        PnmSubtype::ALL
+            .iter()
+            .copied()
+            .find(|subtype| subtype.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown PNM subtype '{}'. Expected one of {}.",
+                    s,
+                    PnmSubtype::ALL
+                        .iter()
+                        .map(|subtype| subtype.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+/// Encodes the image in the given format, wrapped in text if the options say so.
+fn encode(
+    img: &DynamicImage,
+    format: Format,
+    w: &mut impl Write,
+    options: &WriteOptions,
+) -> Result<()> {
+    match options.text {
+        None => encode_binary(img, format, w, options),
+        Some(encoding) => {
+            if encoding == TextEncoding::DataUri {
+                let mime_type = format
+                    .data_uri_mime_type()
+                    .ok_or(XbgdumpError::NoDataUriMimeType(format))?;
+                write!(w, "data:{};base64,", mime_type)?;
+            }
+            match encoding {
+                TextEncoding::Kitty(id) => {
+                    let mut base64 = Base64Writer::new(KittyWriter::new(&mut *w, id));
+                    encode_binary(img, format, &mut base64, options)?;
+                    base64.finish()?.finish()?;
+                }
+                TextEncoding::DataUri | TextEncoding::Base64 => {
+                    let mut base64 = Base64Writer::new(&mut *w);
+                    encode_binary(img, format, &mut base64, options)?;
+                    base64.finish()?;
+                }
+            }
+            if options.newline {
+                w.write_all(b"\n")?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Encodes the image in the given format, using our own encoder where image has none
+/// or doesn't expose the settings we need.
+pub(crate) fn encode_binary(
+    img: &DynamicImage,
+    format: Format,
+    w: &mut impl Write,
+    options: &WriteOptions,
+) -> Result<()> {
+    if format == Format::Pam && options.pnm_subtype != PnmSubtype::Pam {
+        let (img, subtype) = pnm_image(&narrow(img), options.pnm_subtype);
+        img.write_to(w, ImageOutputFormat::Pnm(subtype))?;
+        return Ok(());
+    }
+
+    match format.image_format() {
+        // image's PAM, BMP and TGA encoders only take 8 bits per channel
+        Some(image_format) => {
+            let img = narrow(img);
+            // image's BMP encoder writes grayscale as a palette and silently drops the alpha
+            let img = match (format, &*img, options.bmp_background) {
+                (Format::Bmp, img, Some(background)) if img.color().has_alpha() => {
+                    Cow::Owned(flatten(img, background))
+                }
+                (Format::Bmp, DynamicImage::ImageLumaA8(_), _) => {
+                    Cow::Owned(DynamicImage::ImageRgba8(img.to_rgba8()))
+                }
+                _ => img,
+            };
+            img.write_to(w, image_format)?
+        }
+        None => match format {
+            Format::Png => write_png(img, w, &options.png)?,
+            Format::Tiff => tiff::encode(img, options.tiff_compression, w)?,
+            Format::Qoi => qoi::encode(img, w)?,
+            Format::Farbfeld => farbfeld::encode(img, w)?,
+            #[cfg(feature = "webp")]
+            Format::Webp => webp::encode(img, options.webp_quality, w)?,
+            Format::Jpeg => jpeg::encode(
+                img,
+                options.jpeg_quality,
+                options.png.icc_profile.as_deref(),
+                w,
+            )?,
+            Format::Xpm => xpm::encode(img, options.xpm_colors, options.dither, w)?,
+            Format::Gif => gif::encode(img, options.gif_colors, options.dither, w)?,
+            Format::Sixel => sixel::encode(img, options.sixel_colors, options.dither, w)?,
+            Format::Raw => return Err(XbgdumpError::RawFromImage),
+            _ => unreachable!("{} has an image encoder", format.name()),
+        },
+    }
+
+    Ok(())
+}
+
+/// Converts an 8-bit image to the channels the PNM subtype holds.
+///
+/// PPM and PGM have no alpha channel, so transparent pixels are composited over black,
+/// just like for JPEG.
+fn pnm_image(img: &DynamicImage, subtype: PnmSubtype) -> (DynamicImage, PNMSubtype) {
+    let over_black = |v: u8, a: u8| ((u16::from(v) * u16::from(a) + 127) / 255) as u8;
+    let grayscale = match subtype {
+        PnmSubtype::Pgm => true,
+        PnmSubtype::Ascii => img.color().channel_count() <= 2,
+        PnmSubtype::Pam | PnmSubtype::Ppm => false,
+    };
+    let encoding = match subtype {
+        PnmSubtype::Ascii => SampleEncoding::Ascii,
+        _ => SampleEncoding::Binary,
+    };
+    let (width, height) = img.dimensions();
+
+    // Unwraps safe because the sample counts match the dimensions
+    if grayscale {
+        let samples = img
+            .to_luma_alpha8()
+            .pixels()
+            .map(|p| over_black(p[0], p[1]))
+            .collect();
+        let img = ImageBuffer::from_raw(width, height, samples).unwrap();
+        (DynamicImage::ImageLuma8(img), PNMSubtype::Graymap(encoding))
+    } else {
+        let samples = img
+            .to_rgba8()
+            .pixels()
+            .flat_map(|p| [0, 1, 2].map(|c| over_black(p[c], p[3])))
+            .collect();
+        let img = ImageBuffer::from_raw(width, height, samples).unwrap();
+        (DynamicImage::ImageRgb8(img), PNMSubtype::Pixmap(encoding))
+    }
+}
+
+/// Composites the image over a solid color, dropping the alpha channel.
+fn flatten(img: &DynamicImage, background: Rgb<u8>) -> DynamicImage {
+    log!(
+        Debug,
+        "Compositing over #{:02x}{:02x}{:02x}.",
+        background[0],
+        background[1],
+        background[2]
+    );
+    let rgba = img.to_rgba8();
+    DynamicImage::ImageRgb8(ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let p = rgba.get_pixel(x, y);
+        let a = u16::from(p[3]);
+        Rgb([0, 1, 2].map(|c| {
+            ((u16::from(p[c]) * a + u16::from(background[c]) * (255 - a) + 127) / 255) as u8
+        }))
+    }))
+}
+
+/// Converts 16-bit images to 8 bits per channel, keeping alpha.
+fn narrow(img: &DynamicImage) -> Cow<'_, DynamicImage> {
+    let img = match img {
+        DynamicImage::ImageLuma16(_) => DynamicImage::ImageLuma8(img.to_luma8()),
+        DynamicImage::ImageLumaA16(_) => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        DynamicImage::ImageRgb16(_) => DynamicImage::ImageRgb8(img.to_rgb8()),
+        DynamicImage::ImageRgba16(_) => DynamicImage::ImageRgba8(img.to_rgba8()),
+        img => return Cow::Borrowed(img),
+    };
+    log!(
+        Debug,
+        "Reducing the image to 8 bits per channel for encoding."
+    );
+
+    Cow::Owned(img)
+}
+
+/// Picks the output format: an explicit choice wins, then the file extension.
+///
+/// Stdout defaults to PAM, which is cheap to encode and understood by most image tools.
+pub fn resolve_format(explicit: Option<Format>, dest: &OutputDest) -> Result<Format> {
+    match (explicit, dest) {
+        (Some(format), _) => Ok(format),
+        (None, OutputDest::Stdout) => Ok(Format::Pam),
+        (None, OutputDest::File(path)) => match Format::from_path(path) {
+            Some(format) => Ok(format),
+            None => Err(XbgdumpError::UnknownFormat(path.clone())),
+        },
+    }
+}
+
+/// Text forms the encoded image can be wrapped in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    /// `data:image/png;base64,...`, e.g. for an HTML `<img>` tag.
+    DataUri,
+    /// Just the base64, e.g. for embedding in shell scripts or config files.
+    Base64,
+    /// kitty's graphics protocol escape sequences, to show a PNG in the terminal, optionally
+    /// under the given image ID.
+    Kitty(Option<u32>),
+}
+
+/// Knobs for how the final image is written.
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    /// Replace existing files. Doesn't affect stdout.
+    pub clobber: bool,
+    pub png: PngOptions,
+    /// Which netpbm format [`Format::Pam`] stands for.
+    pub pnm_subtype: PnmSubtype,
+    pub tiff_compression: TiffCompression,
+    /// JPEG quality from 0 to 100, where 0 is the same as 1.
+    pub jpeg_quality: u8,
+    /// Quality of near-lossless WebP from 0 to 100, or `None` for lossless.
+    pub webp_quality: Option<u8>,
+    /// Most colors in XPM output, not counting transparency.
+    pub xpm_colors: u16,
+    /// Most colors in GIF output, including transparency.
+    pub gif_colors: u16,
+    /// Most colors in sixel output.
+    pub sixel_colors: u16,
+    /// Dither XPM, GIF and sixel output when reducing its colors.
+    pub dither: bool,
+    /// Color to composite BMP output over, instead of writing an alpha channel.
+    pub bmp_background: Option<Rgb<u8>>,
+    /// Write the encoded image as text instead of binary.
+    pub text: Option<TextEncoding>,
+    /// End text output with a newline.
+    pub newline: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            clobber: true,
+            png: PngOptions::default(),
+            pnm_subtype: PnmSubtype::default(),
+            tiff_compression: TiffCompression::default(),
+            jpeg_quality: jpeg::DEFAULT_QUALITY,
+            webp_quality: None,
+            xpm_colors: xpm::DEFAULT_COLORS,
+            gif_colors: gif::DEFAULT_COLORS,
+            sixel_colors: sixel::DEFAULT_COLORS,
+            dither: false,
+            bmp_background: None,
+            text: None,
+            newline: false,
+        }
+    }
+}
+
+/// Encodes the image and sends it to the given destination.
+pub fn write_image(img: &DynamicImage, dest: &OutputDest, format: Format) -> Result<()> {
+    write_image_with(img, dest, format, &WriteOptions::default())
+}
+
+/// Like [`write_image`], but with explicit [`WriteOptions`].
+pub fn write_image_with(
+    img: &DynamicImage,
+    dest: &OutputDest,
+    format: Format,
+    options: &WriteOptions,
+) -> Result<()> {
+    write_to(dest, options, |mut w| encode(img, format, &mut w, options))
+}
+
+/// Encodes the image into memory, e.g. to send the same data to several destinations.
+pub fn encode_image(img: &DynamicImage, format: Format, options: &WriteOptions) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    encode(img, format, &mut data, options)?;
+
+    Ok(data)
+}
+
+/// Sends already encoded data to the given destination, like [`write_image_with`] would.
+pub fn write_encoded(data: &[u8], dest: &OutputDest, options: &WriteOptions) -> Result<()> {
+    write_to(dest, options, |w| Ok(w.write_all(data)?))
+}
+
+fn write_to(
+    dest: &OutputDest,
+    options: &WriteOptions,
+    write: impl Fn(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+    match dest {
+        OutputDest::Stdout => {
+            let stdout = stdout();
+            let mut lock = stdout.lock();
+            write(&mut lock)?;
+            Ok(lock.flush()?)
+        }
+        OutputDest::File(path) if options.clobber => write_atomically(path, |writer| write(writer)),
+        OutputDest::File(path) => {
+            // Claiming the name with O_EXCL up front closes the race between checking and writing;
+            // the atomic write then replaces our own empty placeholder
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .map_err(|e| match e.kind() {
+                    ErrorKind::AlreadyExists => XbgdumpError::OutputExists(path.clone()),
+                    _ => e.into(),
+                })?;
+
+            let result = write_atomically(path, |writer| write(writer));
+            if result.is_err() {
+                let _ = fs::remove_file(path);
+            }
+
+            result
+        }
+    }
+}
+
+/// Writes a file via a temporary file next to it, so readers never observe a partial image.
+///
+/// The temporary file lives in the same directory to guarantee the rename is atomic,
+/// and is removed again if anything goes wrong.
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = (|| {
+        let file = File::create(&tmp_path)?;
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        // Make sure the data hits the disk before the rename does,
+        // otherwise a crash could leave an empty file under the final name
+        file.sync_all()?;
+        drop(file);
+
+        Ok(fs::rename(&tmp_path, path)?)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png_writer::{PngCompression, PngFilter};
+
+    /// A fresh directory per test, so parallel tests don't see each other's files.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("xbgdump-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn write_atomically_replaces_target() {
+        let dir = test_dir("atomic-ok");
+        let path = dir.join("bg.png");
+        fs::write(&path, "old").unwrap();
+
+        write_atomically(&path, |w| Ok(w.write_all(b"new")?)).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert!(!dir.join("bg.png.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_atomically_keeps_target_on_failure() {
+        let dir = test_dir("atomic-fail");
+        let path = dir.join("bg.png");
+        fs::write(&path, "old").unwrap();
+
+        let result = write_atomically(&path, |w| {
+            w.write_all(b"partial")?;
+            w.flush()?;
+            Err(XbgdumpError::IoError(ErrorKind::Other.into()))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert!(!dir.join("bg.png.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A small image with every channel varying, so swapped channels or rows show up.
+    fn sample_rgba() -> DynamicImage {
+        DynamicImage::ImageRgba8(ImageBuffer::from_fn(5, 3, |x, y| {
+            image::Rgba([x as u8 * 50, y as u8 * 100, 7, 255 - (x + y) as u8 * 40])
+        }))
+    }
+
+    fn decode(data: &[u8], format: image::ImageFormat) -> DynamicImage {
+        image::load_from_memory_with_format(data, format).unwrap()
+    }
+
+    #[test]
+    fn bmp_and_tga_decode_back() {
+        let rgba = sample_rgba();
+        let rgb = DynamicImage::ImageRgb8(rgba.to_rgb8());
+        let options = WriteOptions::default();
+        for &(format, image_format) in &[
+            (Format::Bmp, image::ImageFormat::Bmp),
+            (Format::Tga, image::ImageFormat::Tga),
+        ] {
+            let decoded = decode(&encode_image(&rgb, format, &options).unwrap(), image_format);
+            assert_eq!(decoded.to_rgb8(), rgb.to_rgb8(), "{} RGB", format.name());
+            assert!(!decoded.color().has_alpha());
+
+            // Both keep alpha as it is
+            let decoded = decode(
+                &encode_image(&rgba, format, &options).unwrap(),
+                image_format,
+            );
+            assert_eq!(
+                decoded.to_rgba8(),
+                rgba.to_rgba8(),
+                "{} RGBA",
+                format.name()
+            );
+        }
+    }
+
+    #[test]
+    fn bmp_narrows_16_bit_images() {
+        let rgba16 = DynamicImage::ImageRgba16(sample_rgba().to_rgba16());
+        let data = encode_image(&rgba16, Format::Bmp, &WriteOptions::default()).unwrap();
+        assert_eq!(
+            decode(&data, image::ImageFormat::Bmp).to_rgba8(),
+            sample_rgba().to_rgba8()
+        );
+    }
+
+    #[test]
+    fn bmp_background_flattens_alpha() {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(3, 1, |x, _| {
+            image::Rgba([200, 100, 0, [255, 0, 128][x as usize]])
+        }));
+        let options = WriteOptions {
+            bmp_background: Some(Rgb([0, 0, 255])),
+            ..WriteOptions::default()
+        };
+        let decoded = decode(
+            &encode_image(&img, Format::Bmp, &options).unwrap(),
+            image::ImageFormat::Bmp,
+        );
+        assert!(!decoded.color().has_alpha());
+        let pixels = decoded.to_rgb8().pixels().map(|p| p.0).collect::<Vec<_>>();
+        assert_eq!(pixels, [[200, 100, 0], [0, 0, 255], [100, 50, 127]]);
+    }
+
+    #[test]
+    fn reproducible_formats_encode_identically() {
+        // What --reproducible pins the PNG settings to
+        let options = WriteOptions {
+            png: PngOptions {
+                compression: PngCompression::Fast,
+                filter: PngFilter::Sub,
+                ..PngOptions::default()
+            },
+            ..WriteOptions::default()
+        };
+        let img = || {
+            DynamicImage::ImageRgba8(ImageBuffer::from_fn(40, 30, |x, y| {
+                image::Rgba([
+                    (x * 6) as u8,
+                    (y * 8) as u8,
+                    (x ^ y) as u8,
+                    200 + (x % 50) as u8,
+                ])
+            }))
+        };
+        // Pinned as well, so changes between builds show up, not just within one
+        let digests = [
+            (
+                Format::Png,
+                "2c5a5303caa5e4e9a3a04a92ee8f6369b802c2209c9126300d7abe1ad3d01a69",
+            ),
+            (
+                Format::Pam,
+                "1847eab2304a77f8cde5559c59e1f5930c6d2a572b9ad785f5d9e6b95d4d1b25",
+            ),
+            (
+                Format::Qoi,
+                "8af89902011dbb120555c79cc3a1328b4c711673827ccf3e2a1db2baada81a21",
+            ),
+            (
+                Format::Farbfeld,
+                "21340215fdd3ef5ab538fa86386d67967dba24ad7ef7bfb2498a994af64e1086",
+            ),
+        ];
+        let reproducible = Format::ALL.iter().filter(|f| f.is_reproducible());
+        assert_eq!(reproducible.count(), digests.len());
+        for &(format, digest) in &digests {
+            let first = encode_image(&img(), format, &options).unwrap();
+            let second = encode_image(&img(), format, &options).unwrap();
+            assert!(first == second, "{} output differs", format.name());
+            let hex = crate::sha256::sha256(&first)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            assert_eq!(hex, digest, "{} output changed", format.name());
+        }
+    }
+}
@@ -0,0 +1,501 @@
+//! PNG encoding through the png crate directly, since image doesn't expose all of its knobs.
+
+use image::{DynamicImage, GenericImageView};
+use png::{BitDepth, ColorType, Compression, Encoder, FilterType};
+use std::{
+    borrow::Cow,
+    fs,
+    io::Write,
+    path::Path,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{Result, XbgdumpError},
+    icc::is_icc_profile,
+};
+
+/// How hard the deflate compressor tries, trading speed for size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PngCompression {
+    #[default]
+    Fast,
+    Default,
+    Best,
+}
+
+impl PngCompression {
+    /// All levels, in the order they are listed in help texts.
+    pub const ALL: &'static [PngCompression] = &[
+        PngCompression::Fast,
+        PngCompression::Default,
+        PngCompression::Best,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PngCompression::Fast => "fast",
+            PngCompression::Default => "default",
+            PngCompression::Best => "best",
+        }
+    }
+
+    fn compression(self) -> Compression {
+        match self {
+            PngCompression::Fast => Compression::Fast,
+            PngCompression::Default => Compression::Default,
+            PngCompression::Best => Compression::Best,
+        }
+    }
+}
+
+impl FromStr for PngCompression {
+    type Err = String;
+
+    /// Accepts the level names as well as zlib-style levels from 1 to 9,
+    /// which are mapped onto the three efforts the encoder supports.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(level) = s.parse::<u8>() {
+            return match level {
+                0 => Err("Uncompressed PNGs are not supported, 1 is the fastest level.".into()),
+                1..=3 => Ok(PngCompression::Fast),
+                4..=6 => Ok(PngCompression::Default),
+                7..=9 => Ok(PngCompression::Best),
+                _ => Err(format!("PNG compression level {} is out of range 1-9.", s)),
+            };
+        }
+
+        PngCompression::ALL
+            .iter()
+            .copied()
+            .find(|c| c.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown PNG compression '{}'. Expected one of {}.",
+                    s,
+                    names(PngCompression::ALL.iter().map(|c| c.name()))
+                )
+            })
+    }
+}
+
+/// Scanline filter applied before compression.
+///
+/// The png crate applies the same filter to every row; there is no adaptive per-row selection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PngFilter {
+    None,
+    #[default]
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+}
+
+impl PngFilter {
+    /// All filters, in the order they are listed in help texts.
+    pub const ALL: &'static [PngFilter] = &[
+        PngFilter::None,
+        PngFilter::Sub,
+        PngFilter::Up,
+        PngFilter::Avg,
+        PngFilter::Paeth,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PngFilter::None => "none",
+            PngFilter::Sub => "sub",
+            PngFilter::Up => "up",
+            PngFilter::Avg => "avg",
+            PngFilter::Paeth => "paeth",
+        }
+    }
+
+    fn filter_type(self) -> FilterType {
+        match self {
+            PngFilter::None => FilterType::NoFilter,
+            PngFilter::Sub => FilterType::Sub,
+            PngFilter::Up => FilterType::Up,
+            PngFilter::Avg => FilterType::Avg,
+            PngFilter::Paeth => FilterType::Paeth,
+        }
+    }
+}
+
+impl FromStr for PngFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PngFilter::ALL
+            .iter()
+            .copied()
+            .find(|f| f.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown PNG filter '{}'. Expected one of {}.",
+                    s,
+                    names(PngFilter::ALL.iter().map(|f| f.name()))
+                )
+            })
+    }
+}
+
+fn names<'a>(names: impl Iterator<Item = &'a str>) -> String {
+    names.collect::<Vec<_>>().join(", ")
+}
+
+/// Which color space PNG output is tagged with, so viewers agree on how to show it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PngColorTag {
+    /// `sRGB`, with the matching `gAMA` and `cHRM` for viewers that don't know it, since that's
+    /// what X backgrounds effectively are.
+    #[default]
+    Srgb,
+    /// `gAMA` for a display with the given gamma, e.g. 2.2, with sRGB primaries in `cHRM`.
+    Gamma(f32),
+    /// No color space information; viewers pick whatever they like.
+    None,
+}
+
+/// Encoder settings for PNG output.
+///
+/// The defaults match what image's own PNG encoder does, except for the sRGB tag.
+#[derive(Debug, Clone, Default)]
+pub struct PngOptions {
+    pub compression: PngCompression,
+    pub filter: PngFilter,
+    /// Not written when there's an ICC profile, which takes precedence anyway.
+    pub color_tag: PngColorTag,
+    /// If set, recorded as `Creation Time` in a `tEXt` chunk, along with a `Software` chunk
+    /// naming the xbgdump version.
+    pub creation_time: Option<SystemTime>,
+    /// More text chunks to write after those, as keyword and text. Text that isn't plain ASCII
+    /// goes into an `iTXt` chunk, since `tEXt` is Latin-1.
+    pub text: Vec<(&'static str, String)>,
+    /// ICC profile to embed in an `iCCP` chunk. This only tags the pixels, they are not
+    /// converted in any way. JPEG output embeds it too, in `APP2` segments.
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+/// Reads an ICC profile from a file, checking that it at least looks like one.
+pub fn read_icc_profile(path: &Path) -> Result<Vec<u8>> {
+    let profile = fs::read(path)?;
+    if !is_icc_profile(&profile) {
+        return Err(XbgdumpError::InvalidIccProfile(path.into()));
+    }
+
+    Ok(profile)
+}
+
+/// The `gAMA` that the `sRGB` chunk implies, 1/2.2 times 100000.
+const SRGB_GAMMA: u32 = 45455;
+/// The `cHRM` that the `sRGB` chunk implies, the white point then red, green and blue as x and y
+/// times 100000.
+const SRGB_CHROMATICITIES: [u32; 8] = [31270, 32900, 64000, 33000, 30000, 60000, 15000, 6000];
+
+/// Encodes the image as PNG with the given settings.
+pub(crate) fn write_png(img: &DynamicImage, w: impl Write, options: &PngOptions) -> Result<()> {
+    let (color, depth, data) = match img {
+        DynamicImage::ImageLuma8(img) => (
+            ColorType::Grayscale,
+            BitDepth::Eight,
+            Cow::Borrowed(img.as_raw().as_slice()),
+        ),
+        DynamicImage::ImageLumaA8(img) => (
+            ColorType::GrayscaleAlpha,
+            BitDepth::Eight,
+            Cow::Borrowed(img.as_raw().as_slice()),
+        ),
+        DynamicImage::ImageRgb8(img) => (
+            ColorType::RGB,
+            BitDepth::Eight,
+            Cow::Borrowed(img.as_raw().as_slice()),
+        ),
+        DynamicImage::ImageRgba8(img) => (
+            ColorType::RGBA,
+            BitDepth::Eight,
+            Cow::Borrowed(img.as_raw().as_slice()),
+        ),
+        DynamicImage::ImageLuma16(img) => (
+            ColorType::Grayscale,
+            BitDepth::Sixteen,
+            Cow::Owned(be_bytes(img)),
+        ),
+        DynamicImage::ImageLumaA16(img) => (
+            ColorType::GrayscaleAlpha,
+            BitDepth::Sixteen,
+            Cow::Owned(be_bytes(img)),
+        ),
+        DynamicImage::ImageRgb16(img) => {
+            (ColorType::RGB, BitDepth::Sixteen, Cow::Owned(be_bytes(img)))
+        }
+        DynamicImage::ImageRgba16(img) => (
+            ColorType::RGBA,
+            BitDepth::Sixteen,
+            Cow::Owned(be_bytes(img)),
+        ),
+        // PNG has no BGR(A); these don't come out of the conversion anyway
+        img => (
+            ColorType::RGBA,
+            BitDepth::Eight,
+            Cow::Owned(img.to_rgba8().into_raw()),
+        ),
+    };
+
+    let mut encoder = Encoder::new(w, img.width(), img.height());
+    encoder.set_color(color);
+    encoder.set_depth(depth);
+    encoder.set_compression(options.compression.compression());
+    encoder.set_filter(options.filter.filter_type());
+
+    let mut writer = encoder.write_header().map_err(std::io::Error::from)?;
+    if let Some(profile) = &options.icc_profile {
+        // The profile name is only informational; compression method 0 is zlib, the only one
+        let chunk = [
+            &b"ICC profile\0\0"[..],
+            &deflate::deflate_bytes_zlib(profile),
+        ]
+        .concat();
+        writer
+            .write_chunk(*b"iCCP", &chunk)
+            .map_err(std::io::Error::from)?;
+    } else {
+        let gamma = match options.color_tag {
+            PngColorTag::Srgb => {
+                // Perceptual rendering intent
+                writer
+                    .write_chunk(*b"sRGB", &[0])
+                    .map_err(std::io::Error::from)?;
+                Some(SRGB_GAMMA)
+            }
+            PngColorTag::Gamma(gamma) => Some((100_000.0 / gamma).round() as u32),
+            PngColorTag::None => None,
+        };
+        if let Some(gamma) = gamma {
+            writer
+                .write_chunk(*b"gAMA", &gamma.to_be_bytes())
+                .map_err(std::io::Error::from)?;
+            let chromaticities = SRGB_CHROMATICITIES
+                .iter()
+                .flat_map(|v| v.to_be_bytes())
+                .collect::<Vec<_>>();
+            writer
+                .write_chunk(*b"cHRM", &chromaticities)
+                .map_err(std::io::Error::from)?;
+        }
+    }
+    let mut text = Vec::new();
+    if let Some(time) = options.creation_time {
+        let software = format!("xbgdump {}", env!("CARGO_PKG_VERSION"));
+        text.extend([("Creation Time", rfc3339(time)), ("Software", software)]);
+    }
+    text.extend(options.text.iter().cloned());
+    for (keyword, text) in text {
+        // Keyword and text are separated by a NUL byte; iTXt adds the compression flag and
+        // method, then an empty language tag and translated keyword
+        let (kind, separator) = if text.is_ascii() {
+            (*b"tEXt", &b"\0"[..])
+        } else {
+            (*b"iTXt", &b"\0\0\0\0\0"[..])
+        };
+        let chunk = [keyword.as_bytes(), separator, text.as_bytes()].concat();
+        writer
+            .write_chunk(kind, &chunk)
+            .map_err(std::io::Error::from)?;
+    }
+    writer
+        .write_image_data(&data)
+        .map_err(std::io::Error::from)?;
+
+    Ok(())
+}
+
+/// Formats the time in UTC, e.g. `2021-05-04T13:37:00Z`.
+fn rfc3339(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// PNG stores 16-bit samples in big endian, image keeps them in native order.
+fn be_bytes(samples: &[u16]) -> Vec<u8> {
+    samples.iter().flat_map(|s| s.to_be_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb, Rgba};
+    use std::{convert::TryInto, time::Duration};
+
+    /// The chunks up to the first `IDAT`, as type and data, checking their CRCs on the way.
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        let mut chunks = Vec::new();
+        let mut pos = 8;
+        loop {
+            let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+            let kind: [u8; 4] = png[pos + 4..pos + 8].try_into().unwrap();
+            let crc = u32::from_be_bytes(png[pos + 8 + len..pos + 12 + len].try_into().unwrap());
+            assert_eq!(
+                crc,
+                crc32(&png[pos + 4..pos + 8 + len]),
+                "CRC of {:?}",
+                kind
+            );
+            if &kind == b"IDAT" {
+                return chunks;
+            }
+            chunks.push((kind, png[pos + 8..pos + 8 + len].to_vec()));
+            pos += 12 + len;
+        }
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        !data.iter().fold(!0, |crc, &byte| {
+            (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+                (crc >> 1) ^ (0xedb8_8320 & (!(crc & 1)).wrapping_add(1))
+            })
+        })
+    }
+
+    fn chunk<'a>(chunks: &'a [([u8; 4], Vec<u8>)], kind: &[u8; 4]) -> Option<&'a [u8]> {
+        chunks
+            .iter()
+            .find(|(k, _)| k == kind)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    fn encoded(img: &DynamicImage, options: &PngOptions) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_png(img, &mut out, options).unwrap();
+        out
+    }
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(256, 64, |x, y| {
+            Rgb([x as u8, (y * 4) as u8, (x + y) as u8])
+        }))
+    }
+
+    #[test]
+    fn fast_output_decodes() {
+        let rgba16 = DynamicImage::ImageRgba16(ImageBuffer::from_fn(9, 5, |x, y| {
+            Rgba([x as u16 * 7000, y as u16, 0xffff, 0x8001])
+        }));
+        for img in &[gradient(), rgba16] {
+            let png = encoded(img, &PngOptions::default());
+            let decoded = image::load_from_memory(&png).unwrap();
+            assert_eq!(decoded.color(), img.color());
+            assert_eq!(decoded.to_bytes(), img.to_bytes());
+        }
+    }
+
+    #[test]
+    fn best_is_not_larger_than_fast() {
+        let size = |compression| {
+            let options = PngOptions {
+                compression,
+                ..PngOptions::default()
+            };
+            encoded(&gradient(), &options).len()
+        };
+        assert!(size(PngCompression::Best) <= size(PngCompression::Fast));
+    }
+
+    #[test]
+    fn color_tags() {
+        let with_tag = |color_tag| {
+            let options = PngOptions {
+                color_tag,
+                ..PngOptions::default()
+            };
+            chunks(&encoded(&gradient(), &options))
+        };
+        let chromaticities = SRGB_CHROMATICITIES
+            .iter()
+            .flat_map(|v| v.to_be_bytes())
+            .collect::<Vec<_>>();
+
+        let srgb = with_tag(PngColorTag::Srgb);
+        assert_eq!(chunk(&srgb, b"sRGB"), Some(&[0][..]));
+        assert_eq!(chunk(&srgb, b"gAMA"), Some(&45455u32.to_be_bytes()[..]));
+        assert_eq!(chunk(&srgb, b"cHRM"), Some(&chromaticities[..]));
+
+        let gamma = with_tag(PngColorTag::Gamma(1.8));
+        assert_eq!(chunk(&gamma, b"sRGB"), None);
+        assert_eq!(chunk(&gamma, b"gAMA"), Some(&55556u32.to_be_bytes()[..]));
+        assert_eq!(chunk(&gamma, b"cHRM"), Some(&chromaticities[..]));
+
+        let none = with_tag(PngColorTag::None);
+        for kind in &[b"sRGB", b"gAMA", b"cHRM"] {
+            assert_eq!(chunk(&none, kind), None);
+        }
+    }
+
+    #[test]
+    fn icc_profile_replaces_color_tags() {
+        let profile = (0..=255).cycle().take(1000).collect::<Vec<u8>>();
+        let options = PngOptions {
+            icc_profile: Some(profile.clone()),
+            ..PngOptions::default()
+        };
+        let chunks = chunks(&encoded(&gradient(), &options));
+        let iccp = chunk(&chunks, b"iCCP").unwrap();
+        let (name, rest) = iccp.split_at(iccp.iter().position(|&b| b == 0).unwrap());
+        assert_eq!(name, b"ICC profile");
+        // Separator and compression method
+        assert_eq!(&rest[..2], [0, 0]);
+        assert_eq!(
+            miniz_oxide::inflate::decompress_to_vec_zlib(&rest[2..]).unwrap(),
+            profile
+        );
+        assert_eq!(chunk(&chunks, b"sRGB"), None);
+        assert_eq!(chunk(&chunks, b"gAMA"), None);
+    }
+
+    #[test]
+    fn text_chunks() {
+        let options = PngOptions {
+            creation_time: Some(UNIX_EPOCH + Duration::from_secs(1_620_135_420)),
+            text: vec![("Title", "Wallpaper".into()), ("Comment", "Café".into())],
+            ..PngOptions::default()
+        };
+        let texts = chunks(&encoded(&gradient(), &options))
+            .into_iter()
+            .filter(|(kind, _)| kind == b"tEXt" || kind == b"iTXt")
+            .collect::<Vec<_>>();
+        let software = format!("Software\0xbgdump {}", env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            texts,
+            [
+                (*b"tEXt", b"Creation Time\x002021-05-04T13:37:00Z".to_vec()),
+                (*b"tEXt", software.into_bytes()),
+                (*b"tEXt", b"Title\0Wallpaper".to_vec()),
+                (*b"iTXt", "Comment\0\0\0\0\0Café".as_bytes().to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Minimal encoder for the [Quite OK Image Format](https://qoiformat.org/qoi-specification.pdf).
+//!
+//! The image crate doesn't support QOI yet, and the format is simple enough to not warrant
+//! another dependency.
+
+use image::{DynamicImage, GenericImageView};
+use std::io::{self, Write};
+
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_RUN: u8 = 0xc0;
+const OP_RGB: u8 = 0xfe;
+const OP_RGBA: u8 = 0xff;
+const END_MARKER: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+const MAX_RUN: u8 = 62;
+
+fn hash([r, g, b, a]: [u8; 4]) -> usize {
+    (usize::from(r) * 3 + usize::from(g) * 5 + usize::from(b) * 7 + usize::from(a) * 11) % 64
+}
+
+/// Encodes the image as QOI, keeping an alpha channel only if the image has one.
+pub fn encode(img: &DynamicImage, w: &mut impl Write) -> io::Result<()> {
+    let (width, height) = img.dimensions();
+    let has_alpha = img.color().has_alpha();
+    let channels: u8 = if has_alpha { 4 } else { 3 };
+
+    let mut header = Vec::with_capacity(14);
+    header.extend_from_slice(b"qoif");
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // sRGB with linear alpha
+    header.extend_from_slice(&[channels, 0]);
+    w.write_all(&header)?;
+
+    let raw = if has_alpha {
+        img.to_rgba8().into_raw()
+    } else {
+        img.to_rgb8().into_raw()
+    };
+    let pixels = raw.chunks_exact(channels.into()).map(|p| match *p {
+        [r, g, b, a] => [r, g, b, a],
+        [r, g, b] => [r, g, b, 255],
+        _ => unreachable!("chunks have exactly 3 or 4 elements"),
+    });
+
+    let mut out = Vec::new();
+    let mut index = [[0u8; 4]; 64];
+    let mut prev = [0, 0, 0, 255];
+    let mut run = 0u8;
+
+    for px in pixels {
+        if px == prev {
+            run += 1;
+            if run == MAX_RUN {
+                out.push(OP_RUN | (run - 1));
+                run = 0;
+            }
+            continue;
+        }
+
+        if run > 0 {
+            out.push(OP_RUN | (run - 1));
+            run = 0;
+        }
+
+        let slot = hash(px);
+        if index[slot] == px {
+            // Truncation safe because slot < 64
+            out.push(OP_INDEX | slot as u8);
+        } else {
+            index[slot] = px;
+
+            if px[3] == prev[3] {
+                // Differences wrap around, so they're computed on the bytes reinterpreted as i8
+                let diff = |i: usize| px[i].wrapping_sub(prev[i]) as i8;
+                let (dr, dg, db) = (diff(0), diff(1), diff(2));
+                let (dr_dg, db_dg) = (dr.wrapping_sub(dg), db.wrapping_sub(dg));
+
+                if (-2..=1).contains(&dr) && (-2..=1).contains(&dg) && (-2..=1).contains(&db) {
+                    out.push(
+                        OP_DIFF | ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2 | (db + 2) as u8,
+                    );
+                } else if (-32..=31).contains(&dg)
+                    && (-8..=7).contains(&dr_dg)
+                    && (-8..=7).contains(&db_dg)
+                {
+                    out.push(OP_LUMA | (dg + 32) as u8);
+                    out.push(((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8);
+                } else {
+                    out.extend_from_slice(&[OP_RGB, px[0], px[1], px[2]]);
+                }
+            } else {
+                out.extend_from_slice(&[OP_RGBA, px[0], px[1], px[2], px[3]]);
+            }
+        }
+
+        prev = px;
+
+        // Keep the buffer bounded for huge images
+        if out.len() >= 64 * 1024 {
+            w.write_all(&out)?;
+            out.clear();
+        }
+    }
+
+    if run > 0 {
+        out.push(OP_RUN | (run - 1));
+    }
+    out.extend_from_slice(&END_MARKER);
+
+    w.write_all(&out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb, Rgba};
+
+    fn encoded(img: DynamicImage) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode(&img, &mut out).unwrap();
+        out
+    }
+
+    fn rgb(pixels: &[[u8; 3]]) -> DynamicImage {
+        let raw = pixels.concat();
+        DynamicImage::ImageRgb8(ImageBuffer::from_raw(pixels.len() as u32, 1, raw).unwrap())
+    }
+
+    /// Header of a one pixel high image, followed by the chunks and the end marker.
+    fn expected(width: u32, channels: u8, chunks: &[u8]) -> Vec<u8> {
+        let mut out = b"qoif".to_vec();
+        out.extend_from_slice(&width.to_be_bytes());
+        out.extend_from_slice(&1u32.to_be_bytes());
+        out.extend_from_slice(&[channels, 0]);
+        out.extend_from_slice(chunks);
+        out.extend_from_slice(&END_MARKER);
+        out
+    }
+
+    #[test]
+    fn every_rgb_op() {
+        let img = rgb(&[
+            [10, 20, 30],
+            [11, 21, 29],
+            [21, 31, 39],
+            [10, 20, 30],
+            [10, 20, 30],
+            [10, 20, 30],
+        ]);
+        let chunks = [
+            OP_RGB, 10, 20, 30,   // too far from the initial black
+            0x7d, // OP_DIFF +1 +1 -1
+            0xaa, 0x88, // OP_LUMA +10 with red and blue following green
+            0x09, // OP_INDEX of the first pixel
+            0xc1, // OP_RUN of 2
+        ];
+        assert_eq!(encoded(img), expected(6, 3, &chunks));
+    }
+
+    #[test]
+    fn alpha() {
+        let img = DynamicImage::ImageRgba8(
+            ImageBuffer::from_raw(2, 1, vec![0, 0, 0, 0, 255, 0, 0, 128]).unwrap(),
+        );
+        // The index starts out all zero, so transparent black is found there right away
+        let chunks = [OP_INDEX, OP_RGBA, 255, 0, 0, 128];
+        assert_eq!(encoded(img), expected(2, 4, &chunks));
+    }
+
+    #[test]
+    fn long_runs_are_split() {
+        // Matches the initial previous pixel, so the whole row is one long run
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(64, 1, Rgb([0, 0, 0])));
+        assert_eq!(encoded(img), expected(64, 3, &[OP_RUN | 61, OP_RUN | 1]));
+    }
+
+    #[test]
+    fn opaque_alpha_is_kept() {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(1, 1, Rgba([0, 0, 0, 255])));
+        assert_eq!(encoded(img), expected(1, 4, &[OP_RUN]));
+    }
+}
@@ -0,0 +1,221 @@
+//! Color reduction for the palette formats, XPM, GIF and sixel.
+//!
+//! True color images are reduced with median cut: the colors are split into boxes along their
+//! widest channel until there are enough boxes, and every box becomes the average of its colors.
+//! Optionally, the rounding error is spread to the neighboring pixels with Floyd-Steinberg
+//! dithering, which trades banding in gradients for noise.
+
+use image::RgbaImage;
+use std::{cmp::Reverse, collections::HashMap};
+
+/// An image reduced to a palette.
+pub(crate) struct Indexed {
+    pub(crate) palette: Vec<[u8; 3]>,
+    /// The palette index of every pixel, row by row, or `None` where it's transparent.
+    pub(crate) pixels: Vec<Option<u16>>,
+}
+
+impl Indexed {
+    pub(crate) fn has_transparency(&self) -> bool {
+        self.pixels.iter().any(Option::is_none)
+    }
+}
+
+/// Reduces the image to at most `max_colors` colors; pixels less opaque than `alpha_threshold`
+/// become transparent, the rest fully opaque.
+///
+/// Images that already have few enough colors keep them exactly, and aren't dithered.
+pub(crate) fn reduce(
+    rgba: &RgbaImage,
+    max_colors: usize,
+    alpha_threshold: u8,
+    dither: bool,
+) -> Indexed {
+    let mut histogram = HashMap::new();
+    for p in rgba.pixels() {
+        if p[3] >= alpha_threshold {
+            *histogram.entry([p[0], p[1], p[2]]).or_insert(0u32) += 1;
+        }
+    }
+
+    let reduced = histogram.len() > max_colors;
+    log!(
+        Debug,
+        "Reducing {} colors to at most {}.",
+        histogram.len(),
+        max_colors
+    );
+    let (palette, index) = median_cut(histogram, max_colors.max(1));
+    let pixels = if dither && reduced {
+        floyd_steinberg(rgba, &palette, alpha_threshold)
+    } else {
+        rgba.pixels()
+            .map(|p| (p[3] >= alpha_threshold).then(|| index[&[p[0], p[1], p[2]]] as u16))
+            .collect()
+    };
+
+    Indexed { palette, pixels }
+}
+
+/// Reduces the colors to at most `max_colors`, returning the palette and the palette index
+/// for every color that occurs.
+pub(crate) fn median_cut(
+    histogram: HashMap<[u8; 3], u32>,
+    max_colors: usize,
+) -> (Vec<[u8; 3]>, HashMap<[u8; 3], usize>) {
+    let mut colors = histogram.into_iter().collect::<Vec<_>>();
+    // Same output for the same image, whatever order the hash map had
+    colors.sort_unstable();
+    if colors.len() <= max_colors {
+        let index = colors
+            .iter()
+            .enumerate()
+            .map(|(i, &(color, _))| (color, i))
+            .collect();
+        return (colors.into_iter().map(|(color, _)| color).collect(), index);
+    }
+
+    // Boxes as ranges of `colors`, which are sorted within a box whenever it's split,
+    // along with their widest channel and its extent
+    let mut boxes = vec![(0..colors.len(), widest_channel(&colors))];
+    while boxes.len() < max_colors {
+        // Split the box spanning the largest range of any channel
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .max_by_key(|&(i, (_, (_, extent)))| (*extent, Reverse(i)));
+        let (i, channel) = match widest {
+            Some((i, (_, (channel, extent)))) if *extent > 0 => (i, *channel),
+            // Only single colors left
+            _ => break,
+        };
+
+        let range = boxes[i].0.clone();
+        let slice = &mut colors[range.clone()];
+        slice.sort_unstable_by_key(|&(color, _)| color[channel]);
+        // Split where half of the pixels are on either side, but keep both boxes non-empty
+        let total = slice
+            .iter()
+            .map(|&(_, count)| u64::from(count))
+            .sum::<u64>();
+        let mut seen = 0;
+        let mut split = 1;
+        for (j, &(_, count)) in slice.iter().enumerate() {
+            seen += u64::from(count);
+            if seen * 2 >= total {
+                split = j + 1;
+                break;
+            }
+        }
+        let split = range.start + split.clamp(1, slice.len() - 1);
+        let (low, high) = (range.start..split, split..range.end);
+        boxes[i] = (low.clone(), widest_channel(&colors[low]));
+        boxes.push((high.clone(), widest_channel(&colors[high])));
+    }
+
+    let mut palette = Vec::with_capacity(boxes.len());
+    let mut index = HashMap::with_capacity(colors.len());
+    for (i, (range, _)) in boxes.into_iter().enumerate() {
+        let mut sums = [0u64; 3];
+        let mut total = 0u64;
+        for &(color, count) in &colors[range] {
+            for (sum, &v) in sums.iter_mut().zip(&color) {
+                *sum += u64::from(v) * u64::from(count);
+            }
+            total += u64::from(count);
+            index.insert(color, i);
+        }
+        palette.push(sums.map(|sum| ((sum + total / 2) / total) as u8));
+    }
+
+    (palette, index)
+}
+
+/// The channel whose values are spread the furthest, and by how much.
+fn widest_channel(colors: &[([u8; 3], u32)]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let values = colors.iter().map(|(color, _)| color[channel]);
+            let extent = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+            (channel, extent)
+        })
+        .max_by_key(|&(channel, extent)| (extent, Reverse(channel)))
+        .unwrap_or((0, 0))
+}
+
+/// Maps every pixel to a palette color, spreading the difference to the pixels right and below.
+///
+/// Transparent pixels neither take nor pass on any error.
+fn floyd_steinberg(rgba: &RgbaImage, palette: &[[u8; 3]], alpha_threshold: u8) -> Vec<Option<u16>> {
+    let width = rgba.width() as usize;
+    let mut nearest = Nearest::new(palette);
+    let mut pixels = Vec::with_capacity(rgba.pixels().len());
+    // Errors times 16 for this row and the next, with a column of padding on either side
+    let mut errors = vec![[0i32; 3]; width + 2];
+    let mut next = errors.clone();
+    for row in rgba.rows() {
+        for (x, p) in row.enumerate() {
+            if p[3] < alpha_threshold {
+                pixels.push(None);
+                continue;
+            }
+
+            let error = errors[x + 1];
+            let color = [0, 1, 2].map(|c| (i32::from(p[c]) + error[c] / 16).clamp(0, 255) as u8);
+            let i = nearest.find(color);
+            pixels.push(Some(i));
+            for c in 0..3 {
+                let diff = i32::from(color[c]) - i32::from(palette[usize::from(i)][c]);
+                errors[x + 2][c] += diff * 7;
+                next[x][c] += diff * 3;
+                next[x + 1][c] += diff * 5;
+                next[x + 2][c] += diff;
+            }
+        }
+        std::mem::swap(&mut errors, &mut next);
+        next.iter_mut().for_each(|error| *error = [0; 3]);
+    }
+
+    pixels
+}
+
+/// Nearest palette color lookup, cached by the top five bits of every channel.
+///
+/// Dithered colors can be anything, so looking up each of them exactly would mean searching
+/// the palette for almost every pixel.
+struct Nearest<'a> {
+    palette: &'a [[u8; 3]],
+    cache: Vec<u16>,
+}
+
+impl<'a> Nearest<'a> {
+    /// Marks cache entries that haven't been looked up yet.
+    const UNKNOWN: u16 = u16::MAX;
+
+    fn new(palette: &'a [[u8; 3]]) -> Self {
+        Self {
+            palette,
+            cache: vec![Self::UNKNOWN; 1 << 15],
+        }
+    }
+
+    fn find(&mut self, color: [u8; 3]) -> u16 {
+        let key = color
+            .iter()
+            .fold(0, |key, &v| key << 5 | usize::from(v >> 3));
+        if self.cache[key] == Self::UNKNOWN {
+            let distance = |entry: &[u8; 3]| {
+                entry
+                    .iter()
+                    .zip(&color)
+                    .map(|(&a, &b)| (i32::from(a) - i32::from(b)).pow(2))
+                    .sum::<i32>()
+            };
+            self.cache[key] = (0..self.palette.len())
+                .min_by_key(|&i| distance(&self.palette[i]))
+                .unwrap_or(0) as u16;
+        }
+
+        self.cache[key]
+    }
+}
@@ -0,0 +1,139 @@
+//! Pixels exactly as the X server sent them, behind a small self-describing header.
+//!
+//! The header is 32 bytes, with all numbers in little endian:
+//!
+//! | Offset | Size | Contents                                                           |
+//! |--------|------|--------------------------------------------------------------------|
+//! | 0      | 8    | Magic `XBGDRAW1`                                                   |
+//! | 8      | 4    | Width in pixels                                                    |
+//! | 12     | 4    | Height in pixels                                                   |
+//! | 16     | 4    | Stride: bytes per row, including padding                           |
+//! | 20     | 1    | Depth                                                              |
+//! | 21     | 1    | Bits per pixel                                                     |
+//! | 22     | 2    | Reserved, zero                                                     |
+//! | 24     | 4    | Channel order in memory as ASCII, e.g. `BGRX`, or zero if unknown  |
+//! | 28     | 4    | Length of the pixel data that follows                              |
+//!
+//! The pixel data is the Z_PIXMAP image as returned by GetImage, row by row from the top.
+
+use std::convert::TryInto;
+
+use crate::error::{Result, XbgdumpError};
+
+/// Identifies raw dumps and their header version.
+pub const RAW_MAGIC: &[u8; 8] = b"XBGDRAW1";
+/// Size of the header in front of the pixel data.
+pub const RAW_HEADER_LEN: usize = 32;
+
+/// An unconverted Z_PIXMAP image of the background.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawImage {
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per row, including the padding the server adds.
+    pub stride: u32,
+    pub depth: u8,
+    pub bits_per_pixel: u8,
+    /// One ASCII letter per byte of a pixel, e.g. `BGRX` for depth 24 on little-endian servers.
+    ///
+    /// All zero for depths whose channels aren't whole bytes.
+    pub channel_order: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl RawImage {
+    /// The header describing this image.
+    pub fn header(&self) -> [u8; RAW_HEADER_LEN] {
+        let mut header = [0; RAW_HEADER_LEN];
+        header[..8].copy_from_slice(RAW_MAGIC);
+        header[8..12].copy_from_slice(&self.width.to_le_bytes());
+        header[12..16].copy_from_slice(&self.height.to_le_bytes());
+        header[16..20].copy_from_slice(&self.stride.to_le_bytes());
+        header[20] = self.depth;
+        header[21] = self.bits_per_pixel;
+        header[24..28].copy_from_slice(&self.channel_order);
+        header[28..32].copy_from_slice(&(self.data.len() as u32).to_le_bytes());
+
+        header
+    }
+
+    /// Header and pixel data, ready to be written out.
+    pub fn encode(&self) -> Vec<u8> {
+        [&self.header()[..], &self.data].concat()
+    }
+
+    /// Reads back what [`encode`](RawImage::encode) wrote. Anything after the pixel data
+    /// is ignored.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < RAW_HEADER_LEN || &bytes[..8] != RAW_MAGIC {
+            return Err(XbgdumpError::InvalidRawImage);
+        }
+
+        // Unwraps safe because the header is long enough
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let data = bytes[RAW_HEADER_LEN..]
+            .get(..u32_at(28) as usize)
+            .ok_or(XbgdumpError::InvalidRawImage)?;
+
+        Ok(RawImage {
+            width: u32_at(8),
+            height: u32_at(12),
+            stride: u32_at(16),
+            depth: bytes[20],
+            bits_per_pixel: bytes[21],
+            channel_order: bytes[24..28].try_into().unwrap(),
+            data: data.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> RawImage {
+        RawImage {
+            width: 3,
+            height: 2,
+            // One pixel of padding per row
+            stride: 16,
+            depth: 24,
+            bits_per_pixel: 32,
+            channel_order: *b"BGRX",
+            data: (0..32).collect(),
+        }
+    }
+
+    #[test]
+    fn header_layout() {
+        let header = image().header();
+        assert_eq!(&header[..8], b"XBGDRAW1");
+        assert_eq!(header[8..12], 3u32.to_le_bytes());
+        assert_eq!(header[12..16], 2u32.to_le_bytes());
+        assert_eq!(header[16..20], 16u32.to_le_bytes());
+        assert_eq!(header[20..24], [24, 32, 0, 0]);
+        assert_eq!(&header[24..28], b"BGRX");
+        assert_eq!(header[28..32], 32u32.to_le_bytes());
+    }
+
+    #[test]
+    fn round_trip() {
+        let img = image();
+        let encoded = img.encode();
+        assert_eq!(encoded.len(), RAW_HEADER_LEN + img.data.len());
+        assert_eq!(RawImage::decode(&encoded).unwrap(), img);
+    }
+
+    #[test]
+    fn invalid() {
+        let encoded = image().encode();
+        let mut wrong_magic = encoded.clone();
+        wrong_magic[7] = b'2';
+        for bytes in [&encoded[..31], &encoded[..40], &wrong_magic[..]] {
+            assert!(matches!(
+                RawImage::decode(bytes),
+                Err(XbgdumpError::InvalidRawImage)
+            ));
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+use std::str::FromStr;
+
+use crate::error::{Result, XbgdumpError};
+
+/// How large the scaled image should be.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScaleTarget {
+    /// Multiply both dimensions by this factor.
+    Factor(f64),
+    /// Scale so the longer side ends up this many pixels long.
+    MaxDim(u32),
+    /// Scale to exactly this size, ignoring the aspect ratio.
+    Exact(u32, u32),
+    /// Scale to this width, keeping the aspect ratio.
+    Width(u32),
+    /// Scale to this height, keeping the aspect ratio.
+    Height(u32),
+}
+
+impl ScaleTarget {
+    /// Parses a size as accepted by `--resize`: `WxH`, `W` or `xH`.
+    pub fn parse_size(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid size '{}'. Expected WxH, W or xH.", s);
+        let dim = |dim: &str| match dim.parse() {
+            Ok(0) => Err(format!("Size '{}' must be positive.", s)),
+            Ok(dim) => Ok(dim),
+            Err(_) => Err(invalid()),
+        };
+
+        match s.split_once(['x', 'X']) {
+            Some(("", height)) => Ok(ScaleTarget::Height(dim(height)?)),
+            Some((width, height)) => Ok(ScaleTarget::Exact(dim(width)?, dim(height)?)),
+            None => Ok(ScaleTarget::Width(dim(s)?)),
+        }
+    }
+}
+
+impl FromStr for ScaleTarget {
+    type Err = String;
+
+    /// Accepts percentages like `25%` as well as plain factors like `0.25`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid scale '{}'. Expected a percentage or factor.", s);
+        let factor = match s.strip_suffix('%') {
+            Some(percent) => percent.parse::<f64>().map_err(|_| invalid())? / 100.0,
+            None => s.parse().map_err(|_| invalid())?,
+        };
+
+        if !factor.is_finite() || factor <= 0.0 {
+            return Err(format!("Scale must be positive, got '{}'.", s));
+        }
+
+        Ok(ScaleTarget::Factor(factor))
+    }
+}
+
+/// Resampling filter used for scaling, from fastest to best-looking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScaleFilter {
+    Nearest,
+    #[default]
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl ScaleFilter {
+    /// All filters, in the order they are listed in help texts.
+    pub const ALL: &'static [ScaleFilter] = &[
+        ScaleFilter::Nearest,
+        ScaleFilter::Triangle,
+        ScaleFilter::CatmullRom,
+        ScaleFilter::Gaussian,
+        ScaleFilter::Lanczos3,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ScaleFilter::Nearest => "nearest",
+            ScaleFilter::Triangle => "triangle",
+            ScaleFilter::CatmullRom => "catmullrom",
+            ScaleFilter::Gaussian => "gaussian",
+            ScaleFilter::Lanczos3 => "lanczos3",
+        }
+    }
+
+    fn filter_type(self) -> FilterType {
+        match self {
+            ScaleFilter::Nearest => FilterType::Nearest,
+            ScaleFilter::Triangle => FilterType::Triangle,
+            ScaleFilter::CatmullRom => FilterType::CatmullRom,
+            ScaleFilter::Gaussian => FilterType::Gaussian,
+            ScaleFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+impl FromStr for ScaleFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ScaleFilter::ALL
+            .iter()
+            .copied()
+            .find(|f| f.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown filter '{}'. Expected one of {}.",
+                    s,
+                    ScaleFilter::ALL
+                        .iter()
+                        .map(|f| f.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+/// Resizes the image, keeping its aspect ratio unless the target is an exact size.
+///
+/// Enlarging the image is an error unless `allow_upscale` is set.
+pub fn scale(
+    img: &DynamicImage,
+    target: ScaleTarget,
+    filter: ScaleFilter,
+    allow_upscale: bool,
+) -> Result<DynamicImage> {
+    let (width, height) = img.dimensions();
+    let factor = match target {
+        ScaleTarget::Factor(factor) => factor,
+        ScaleTarget::MaxDim(max) => f64::from(max) / f64::from(width.max(height)),
+        ScaleTarget::Width(new_width) => f64::from(new_width) / f64::from(width),
+        ScaleTarget::Height(new_height) => f64::from(new_height) / f64::from(height),
+        ScaleTarget::Exact(..) => 1.0,
+    };
+
+    // Truncation is fine because the result is rounded and can't exceed u32 for sane factors
+    let resize = |len: u32| ((f64::from(len) * factor).round() as u32).max(1);
+    let (new_width, new_height) = match target {
+        ScaleTarget::Exact(new_width, new_height) => (new_width, new_height),
+        // Pin the requested side, rounding could otherwise be off by one
+        ScaleTarget::Width(new_width) => (new_width, resize(height)),
+        ScaleTarget::Height(new_height) => (resize(width), new_height),
+        _ => (resize(width), resize(height)),
+    };
+
+    if (new_width, new_height) == (width, height) {
+        return Ok(img.clone());
+    }
+    if !allow_upscale && (new_width > width || new_height > height) {
+        return Err(XbgdumpError::UpscaleRejected {
+            from: (width, height),
+            to: (new_width, new_height),
+        });
+    }
+
+    log!(
+        Info,
+        "Scaling {}x{} to {}x{} using {} filtering.",
+        width,
+        height,
+        new_width,
+        new_height,
+        filter.name()
+    );
+
+    Ok(img.resize_exact(new_width, new_height, filter.filter_type()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(32, 16, |x, y| {
+            Rgb([(x * 8) as u8, (y * 16) as u8, 0])
+        }))
+    }
+
+    #[test]
+    fn original_size_is_identity() {
+        let img = gradient();
+        let targets = [
+            ScaleTarget::Factor(1.0),
+            ScaleTarget::MaxDim(32),
+            ScaleTarget::Exact(32, 16),
+            ScaleTarget::Width(32),
+            ScaleTarget::Height(16),
+        ];
+        for target in targets {
+            for filter in ScaleFilter::ALL.iter().copied() {
+                let scaled = scale(&img, target, filter, false).unwrap();
+                assert_eq!(scaled, img, "{:?} {}", target, filter.name());
+            }
+        }
+    }
+
+    #[test]
+    fn target_sizes() {
+        let img = gradient();
+        let cases = [
+            (ScaleTarget::Factor(0.5), (16, 8)),
+            (ScaleTarget::MaxDim(8), (8, 4)),
+            (ScaleTarget::Exact(10, 10), (10, 10)),
+            (ScaleTarget::Width(8), (8, 4)),
+            (ScaleTarget::Height(4), (8, 4)),
+        ];
+        for (target, size) in cases {
+            let scaled = scale(&img, target, ScaleFilter::Triangle, false).unwrap();
+            assert_eq!(scaled.dimensions(), size, "{:?}", target);
+        }
+
+        assert!(matches!(
+            scale(&img, ScaleTarget::Factor(2.0), ScaleFilter::Triangle, false),
+            Err(XbgdumpError::UpscaleRejected { .. })
+        ));
+    }
+}
@@ -0,0 +1,334 @@
+//! Serving encoded images on X selections, so they can be pasted without a helper like xclip.
+//!
+//! Transfers larger than a single request can carry use the ICCCM `INCR` protocol.
+
+use std::{sync::mpsc, thread, time::Duration};
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xproto::{
+            Atom, AtomEnum, ChangeWindowAttributesAux, ClientMessageEvent, ConnectionExt,
+            CreateWindowAux, EventMask, PropMode, Property, SelectionNotifyEvent,
+            SelectionRequestEvent, Window, WindowClass, CLIENT_MESSAGE_EVENT,
+            SELECTION_NOTIFY_EVENT,
+        },
+        Event,
+    },
+    wrapper::ConnectionExt as _,
+    COPY_DEPTH_FROM_PARENT, COPY_FROM_PARENT, CURRENT_TIME, NONE,
+};
+
+use crate::error::{Result, XbgdumpError};
+
+/// Encoded image data offered on a selection.
+#[derive(Debug, Clone)]
+pub struct SelectionData {
+    /// Used as the target name, e.g. `image/png`.
+    pub mime_type: &'static str,
+    pub data: Vec<u8>,
+}
+
+/// An `INCR` transfer waiting for the requestor to delete the property we wrote.
+struct Transfer {
+    requestor: Window,
+    property: Atom,
+    target: Atom,
+    /// Index into the offered data.
+    offer: usize,
+    /// How much of the data has been sent already.
+    sent: usize,
+}
+
+/// Takes ownership of the named selections, e.g. `CLIPBOARD` and `PRIMARY`, and answers
+/// requests for the offered data until other clients take them over or the timeout expires.
+///
+/// This blocks for as long as any selection is ours. Transfers still in progress when
+/// ownership is lost are finished first.
+pub fn serve_selections<C: Connection + Sync>(
+    c: &C,
+    screen_num: usize,
+    selections: &[&str],
+    offers: &[SelectionData],
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let root = c
+        .setup()
+        .roots
+        .get(screen_num)
+        .ok_or(XbgdumpError::NoSuchScreen(screen_num))?
+        .root;
+
+    let names = ["TARGETS", "INCR"]
+        .iter()
+        .copied()
+        .chain(selections.iter().copied())
+        .chain(offers.iter().map(|offer| offer.mime_type))
+        .collect::<Vec<_>>();
+    let atom_cookies = names
+        .iter()
+        .map(|name| c.intern_atom(false, name.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let atoms = atom_cookies
+        .into_iter()
+        .map(|cookie| cookie.reply().map(|reply| reply.atom))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (selection_atoms, offer_atoms) = atoms[2..].split_at(selections.len());
+
+    // Selections belong to windows, so we need one, even if it's never shown
+    let window = c.generate_id()?;
+    c.create_window(
+        COPY_DEPTH_FROM_PARENT,
+        window,
+        root,
+        0,
+        0,
+        1,
+        1,
+        0,
+        WindowClass::INPUT_ONLY,
+        COPY_FROM_PARENT,
+        &CreateWindowAux::new(),
+    )?
+    .check()?;
+    for (&name, &atom) in selections.iter().zip(selection_atoms) {
+        c.set_selection_owner(window, atom, CURRENT_TIME)?.check()?;
+        if c.get_selection_owner(atom)?.reply()?.owner != window {
+            return Err(XbgdumpError::SelectionUnavailable(name.into()));
+        }
+        log!(Info, "Serving the {} selection.", name);
+    }
+
+    let mut server = Server {
+        c,
+        targets_atom: atoms[0],
+        incr_atom: atoms[1],
+        offer_atoms,
+        offers,
+        // Leave some room for the request header
+        chunk_size: c.maximum_request_bytes().saturating_sub(64).min(1 << 20),
+        transfers: Vec::new(),
+    };
+    let mut owned = vec![true; selections.len()];
+
+    thread::scope(|scope| {
+        // Dropped when serving ends, which wakes the timer up early
+        let (_stop, stopped) = mpsc::channel::<()>();
+        if let Some(timeout) = timeout {
+            scope.spawn(move || {
+                if stopped.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout) {
+                    // Wakes up the event loop below, which can't wait for a timeout itself
+                    let _ = wake(c, window);
+                }
+            });
+        }
+
+        while owned.contains(&true) || !server.transfers.is_empty() {
+            match c.wait_for_event()? {
+                Event::SelectionRequest(request) => {
+                    let owned = selection_atoms
+                        .iter()
+                        .position(|&atom| atom == request.selection)
+                        .is_some_and(|i| owned[i]);
+                    if owned {
+                        server.answer(&request)?;
+                    } else {
+                        // Request from before the selection was lost, the new owner will answer it
+                        notify(c, &request, NONE)?;
+                    }
+                }
+                Event::SelectionClear(event) => {
+                    if let Some(i) = selection_atoms.iter().position(|&a| a == event.selection) {
+                        log!(
+                            Info,
+                            "Another client took over the {} selection.",
+                            selections[i]
+                        );
+                        owned[i] = false;
+                    }
+                }
+                Event::ClientMessage(event) if event.window == window => {
+                    log!(
+                        Info,
+                        "Selection timeout expired, no longer serving the image."
+                    );
+                    owned.iter_mut().for_each(|owned| *owned = false);
+                }
+                Event::PropertyNotify(event) if event.state == Property::DELETE => {
+                    server.continue_transfer(event.window, event.atom)?;
+                }
+                Event::DestroyNotify(event) => {
+                    // Requestors that vanish mid-transfer won't ever delete the property
+                    server.transfers.retain(|t| t.requestor != event.window);
+                }
+                _ => {}
+            }
+            c.flush()?;
+        }
+
+        Ok::<_, XbgdumpError>(())
+    })?;
+
+    // Releases whatever selections are still ours
+    c.destroy_window(window)?;
+    c.flush()?;
+
+    Ok(())
+}
+
+/// Everything needed to answer requests, independent of which selection they are for.
+struct Server<'a, C: Connection> {
+    c: &'a C,
+    targets_atom: Atom,
+    incr_atom: Atom,
+    offer_atoms: &'a [Atom],
+    offers: &'a [SelectionData],
+    chunk_size: usize,
+    transfers: Vec<Transfer>,
+}
+
+impl<C: Connection> Server<'_, C> {
+    /// Stores the requested target on the requestor's window, or starts an `INCR` transfer,
+    /// and tells the requestor about it.
+    fn answer(&mut self, request: &SelectionRequestEvent) -> Result<()> {
+        let c = self.c;
+        // Obsolete clients don't name a property, ICCCM says to use the target then
+        let property = if request.property == NONE {
+            request.target
+        } else {
+            request.property
+        };
+
+        let offer = self.offer_atoms.iter().position(|&a| a == request.target);
+        let reply_property = if request.target == self.targets_atom {
+            let mut targets = vec![self.targets_atom];
+            targets.extend_from_slice(self.offer_atoms);
+            c.change_property32(
+                PropMode::REPLACE,
+                request.requestor,
+                property,
+                AtomEnum::ATOM,
+                &targets,
+            )?;
+            property
+        } else if let Some(offer) = offer {
+            let data = &self.offers[offer].data;
+            log!(
+                Debug,
+                "Sending {} bytes of {} to window {}.",
+                data.len(),
+                self.offers[offer].mime_type,
+                request.requestor
+            );
+            if data.len() <= self.chunk_size {
+                c.change_property8(
+                    PropMode::REPLACE,
+                    request.requestor,
+                    property,
+                    request.target,
+                    data,
+                )?;
+            } else {
+                // Subscribe before announcing the transfer, so no deletion is missed
+                c.change_window_attributes(
+                    request.requestor,
+                    &ChangeWindowAttributesAux::new()
+                        .event_mask(EventMask::PROPERTY_CHANGE | EventMask::STRUCTURE_NOTIFY),
+                )?;
+                c.change_property32(
+                    PropMode::REPLACE,
+                    request.requestor,
+                    property,
+                    self.incr_atom,
+                    &[data.len() as u32],
+                )?;
+                self.transfers.push(Transfer {
+                    requestor: request.requestor,
+                    property,
+                    target: request.target,
+                    offer,
+                    sent: 0,
+                });
+            }
+            property
+        } else {
+            log!(
+                Debug,
+                "Refusing request for unsupported target {}.",
+                request.target
+            );
+            NONE
+        };
+
+        notify(c, request, reply_property)
+    }
+
+    /// Sends the next chunk of an `INCR` transfer once the requestor deleted the last one.
+    fn continue_transfer(&mut self, requestor: Window, property: Atom) -> Result<()> {
+        let index = match self
+            .transfers
+            .iter()
+            .position(|t| t.requestor == requestor && t.property == property)
+        {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let transfer = &mut self.transfers[index];
+        let data = &self.offers[transfer.offer].data;
+        let end = data.len().min(transfer.sent + self.chunk_size);
+        // The final, empty chunk tells the requestor that the transfer is complete
+        self.c.change_property8(
+            PropMode::REPLACE,
+            transfer.requestor,
+            transfer.property,
+            transfer.target,
+            &data[transfer.sent..end],
+        )?;
+        if transfer.sent < end {
+            transfer.sent = end;
+            return Ok(());
+        }
+
+        self.transfers.swap_remove(index);
+        if self.transfers.iter().all(|t| t.requestor != requestor) {
+            // Done with this window
+            self.c.change_window_attributes(
+                requestor,
+                &ChangeWindowAttributesAux::new().event_mask(EventMask::NO_EVENT),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Tells the requestor that its request was handled, or refused if `property` is `NONE`.
+fn notify(c: &impl Connection, request: &SelectionRequestEvent, property: Atom) -> Result<()> {
+    let event = SelectionNotifyEvent {
+        response_type: SELECTION_NOTIFY_EVENT,
+        sequence: 0,
+        time: request.time,
+        requestor: request.requestor,
+        selection: request.selection,
+        target: request.target,
+        property,
+    };
+    c.send_event(false, request.requestor, EventMask::NO_EVENT, event)?;
+
+    Ok(())
+}
+
+/// Sends a client message to our own window, which the server delivers back to us.
+fn wake(c: &impl Connection, window: Window) -> Result<()> {
+    let event = ClientMessageEvent {
+        response_type: CLIENT_MESSAGE_EVENT,
+        format: 32,
+        sequence: 0,
+        window,
+        type_: NONE,
+        data: [0; 5].into(),
+    };
+    c.send_event(false, window, EventMask::NO_EVENT, event)?;
+    c.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    sys::{
+        mman::{mmap, munmap, shm_open, shm_unlink, MapFlags, ProtFlags},
+        stat::Mode,
+    },
+    unistd::ftruncate,
+};
+use std::{convert::TryInto, ffi::c_void, io, os::unix::io::AsRawFd, ptr, slice};
+use x11rb::{
+    connection::Connection,
+    protocol::shm::{ConnectionExt as ShmConnectionExt, Seg},
+    utils::RawFdContainer,
+};
+
+use crate::error::{Result, XbgdumpError};
+
+/// Keeps nix out of the public error type.
+fn sys(e: nix::Error) -> XbgdumpError {
+    XbgdumpError::Sys(match e.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::other(e),
+    })
+}
+
+/// A POSIX shared memory segment mapped into our address space and attached to the X server.
+///
+/// Detaches and unmaps itself when dropped.
+pub struct ShmSegment<'c, C: Connection> {
+    c: &'c C,
+    seg: Seg,
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl<'c, C: Connection> ShmSegment<'c, C> {
+    /// Allocates a segment of `len` bytes and shares it with the server.
+    ///
+    /// Fails if the server doesn't support passing the segment as a file descriptor (MIT-SHM 1.2),
+    /// which is also the case for remote connections.
+    pub fn new(c: &'c C, len: usize) -> Result<Self> {
+        let version = c
+            .shm_query_version()
+            .map_err(|_| XbgdumpError::ShmUnsupported)?
+            .reply()?;
+        if (version.major_version, version.minor_version) < (1, 2) {
+            return Err(XbgdumpError::ShmUnsupported);
+        }
+
+        // The name only needs to be unique until we unlink it right after creation
+        let name = format!("/xbgdump-{}", std::process::id());
+        let fd = shm_open(
+            name.as_str(),
+            OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_EXCL,
+            Mode::S_IRUSR | Mode::S_IWUSR,
+        )
+        .map_err(sys)?;
+        let fd = RawFdContainer::new(fd);
+        shm_unlink(name.as_str()).map_err(sys)?;
+
+        ftruncate(
+            fd.as_raw_fd(),
+            // Same error ftruncate() itself would report
+            len.try_into()
+                .map_err(|_| sys(nix::Error::Sys(Errno::EFBIG)))?,
+        )
+        .map_err(sys)?;
+
+        // SAFETY: We map a fresh object of exactly `len` bytes that nothing else has access to yet.
+        // The mapping is only ever read through `data()`, which borrows `self`.
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        }
+        .map_err(sys)?;
+
+        let seg = match c.generate_id() {
+            Ok(seg) => seg,
+            Err(e) => {
+                // SAFETY: Mapped above and not handed out anywhere.
+                let _ = unsafe { munmap(ptr, len) };
+                return Err(e.into());
+            }
+        };
+
+        // From here on, Drop takes care of the cleanup
+        let segment = Self { c, seg, ptr, len };
+        c.shm_attach_fd(seg, fd, false)?.check()?;
+
+        log!(Debug, "Attached {} bytes of shared memory.", len);
+
+        Ok(segment)
+    }
+
+    pub fn id(&self) -> Seg {
+        self.seg
+    }
+
+    /// Current contents of the segment.
+    pub fn data(&self) -> &[u8] {
+        // SAFETY: The mapping is valid for `len` bytes for as long as `self` lives.
+        // The server only writes to it while we're blocked waiting for a reply.
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl<C: Connection> Drop for ShmSegment<'_, C> {
+    fn drop(&mut self) {
+        // If the segment was never attached, this just produces an error we don't care about
+        if let Ok(cookie) = self.c.shm_detach(self.seg) {
+            let _ = cookie.check();
+        }
+
+        // SAFETY: Mapped in new() and no borrows of `data()` can outlive `self`.
+        let _ = unsafe { munmap(self.ptr, self.len) };
+    }
+}
@@ -0,0 +1,115 @@
+//! Encoder for DEC sixel graphics, which many terminal emulators display inline.
+//!
+//! Sixel images are palette-based, so the image goes through the same median cut as XPM and
+//! GIF first, see [`quantize`](crate::quantize). Pixels are then sent in bands six rows high,
+//! one pass over the band per color, each column of a pass being a character whose bits say
+//! which of the six pixels get the color.
+
+use image::{DynamicImage, GenericImageView};
+use std::io::{self, Write};
+
+use crate::quantize;
+
+/// Used without an explicit limit, the most colors terminals are guaranteed to have registers for.
+pub(crate) const DEFAULT_COLORS: u16 = 256;
+/// Pixels less opaque than this are left alone, the rest drawn fully opaque.
+const ALPHA_THRESHOLD: u8 = 128;
+
+/// Device control string introducer, with 1:1 pixels and an untouched background.
+const START: &[u8] = b"\x1bP0;1;0q";
+/// String terminator.
+const END: &[u8] = b"\x1b\\";
+/// Runs at least this long are shorter with a repeat introducer.
+const MIN_REPEAT: usize = 4;
+
+/// Encodes the image as sixels with at most `max_colors` color registers.
+///
+/// Transparent pixels are never drawn, so the terminal's background shows through.
+pub fn encode(
+    img: &DynamicImage,
+    max_colors: u16,
+    dither: bool,
+    w: &mut impl Write,
+) -> io::Result<()> {
+    let (width, height) = img.dimensions();
+    let indexed = quantize::reduce(&img.to_rgba8(), max_colors.into(), ALPHA_THRESHOLD, dither);
+    let width = width as usize;
+
+    let mut out = Vec::with_capacity(64 * 1024);
+    out.extend_from_slice(START);
+    // Raster attributes: aspect ratio, then the size
+    write!(out, "\"1;1;{};{}", width, height)?;
+    for (i, color) in indexed.palette.iter().enumerate() {
+        // RGB in percent
+        let [r, g, b] = color.map(|v| (u32::from(v) * 100 + 127) / 255);
+        write!(out, "#{};2;{};{};{}", i, r, g, b)?;
+    }
+
+    // The sixels of every color in the current band, and which colors occur in it
+    let mut bands = vec![Vec::new(); indexed.palette.len()];
+    let mut used = Vec::with_capacity(indexed.palette.len());
+    for (band_index, band) in indexed.pixels.chunks(width * 6).enumerate() {
+        for (y, row) in band.chunks(width).enumerate() {
+            for (x, &p) in row.iter().enumerate() {
+                let i = match p {
+                    Some(i) => usize::from(i),
+                    None => continue,
+                };
+                let sixels = &mut bands[i];
+                if sixels.is_empty() {
+                    sixels.resize(width, 0u8);
+                    used.push(i);
+                }
+                sixels[x] |= 1 << y;
+            }
+        }
+
+        if band_index > 0 {
+            // Graphics new line
+            out.push(b'-');
+        }
+        // Same output for the same image, in whatever order the colors were found
+        used.sort_unstable();
+        for (n, &i) in used.iter().enumerate() {
+            if n > 0 {
+                // Graphics carriage return, back to the start of the band for the next color
+                out.push(b'$');
+            }
+            write!(out, "#{}", i)?;
+            write_runs(&mut out, &bands[i])?;
+            bands[i].clear();
+        }
+        used.clear();
+
+        if out.len() >= 64 * 1024 {
+            w.write_all(&out)?;
+            out.clear();
+        }
+    }
+    out.extend_from_slice(END);
+
+    w.write_all(&out)
+}
+
+/// Writes one pass of sixels, run-length encoded, leaving out empty columns at the end.
+fn write_runs(out: &mut Vec<u8>, sixels: &[u8]) -> io::Result<()> {
+    let len = sixels.iter().rposition(|&s| s != 0).map_or(0, |i| i + 1);
+    let mut sixels = sixels[..len].iter().peekable();
+    while let Some(&sixel) = sixels.next() {
+        let mut run = 1;
+        while sixels.next_if_eq(&&sixel).is_some() {
+            run += 1;
+        }
+
+        // Sixel characters start at '?', which has none of the bits set
+        let c = b'?' + sixel;
+        if run >= MIN_REPEAT {
+            write!(out, "!{}", run)?;
+            out.push(c);
+        } else {
+            out.resize(out.len() + run, c);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,174 @@
+//! Color statistics of the background, for scripts that derive color schemes from it.
+
+use image::{DynamicImage, GenericImageView, Rgb};
+use std::collections::HashMap;
+
+use crate::quantize::median_cut;
+
+/// Only every this many pixels are looked at; backgrounds are large and mostly smooth.
+const SAMPLE_STEP: usize = 8;
+/// How many dominant colors are looked for.
+const DOMINANT_COLORS: usize = 5;
+/// k-means stops after this many rounds even if the clusters still move.
+const MAX_ROUNDS: usize = 32;
+
+/// The overall colors of an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColorStats {
+    /// Mean of every channel.
+    pub average: Rgb<u8>,
+    /// Median of every channel, on its own.
+    pub median: Rgb<u8>,
+    /// The centers of the largest color clusters, largest first. Fewer than five if the
+    /// image has fewer colors.
+    pub dominant: Vec<Rgb<u8>>,
+}
+
+/// Computes the statistics from a sample of the image's pixels, leaving out fully transparent
+/// ones, e.g. masked areas. Returns `None` if there's nothing left to look at.
+///
+/// The dominant colors are found with k-means, started from a median cut of the sample so the
+/// result is the same every time.
+pub fn color_stats(img: &DynamicImage) -> Option<ColorStats> {
+    let rgba = img.to_rgba8();
+    let sample = rgba
+        .pixels()
+        .step_by(SAMPLE_STEP)
+        .filter(|p| p[3] > 0)
+        .map(|p| [p[0], p[1], p[2]])
+        .collect::<Vec<_>>();
+    if sample.is_empty() {
+        return None;
+    }
+    log!(
+        Debug,
+        "Computing color statistics from {} pixels.",
+        sample.len()
+    );
+
+    let mut sums = [0u64; 3];
+    for color in &sample {
+        for (sum, &v) in sums.iter_mut().zip(color) {
+            *sum += u64::from(v);
+        }
+    }
+    let total = sample.len() as u64;
+    let average = sums.map(|sum| ((sum + total / 2) / total) as u8);
+
+    let median = [0, 1, 2].map(|c| {
+        let mut values = sample.iter().map(|color| color[c]).collect::<Vec<_>>();
+        let middle = values.len() / 2;
+        *values.select_nth_unstable(middle).1
+    });
+
+    Some(ColorStats {
+        average: Rgb(average),
+        median: Rgb(median),
+        dominant: k_means(&sample).into_iter().map(Rgb).collect(),
+    })
+}
+
+/// How many pixels have each intensity, per channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    pub r: [u64; 256],
+    pub g: [u64; 256],
+    pub b: [u64; 256],
+    /// `None` if the image has no alpha channel.
+    pub a: Option<[u64; 256]>,
+}
+
+/// Counts the intensities of every pixel, with 16-bit channels scaled down to 8 bits.
+///
+/// With an alpha channel, fully transparent pixels only count towards it, since their color
+/// is meaningless.
+pub fn histogram(img: &DynamicImage) -> Histogram {
+    let has_alpha = img.color().has_alpha();
+    let mut histogram = Histogram {
+        r: [0; 256],
+        g: [0; 256],
+        b: [0; 256],
+        a: has_alpha.then_some([0; 256]),
+    };
+    for (_, _, p) in img.pixels() {
+        if let Some(a) = &mut histogram.a {
+            a[usize::from(p[3])] += 1;
+            if p[3] == 0 {
+                continue;
+            }
+        }
+        histogram.r[usize::from(p[0])] += 1;
+        histogram.g[usize::from(p[1])] += 1;
+        histogram.b[usize::from(p[2])] += 1;
+    }
+
+    histogram
+}
+
+/// Clusters the colors, returning the cluster centers by size, largest first.
+fn k_means(sample: &[[u8; 3]]) -> Vec<[u8; 3]> {
+    let mut histogram = HashMap::new();
+    for &color in sample {
+        *histogram.entry(color).or_insert(0u32) += 1;
+    }
+    let colors = histogram.into_iter().collect::<Vec<_>>();
+    let (initial, _) = median_cut(colors.iter().copied().collect(), DOMINANT_COLORS);
+    let mut centers = initial
+        .iter()
+        .map(|color| color.map(f64::from))
+        .collect::<Vec<_>>();
+
+    let mut sizes = vec![0u64; centers.len()];
+    for round in 0..MAX_ROUNDS {
+        let mut sums = vec![[0f64; 3]; centers.len()];
+        sizes.iter_mut().for_each(|size| *size = 0);
+        for &(color, count) in &colors {
+            let i = nearest(&centers, color);
+            for (sum, &v) in sums[i].iter_mut().zip(&color) {
+                *sum += f64::from(v) * f64::from(count);
+            }
+            sizes[i] += u64::from(count);
+        }
+
+        let mut moved = false;
+        for ((center, sum), &size) in centers.iter_mut().zip(&sums).zip(&sizes) {
+            // Clusters that lost all their colors stay where they are
+            if size == 0 {
+                continue;
+            }
+            let new = sum.map(|s| s / size as f64);
+            moved |= new
+                .iter()
+                .zip(center.iter())
+                .any(|(a, b)| (a - b).abs() >= 0.5);
+            *center = new;
+        }
+        if !moved {
+            log!(Trace, "k-means settled after {} rounds.", round + 1);
+            break;
+        }
+    }
+
+    let mut clusters = centers.into_iter().zip(sizes).collect::<Vec<_>>();
+    clusters.retain(|&(_, size)| size > 0);
+    // Ties keep the median cut's order, so the output is stable
+    clusters.sort_by_key(|&(_, size)| std::cmp::Reverse(size));
+    clusters
+        .into_iter()
+        .map(|(center, _)| center.map(|v| v.round() as u8))
+        .collect()
+}
+
+/// Index of the center closest to the color.
+fn nearest(centers: &[[f64; 3]], color: [u8; 3]) -> usize {
+    let distance = |center: &[f64; 3]| {
+        center
+            .iter()
+            .zip(&color)
+            .map(|(&a, &b)| (a - f64::from(b)).powi(2))
+            .sum::<f64>()
+    };
+    (0..centers.len())
+        .min_by(|&a, &b| distance(&centers[a]).total_cmp(&distance(&centers[b])))
+        .unwrap_or(0)
+}
@@ -0,0 +1,156 @@
+//! strftime-style file name templates, e.g. `bg-%Y%m%d-%H%M%S-%wx%h.png`.
+
+use nix::libc::{c_char, localtime_r, size_t, time_t, tm};
+use std::{
+    mem::MaybeUninit,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::error::{Result, XbgdumpError};
+
+// Not bound by the libc crate for Linux, but part of every C library
+extern "C" {
+    fn strftime(s: *mut c_char, max: size_t, format: *const c_char, tm: *const tm) -> size_t;
+}
+
+/// Conversions passed on to strftime(3). `%w` and `%h` are not among them, since they
+/// stand for the image dimensions instead of the weekday and the abbreviated month.
+const STRFTIME_SPECIFIERS: &str = "aAbBcCdDeFgGHIjklmMprRsSTuUVWxXyYzZ";
+
+/// Expands `%w` and `%h` to the final image size and strftime conversions to the given time
+/// in the local time zone. `%%` is a literal percent sign.
+pub fn expand_filename(
+    template: &str,
+    width: u32,
+    height: u32,
+    time: SystemTime,
+) -> Result<String> {
+    let local = local_time(time);
+    expand(template, |specifier| match specifier {
+        'w' => width.to_string(),
+        'h' => height.to_string(),
+        specifier => format_time(&local, specifier),
+    })
+}
+
+/// Checks a template for unknown specifiers without expanding it.
+pub fn check_filename_template(template: &str) -> Result<()> {
+    expand(template, |_| String::new()).map(drop)
+}
+
+fn expand(template: &str, mut expand_specifier: impl FnMut(char) -> String) -> Result<String> {
+    let unknown = |specifier: String| XbgdumpError::UnknownSpecifier {
+        template: template.into(),
+        specifier,
+    };
+
+    let mut expanded = String::with_capacity(template.len());
+    let mut chars = template.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            expanded.push(ch);
+            continue;
+        }
+
+        match chars.next() {
+            Some('%') => expanded.push('%'),
+            Some(specifier @ ('w' | 'h')) => expanded += &expand_specifier(specifier),
+            Some(specifier) if STRFTIME_SPECIFIERS.contains(specifier) => {
+                expanded += &expand_specifier(specifier)
+            }
+            Some(specifier) => return Err(unknown(format!("%{}", specifier))),
+            None => return Err(unknown("%".into())),
+        }
+    }
+
+    Ok(expanded)
+}
+
+fn local_time(time: SystemTime) -> tm {
+    // Times before the epoch don't come out of SystemTime::now() in practice
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs()) as time_t;
+    let mut local = MaybeUninit::<tm>::zeroed();
+    // SAFETY: Both pointers are valid for the duration of the call, and localtime_r only
+    // writes to `local`. If it fails, `local` stays zeroed, which is a valid `tm`.
+    unsafe {
+        localtime_r(&secs, local.as_mut_ptr());
+        local.assume_init()
+    }
+}
+
+fn format_time(local: &tm, specifier: char) -> String {
+    let format = [b'%' as c_char, specifier as c_char, 0];
+    let mut buf = [0u8; 128];
+    // SAFETY: The format is NUL-terminated and strftime never writes more than `buf.len()`
+    // bytes. A return value of 0 means the result didn't fit (or was empty), so nothing is used.
+    let len = unsafe {
+        strftime(
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len(),
+            format.as_ptr(),
+            local,
+        )
+    };
+
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // 2023-11-14 22:13:20 UTC. Only the seconds are the same in every time zone.
+    fn time() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    fn expand_at(template: &str) -> String {
+        expand_filename(template, 3840, 1080, time()).unwrap()
+    }
+
+    #[test]
+    fn date_and_time() {
+        let local = local_time(time());
+        let cases = [
+            ("%Y", format!("{}", local.tm_year + 1900)),
+            ("%m", format!("{:02}", local.tm_mon + 1)),
+            ("%d", format!("{:02}", local.tm_mday)),
+            ("%H", format!("{:02}", local.tm_hour)),
+            ("%M", format!("{:02}", local.tm_min)),
+            ("%S", format!("{:02}", local.tm_sec)),
+        ];
+        for (template, expected) in cases {
+            assert_eq!(expand_at(template), expected, "{}", template);
+        }
+        assert_eq!(expand_at("%S"), "20");
+    }
+
+    #[test]
+    fn image_size() {
+        assert_eq!(expand_at("bg-%wx%h.png"), "bg-3840x1080.png");
+    }
+
+    #[test]
+    fn percent_escape() {
+        assert_eq!(expand_at("100%%-%%w.png"), "100%-%w.png");
+    }
+
+    #[test]
+    fn monitor_placeholders_are_left_alone() {
+        // Monitor::expand_template fills these in before the % conversions
+        assert_eq!(expand_at("bg_{name}_{index}.png"), "bg_{name}_{index}.png");
+    }
+
+    #[test]
+    fn unknown_specifiers() {
+        for template in ["bg-%q.png", "bg-%"] {
+            assert!(matches!(
+                check_filename_template(template),
+                Err(XbgdumpError::UnknownSpecifier { .. })
+            ));
+        }
+    }
+}
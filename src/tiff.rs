@@ -0,0 +1,599 @@
+//! Minimal encoder for baseline TIFF, uncompressed or with LZW or deflate.
+//!
+//! The image crate needs the tiff crate for this, which doesn't seem worth it for a handful of
+//! tags. Everything is written front to back, so stdout works just as well as files.
+
+use image::{DynamicImage, GenericImageView};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    convert::TryFrom,
+    io::{self, ErrorKind, Write},
+    str::FromStr,
+};
+
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+const STRIP_OFFSETS: u16 = 273;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const ROWS_PER_STRIP: u16 = 278;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const X_RESOLUTION: u16 = 282;
+const Y_RESOLUTION: u16 = 283;
+const PLANAR_CONFIGURATION: u16 = 284;
+const RESOLUTION_UNIT: u16 = 296;
+const PREDICTOR: u16 = 317;
+const EXTRA_SAMPLES: u16 = 338;
+
+const HEADER_LEN: u32 = 8;
+const ENTRY_LEN: u32 = 12;
+
+const LZW_CLEAR: u16 = 256;
+const LZW_END: u16 = 257;
+const LZW_FIRST_CODE: u16 = 258;
+const LZW_MIN_BITS: u8 = 9;
+/// Codes never get longer than 12 bits; the table starts over before that would be needed.
+const LZW_MAX_CODE: u16 = (1 << 12) - 1;
+
+/// How the pixels in TIFF files are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TiffCompression {
+    /// The fastest to write and to read, but as large as the pixels themselves.
+    #[default]
+    None,
+    /// Understood by every TIFF reader.
+    Lzw,
+    /// zlib, usually smaller than LZW, but unknown to some older readers.
+    Deflate,
+}
+
+impl TiffCompression {
+    /// All methods, in the order they are listed in help texts.
+    pub const ALL: &'static [TiffCompression] = &[
+        TiffCompression::None,
+        TiffCompression::Lzw,
+        TiffCompression::Deflate,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TiffCompression::None => "none",
+            TiffCompression::Lzw => "lzw",
+            TiffCompression::Deflate => "deflate",
+        }
+    }
+
+    /// Value of the Compression tag.
+    fn tag_value(self) -> u32 {
+        match self {
+            TiffCompression::None => 1,
+            TiffCompression::Lzw => 5,
+            // The code registered by Adobe, which is what everyone writes
+            TiffCompression::Deflate => 8,
+        }
+    }
+}
+
+impl FromStr for TiffCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TiffCompression::ALL
+            .iter()
+            .copied()
+            .find(|c| c.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown TIFF compression '{}'. Expected one of {}.",
+                    s,
+                    TiffCompression::ALL
+                        .iter()
+                        .map(|c| c.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+/// Encodes the image as a little-endian TIFF with a single strip, keeping grayscale, alpha
+/// and 16-bit channels as they are.
+pub fn encode(
+    img: &DynamicImage,
+    compression: TiffCompression,
+    w: &mut impl Write,
+) -> io::Result<()> {
+    let (channels, bits, data) = samples(img);
+    let data = match compression {
+        TiffCompression::None => data,
+        TiffCompression::Lzw => Cow::Owned(lzw(&predict(data, img.width(), channels, bits))),
+        TiffCompression::Deflate => Cow::Owned(deflate::deflate_bytes_zlib(&predict(
+            data,
+            img.width(),
+            channels,
+            bits,
+        ))),
+    };
+    let has_alpha = channels % 2 == 0;
+    let len = u32::try_from(data.len())
+        .ok()
+        .filter(|&len| len <= u32::MAX - 1024)
+        .ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "TIFF files must stay below 4 GiB")
+        })?;
+
+    // (tag, type, count, value or offset into the extra data); these must be sorted by tag
+    let mut entries = vec![
+        (IMAGE_WIDTH, LONG, 1, Value::Inline(img.width())),
+        (IMAGE_LENGTH, LONG, 1, Value::Inline(img.height())),
+        (
+            BITS_PER_SAMPLE,
+            SHORT,
+            u32::from(channels),
+            Value::Shorts(vec![bits; channels.into()]),
+        ),
+        (
+            COMPRESSION,
+            SHORT,
+            1,
+            Value::Inline(compression.tag_value()),
+        ),
+        // BlackIsZero for grayscale, RGB otherwise
+        (
+            PHOTOMETRIC_INTERPRETATION,
+            SHORT,
+            1,
+            Value::Inline(if channels <= 2 { 1 } else { 2 }),
+        ),
+        (STRIP_OFFSETS, LONG, 1, Value::DataOffset),
+        (SAMPLES_PER_PIXEL, SHORT, 1, Value::Inline(channels.into())),
+        (ROWS_PER_STRIP, LONG, 1, Value::Inline(img.height())),
+        (STRIP_BYTE_COUNTS, LONG, 1, Value::Inline(len)),
+        // Screens have no physical resolution, so claim the customary 72 dpi
+        (X_RESOLUTION, RATIONAL, 1, Value::Rational(72, 1)),
+        (Y_RESOLUTION, RATIONAL, 1, Value::Rational(72, 1)),
+        // Chunky, i.e. channels interleaved
+        (PLANAR_CONFIGURATION, SHORT, 1, Value::Inline(1)),
+        // Inches
+        (RESOLUTION_UNIT, SHORT, 1, Value::Inline(2)),
+    ];
+    if compression != TiffCompression::None {
+        // Horizontal differencing
+        entries.push((PREDICTOR, SHORT, 1, Value::Inline(2)));
+    }
+    if has_alpha {
+        // Unassociated alpha, i.e. not premultiplied
+        entries.push((EXTRA_SAMPLES, SHORT, 1, Value::Inline(2)));
+    }
+
+    // Header, then the directory, then values too large for their entries, then the pixels
+    let ifd_len = 2 + entries.len() as u32 * ENTRY_LEN + 4;
+    let mut extra = Vec::new();
+    let extra_start = HEADER_LEN + ifd_len;
+    let mut offsets = Vec::with_capacity(entries.len());
+    for (_, _, _, value) in &entries {
+        offsets.push(extra_start + extra.len() as u32);
+        match value {
+            // Up to two shorts fit into the entry itself
+            Value::Shorts(shorts) if shorts.len() > 2 => shorts
+                .iter()
+                .for_each(|s| extra.extend_from_slice(&s.to_le_bytes())),
+            Value::Rational(numerator, denominator) => {
+                extra.extend_from_slice(&numerator.to_le_bytes());
+                extra.extend_from_slice(&denominator.to_le_bytes());
+            }
+            _ => {}
+        }
+    }
+    let data_offset = extra_start + extra.len() as u32;
+
+    let mut out = Vec::with_capacity((extra_start + extra.len() as u32) as usize);
+    out.extend_from_slice(b"II");
+    out.extend_from_slice(&42u16.to_le_bytes());
+    out.extend_from_slice(&HEADER_LEN.to_le_bytes());
+    out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for ((tag, kind, count, value), offset) in entries.iter().zip(offsets) {
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.extend_from_slice(&kind.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+        let field = match value {
+            // Shorts are left-justified, which in little endian is the same as a long
+            Value::Inline(v) => *v,
+            Value::Shorts(shorts) if shorts.len() <= 2 => {
+                u32::from(shorts[0]) | u32::from(*shorts.get(1).unwrap_or(&0)) << 16
+            }
+            Value::Shorts(_) | Value::Rational(..) => offset,
+            Value::DataOffset => data_offset,
+        };
+        out.extend_from_slice(&field.to_le_bytes());
+    }
+    // No further directories
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&extra);
+
+    w.write_all(&out)?;
+    w.write_all(&data)
+}
+
+/// Replaces every sample with its difference to the same channel of the pixel to the left,
+/// which turns gradients into long runs for the compressor.
+fn predict(data: Cow<'_, [u8]>, width: u32, channels: u16, bits: u16) -> Vec<u8> {
+    let mut data = data.into_owned();
+    let channels = usize::from(channels);
+    let row_len = width as usize * channels * usize::from(bits / 8);
+    let sample = |row: &[u8], i: usize| u16::from_le_bytes([row[2 * i], row[2 * i + 1]]);
+    // Back to front, so every difference is taken to the original value
+    for row in data.chunks_exact_mut(row_len.max(1)) {
+        if bits == 16 {
+            for i in (channels..row.len() / 2).rev() {
+                let diff = sample(row, i).wrapping_sub(sample(row, i - channels));
+                row[2 * i..2 * i + 2].copy_from_slice(&diff.to_le_bytes());
+            }
+        } else {
+            for i in (channels..row.len()).rev() {
+                row[i] = row[i].wrapping_sub(row[i - channels]);
+            }
+        }
+    }
+
+    data
+}
+
+/// Compresses the strip with LZW as described in section 13 of the TIFF 6.0 specification.
+///
+/// Codes are packed from the most significant bit, and their width grows one code earlier than
+/// in GIF, which is how libtiff does it and what every reader expects.
+fn lzw(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2);
+    let mut bits = LzwWriter::default();
+    // Longer strings as (code of the prefix, next byte) to their codes
+    let mut table = HashMap::new();
+
+    bits.write(&mut out, LZW_CLEAR);
+    let mut prefix = None;
+    for &byte in data {
+        let current = match prefix {
+            None => {
+                prefix = Some(u16::from(byte));
+                continue;
+            }
+            Some(current) => current,
+        };
+        if let Some(&code) = table.get(&(current, byte)) {
+            prefix = Some(code);
+            continue;
+        }
+
+        bits.write(&mut out, current);
+        table.insert((current, byte), bits.next_code);
+        if bits.advance(&mut out) {
+            table.clear();
+        }
+        prefix = Some(u16::from(byte));
+    }
+    if let Some(current) = prefix {
+        bits.write(&mut out, current);
+        bits.advance(&mut out);
+    }
+    bits.write(&mut out, LZW_END);
+    bits.finish(&mut out);
+
+    out
+}
+
+/// Packs LZW codes from the most significant bit, keeping track of how wide they are.
+struct LzwWriter {
+    buffer: u32,
+    len: u8,
+    /// The code the next table entry gets, which determines the width.
+    next_code: u16,
+    width: u8,
+}
+
+impl Default for LzwWriter {
+    fn default() -> Self {
+        Self {
+            buffer: 0,
+            len: 0,
+            next_code: LZW_FIRST_CODE,
+            width: LZW_MIN_BITS,
+        }
+    }
+}
+
+impl LzwWriter {
+    /// Moves on to the next table entry. Once the table is full, a clear code is written and
+    /// true returned, so the caller starts over as well.
+    fn advance(&mut self, out: &mut Vec<u8>) -> bool {
+        self.next_code += 1;
+        if self.next_code == LZW_MAX_CODE - 1 {
+            self.write(out, LZW_CLEAR);
+            self.next_code = LZW_FIRST_CODE;
+            self.width = LZW_MIN_BITS;
+            return true;
+        }
+        if u32::from(self.next_code) > (1 << self.width) - 1 {
+            self.width += 1;
+        }
+
+        false
+    }
+
+    fn write(&mut self, out: &mut Vec<u8>, code: u16) {
+        self.buffer = self.buffer << self.width | u32::from(code);
+        self.len += self.width;
+        while self.len >= 8 {
+            self.len -= 8;
+            out.push((self.buffer >> self.len) as u8);
+        }
+        self.buffer &= (1 << self.len) - 1;
+    }
+
+    /// Writes out the last bits, padded with zeros.
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if self.len > 0 {
+            out.push((self.buffer << (8 - self.len)) as u8);
+        }
+    }
+}
+
+enum Value {
+    Inline(u32),
+    Shorts(Vec<u16>),
+    Rational(u32, u32),
+    /// Where the pixels start, which is only known once all other values are laid out.
+    DataOffset,
+}
+
+/// Channel count, bits per channel and the samples in file order.
+fn samples(img: &DynamicImage) -> (u16, u16, Cow<'_, [u8]>) {
+    let le = |samples: &[u16]| samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    match img {
+        DynamicImage::ImageLuma8(buf) => (1, 8, Cow::Borrowed(buf.as_raw())),
+        DynamicImage::ImageLumaA8(buf) => (2, 8, Cow::Borrowed(buf.as_raw())),
+        DynamicImage::ImageRgb8(buf) => (3, 8, Cow::Borrowed(buf.as_raw())),
+        DynamicImage::ImageRgba8(buf) => (4, 8, Cow::Borrowed(buf.as_raw())),
+        DynamicImage::ImageLuma16(buf) => (1, 16, Cow::Owned(le(buf.as_raw()))),
+        DynamicImage::ImageLumaA16(buf) => (2, 16, Cow::Owned(le(buf.as_raw()))),
+        DynamicImage::ImageRgb16(buf) => (3, 16, Cow::Owned(le(buf.as_raw()))),
+        DynamicImage::ImageRgba16(buf) => (4, 16, Cow::Owned(le(buf.as_raw()))),
+        img if img.color().has_alpha() => (4, 8, Cow::Owned(img.to_rgba8().into_raw())),
+        img => (3, 8, Cow::Owned(img.to_rgb8().into_raw())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Luma, Rgb, Rgba};
+    use std::convert::TryInto;
+
+    /// The first directory's entries as tag to (type, count, value or offset).
+    fn tags(tiff: &[u8]) -> HashMap<u16, (u16, u32, u32)> {
+        let short = |at: usize| u16::from_le_bytes(tiff[at..at + 2].try_into().unwrap());
+        let long = |at: usize| u32::from_le_bytes(tiff[at..at + 4].try_into().unwrap());
+        assert_eq!(&tiff[..4], b"II*\0");
+        let ifd = long(4) as usize;
+        let mut previous = 0;
+        let tags = (0..usize::from(short(ifd)))
+            .map(|i| {
+                let entry = ifd + 2 + 12 * i;
+                let tag = short(entry);
+                assert!(tag > previous, "tags must be sorted");
+                previous = tag;
+                (tag, (short(entry + 2), long(entry + 4), long(entry + 8)))
+            })
+            .collect();
+        assert_eq!(long(ifd + 2 + 12 * usize::from(short(ifd))), 0);
+        tags
+    }
+
+    /// The value of a single short or long, which is left-justified in its entry.
+    fn value(tags: &HashMap<u16, (u16, u32, u32)>, tag: u16) -> u32 {
+        let (kind, count, value) = tags[&tag];
+        assert_eq!(count, 1);
+        if kind == SHORT {
+            value & 0xffff
+        } else {
+            value
+        }
+    }
+
+    /// The bytes of the only strip.
+    fn strip<'a>(tiff: &'a [u8], tags: &HashMap<u16, (u16, u32, u32)>) -> &'a [u8] {
+        let offset = value(tags, STRIP_OFFSETS) as usize;
+        &tiff[offset..offset + value(tags, STRIP_BYTE_COUNTS) as usize]
+    }
+
+    fn encoded(img: &DynamicImage, compression: TiffCompression) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode(img, compression, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn uncompressed_rgb() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(4, 3, |x, y| {
+            Rgb([x as u8, y as u8, 99])
+        }));
+        let tiff = encoded(&img, TiffCompression::None);
+        let tags = tags(&tiff);
+        assert_eq!(value(&tags, IMAGE_WIDTH), 4);
+        assert_eq!(value(&tags, IMAGE_LENGTH), 3);
+        assert_eq!(value(&tags, COMPRESSION), 1);
+        assert_eq!(value(&tags, PHOTOMETRIC_INTERPRETATION), 2);
+        assert_eq!(value(&tags, SAMPLES_PER_PIXEL), 3);
+        assert_eq!(value(&tags, ROWS_PER_STRIP), 3);
+        assert_eq!(value(&tags, PLANAR_CONFIGURATION), 1);
+        assert!(!tags.contains_key(&EXTRA_SAMPLES));
+        assert!(!tags.contains_key(&PREDICTOR));
+
+        // Three shorts don't fit the entry, so they are stored after the directory
+        let (kind, count, offset) = tags[&BITS_PER_SAMPLE];
+        assert_eq!((kind, count), (SHORT, 3));
+        let offset = offset as usize;
+        assert_eq!(&tiff[offset..offset + 6], [8, 0, 8, 0, 8, 0]);
+
+        assert_eq!(strip(&tiff, &tags), img.to_rgb8().as_raw().as_slice());
+    }
+
+    #[test]
+    fn uncompressed_rgba_keeps_alpha() {
+        let img = DynamicImage::ImageRgba16(ImageBuffer::from_fn(3, 2, |x, y| {
+            Rgba([x as u16 * 1000, y as u16, 0xfffe, 0x8000 + x as u16])
+        }));
+        let tiff = encoded(&img, TiffCompression::None);
+        let tags = tags(&tiff);
+        assert_eq!(value(&tags, SAMPLES_PER_PIXEL), 4);
+        // Unassociated alpha
+        assert_eq!(value(&tags, EXTRA_SAMPLES), 2);
+        let offset = tags[&BITS_PER_SAMPLE].2 as usize;
+        assert_eq!(&tiff[offset..offset + 8], [16, 0, 16, 0, 16, 0, 16, 0]);
+
+        let samples = strip(&tiff, &tags)
+            .chunks_exact(2)
+            .map(|s| u16::from_le_bytes([s[0], s[1]]))
+            .collect::<Vec<_>>();
+        assert_eq!(samples, img.to_rgba16().into_raw());
+    }
+
+    #[test]
+    fn uncompressed_gray() {
+        let img = DynamicImage::ImageLuma8(ImageBuffer::from_fn(2, 2, |x, y| {
+            Luma([(x + 2 * y) as u8 * 60])
+        }));
+        let tiff = encoded(&img, TiffCompression::None);
+        let tags = tags(&tiff);
+        // BlackIsZero, and a single short inline
+        assert_eq!(value(&tags, PHOTOMETRIC_INTERPRETATION), 1);
+        assert_eq!(value(&tags, BITS_PER_SAMPLE), 8);
+        assert_eq!(strip(&tiff, &tags), [0, 60, 120, 180]);
+    }
+
+    /// Decodes TIFF's flavor of LZW, as described in section 13 of the specification.
+    fn unlzw(data: &[u8]) -> Vec<u8> {
+        let reset = |table: &mut Vec<Vec<u8>>| {
+            table.clear();
+            table.extend((0..=255).map(|b| vec![b]));
+            // Clear and end
+            table.extend(vec![Vec::new(); 2]);
+        };
+        let mut table = Vec::new();
+        reset(&mut table);
+        let (mut out, mut pos, mut width) = (Vec::new(), 0, LZW_MIN_BITS);
+        let mut previous: Option<Vec<u8>> = None;
+        loop {
+            let mut code = 0;
+            for _ in 0..width {
+                code = code << 1 | usize::from(data[pos / 8] >> (7 - pos % 8) & 1);
+                pos += 1;
+            }
+            match code as u16 {
+                LZW_CLEAR => {
+                    reset(&mut table);
+                    width = LZW_MIN_BITS;
+                    previous = None;
+                    continue;
+                }
+                LZW_END => break,
+                _ => {}
+            }
+
+            let entry = match (table.get(code), &previous) {
+                (Some(entry), _) => entry.clone(),
+                // The code being defined right now
+                (None, Some(previous)) => [&previous[..], &previous[..1]].concat(),
+                (None, None) => panic!("undefined code {}", code),
+            };
+            if let Some(mut previous) = previous {
+                previous.push(entry[0]);
+                table.push(previous);
+            }
+            out.extend_from_slice(&entry);
+            previous = Some(entry);
+            // One code early, like the encoder
+            if table.len() + 1 >= 1 << width && width < 12 {
+                width += 1;
+            }
+        }
+        out
+    }
+
+    /// Undoes horizontal differencing for 8 or 16-bit samples.
+    fn unpredict(mut data: Vec<u8>, width: usize, channels: usize, bits: usize) -> Vec<u8> {
+        let bytes = bits / 8;
+        for row in data.chunks_exact_mut(width * channels * bytes) {
+            for i in channels..row.len() / bytes {
+                if bytes == 2 {
+                    let sample =
+                        |row: &[u8], i: usize| u16::from_le_bytes([row[2 * i], row[2 * i + 1]]);
+                    let sum = sample(row, i).wrapping_add(sample(row, i - channels));
+                    row[2 * i..2 * i + 2].copy_from_slice(&sum.to_le_bytes());
+                } else {
+                    row[i] = row[i].wrapping_add(row[i - channels]);
+                }
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn compressed_strips_decode_back() {
+        // Noise fills the LZW table, so it has to start over at least once
+        let noise = |x: u32, y: u32| ((x * 7 + y * 13).wrapping_mul(2_654_435_761) >> 24) as u8;
+        let images = [
+            DynamicImage::ImageRgba8(ImageBuffer::from_fn(300, 40, |x, y| {
+                Rgba([noise(x, y), x as u8, (y * 6) as u8, noise(y, x)])
+            })),
+            DynamicImage::ImageRgb16(ImageBuffer::from_fn(50, 20, |x, y| {
+                Rgb([
+                    x as u16 * 1300,
+                    0xffff - y as u16,
+                    u16::from(noise(x, y)) << 8,
+                ])
+            })),
+            DynamicImage::ImageLuma8(ImageBuffer::from_pixel(100, 100, Luma([42]))),
+        ];
+        for img in &images {
+            let (channels, bits, raw) = samples(img);
+            for &(compression, tag) in &[
+                (TiffCompression::None, 1),
+                (TiffCompression::Lzw, 5),
+                (TiffCompression::Deflate, 8),
+            ] {
+                let tiff = encoded(img, compression);
+                let tags = tags(&tiff);
+                assert_eq!(value(&tags, COMPRESSION), tag);
+                let data = strip(&tiff, &tags);
+                let decoded = match compression {
+                    TiffCompression::None => {
+                        assert!(!tags.contains_key(&PREDICTOR));
+                        data.to_vec()
+                    }
+                    TiffCompression::Lzw | TiffCompression::Deflate => {
+                        assert_eq!(value(&tags, PREDICTOR), 2);
+                        let data = if compression == TiffCompression::Lzw {
+                            unlzw(data)
+                        } else {
+                            miniz_oxide::inflate::decompress_to_vec_zlib(data).unwrap()
+                        };
+                        unpredict(data, img.width() as usize, channels.into(), bits.into())
+                    }
+                };
+                assert!(
+                    decoded == *raw,
+                    "{:?} of {:?} doesn't decode back",
+                    compression,
+                    img.color()
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Lossless geometric transforms of the final image.
+
+use image::DynamicImage;
+use std::str::FromStr;
+
+/// Clockwise rotation in quarter turns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "90" => Ok(Rotation::Rotate90),
+            "180" => Ok(Rotation::Rotate180),
+            "270" => Ok(Rotation::Rotate270),
+            _ => Err(format!(
+                "Invalid rotation '{}'. Expected 90, 180 or 270.",
+                s
+            )),
+        }
+    }
+}
+
+/// Mirroring along one axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flip {
+    /// Swap left and right.
+    Horizontal,
+    /// Swap top and bottom.
+    Vertical,
+}
+
+impl FromStr for Flip {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "h" | "horizontal" => Ok(Flip::Horizontal),
+            "v" | "vertical" => Ok(Flip::Vertical),
+            _ => Err(format!("Invalid flip '{}'. Expected h or v.", s)),
+        }
+    }
+}
+
+/// Flips, then rotates the image. Alpha is carried along unchanged.
+pub fn transform(
+    img: DynamicImage,
+    flip: Option<Flip>,
+    rotation: Option<Rotation>,
+) -> DynamicImage {
+    let img = match flip {
+        Some(Flip::Horizontal) => img.fliph(),
+        Some(Flip::Vertical) => img.flipv(),
+        None => img,
+    };
+
+    match rotation {
+        Some(Rotation::Rotate90) => img.rotate90(),
+        Some(Rotation::Rotate180) => img.rotate180(),
+        Some(Rotation::Rotate270) => img.rotate270(),
+        None => img,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgba, RgbaImage};
+
+    /// 3x2, with a semi-transparent marker pixel in the top left corner.
+    fn marked() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(3, 2, |x, y| {
+            if (x, y) == (0, 0) {
+                Rgba([255, 0, 0, 100])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        }))
+    }
+
+    /// Coordinates of the marker.
+    fn marker(img: &DynamicImage) -> (u32, u32) {
+        let found = img
+            .pixels()
+            .filter(|(_, _, p)| *p == Rgba([255, 0, 0, 100]))
+            .map(|(x, y, _)| (x, y))
+            .collect::<Vec<_>>();
+        assert_eq!(found.len(), 1);
+        found[0]
+    }
+
+    #[test]
+    fn quarter_turns_swap_dimensions() {
+        for &(rotation, dimensions, corner) in &[
+            (Rotation::Rotate90, (2, 3), (1, 0)),
+            (Rotation::Rotate180, (3, 2), (2, 1)),
+            (Rotation::Rotate270, (2, 3), (0, 2)),
+        ] {
+            let img = transform(marked(), None, Some(rotation));
+            assert_eq!(img.dimensions(), dimensions, "{:?}", rotation);
+            // Also checks that alpha is kept
+            assert_eq!(marker(&img), corner, "{:?}", rotation);
+        }
+    }
+
+    #[test]
+    fn flips() {
+        assert_eq!(
+            marker(&transform(marked(), Some(Flip::Horizontal), None)),
+            (2, 0)
+        );
+        assert_eq!(
+            marker(&transform(marked(), Some(Flip::Vertical), None)),
+            (0, 1)
+        );
+    }
+
+    #[test]
+    fn flip_comes_before_rotation() {
+        // Flipping first moves the marker to the top right, which a clockwise turn takes to
+        // the bottom right; the other way around, it would end up in the top left
+        let img = transform(marked(), Some(Flip::Horizontal), Some(Rotation::Rotate90));
+        assert_eq!(img.dimensions(), (2, 3));
+        assert_eq!(marker(&img), (1, 2));
+    }
+}
@@ -0,0 +1,59 @@
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xproto::{Atom, ChangeWindowAttributesAux, ConnectionExt, EventMask, Window},
+        Event,
+    },
+};
+
+use crate::{background::BACKGROUND_ATOMS, error::Result};
+
+/// Tracks changes to the background properties of a root window.
+///
+/// The watcher never blocks; callers are expected to wait for the connection to become readable
+/// themselves and then ask [`BackgroundWatcher::changed`] whether anything relevant happened.
+pub struct BackgroundWatcher<'c, C: Connection> {
+    c: &'c C,
+    root: Window,
+    atoms: Vec<Atom>,
+}
+
+impl<'c, C: Connection> BackgroundWatcher<'c, C> {
+    /// Subscribes to property changes on `root`.
+    pub fn new(c: &'c C, root: Window) -> Result<Self> {
+        Self::with_properties(c, root, BACKGROUND_ATOMS)
+    }
+
+    /// Like [`BackgroundWatcher::new`], but only reports changes to the given properties.
+    pub fn with_properties(c: &'c C, root: Window, properties: &[&str]) -> Result<Self> {
+        // Create the atoms if necessary so we also notice the first background being set
+        let atom_cookies = properties
+            .iter()
+            .map(|name| c.intern_atom(false, name.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let atoms = atom_cookies
+            .into_iter()
+            .map(|cookie| cookie.reply().map(|reply| reply.atom))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        c.change_window_attributes(
+            root,
+            &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+        )?
+        .check()?;
+
+        Ok(Self { c, root, atoms })
+    }
+
+    /// Processes all queued events and reports whether any background property changed.
+    pub fn changed(&self) -> Result<bool> {
+        let mut changed = false;
+        while let Some(event) = self.c.poll_for_event()? {
+            if let Event::PropertyNotify(event) = event {
+                changed |= event.window == self.root && self.atoms.contains(&event.atom);
+            }
+        }
+
+        Ok(changed)
+    }
+}
@@ -0,0 +1,1118 @@
+//! Minimal encoder for [lossless WebP](https://developers.google.com/speed/webp/docs/webp_lossless_bitstream_specification).
+//!
+//! The image crate can only decode WebP, so this writes the VP8L bitstream itself. It uses the
+//! subtract-green and predictor transforms and copies from the left and from above, which is
+//! nowhere near libwebp, but already a lot smaller than PNG for most wallpapers.
+//!
+//! There is no VP8 encoder for truly lossy WebP. Instead, like libwebp's near-lossless mode,
+//! colors in busy areas can be rounded before encoding, which still gives VP8L files, but
+//! smaller ones.
+
+use image::{DynamicImage, GenericImageView};
+use std::{cmp::Reverse, collections::BinaryHeap, io::Write};
+
+use crate::{
+    error::{Result, XbgdumpError},
+    output::Format,
+};
+
+/// Neither side of an image may be longer than this.
+pub(crate) const MAX_DIMENSION: u32 = 1 << 14;
+
+const SIGNATURE: u32 = 0x2f;
+const PREDICTOR_TRANSFORM: u32 = 0;
+const SUBTRACT_GREEN_TRANSFORM: u32 = 2;
+/// Predictor modes are chosen per block of 2^PREDICTOR_BITS by 2^PREDICTOR_BITS pixels.
+const PREDICTOR_BITS: u32 = 5;
+/// Left, top, their average, select and clamped gradient; none of them need the top right pixel,
+/// which the format handles oddly at the right edge.
+const PREDICTOR_MODES: [u8; 5] = [1, 2, 7, 11, 12];
+
+const NUM_LITERALS: usize = 256;
+const NUM_LENGTH_CODES: usize = 24;
+const NUM_DISTANCE_CODES: usize = 40;
+/// Green and copy lengths, red, blue, alpha and distances each get their own prefix code.
+const ALPHABET_SIZES: [usize; 5] = [
+    NUM_LITERALS + NUM_LENGTH_CODES,
+    NUM_LITERALS,
+    NUM_LITERALS,
+    NUM_LITERALS,
+    NUM_DISTANCE_CODES,
+];
+const MAX_CODE_LENGTH: u8 = 15;
+/// The code lengths are prefix-coded as well, but only with codes up to 7 bits.
+const MAX_CODE_LENGTH_CODE_LENGTH: u8 = 7;
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    17, 18, 0, 1, 2, 3, 4, 5, 16, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+const REPEAT_PREVIOUS: usize = 16;
+const REPEAT_ZERO_SHORT: usize = 17;
+const REPEAT_ZERO_LONG: usize = 18;
+
+const MIN_COPY: usize = 3;
+const MAX_COPY: usize = 4096;
+/// The first 120 distance codes are reserved for nearby pixels, the rest only reach this far.
+const MAX_DISTANCE: usize = (1 << 20) - 120;
+const HASH_BITS: u32 = 16;
+/// Near-lossless rounding is skipped for images smaller than this in both directions.
+const MIN_NEAR_LOSSLESS_SIZE: usize = 64;
+
+/// Encodes the image as lossless WebP, keeping an alpha channel only if the image has one.
+///
+/// With a `quality` below 100, colors are rounded near-losslessly first, see
+/// [`near_lossless`]. Images are reduced to 8 bits per channel, as that's all the format
+/// supports.
+pub fn encode(img: &DynamicImage, quality: Option<u8>, w: &mut impl Write) -> Result<()> {
+    let (width, height) = img.dimensions();
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(XbgdumpError::ImageTooLarge {
+            format: Format::Webp,
+            max: MAX_DIMENSION,
+        });
+    }
+
+    let mut pixels = img
+        .to_rgba8()
+        .pixels()
+        .map(|p| {
+            let [r, g, b, a] = p.0;
+            u32::from_be_bytes([a, r, g, b])
+        })
+        .collect::<Vec<_>>();
+    if let Some(quality) = quality {
+        near_lossless(&mut pixels, width as usize, height as usize, quality);
+    }
+
+    let mut bits = BitWriter::default();
+    bits.write(SIGNATURE, 8);
+    bits.write(width - 1, 14);
+    bits.write(height - 1, 14);
+    bits.write(img.color().has_alpha().into(), 1);
+    // Version
+    bits.write(0, 3);
+
+    // Transforms are undone in reverse order, so subtracting green comes first here
+    subtract_green(&mut pixels);
+    bits.write(1, 1);
+    bits.write(SUBTRACT_GREEN_TRANSFORM, 2);
+
+    let (width, height) = (width as usize, height as usize);
+    let (residuals, modes) = predict(&pixels, width, height);
+    bits.write(1, 1);
+    bits.write(PREDICTOR_TRANSFORM, 2);
+    bits.write(PREDICTOR_BITS - 2, 3);
+    write_image(&mut bits, &modes, blocks(width), false);
+
+    bits.write(0, 1);
+    write_image(&mut bits, &residuals, width, true);
+
+    let data = bits.finish();
+    // RIFF chunks are padded to an even length
+    let padding = data.len() % 2;
+    let mut header = Vec::with_capacity(20);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&((12 + data.len() + padding) as u32).to_le_bytes());
+    header.extend_from_slice(b"WEBPVP8L");
+    header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    w.write_all(&header)?;
+    w.write_all(&data)?;
+    w.write_all(&[0][..padding])?;
+
+    Ok(())
+}
+
+/// Collects bits starting with the least significant one, as the format wants them.
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.pending |= u64::from(value) << self.pending_bits;
+        self.pending_bits += bits;
+        while self.pending_bits >= 8 {
+            // Truncation intended, the rest stays pending
+            self.out.push(self.pending as u8);
+            self.pending >>= 8;
+            self.pending_bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.pending_bits > 0 {
+            self.out.push(self.pending as u8);
+        }
+
+        self.out
+    }
+}
+
+/// Rounds the color channels of pixels that differ a lot from their neighbors to multiples of
+/// a power of two, like libwebp's near-lossless mode, so that the residuals repeat more often.
+///
+/// Quality 100 changes nothing, every 20 below it doubles the rounding step, up to 32 at
+/// quality 0. Smooth areas like gradients, the edges of the image and alpha stay exact.
+fn near_lossless(pixels: &mut [u32], width: usize, height: usize, quality: u8) {
+    let max_bits = 5 - u32::from(quality.min(100)) / 20;
+    if (width < MIN_NEAR_LOSSLESS_SIZE && height < MIN_NEAR_LOSSLESS_SIZE) || height < 3 {
+        return;
+    }
+
+    // Each pass looks at the previous one's result, with finer steps
+    for bits in (1..=max_bits).rev() {
+        let limit = 1 << bits;
+        let source = pixels.to_vec();
+        for y in 1..height - 1 {
+            for x in 1..width.saturating_sub(1) {
+                let i = y * width + x;
+                let smooth = [i - 1, i + 1, i - width, i + width]
+                    .iter()
+                    .all(|&j| is_near(source[i], source[j], limit));
+                if !smooth {
+                    pixels[i] = round_colors(source[i], bits);
+                }
+            }
+        }
+    }
+}
+
+/// Whether no color channel differs by `limit` or more.
+fn is_near(a: u32, b: u32, limit: i16) -> bool {
+    a.to_le_bytes()[..3]
+        .iter()
+        .zip(&b.to_le_bytes()[..3])
+        .all(|(&a, &b)| (i16::from(a) - i16::from(b)).abs() < limit)
+}
+
+/// Rounds the color channels to the closest multiple of `1 << bits`, or to 255, with ties
+/// going to the even multiple.
+fn round_colors(argb: u32, bits: u32) -> u32 {
+    let mask = (1 << bits) - 1;
+    let round = |c: u8| {
+        let c = u32::from(c);
+        let biased = c + (mask >> 1) + ((c >> bits) & 1);
+        // Truncation safe because of the check
+        (if biased > 0xff { 0xff } else { biased & !mask }) as u8
+    };
+    let [b, g, r, a] = argb.to_le_bytes();
+
+    u32::from_le_bytes([round(b), round(g), round(r), a])
+}
+
+/// Number of predictor blocks needed to cover `size` pixels.
+fn blocks(size: usize) -> usize {
+    (size + (1 << PREDICTOR_BITS) - 1) >> PREDICTOR_BITS
+}
+
+fn subtract_green(pixels: &mut [u32]) {
+    for p in pixels {
+        let green = (*p >> 8) & 0xff;
+        let red = (*p >> 16).wrapping_sub(green) & 0xff;
+        let blue = p.wrapping_sub(green) & 0xff;
+        *p = (*p & 0xff00_ff00) | red << 16 | blue;
+    }
+}
+
+/// Applies `f` to each pair of channels.
+fn per_channel(a: u32, b: u32, f: impl Fn(u8, u8) -> u8) -> u32 {
+    let (a, b) = (a.to_le_bytes(), b.to_le_bytes());
+    u32::from_le_bytes([f(a[0], b[0]), f(a[1], b[1]), f(a[2], b[2]), f(a[3], b[3])])
+}
+
+fn average2(a: u32, b: u32) -> u32 {
+    per_channel(a, b, |a, b| ((u16::from(a) + u16::from(b)) / 2) as u8)
+}
+
+/// Whichever of left and top is closer to the gradient `left + top - top_left`.
+fn select(left: u32, top: u32, top_left: u32) -> u32 {
+    let distance = |a: u32, b: u32| -> i32 {
+        a.to_le_bytes()
+            .iter()
+            .zip(b.to_le_bytes())
+            .map(|(&a, b)| (i32::from(a) - i32::from(b)).abs())
+            .sum()
+    };
+
+    if distance(left, top_left) <= distance(top, top_left) {
+        top
+    } else {
+        left
+    }
+}
+
+/// The gradient `left + top - top_left`, clamped to the valid range.
+fn clamp_add_subtract_full(left: u32, top: u32, top_left: u32) -> u32 {
+    let (left, top, top_left) = (
+        left.to_le_bytes(),
+        top.to_le_bytes(),
+        top_left.to_le_bytes(),
+    );
+    let channel = |i: usize| {
+        (i16::from(left[i]) + i16::from(top[i]) - i16::from(top_left[i])).clamp(0, 255) as u8
+    };
+
+    u32::from_le_bytes([channel(0), channel(1), channel(2), channel(3)])
+}
+
+/// The prediction for the pixel at `x`, `y`. The top row and left column always predict from
+/// their only neighbor, regardless of the mode.
+fn prediction(pixels: &[u32], width: usize, x: usize, y: usize, mode: u8) -> u32 {
+    let i = y * width + x;
+    match (x, y) {
+        (0, 0) => 0xff00_0000,
+        (_, 0) => pixels[i - 1],
+        (0, _) => pixels[i - width],
+        _ => {
+            let (left, top, top_left) = (pixels[i - 1], pixels[i - width], pixels[i - width - 1]);
+            match mode {
+                1 => left,
+                2 => top,
+                7 => average2(left, top),
+                11 => select(left, top, top_left),
+                12 => clamp_add_subtract_full(left, top, top_left),
+                _ => unreachable!("predictor mode {} is never chosen", mode),
+            }
+        }
+    }
+}
+
+fn residual(pixel: u32, prediction: u32) -> u32 {
+    per_channel(pixel, prediction, u8::wrapping_sub)
+}
+
+/// Picks the mode with the smallest residuals for each block and returns the residuals along
+/// with the modes, stored in the green channel of a smaller image as the format wants them.
+fn predict(pixels: &[u32], width: usize, height: usize) -> (Vec<u32>, Vec<u32>) {
+    let size = 1 << PREDICTOR_BITS;
+    let mut residuals = vec![0; pixels.len()];
+    let mut modes = Vec::with_capacity(blocks(width) * blocks(height));
+
+    for block_y in (0..height).step_by(size) {
+        for block_x in (0..width).step_by(size) {
+            let block = || {
+                (block_y..height.min(block_y + size))
+                    .flat_map(move |y| (block_x..width.min(block_x + size)).map(move |x| (x, y)))
+            };
+            let cost = |mode| -> u32 {
+                block()
+                    .map(|(x, y)| {
+                        let r =
+                            residual(pixels[y * width + x], prediction(pixels, width, x, y, mode));
+                        // Small differences in either direction are cheap
+                        r.to_le_bytes()
+                            .iter()
+                            .map(|&c| u32::from((c as i8).unsigned_abs()))
+                            .sum::<u32>()
+                    })
+                    .sum()
+            };
+            // Unwrap safe because there are several modes to choose from
+            let mode = PREDICTOR_MODES
+                .iter()
+                .copied()
+                .min_by_key(|&m| cost(m))
+                .unwrap();
+
+            for (x, y) in block() {
+                let i = y * width + x;
+                residuals[i] = residual(pixels[i], prediction(pixels, width, x, y, mode));
+            }
+            modes.push(0xff00_0000 | u32::from(mode) << 8);
+        }
+    }
+
+    (residuals, modes)
+}
+
+#[derive(Clone, Copy)]
+enum Token {
+    Literal(u32),
+    /// `distance` is already mapped to a distance code.
+    Copy {
+        length: u16,
+        distance: u32,
+    },
+}
+
+/// Splits a copy length or distance code into its prefix symbol and the extra bits following it.
+fn prefix_encode(value: usize) -> (usize, u32, u32) {
+    let d = value - 1;
+    if d < 4 {
+        return (d, 0, 0);
+    }
+
+    let highest = usize::BITS - 1 - d.leading_zeros();
+    let extra_bits = highest - 1;
+    let symbol = 2 * highest as usize + ((d >> extra_bits) & 1);
+
+    (symbol, extra_bits, (d & ((1 << extra_bits) - 1)) as u32)
+}
+
+/// Maps a distance in pixels to a distance code, using the short codes for the pixel to the left
+/// and the one above.
+fn distance_code(distance: usize, width: usize) -> u32 {
+    if distance == 1 {
+        2
+    } else if distance == width {
+        1
+    } else {
+        (distance + 120) as u32
+    }
+}
+
+/// Greedily replaces repeated pixels with copies from the left, from above or from wherever
+/// the same two pixels showed up last.
+fn backward_references(pixels: &[u32], width: usize) -> Vec<Token> {
+    let hash = |i: usize| {
+        let pair = u64::from(pixels[i]) << 32 | u64::from(pixels[i + 1]);
+        (pair.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - HASH_BITS)) as usize
+    };
+    let mut last_seen = vec![usize::MAX; 1 << HASH_BITS];
+    let mut tokens = Vec::new();
+
+    let mut i = 0;
+    while i < pixels.len() {
+        let max_length = (pixels.len() - i).min(MAX_COPY);
+        let mut best = (0, 0);
+        if max_length >= MIN_COPY {
+            let h = hash(i);
+            let seen = last_seen[h];
+            last_seen[h] = i;
+
+            let candidates = [Some(1), Some(width), (seen != usize::MAX).then(|| i - seen)];
+            for distance in candidates.iter().flatten().copied() {
+                if distance > i.min(MAX_DISTANCE) {
+                    continue;
+                }
+                let length = (0..max_length)
+                    .take_while(|&k| pixels[i + k] == pixels[i + k - distance])
+                    .count();
+                if length > best.0 {
+                    best = (length, distance);
+                }
+            }
+        }
+
+        if best.0 >= MIN_COPY {
+            for j in i + 1..(i + best.0).min(pixels.len() - 1) {
+                last_seen[hash(j)] = j;
+            }
+            tokens.push(Token::Copy {
+                // Truncation safe because copies are at most MAX_COPY long
+                length: best.0 as u16,
+                distance: distance_code(best.1, width),
+            });
+            i += best.0;
+        } else {
+            tokens.push(Token::Literal(pixels[i]));
+            i += 1;
+        }
+    }
+
+    tokens
+}
+
+/// Writes the pixels with one set of prefix codes, computed from their statistics.
+///
+/// Only the main image has to say that it doesn't use several sets, the predictor modes
+/// can't.
+fn write_image(bits: &mut BitWriter, pixels: &[u32], width: usize, is_main: bool) {
+    let tokens = backward_references(pixels, width);
+
+    let mut histograms = ALPHABET_SIZES
+        .iter()
+        .map(|&size| vec![0; size])
+        .collect::<Vec<_>>();
+    for &token in &tokens {
+        match token {
+            Token::Literal(argb) => {
+                for (histogram, shift) in histograms.iter_mut().zip([8, 16, 0, 24]) {
+                    histogram[(argb >> shift) as usize & 0xff] += 1;
+                }
+            }
+            Token::Copy { length, distance } => {
+                histograms[0][NUM_LITERALS + prefix_encode(length.into()).0] += 1;
+                histograms[4][prefix_encode(distance as usize).0] += 1;
+            }
+        }
+    }
+    let codes = histograms
+        .iter()
+        .map(|histogram| PrefixCode::new(histogram, MAX_CODE_LENGTH))
+        .collect::<Vec<_>>();
+
+    // No color cache
+    bits.write(0, 1);
+    if is_main {
+        // No meta prefix codes
+        bits.write(0, 1);
+    }
+    for code in &codes {
+        code.write_header(bits);
+    }
+
+    for token in tokens {
+        match token {
+            Token::Literal(argb) => {
+                for (code, shift) in codes.iter().zip([8, 16, 0, 24]) {
+                    code.write(bits, (argb >> shift) as usize & 0xff);
+                }
+            }
+            Token::Copy { length, distance } => {
+                let (symbol, extra_bits, extra) = prefix_encode(length.into());
+                codes[0].write(bits, NUM_LITERALS + symbol);
+                bits.write(extra, extra_bits);
+                let (symbol, extra_bits, extra) = prefix_encode(distance as usize);
+                codes[4].write(bits, symbol);
+                bits.write(extra, extra_bits);
+            }
+        }
+    }
+}
+
+/// A canonical prefix code, like DEFLATE's Huffman codes.
+struct PrefixCode {
+    lengths: Vec<u8>,
+    /// Bit-reversed, since codes are read starting with their most significant bit.
+    codes: Vec<u32>,
+    /// Codes with only one symbol take up no bits at all.
+    single: bool,
+}
+
+impl PrefixCode {
+    fn new(counts: &[u32], max_length: u8) -> Self {
+        let lengths = code_lengths(counts, max_length);
+
+        let mut next_code = [0; MAX_CODE_LENGTH as usize + 1];
+        let mut code = 0;
+        for (length, next) in next_code.iter_mut().enumerate().skip(1) {
+            let shorter = lengths
+                .iter()
+                .filter(|&&l| usize::from(l) == length - 1 && l > 0);
+            code = (code + shorter.count() as u32) << 1;
+            *next = code;
+        }
+        let codes = lengths
+            .iter()
+            .map(|&length| {
+                if length == 0 {
+                    return 0;
+                }
+                let code = &mut next_code[usize::from(length)];
+                *code += 1;
+                (*code - 1).reverse_bits() >> (32 - u32::from(length))
+            })
+            .collect();
+
+        Self {
+            single: lengths.iter().filter(|&&l| l > 0).count() <= 1,
+            lengths,
+            codes,
+        }
+    }
+
+    fn write(&self, bits: &mut BitWriter, symbol: usize) {
+        if !self.single {
+            bits.write(self.codes[symbol], self.lengths[symbol].into());
+        }
+    }
+
+    /// Describes the code, either by listing its one or two symbols or by its code lengths.
+    fn write_header(&self, bits: &mut BitWriter) {
+        let used = (0..self.lengths.len())
+            .filter(|&s| self.lengths[s] > 0)
+            .collect::<Vec<_>>();
+        if used.len() <= 2 && used.iter().all(|&s| s < NUM_LITERALS) {
+            // A code without symbols is never used, so any symbol will do
+            let symbols = if used.is_empty() { vec![0] } else { used };
+            bits.write(1, 1);
+            bits.write(symbols.len() as u32 - 1, 1);
+            if symbols[0] < 2 {
+                bits.write(0, 1);
+                bits.write(symbols[0] as u32, 1);
+            } else {
+                bits.write(1, 1);
+                bits.write(symbols[0] as u32, 8);
+            }
+            if let Some(&second) = symbols.get(1) {
+                bits.write(second as u32, 8);
+            }
+            return;
+        }
+
+        let tokens = code_length_tokens(&self.lengths);
+        let mut counts = [0; CODE_LENGTH_ORDER.len()];
+        for &(symbol, _) in &tokens {
+            counts[symbol] += 1;
+        }
+        let code = PrefixCode::new(&counts, MAX_CODE_LENGTH_CODE_LENGTH);
+        let stored = CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|&s| code.lengths[s] > 0)
+            .map_or(0, |i| i + 1)
+            .max(4);
+
+        bits.write(0, 1);
+        bits.write(stored as u32 - 4, 4);
+        for &symbol in &CODE_LENGTH_ORDER[..stored] {
+            bits.write(code.lengths[symbol].into(), 3);
+        }
+        // Lengths for the whole alphabet follow, without an early end
+        bits.write(0, 1);
+        for (symbol, extra) in tokens {
+            code.write(bits, symbol);
+            match symbol {
+                REPEAT_PREVIOUS => bits.write(extra, 2),
+                REPEAT_ZERO_SHORT => bits.write(extra, 3),
+                REPEAT_ZERO_LONG => bits.write(extra, 7),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Huffman code lengths for the given symbol counts, limited to `max_length`.
+fn code_lengths(counts: &[u32], max_length: u8) -> Vec<u8> {
+    let mut lengths = vec![0; counts.len()];
+    let used = (0..counts.len())
+        .filter(|&s| counts[s] > 0)
+        .collect::<Vec<_>>();
+    if used.len() <= 1 {
+        for &symbol in &used {
+            lengths[symbol] = 1;
+        }
+        return lengths;
+    }
+
+    // Rare symbols are made more common until the tree is shallow enough
+    let mut min_count = 1;
+    loop {
+        // The leaves come first, then each inner node after its children
+        let mut parents = vec![0; used.len()];
+        let mut heap = used
+            .iter()
+            .enumerate()
+            .map(|(node, &symbol)| Reverse((u64::from(counts[symbol]).max(min_count), node)))
+            .collect::<BinaryHeap<_>>();
+        while let (Some(Reverse((a, left))), Some(Reverse((b, right)))) = (heap.pop(), heap.pop()) {
+            let node = parents.len();
+            parents.push(0);
+            parents[left] = node;
+            parents[right] = node;
+            heap.push(Reverse((a + b, node)));
+        }
+
+        // Walking down from the root, which is the last node
+        let mut depths = vec![0; parents.len()];
+        for node in (0..parents.len() - 1).rev() {
+            depths[node] = depths[parents[node]] + 1;
+        }
+        if depths[..used.len()]
+            .iter()
+            .all(|&d| d <= u32::from(max_length))
+        {
+            for (leaf, &symbol) in used.iter().enumerate() {
+                // Truncation safe because of the check above
+                lengths[symbol] = depths[leaf] as u8;
+            }
+            return lengths;
+        }
+
+        min_count *= 2;
+    }
+}
+
+/// Run-length encodes code lengths into symbols of the code length code and their extra bits.
+fn code_length_tokens(lengths: &[u8]) -> Vec<(usize, u32)> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let length = lengths[i];
+        let run = lengths[i..].iter().take_while(|&&l| l == length).count();
+        if length == 0 && run >= 3 {
+            let run = run.min(138);
+            if run >= 11 {
+                tokens.push((REPEAT_ZERO_LONG, run as u32 - 11));
+            } else {
+                tokens.push((REPEAT_ZERO_SHORT, run as u32 - 3));
+            }
+            i += run;
+            continue;
+        }
+
+        tokens.push((length.into(), 0));
+        i += 1;
+        let mut repeats = run - 1;
+        while length != 0 && repeats >= 3 {
+            let chunk = repeats.min(6);
+            tokens.push((REPEAT_PREVIOUS, chunk as u32 - 3));
+            i += chunk;
+            repeats -= chunk;
+        }
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+    use std::{collections::HashMap, convert::TryInto};
+
+    /// Reads bits starting with the least significant one.
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn read(&mut self, bits: u32) -> u32 {
+            let mut value = 0;
+            for i in 0..bits {
+                let byte = self.data[self.pos / 8];
+                value |= u32::from(byte >> (self.pos % 8) & 1) << i;
+                self.pos += 1;
+            }
+            value
+        }
+    }
+
+    /// A canonical prefix code for decoding.
+    struct Decoder {
+        symbols: HashMap<(u32, u32), usize>,
+        single: Option<usize>,
+    }
+
+    impl Decoder {
+        fn from_lengths(lengths: &[u8]) -> Self {
+            let used = (0..lengths.len())
+                .filter(|&s| lengths[s] > 0)
+                .collect::<Vec<_>>();
+            if used.len() == 1 {
+                // A lone symbol takes no bits at all
+                return Decoder {
+                    symbols: HashMap::new(),
+                    single: Some(used[0]),
+                };
+            }
+
+            let mut symbols = HashMap::new();
+            let mut code = 0;
+            for length in 1..=15 {
+                for &s in &used {
+                    if u32::from(lengths[s]) == length {
+                        symbols.insert((length, code), s);
+                        code += 1;
+                    }
+                }
+                code <<= 1;
+            }
+            Decoder {
+                symbols,
+                single: None,
+            }
+        }
+
+        fn read(&self, bits: &mut BitReader) -> usize {
+            if let Some(s) = self.single {
+                return s;
+            }
+            let mut code = 0;
+            for length in 1..=15 {
+                code = code << 1 | bits.read(1);
+                if let Some(&s) = self.symbols.get(&(length, code)) {
+                    return s;
+                }
+            }
+            panic!("invalid prefix code");
+        }
+    }
+
+    fn read_code(bits: &mut BitReader, alphabet: usize) -> Decoder {
+        let mut lengths = vec![0; alphabet];
+        if bits.read(1) == 1 {
+            let count = bits.read(1) + 1;
+            let first_bits = if bits.read(1) == 1 { 8 } else { 1 };
+            lengths[bits.read(first_bits) as usize] = 1;
+            if count == 2 {
+                lengths[bits.read(8) as usize] = 1;
+            }
+            return Decoder::from_lengths(&lengths);
+        }
+
+        let order = [
+            17, 18, 0, 1, 2, 3, 4, 5, 16, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+        ];
+        let mut length_lengths = [0; 19];
+        for &s in &order[..bits.read(4) as usize + 4] {
+            length_lengths[s] = bits.read(3) as u8;
+        }
+        let length_code = Decoder::from_lengths(&length_lengths);
+        let mut remaining = alphabet;
+        if bits.read(1) == 1 {
+            let length_bits = 2 + 2 * bits.read(3);
+            remaining = 2 + bits.read(length_bits) as usize;
+        }
+
+        let mut previous = 8;
+        let mut symbol = 0;
+        while symbol < alphabet && remaining > 0 {
+            remaining -= 1;
+            match length_code.read(bits) {
+                16 => {
+                    for _ in 0..3 + bits.read(2) {
+                        lengths[symbol] = previous;
+                        symbol += 1;
+                    }
+                }
+                17 => symbol += 3 + bits.read(3) as usize,
+                18 => symbol += 11 + bits.read(7) as usize,
+                length => {
+                    lengths[symbol] = length as u8;
+                    if length != 0 {
+                        previous = length as u8;
+                    }
+                    symbol += 1;
+                }
+            }
+        }
+        Decoder::from_lengths(&lengths)
+    }
+
+    fn read_prefixed(bits: &mut BitReader, prefix: usize) -> usize {
+        if prefix < 4 {
+            return prefix + 1;
+        }
+        let extra = (prefix - 2) >> 1;
+        let offset = (2 + (prefix & 1)) << extra;
+        offset + bits.read(extra as u32) as usize + 1
+    }
+
+    fn read_image(bits: &mut BitReader, width: usize, height: usize, main: bool) -> Vec<u32> {
+        assert_eq!(bits.read(1), 0, "no color cache");
+        if main {
+            assert_eq!(bits.read(1), 0, "no meta prefix codes");
+        }
+        let codes = [280, 256, 256, 256, 40]
+            .iter()
+            .map(|&size| read_code(bits, size))
+            .collect::<Vec<_>>();
+
+        let mut pixels = Vec::<u32>::with_capacity(width * height);
+        while pixels.len() < width * height {
+            let green = codes[0].read(bits);
+            if green < 256 {
+                let red = codes[1].read(bits) as u32;
+                let blue = codes[2].read(bits) as u32;
+                let alpha = codes[3].read(bits) as u32;
+                pixels.push(alpha << 24 | red << 16 | (green as u32) << 8 | blue);
+                continue;
+            }
+
+            let length = read_prefixed(bits, green - 256);
+            let distance_code = codes[4].read(bits);
+            let distance = match read_prefixed(bits, distance_code) {
+                1 => width,
+                2 => 1,
+                code @ 3..=120 => panic!("distance code {} isn't written", code),
+                code => code - 120,
+            };
+            for _ in 0..length {
+                pixels.push(pixels[pixels.len() - distance]);
+            }
+        }
+        assert_eq!(pixels.len(), width * height, "copy past the end");
+        pixels
+    }
+
+    fn channels(argb: u32) -> [i32; 4] {
+        let [a, r, g, b] = argb.to_be_bytes();
+        [a.into(), r.into(), g.into(), b.into()]
+    }
+
+    fn pack(channels: [i32; 4]) -> u32 {
+        let [a, r, g, b] = channels;
+        u32::from_be_bytes([a as u8, r as u8, g as u8, b as u8])
+    }
+
+    fn unpredict(pixels: &mut [u32], width: usize, size_bits: u32, modes: &[u32]) {
+        let blocks_per_row = (width + (1 << size_bits) - 1) >> size_bits;
+        for i in 0..pixels.len() {
+            let (x, y) = (i % width, i / width);
+            let prediction = if i == 0 {
+                0xff00_0000
+            } else if y == 0 {
+                pixels[i - 1]
+            } else if x == 0 {
+                pixels[i - width]
+            } else {
+                let (l, t, tl) = (pixels[i - 1], pixels[i - width], pixels[i - width - 1]);
+                let (cl, ct, ctl) = (channels(l), channels(t), channels(tl));
+                let mut out = [0; 4];
+                match modes[(y >> size_bits) * blocks_per_row + (x >> size_bits)] >> 8 & 0xff {
+                    1 => l,
+                    2 => t,
+                    7 => {
+                        for c in 0..4 {
+                            out[c] = (cl[c] + ct[c]) / 2;
+                        }
+                        pack(out)
+                    }
+                    11 => {
+                        let (mut pl, mut pt) = (0, 0);
+                        for c in 0..4 {
+                            let p = cl[c] + ct[c] - ctl[c];
+                            pl += (p - cl[c]).abs();
+                            pt += (p - ct[c]).abs();
+                        }
+                        if pl < pt {
+                            l
+                        } else {
+                            t
+                        }
+                    }
+                    12 => {
+                        for c in 0..4 {
+                            out[c] = (cl[c] + ct[c] - ctl[c]).clamp(0, 255);
+                        }
+                        pack(out)
+                    }
+                    mode => panic!("predictor mode {} isn't written", mode),
+                }
+            };
+            let (residual, prediction) = (channels(pixels[i]), channels(prediction));
+            let mut sum = [0; 4];
+            for c in 0..4 {
+                sum[c] = (residual[c] + prediction[c]) & 0xff;
+            }
+            pixels[i] = pack(sum);
+        }
+    }
+
+    fn add_green(pixels: &mut [u32]) {
+        for p in pixels {
+            let [a, r, g, b] = channels(*p);
+            *p = pack([a, (r + g) & 0xff, g, (b + g) & 0xff]);
+        }
+    }
+
+    enum Transform {
+        SubtractGreen,
+        Predictor(u32, Vec<u32>),
+    }
+
+    /// Decodes the subset of VP8L that the encoder writes, returning the size, the alpha hint
+    /// and the pixels as ARGB.
+    fn decode(webp: &[u8]) -> (usize, usize, bool, Vec<u32>) {
+        let le = |at: usize| u32::from_le_bytes(webp[at..at + 4].try_into().unwrap()) as usize;
+        assert_eq!(&webp[..4], b"RIFF");
+        assert_eq!(le(4), webp.len() - 8);
+        assert_eq!(&webp[8..16], b"WEBPVP8L");
+        let mut bits = BitReader {
+            data: &webp[20..20 + le(16)],
+            pos: 0,
+        };
+
+        assert_eq!(bits.read(8), SIGNATURE);
+        let width = bits.read(14) as usize + 1;
+        let height = bits.read(14) as usize + 1;
+        let alpha = bits.read(1) == 1;
+        assert_eq!(bits.read(3), 0, "version");
+
+        let mut transforms = Vec::new();
+        while bits.read(1) == 1 {
+            match bits.read(2) {
+                2 => transforms.push(Transform::SubtractGreen),
+                0 => {
+                    let size_bits = bits.read(3) + 2;
+                    let size = |n: usize| (n + (1 << size_bits) - 1) >> size_bits;
+                    let modes = read_image(&mut bits, size(width), size(height), false);
+                    transforms.push(Transform::Predictor(size_bits, modes));
+                }
+                transform => panic!("transform {} isn't written", transform),
+            }
+        }
+
+        let mut pixels = read_image(&mut bits, width, height, true);
+        for transform in transforms.iter().rev() {
+            match transform {
+                Transform::SubtractGreen => add_green(&mut pixels),
+                Transform::Predictor(size_bits, modes) => {
+                    unpredict(&mut pixels, width, *size_bits, modes)
+                }
+            }
+        }
+        (width, height, alpha, pixels)
+    }
+
+    fn encoded(img: &DynamicImage, quality: Option<u8>) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode(img, quality, &mut out).unwrap();
+        out
+    }
+
+    fn argb(img: &DynamicImage) -> Vec<u32> {
+        img.to_rgba8()
+            .pixels()
+            .map(|p| {
+                let [r, g, b, a] = p.0;
+                u32::from_be_bytes([a, r, g, b])
+            })
+            .collect()
+    }
+
+    /// Deterministic noise, so the tests don't need a random number generator.
+    fn noise(seed: u32) -> u8 {
+        (seed.wrapping_mul(2_654_435_761) >> 24) as u8
+    }
+
+    fn assert_round_trip(img: &DynamicImage) {
+        let (width, height, alpha, pixels) = decode(&encoded(img, None));
+        assert_eq!((width as u32, height as u32), img.dimensions());
+        assert_eq!(alpha, img.color().has_alpha());
+        assert!(pixels == argb(img), "pixels differ after decoding");
+    }
+
+    #[test]
+    fn header() {
+        let img = RgbaImage::from_fn(5, 3, |x, y| Rgba([x as u8, y as u8, 0, 128]));
+        let webp = encoded(&DynamicImage::ImageRgba8(img.clone()), None);
+        assert_eq!(&webp[..4], b"RIFF");
+        assert_eq!(
+            u32::from_le_bytes(webp[4..8].try_into().unwrap()) as usize,
+            webp.len() - 8
+        );
+        assert_eq!(&webp[8..16], b"WEBPVP8L");
+        let chunk = u32::from_le_bytes(webp[16..20].try_into().unwrap()) as usize;
+        assert_eq!(20 + chunk + chunk % 2, webp.len());
+
+        // Signature, then 14 bits each of width - 1 and height - 1, the alpha hint and version
+        assert_eq!(webp[20], 0x2f);
+        let header = u32::from_le_bytes(webp[21..25].try_into().unwrap());
+        assert_eq!(header & 0x3fff, 4);
+        assert_eq!(header >> 14 & 0x3fff, 2);
+        assert_eq!(header >> 28 & 1, 1);
+        assert_eq!(header >> 29, 0);
+
+        let opaque = encoded(
+            &DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img).to_rgb8()),
+            None,
+        );
+        let header = u32::from_le_bytes(opaque[21..25].try_into().unwrap());
+        assert_eq!(header >> 28 & 1, 0);
+    }
+
+    #[test]
+    fn round_trip() {
+        // Different content per predictor block, with noise and varying alpha
+        assert_round_trip(&DynamicImage::ImageRgba8(RgbaImage::from_fn(
+            70,
+            45,
+            |x, y| {
+                let n = noise(y * 70 + x);
+                match (x / 32 + y / 32) % 3 {
+                    0 => Rgba([(x * 3) as u8, (y * 5) as u8, n, 255]),
+                    1 => Rgba([n, n / 2, (x + y) as u8, (x * 7) as u8]),
+                    _ => Rgba([n, noise(n.into()), noise(x), n]),
+                }
+            },
+        )));
+        // Repeating rows and columns, and runs longer than the longest copy
+        assert_round_trip(&DynamicImage::ImageRgb8(RgbImage::from_fn(
+            200,
+            100,
+            |x, y| {
+                if y < 50 {
+                    Rgb([noise(x % 13), noise(y % 3), 9])
+                } else {
+                    Rgb([20, 40, 60])
+                }
+            },
+        )));
+        // Two colors get simple prefix codes, a single pixel lone symbols
+        assert_round_trip(&DynamicImage::ImageRgb8(RgbImage::from_fn(9, 4, |x, y| {
+            Rgb([255 * ((x + y) % 2) as u8, 0, 0])
+        })));
+        assert_round_trip(&DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+            1,
+            1,
+            Rgba([1, 2, 3, 4]),
+        )));
+    }
+
+    #[test]
+    fn masked_alpha_survives() {
+        // Like a --mask capture: opaque monitors and fully transparent gaps between them
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(128, 72, |x, y| {
+            let n = noise(y * 128 + x);
+            if (40..60).contains(&x) || y >= 64 {
+                Rgba([0, 0, 0, 0])
+            } else {
+                Rgba([n, n / 3, 200, 255])
+            }
+        }));
+        for &quality in &[None, Some(0), Some(75)] {
+            let (_, _, alpha, pixels) = decode(&encoded(&img, quality));
+            assert!(alpha);
+            let alphas = pixels.iter().map(|p| p >> 24).collect::<Vec<_>>();
+            let expected = argb(&img).iter().map(|p| p >> 24).collect::<Vec<_>>();
+            assert!(alphas == expected, "alpha differs at quality {:?}", quality);
+        }
+    }
+
+    #[test]
+    fn full_quality_is_lossless() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(80, 80, |x, y| {
+            let n = noise(y * 80 + x);
+            Rgba([n, n.wrapping_add(7), 3, 255])
+        }));
+        assert_eq!(encoded(&img, Some(100)), encoded(&img, None));
+    }
+
+    #[test]
+    fn near_lossless_rounds_busy_colors() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(96, 96, |x, y| {
+            let n = noise(y * 96 + x);
+            Rgba([n, noise(n.into()), 128, if x < 48 { 255 } else { n }])
+        }));
+        let lossless = encoded(&img, None);
+        let lossy = encoded(&img, Some(0));
+        assert!(lossy.len() < lossless.len());
+
+        let original = argb(&img);
+        let (_, _, _, pixels) = decode(&lossy);
+        assert_ne!(pixels, original);
+        for (i, (&p, &o)) in pixels.iter().zip(&original).enumerate() {
+            let (x, y) = (i % 96, i / 96);
+            if x == 0 || y == 0 || x == 95 || y == 95 {
+                assert_eq!(p, o, "edge pixel {},{} changed", x, y);
+            }
+            let (p, o) = (channels(p), channels(o));
+            assert_eq!(p[0], o[0], "alpha changed");
+            for c in 1..4 {
+                assert!((p[c] - o[c]).abs() <= 16);
+            }
+        }
+    }
+
+    #[test]
+    fn near_lossless_keeps_gradients() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(100, 100, |x, y| {
+            Rgb([x as u8, y as u8, (x + y) as u8])
+        }));
+        let (_, _, _, pixels) = decode(&encoded(&img, Some(0)));
+        assert!(pixels == argb(&img));
+    }
+
+    #[test]
+    fn small_images_stay_lossless() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(63, 63, |x, y| {
+            Rgb([noise(y * 63 + x), 0, 0])
+        }));
+        assert_eq!(encoded(&img, Some(0)), encoded(&img, None));
+    }
+}
@@ -0,0 +1,221 @@
+//! Encoder for XPM3, the C source format many old X tools read images from.
+//!
+//! XPM is a palette format, so true color images are reduced with median cut first, see
+//! [`quantize`](crate::quantize).
+
+use image::{DynamicImage, GenericImageView};
+use std::io::{self, Write};
+
+use crate::quantize;
+
+/// Used without an explicit limit, which keeps the characters per pixel at two.
+pub(crate) const DEFAULT_COLORS: u16 = 256;
+/// Pixels less opaque than this become transparent, the rest fully opaque.
+const ALPHA_THRESHOLD: u8 = 128;
+
+/// Encodes the image as XPM3 with at most `max_colors` colors, plus one for transparency.
+pub fn encode(
+    img: &DynamicImage,
+    max_colors: u16,
+    dither: bool,
+    w: &mut impl Write,
+) -> io::Result<()> {
+    let (width, height) = img.dimensions();
+    let indexed = quantize::reduce(&img.to_rgba8(), max_colors.into(), ALPHA_THRESHOLD, dither);
+    let palette = &indexed.palette;
+    let transparent = indexed.has_transparency();
+    let chars = key_chars();
+    let total = palette.len() + usize::from(transparent);
+    let key_len = (1..)
+        .find(|&len| chars.len().pow(len) >= total)
+        .unwrap_or(1) as usize;
+    let key = |mut i: usize| {
+        let mut key = Vec::with_capacity(key_len);
+        for _ in 0..key_len {
+            key.push(chars[i % chars.len()]);
+            i /= chars.len();
+        }
+        key
+    };
+    // Transparency takes the last key, after all colors
+    let transparent_key = key(palette.len());
+
+    let mut out = Vec::with_capacity(64 * 1024);
+    out.extend_from_slice(b"/* XPM */\nstatic char *background[] = {\n");
+    out.extend_from_slice(b"/* columns rows colors chars-per-pixel */\n");
+    writeln!(out, "\"{} {} {} {}\",", width, height, total, key_len)?;
+    for (i, color) in palette.iter().enumerate() {
+        out.push(b'"');
+        out.extend_from_slice(&key(i));
+        writeln!(
+            out,
+            " c #{:02x}{:02x}{:02x}\",",
+            color[0], color[1], color[2]
+        )?;
+    }
+    if transparent {
+        out.push(b'"');
+        out.extend_from_slice(&transparent_key);
+        out.extend_from_slice(b" c None\",\n");
+    }
+    out.extend_from_slice(b"/* pixels */\n");
+
+    let keys = (0..palette.len()).map(key).collect::<Vec<_>>();
+    for (y, row) in indexed.pixels.chunks(width as usize).enumerate() {
+        out.push(b'"');
+        for p in row {
+            match p {
+                Some(i) => out.extend_from_slice(&keys[usize::from(*i)]),
+                None => out.extend_from_slice(&transparent_key),
+            }
+        }
+        out.extend_from_slice(if y + 1 < height as usize {
+            b"\",\n"
+        } else {
+            b"\"\n"
+        });
+        if out.len() >= 64 * 1024 {
+            w.write_all(&out)?;
+            out.clear();
+        }
+    }
+    out.extend_from_slice(b"};\n");
+
+    w.write_all(&out)
+}
+
+/// Printable ASCII except for the quote and backslash, which would need escaping.
+fn key_chars() -> Vec<u8> {
+    (b' '..=b'~').filter(|&c| c != b'"' && c != b'\\').collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    use std::collections::HashMap;
+
+    /// The header values and the pixels, `None` for transparent ones.
+    struct Decoded {
+        width: usize,
+        height: usize,
+        colors: usize,
+        cpp: usize,
+        pixels: Vec<Option<[u8; 3]>>,
+    }
+
+    fn decode(xpm: &[u8]) -> Decoded {
+        let xpm = std::str::from_utf8(xpm).unwrap();
+        assert!(xpm.starts_with("/* XPM */\n"));
+        assert!(xpm.ends_with("};\n"));
+        // Everything between the first and last quote of lines that have them
+        let mut strings = xpm
+            .lines()
+            .filter(|line| line.starts_with('"'))
+            .map(|line| &line[1..line.rfind('"').unwrap()]);
+
+        let values = strings
+            .next()
+            .unwrap()
+            .split(' ')
+            .map(|v| v.parse().unwrap())
+            .collect::<Vec<usize>>();
+        let (width, height, colors, cpp) = (values[0], values[1], values[2], values[3]);
+        let palette = (0..colors)
+            .map(|_| {
+                let color = strings.next().unwrap();
+                let (key, value) = color.split_at(cpp);
+                let value = match value.strip_prefix(" c ").unwrap() {
+                    "None" => None,
+                    hex => {
+                        let hex = hex.strip_prefix('#').unwrap();
+                        let channel =
+                            |i: usize| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+                        Some([channel(0), channel(1), channel(2)])
+                    }
+                };
+                (key, value)
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(palette.len(), colors, "keys must be unique");
+
+        let mut pixels = Vec::with_capacity(width * height);
+        for _ in 0..height {
+            let row = strings.next().unwrap();
+            assert_eq!(row.len(), width * cpp);
+            for i in 0..width {
+                pixels.push(palette[&row[i * cpp..(i + 1) * cpp]]);
+            }
+        }
+        assert_eq!(strings.next(), None);
+
+        Decoded {
+            width,
+            height,
+            colors,
+            cpp,
+            pixels,
+        }
+    }
+
+    fn encoded(img: &RgbaImage, max_colors: u16) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode(
+            &DynamicImage::ImageRgba8(img.clone()),
+            max_colors,
+            false,
+            &mut out,
+        )
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn header_and_pixels() {
+        let colors = [[255, 0, 0], [0, 128, 255], [1, 2, 3]];
+        let img = RgbaImage::from_fn(7, 4, |x, y| {
+            let [r, g, b] = colors[((x + y) % 3) as usize];
+            Rgba([r, g, b, if x == 6 { 0 } else { 255 }])
+        });
+        let xpm = decode(&encoded(&img, DEFAULT_COLORS));
+        assert_eq!((xpm.width, xpm.height), (7, 4));
+        // Three colors and transparency fit into one character each
+        assert_eq!((xpm.colors, xpm.cpp), (4, 1));
+        for (pixel, original) in xpm.pixels.iter().zip(img.pixels()) {
+            if original[3] < ALPHA_THRESHOLD {
+                assert_eq!(*pixel, None);
+            } else {
+                assert_eq!(*pixel, Some([original[0], original[1], original[2]]));
+            }
+        }
+    }
+
+    #[test]
+    fn colors_stay_within_limit() {
+        let img = RgbaImage::from_fn(40, 30, |x, y| Rgba([x as u8 * 6, y as u8 * 8, 0, 255]));
+        for &max_colors in &[1, 16, 200] {
+            let xpm = decode(&encoded(&img, max_colors));
+            assert!(xpm.colors <= usize::from(max_colors));
+            assert!(xpm.pixels.iter().all(Option::is_some));
+            assert_eq!(xpm.cpp, if xpm.colors > 92 { 2 } else { 1 });
+        }
+    }
+
+    #[test]
+    fn transparency_counts_extra() {
+        // Partially transparent pixels are split at the threshold
+        let img = RgbaImage::from_fn(20, 20, |x, y| {
+            Rgba([
+                x as u8 * 12,
+                y as u8 * 12,
+                0,
+                if x < 10 { 127 } else { 128 },
+            ])
+        });
+        let xpm = decode(&encoded(&img, 8));
+        assert!(xpm.colors <= 9);
+        for (i, pixel) in xpm.pixels.iter().enumerate() {
+            assert_eq!(pixel.is_none(), i % 20 < 10);
+        }
+    }
+}